@@ -31,6 +31,15 @@ pub trait Asset: Send + Sync + 'static {
 
     /// The ECS storage type to be used. You'll want to use `VecStorage` in most cases.
     type HandleStorage: UnprotectedStorage<Handle<Self>> + Send + Sync;
+
+    /// Returns the approximate amount of memory used by the asset, in bytes.
+    ///
+    /// This is used for the memory budget of the `AssetStorage`, see
+    /// `AssetStorage::set_memory_budget`. The default implementation returns `0`,
+    /// which leaves the asset out of the budget.
+    fn memory_size(&self) -> usize {
+        0
+    }
 }
 
 /// A format, providing a conversion from bytes to asset data, which is then
@@ -0,0 +1,590 @@
+use std::{
+    collections::HashSet,
+    fs,
+    hash::Hasher,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use fnv::FnvHasher;
+use log::{debug, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use amethyst_error::{format_err, Error, ResultExt};
+
+use crate::{Asset, Directory, Format, FormatValue, Reload, Source};
+
+// Bumped when the layout of the cached files changes, invalidating every cached asset.
+const CACHE_VERSION: u32 = 2;
+
+// Written before the data of a cached asset: what its file name is hashed from, besides the bytes
+// of the asset of which only the size is kept. It is checked when the asset is loaded, so a hash
+// collision reads as a missing asset instead of the data of another one.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct CacheKey {
+    version: u32,
+    asset: String,
+    format: String,
+    name: String,
+    options: Vec<u8>,
+    size: u64,
+}
+
+impl CacheKey {
+    fn new<A, F>(name: &str, bytes: &[u8], options: &F::Options) -> Result<Self, Error>
+    where
+        A: Asset,
+        F: Format<A>,
+        F::Options: Serialize,
+    {
+        let options = bincode::serialize(options)
+            .with_context(|_| format_err!("Failed serializing {} options", F::NAME))?;
+        Ok(CacheKey {
+            version: CACHE_VERSION,
+            asset: A::NAME.to_string(),
+            format: F::NAME.to_string(),
+            name: name.to_string(),
+            options,
+            size: bytes.len() as u64,
+        })
+    }
+
+    fn hash(&self, bytes: &[u8]) -> u64 {
+        let mut hasher = FnvHasher::default();
+        hasher.write_u32(self.version);
+        hasher.write(self.asset.as_bytes());
+        hasher.write(self.format.as_bytes());
+        hasher.write(self.name.as_bytes());
+        hasher.write(&self.options);
+        hasher.write(bytes);
+        hasher.finish()
+    }
+}
+
+/// A directory of assets preprocessed to their `Asset::Data`, encoded with bincode.
+///
+/// Assets are cached by a hash of their name and bytes, of their format and of the format options,
+/// so a cached asset is only used as long as the file it was imported from is unchanged. The
+/// cached files start with these, besides the bytes, which are checked when they are loaded.
+/// Formats reading other files than the asset itself, like the buffers of glTF files,
+/// only have the main file checked.
+///
+/// Assets are loaded through the cache with `Loader::load_cached`, once the cache is set with
+/// `Loader::set_cache`. The cache can be built ahead of time with a `CachePrebuilder`.
+#[derive(Clone, Debug)]
+pub struct BinaryCache {
+    directory: PathBuf,
+}
+
+impl BinaryCache {
+    /// Creates a cache storing the assets in the given directory, which is created when the
+    /// first asset is cached.
+    pub fn new<P>(directory: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        BinaryCache {
+            directory: directory.into(),
+        }
+    }
+
+    /// Returns the directory of the cache.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Removes every cached asset.
+    pub fn clear(&self) -> Result<(), Error> {
+        match fs::remove_dir_all(&self.directory) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.with_context(|_| {
+                format_err!("Failed clearing the asset cache {:?}", self.directory)
+            }),
+        }
+    }
+
+    /// Imports an asset and caches its data, unless the cached data is still valid.
+    ///
+    /// Returns `true` if the asset was imported, `false` if the cache was up to date.
+    pub fn prebuild<A, F>(
+        &self,
+        name: &str,
+        format: &F,
+        options: F::Options,
+        source: Arc<dyn Source>,
+    ) -> Result<bool, Error>
+    where
+        A: Asset,
+        A::Data: Serialize + DeserializeOwned,
+        F: Format<A>,
+        F::Options: Serialize + Clone,
+    {
+        self.prebuild_file::<A, F>(name, format, options, source)
+            .map(|(built, _)| built)
+    }
+
+    // Like `prebuild`, also returning the path of the cached file.
+    fn prebuild_file<A, F>(
+        &self,
+        name: &str,
+        format: &F,
+        options: F::Options,
+        source: Arc<dyn Source>,
+    ) -> Result<(bool, PathBuf), Error>
+    where
+        A: Asset,
+        A::Data: Serialize + DeserializeOwned,
+        F: Format<A>,
+        F::Options: Serialize + Clone,
+    {
+        let bytes = source.load(name)?;
+        let key = CacheKey::new::<A, F>(name, &bytes, &options)?;
+        let hash = key.hash(&bytes);
+        if self.read(&key, hash).is_some() {
+            return Ok((false, self.path(hash)));
+        }
+        let data = format
+            .import(name.to_string(), source, options, false)?
+            .data;
+        self.store::<A>(&key, hash, &data)?;
+        Ok((true, self.path(hash)))
+    }
+
+    fn path(&self, hash: u64) -> PathBuf {
+        self.directory.join(format!("{:016x}.bin", hash))
+    }
+
+    // Reads a cached file, returning its data if its key matches.
+    fn read(&self, key: &CacheKey, hash: u64) -> Option<Vec<u8>> {
+        let path = self.path(hash);
+        let bytes = fs::read(&path).ok()?;
+        let mut data = &bytes[..];
+        match bincode::deserialize_from::<_, CacheKey>(&mut data) {
+            Ok(ref cached) if cached == key => Some(data.to_vec()),
+            Ok(cached) => {
+                warn!(
+                    "Ignoring cached {:?} {:?}, it is {:?}",
+                    key.name, path, cached.name
+                );
+                None
+            }
+            Err(e) => {
+                warn!("Ignoring invalid cached {} {:?}: {}", key.asset, path, e);
+                None
+            }
+        }
+    }
+
+    fn load<A>(&self, key: &CacheKey, hash: u64) -> Option<A::Data>
+    where
+        A: Asset,
+        A::Data: DeserializeOwned,
+    {
+        let bytes = self.read(key, hash)?;
+        match bincode::deserialize(&bytes) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                warn!(
+                    "Ignoring invalid cached {} {:?}: {}",
+                    A::NAME,
+                    self.path(hash),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    // Writes the key and data of an asset, checking the data can be read back.
+    fn store<A>(&self, key: &CacheKey, hash: u64, data: &A::Data) -> Result<(), Error>
+    where
+        A: Asset,
+        A::Data: Serialize + DeserializeOwned,
+    {
+        let data = bincode::serialize(data)
+            .with_context(|_| format_err!("Failed serializing {} data", A::NAME))?;
+        bincode::deserialize::<A::Data>(&data)
+            .with_context(|_| format_err!("{} data can't be read back from bincode", A::NAME))?;
+        let mut bytes = bincode::serialize(key)
+            .with_context(|_| format_err!("Failed serializing {} cache key", A::NAME))?;
+        bytes.extend(data);
+
+        fs::create_dir_all(&self.directory).with_context(|_| {
+            format_err!("Failed creating the asset cache {:?}", self.directory)
+        })?;
+        // Written aside first, so loading threads never read a partial file.
+        let path = self.path(hash);
+        let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&temp, bytes)
+            .and_then(|_| fs::rename(&temp, &path))
+            .with_context(|_| format_err!("Failed writing cached asset {:?}", path))?;
+        Ok(())
+    }
+}
+
+/// Loads an asset through the cache, importing it with `format` and caching its data when the
+/// cache is missing or outdated.
+pub(crate) fn import<A, F>(
+    cache: &Arc<BinaryCache>,
+    format: F,
+    name: String,
+    source: Arc<dyn Source>,
+    options: F::Options,
+    create_reload: bool,
+) -> Result<FormatValue<A>, Error>
+where
+    A: Asset,
+    A::Data: Serialize + DeserializeOwned,
+    F: Format<A> + Clone + Sync,
+    F::Options: Serialize + Clone + Sync,
+{
+    let (bytes, modified) = if create_reload {
+        source.load_with_metadata(&name)?
+    } else {
+        (source.load(&name)?, 0)
+    };
+
+    let key = CacheKey::new::<A, F>(&name, &bytes, &options)?;
+    let hash = key.hash(&bytes);
+    let data = match cache.load::<A>(&key, hash) {
+        Some(data) => {
+            debug!("{:?}: Asset {:?} loaded from the cache", A::NAME, name);
+            data
+        }
+        None => {
+            let data = format
+                .import(name.clone(), source.clone(), options.clone(), false)?
+                .data;
+            if let Err(e) = cache.store::<A>(&key, hash, &data) {
+                warn!("{:?}: Asset {:?} could not be cached: {}", A::NAME, name, e);
+            }
+            data
+        }
+    };
+
+    let reload = if create_reload {
+        let reload = CachedReload {
+            format,
+            cache: cache.clone(),
+            modified,
+            options,
+            path: name,
+            source,
+        };
+        Some(Box::new(reload) as Box<dyn Reload<A>>)
+    } else {
+        None
+    };
+    Ok(FormatValue { data, reload })
+}
+
+// Like `SingleFile`, reloading through the cache.
+struct CachedReload<A: Asset, F: Format<A>> {
+    format: F,
+    cache: Arc<BinaryCache>,
+    modified: u64,
+    options: F::Options,
+    path: String,
+    source: Arc<dyn Source>,
+}
+
+impl<A, F> Clone for CachedReload<A, F>
+where
+    A: Asset,
+    F: Clone + Format<A>,
+    F::Options: Clone,
+{
+    fn clone(&self) -> Self {
+        CachedReload {
+            format: self.format.clone(),
+            cache: self.cache.clone(),
+            modified: self.modified,
+            options: self.options.clone(),
+            path: self.path.clone(),
+            source: self.source.clone(),
+        }
+    }
+}
+
+impl<A, F> Reload<A> for CachedReload<A, F>
+where
+    A: Asset,
+    A::Data: Serialize + DeserializeOwned,
+    F: Clone + Format<A> + Sync,
+    F::Options: Serialize + Clone + Sync,
+{
+    fn needs_reload(&self) -> bool {
+        self.modified != 0 && (self.source.modified(&self.path).unwrap_or(0) > self.modified)
+    }
+
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn format(&self) -> &'static str {
+        F::NAME
+    }
+
+    fn reload(self: Box<Self>) -> Result<FormatValue<A>, Error> {
+        let CachedReload {
+            format,
+            cache,
+            options,
+            path,
+            source,
+            ..
+        } = *self;
+        import(&cache, format, path, source, options, true)
+    }
+}
+
+/// Outcome of `CachePrebuilder::build`, listing the asset names.
+#[derive(Debug, Default)]
+pub struct PrebuildReport {
+    /// Assets which were imported and cached
+    pub built: Vec<String>,
+    /// Assets already cached
+    pub up_to_date: Vec<String>,
+    /// Assets which could not be cached
+    pub failed: Vec<(String, Error)>,
+    /// Cached files deleted as no asset of the directory was cached in them anymore
+    pub removed: Vec<PathBuf>,
+}
+
+type Prebuild = Box<dyn Fn(&BinaryCache, &str, Arc<dyn Source>) -> Result<(bool, PathBuf), Error>>;
+
+/// Builds a `BinaryCache` ahead of time, for the assets of a directory.
+///
+/// Every asset whose name ends with a registered suffix is imported with the format registered
+/// for it, for example `.obj` for all meshes, or `prefab/scene.ron` for a single prefab.
+///
+/// ### Example:
+///
+/// ```rust,ignore
+/// let report = CachePrebuilder::new(BinaryCache::new("cache"))
+///     .with_format::<Mesh, _>(".obj", ObjFormat, ())
+///     .build("assets")?;
+/// ```
+pub struct CachePrebuilder {
+    cache: BinaryCache,
+    formats: Vec<(String, Prebuild)>,
+}
+
+impl CachePrebuilder {
+    /// Creates a prebuilder filling the given cache.
+    pub fn new(cache: BinaryCache) -> Self {
+        CachePrebuilder {
+            cache,
+            formats: Vec::new(),
+        }
+    }
+
+    /// Imports the assets whose names end with `suffix` as `A`, with the given format.
+    ///
+    /// The first registered suffix matching an asset is used.
+    pub fn with_format<A, F>(mut self, suffix: &str, format: F, options: F::Options) -> Self
+    where
+        A: Asset,
+        A::Data: Serialize + DeserializeOwned,
+        F: Format<A>,
+        F::Options: Serialize + Clone,
+    {
+        self.formats.push((
+            suffix.to_string(),
+            Box::new(
+                move |cache: &BinaryCache, name: &str, source: Arc<dyn Source>| {
+                    cache.prebuild_file::<A, F>(name, &format, options.clone(), source)
+                },
+            ),
+        ));
+        self
+    }
+
+    /// Caches the matching assets of the directory.
+    ///
+    /// Asset names are the paths relative to the directory, as they are given to the `Loader`.
+    /// The other cached assets are deleted, including the outdated versions of the assets and the
+    /// ones cached while loading, so the cache holds the assets of the directory only.
+    pub fn build<P>(&self, directory: P) -> Result<PrebuildReport, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let directory = directory.as_ref();
+        let mut names = Vec::new();
+        list_files(directory, "", &mut names)
+            .with_context(|_| format_err!("Failed listing the assets of {:?}", directory))?;
+        names.sort();
+
+        let source = Arc::new(Directory::new(directory)) as Arc<dyn Source>;
+        let mut report = PrebuildReport::default();
+        let mut cached = HashSet::new();
+        for name in names {
+            let prebuild = match self.formats.iter().find(|(s, _)| name.ends_with(&**s)) {
+                Some((_, prebuild)) => prebuild,
+                None => continue,
+            };
+            match prebuild(&self.cache, &name, source.clone()) {
+                Ok((true, path)) => {
+                    cached.insert(path);
+                    report.built.push(name);
+                }
+                Ok((false, path)) => {
+                    cached.insert(path);
+                    report.up_to_date.push(name);
+                }
+                Err(e) => report.failed.push((name, e)),
+            }
+        }
+        report.removed = self.remove_stale(&cached).with_context(|_| {
+            format_err!("Failed deleting stale assets of {:?}", self.cache.directory)
+        })?;
+        Ok(report)
+    }
+
+    // Deletes the cached files not in `cached`, returning their paths.
+    fn remove_stale(&self, cached: &HashSet<PathBuf>) -> io::Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(&self.cache.directory) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            entries => entries?,
+        };
+        let mut removed = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map_or(false, |e| e == "bin") && !cached.contains(&path) {
+                fs::remove_file(&path)?;
+                removed.push(path);
+            }
+        }
+        removed.sort();
+        Ok(removed)
+    }
+}
+
+fn list_files(directory: &Path, prefix: &str, names: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &format!("{}/", name), names)?;
+        } else {
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process, sync::Arc};
+
+    use amethyst_core::specs::prelude::VecStorage;
+
+    use crate::{Asset, Handle, Memory, RonFormat, Source};
+
+    use super::{import, BinaryCache, CacheKey, CachePrebuilder};
+
+    #[derive(Debug, PartialEq)]
+    struct Value(Vec<u32>);
+
+    impl Asset for Value {
+        const NAME: &'static str = "Value";
+        type Data = Vec<u32>;
+        type HandleStorage = VecStorage<Handle<Self>>;
+    }
+
+    #[test]
+    fn cached_assets_are_reused_while_unchanged() {
+        let cache = Arc::new(BinaryCache::new(
+            env::temp_dir().join(format!("amethyst_binary_cache_{}", process::id())),
+        ));
+        cache.clear().unwrap();
+        let memory = Memory::new();
+        memory.insert("value.ron", "[1, 2]");
+        let source = Arc::new(memory.clone()) as Arc<dyn Source>;
+        let prebuild = || {
+            cache
+                .prebuild::<Value, _>("value.ron", &RonFormat, (), source.clone())
+                .unwrap()
+        };
+
+        assert!(prebuild());
+        assert!(!prebuild());
+
+        let value = import::<Value, _>(
+            &cache,
+            RonFormat,
+            "value.ron".into(),
+            source.clone(),
+            (),
+            true,
+        )
+        .unwrap();
+        assert_eq!(value.data, vec![1, 2]);
+        assert!(value.reload.is_some());
+
+        memory.insert("value.ron", "[3]");
+        let value = import::<Value, _>(
+            &cache,
+            RonFormat,
+            "value.ron".into(),
+            source.clone(),
+            (),
+            false,
+        )
+        .unwrap();
+        assert_eq!(value.data, vec![3]);
+        assert!(!prebuild());
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn cached_assets_of_other_keys_are_ignored() {
+        let cache = BinaryCache::new(
+            env::temp_dir().join(format!("amethyst_binary_cache_keys_{}", process::id())),
+        );
+        cache.clear().unwrap();
+        let source = Arc::new(Memory::new().with_asset("value.ron", "[1, 2]")) as Arc<dyn Source>;
+        assert!(cache
+            .prebuild::<Value, _>("value.ron", &RonFormat, (), source.clone())
+            .unwrap());
+
+        let bytes = b"[1, 2]";
+        let key = CacheKey::new::<Value, RonFormat>("value.ron", bytes, &()).unwrap();
+        let hash = key.hash(bytes);
+        assert_eq!(cache.load::<Value>(&key, hash), Some(vec![1, 2]));
+        // As if the hash of another asset collided with this one.
+        let other = CacheKey::new::<Value, RonFormat>("other.ron", bytes, &()).unwrap();
+        fs::copy(cache.path(hash), cache.path(other.hash(bytes))).unwrap();
+        assert_eq!(cache.load::<Value>(&other, hash), None);
+        assert_eq!(cache.load::<Value>(&other, other.hash(bytes)), None);
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn prebuilding_removes_stale_assets() {
+        let root = env::temp_dir().join(format!("amethyst_cache_prebuilder_{}", process::id()));
+        let assets = root.join("assets");
+        fs::create_dir_all(&assets).unwrap();
+        fs::write(assets.join("value.ron"), "[1, 2]").unwrap();
+        let cache = BinaryCache::new(root.join("cache"));
+        let prebuilder =
+            CachePrebuilder::new(cache.clone()).with_format::<Value, _>(".ron", RonFormat, ());
+
+        let report = prebuilder.build(&assets).unwrap();
+        assert_eq!(report.built, vec!["value.ron".to_string()]);
+        assert!(report.removed.is_empty());
+        let old: Vec<_> = fs::read_dir(cache.directory())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(old.len(), 1);
+
+        fs::write(assets.join("value.ron"), "[3]").unwrap();
+        let report = prebuilder.build(&assets).unwrap();
+        assert_eq!(report.built, vec!["value.ron".to_string()]);
+        assert_eq!(report.removed, old);
+        assert_eq!(fs::read_dir(cache.directory()).unwrap().count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+//! Tracking of the assets loaded on behalf of other assets.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+use log::warn;
+use parking_lot::RwLock;
+
+/// An asset of the `Dependencies` graph, identified by the source it is loaded from, its type
+/// and its name, so assets with the same name in different sources or of different types are
+/// told apart.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct AssetKey {
+    /// Id of the source the asset is loaded from, empty for the default source
+    pub source: String,
+    /// Type of the asset, as given by `Asset::NAME`
+    pub asset_type: &'static str,
+    /// Name the asset is loaded with
+    pub name: String,
+}
+
+impl AssetKey {
+    /// Creates the key of the asset with the given name and type, loaded from the source with
+    /// the given id.
+    pub fn new(source: &str, asset_type: &'static str, name: &str) -> Self {
+        AssetKey {
+            source: source.to_string(),
+            asset_type,
+            name: name.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for AssetKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self.source.as_str() {
+            "" => "[default source]",
+            other => other,
+        };
+        write!(f, "{} {:?} from {:?}", self.asset_type, self.name, source)
+    }
+}
+
+/// Graph of the dependencies between assets, by `AssetKey`.
+///
+/// An asset depends on the assets loaded while it is processed, for example the sub assets a
+/// `Prefab` loads with `PrefabData::load_sub_assets`. The `Loader` records these dependencies,
+/// and `Loader::dependencies` gives access to the graph for inspection.
+///
+/// When an asset is hot reloaded, the assets depending on it are reloaded the frame after its
+/// reload is processed, so a change cascades from the dependencies to the dependents in order.
+/// Dependencies which would create a cycle are ignored, and the assets are forgotten once freed.
+#[derive(Clone, Default)]
+pub struct Dependencies {
+    inner: Arc<RwLock<Graph>>,
+}
+
+#[derive(Default)]
+struct Graph {
+    // assets depended on, by dependent.
+    dependencies: HashMap<AssetKey, HashSet<AssetKey>>,
+    // dependents, by asset depended on.
+    dependents: HashMap<AssetKey, HashSet<AssetKey>>,
+    // dependents of the reloaded assets, waiting to be reloaded.
+    pending: HashSet<AssetKey>,
+}
+
+impl Dependencies {
+    /// Creates an empty graph.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Records that `dependent` depends on `dependency`, which is mostly useful for formats
+    /// reading several files.
+    ///
+    /// Returns `false` if the dependency is ignored because it would create a cycle.
+    pub fn add(&self, dependent: &AssetKey, dependency: &AssetKey) -> bool {
+        let mut graph = self.inner.write();
+        if dependent == dependency || graph.depends_on(dependency, dependent) {
+            warn!(
+                "Ignoring the dependency of {} on {}, which would create a cycle",
+                dependent, dependency
+            );
+            return false;
+        }
+
+        graph
+            .dependencies
+            .entry(dependent.clone())
+            .or_insert_with(HashSet::new)
+            .insert(dependency.clone());
+        graph
+            .dependents
+            .entry(dependency.clone())
+            .or_insert_with(HashSet::new)
+            .insert(dependent.clone());
+        true
+    }
+
+    /// Forgets the dependencies of the given asset, they are recorded again when it is reloaded.
+    pub fn clear(&self, dependent: &AssetKey) {
+        self.inner.write().clear(dependent);
+    }
+
+    /// Forgets the given asset, with its dependencies and its dependents.
+    ///
+    /// The `AssetStorage` calls it once the asset is freed or unloaded.
+    pub fn remove(&self, asset: &AssetKey) {
+        let mut graph = self.inner.write();
+        graph.clear(asset);
+        for dependent in graph.dependents.remove(asset).unwrap_or_default() {
+            let now_independent =
+                graph
+                    .dependencies
+                    .get_mut(&dependent)
+                    .map_or(false, |dependencies| {
+                        dependencies.remove(asset);
+                        dependencies.is_empty()
+                    });
+            if now_independent {
+                graph.dependencies.remove(&dependent);
+            }
+        }
+        graph.pending.remove(asset);
+    }
+
+    /// Returns the assets the given asset depends on directly, sorted.
+    pub fn dependencies_of(&self, asset: &AssetKey) -> Vec<AssetKey> {
+        sorted(self.inner.read().dependencies.get(asset))
+    }
+
+    /// Returns the assets depending directly on the given asset, sorted.
+    pub fn dependents_of(&self, asset: &AssetKey) -> Vec<AssetKey> {
+        sorted(self.inner.read().dependents.get(asset))
+    }
+
+    /// Marks the direct dependents of a reloaded asset to be reloaded.
+    pub(crate) fn reloaded(&self, asset: &AssetKey) {
+        let mut graph = self.inner.write();
+        let dependents = sorted(graph.dependents.get(asset));
+        graph.pending.extend(dependents);
+    }
+
+    /// Takes the assets waiting to be reloaded.
+    pub(crate) fn take_pending(&self) -> Vec<AssetKey> {
+        self.inner.write().pending.drain().collect()
+    }
+}
+
+impl Graph {
+    fn clear(&mut self, dependent: &AssetKey) {
+        for dependency in self.dependencies.remove(dependent).unwrap_or_default() {
+            let now_unused = self
+                .dependents
+                .get_mut(&dependency)
+                .map_or(false, |dependents| {
+                    dependents.remove(dependent);
+                    dependents.is_empty()
+                });
+            if now_unused {
+                self.dependents.remove(&dependency);
+            }
+        }
+    }
+
+    // Returns `true` if `dependent` depends on `dependency`, directly or not.
+    fn depends_on(&self, dependent: &AssetKey, dependency: &AssetKey) -> bool {
+        self.dependents_closure(dependency).contains(dependent)
+    }
+
+    // Returns every asset depending on `asset`, directly or not.
+    fn dependents_closure(&self, asset: &AssetKey) -> HashSet<AssetKey> {
+        let mut found = HashSet::new();
+        let mut stack = vec![asset];
+        while let Some(asset) = stack.pop() {
+            for dependent in self.dependents.get(asset).into_iter().flatten() {
+                if found.insert(dependent.clone()) {
+                    stack.push(dependent);
+                }
+            }
+        }
+        found
+    }
+}
+
+fn sorted(assets: Option<&HashSet<AssetKey>>) -> Vec<AssetKey> {
+    let mut assets: Vec<AssetKey> = assets.into_iter().flatten().cloned().collect();
+    assets.sort();
+    assets
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AssetKey, Dependencies};
+
+    fn key(name: &str) -> AssetKey {
+        AssetKey::new("", "Prefab", name)
+    }
+
+    fn keys(names: &[&str]) -> Vec<AssetKey> {
+        names.iter().map(|name| key(name)).collect()
+    }
+
+    #[test]
+    fn dependents_are_reloaded_after_their_dependencies() {
+        let dependencies = Dependencies::new();
+        let (level, player, texture) = (key("level.ron"), key("player.ron"), key("texture.png"));
+        assert!(dependencies.add(&level, &player));
+        assert!(dependencies.add(&level, &texture));
+        assert!(dependencies.add(&player, &texture));
+        assert!(!dependencies.add(&texture, &level));
+        assert!(!dependencies.add(&texture, &texture));
+
+        assert_eq!(
+            dependencies.dependencies_of(&level),
+            keys(&["player.ron", "texture.png"])
+        );
+        assert_eq!(
+            dependencies.dependents_of(&texture),
+            keys(&["level.ron", "player.ron"])
+        );
+
+        dependencies.reloaded(&texture);
+        let mut pending = dependencies.take_pending();
+        pending.sort();
+        assert_eq!(pending, keys(&["level.ron", "player.ron"]));
+        assert!(dependencies.take_pending().is_empty());
+
+        dependencies.clear(&level);
+        assert_eq!(dependencies.dependents_of(&texture), keys(&["player.ron"]));
+        assert!(dependencies.dependents_of(&player).is_empty());
+
+        // A freed asset is forgotten, and not reloaded anymore.
+        assert!(dependencies.add(&level, &player));
+        dependencies.reloaded(&texture);
+        dependencies.remove(&player);
+        assert!(dependencies.dependents_of(&texture).is_empty());
+        assert!(dependencies.dependencies_of(&level).is_empty());
+        assert!(dependencies.take_pending().is_empty());
+    }
+
+    #[test]
+    fn assets_with_the_same_name_are_told_apart_by_source_and_type() {
+        let dependencies = Dependencies::new();
+        let base = key("tree.ron");
+        let modded = AssetKey::new("mods", "Prefab", "tree.ron");
+        let mesh = AssetKey::new("mods", "Mesh", "tree.ron");
+
+        // A mod's prefab extending the base one with the same name isn't a cycle.
+        assert!(dependencies.add(&modded, &base));
+        assert!(dependencies.add(&modded, &mesh));
+        assert!(!dependencies.add(&base, &modded));
+        assert_eq!(
+            dependencies.dependencies_of(&modded),
+            vec![base.clone(), mesh.clone()]
+        );
+        assert!(dependencies.dependencies_of(&base).is_empty());
+
+        dependencies.reloaded(&base);
+        assert_eq!(dependencies.take_pending(), vec![modded.clone()]);
+
+        // Freeing the mod's prefab leaves the base one alone.
+        dependencies.remove(&modded);
+        assert!(dependencies.dependents_of(&base).is_empty());
+        assert!(dependencies.dependents_of(&mesh).is_empty());
+        assert!(dependencies.add(&base, &mesh));
+        assert_eq!(dependencies.dependencies_of(&base), vec![mesh]);
+    }
+}
@@ -8,8 +8,6 @@ pub enum Error {
     Source,
     #[error(display = "Format {:?} could not load asset", _0)]
     Format(&'static str),
-    #[error(display = "Asset was loaded but no handle to it was saved.")]
-    UnusedHandle,
     #[error(display = "Some error has occurred")]
     #[doc(hidden)]
     __Nonexhaustive,
@@ -7,6 +7,19 @@ use amethyst_error::{format_err, Error, ResultExt};
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct RonFormat;
 
+impl RonFormat {
+    /// Writes a value as a Ron string which can be loaded back with this format,
+    /// for example a `Prefab` created with `Prefab::capture`.
+    pub fn export<T>(&self, value: &T) -> Result<String, Error>
+    where
+        T: Serialize,
+    {
+        use ron::ser::{to_string_pretty, PrettyConfig};
+        Ok(to_string_pretty(value, PrettyConfig::default())
+            .with_context(|_| format_err!("Failed serializing Ron file"))?)
+    }
+}
+
 impl<T> SimpleFormat<T> for RonFormat
 where
     T: Asset,
@@ -13,24 +13,32 @@
 pub use crate::formats::JsonFormat;
 pub use crate::{
     asset::{Asset, Format, FormatValue, SimpleFormat},
+    binary_cache::{BinaryCache, CachePrebuilder, PrebuildReport},
     cache::Cache,
+    dependencies::{AssetKey, Dependencies},
     formats::RonFormat,
     helper::AssetLoaderSystemData,
     loader::Loader,
-    prefab::{AssetPrefab, Prefab, PrefabData, PrefabLoader, PrefabLoaderSystem},
-    progress::{Completion, Progress, ProgressCounter, Tracker},
+    prefab::{
+        AssetPrefab, Prefab, PrefabData, PrefabLoader, PrefabLoaderSystem, PrefabOverride,
+        PrefabReference,
+    },
+    progress::{AssetProgress, Completion, Progress, ProgressCounter, Tracker, TypeProgress},
     reload::{HotReloadBundle, HotReloadStrategy, HotReloadSystem, Reload, SingleFile},
-    source::{Directory, Source},
+    source::{Archive, Directory, Layered, Memory, Source},
     storage::{AssetStorage, Handle, ProcessingState, Processor, WeakHandle},
 };
 
 mod asset;
+mod binary_cache;
 mod cache;
+mod dependencies;
 mod error;
 mod formats;
 mod helper;
 mod loader;
 mod prefab;
+mod processing;
 mod progress;
 mod reload;
 mod source;
@@ -1,17 +1,35 @@
-use std::{borrow::Borrow, hash::Hash, path::PathBuf, sync::Arc};
+use std::{
+    any::{Any, TypeId},
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::BinaryHeap,
+    hash::Hash,
+    mem,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        mpsc::Sender,
+        Arc,
+    },
+};
 
-use fnv::FnvHashMap;
-use log::debug;
+use fnv::{FnvHashMap, FnvHashSet};
+use log::{debug, warn};
+use parking_lot::Mutex;
 use rayon::ThreadPool;
+use serde::{de::DeserializeOwned, Serialize};
 
 use amethyst_error::ResultExt;
 #[cfg(feature = "profiler")]
 use thread_profiler::profile_scope;
 
 use crate::{
+    binary_cache::{self, BinaryCache},
+    dependencies::{AssetKey, Dependencies},
     error::Error,
-    storage::{AssetStorage, Handle, Processed},
-    Asset, Directory, Format, FormatValue, Progress, Source,
+    processing,
+    storage::{AssetQueue, AssetStorage, Handle, Processed},
+    Asset, Directory, Format, FormatValue, Layered, Progress, Source,
 };
 
 /// The asset loader, holding the sources and a reference to the `ThreadPool`.
@@ -19,6 +37,124 @@ pub struct Loader {
     hot_reload: bool,
     pool: Arc<ThreadPool>,
     sources: FnvHashMap<String, Arc<dyn Source>>,
+    // sources mounted with a priority, by id, from the highest priority to the lowest.
+    mounts: FnvHashMap<String, Vec<(i32, Arc<dyn Source>)>>,
+    // receives the changed assets of the watched sources.
+    changes: Option<Sender<String>>,
+    // ids of the sources which can't be watched.
+    unwatched: FnvHashSet<String>,
+    dependencies: Dependencies,
+    cache: Option<Arc<BinaryCache>>,
+    pending: Arc<Mutex<PendingLoads>>,
+    // formats and options the `AssetPrefab`s loaded assets with, by asset and format type and name.
+    prefab_formats: Mutex<FnvHashMap<(TypeId, String), Box<dyn Any + Send>>>,
+}
+
+// Loads waiting for a thread of the pool, run from the highest priority, in request order.
+#[derive(Default)]
+struct PendingLoads {
+    loads: BinaryHeap<PendingLoad>,
+    requested: u64,
+}
+
+struct PendingLoad {
+    priority: i32,
+    order: u64,
+    // type and handle id of the asset.
+    asset: (TypeId, u32),
+    load: Box<dyn RunLoad>,
+}
+
+impl PartialEq for PendingLoad {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PendingLoad {}
+
+impl PartialOrd for PendingLoad {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PendingLoad {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+// Counts the bytes read to import an asset.
+struct CountingSource {
+    source: Arc<dyn Source>,
+    bytes: AtomicUsize,
+}
+
+impl Source for CountingSource {
+    fn modified(&self, path: &str) -> Result<u64, amethyst_error::Error> {
+        self.source.modified(path)
+    }
+
+    fn load(&self, path: &str) -> Result<Vec<u8>, amethyst_error::Error> {
+        let bytes = self.source.load(path)?;
+        self.bytes.fetch_add(bytes.len(), AtomicOrdering::Relaxed);
+        Ok(bytes)
+    }
+
+    fn load_with_metadata(&self, path: &str) -> Result<(Vec<u8>, u64), amethyst_error::Error> {
+        let (bytes, modified) = self.source.load_with_metadata(path)?;
+        self.bytes.fetch_add(bytes.len(), AtomicOrdering::Relaxed);
+        Ok((bytes, modified))
+    }
+
+    fn watch(&self, changes: Sender<String>) -> Result<bool, amethyst_error::Error> {
+        self.source.watch(changes)
+    }
+
+    fn needs_polling(&self, path: &str) -> bool {
+        self.source.needs_polling(path)
+    }
+}
+
+/// The assets `HotReloadStrategy::when_changed` polls, because their sources can't watch them.
+#[derive(Clone)]
+pub(crate) struct Polled {
+    // whether every asset is polled, if the sources aren't watched.
+    all: bool,
+    // ids of the sources which can't be watched.
+    unwatched: FnvHashSet<String>,
+    // the watched sources, which may still poll some of their assets.
+    watched: FnvHashMap<String, Arc<dyn Source>>,
+}
+
+impl Polled {
+    /// Returns `true` if the asset with the given name, loaded from the source with the given
+    /// id, has to be polled.
+    pub(crate) fn polls(&self, source: &str, name: &str) -> bool {
+        self.all
+            || self.unwatched.contains(source)
+            || self
+                .watched
+                .get(source)
+                .map_or(false, |watched| watched.needs_polling(name))
+    }
+}
+
+// A boxed `FnOnce` can't be called directly.
+trait RunLoad: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F> RunLoad for F
+where
+    F: FnOnce() + Send,
+{
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
 }
 
 impl Loader {
@@ -40,6 +176,13 @@ impl Loader {
             hot_reload: true,
             pool,
             sources: Default::default(),
+            mounts: Default::default(),
+            changes: None,
+            unwatched: Default::default(),
+            dependencies: Dependencies::new(),
+            cache: None,
+            pending: Default::default(),
+            prefab_formats: Default::default(),
         };
 
         loader.set_default_source(source);
@@ -47,13 +190,59 @@ impl Loader {
     }
 
     /// Add a source to the `Loader`, given an id and the source.
+    ///
+    /// This replaces every source previously added with the same id.
     pub fn add_source<I, S>(&mut self, id: I, source: S)
     where
         I: Into<String>,
         S: Source,
     {
-        self.sources
-            .insert(id.into(), Arc::new(source) as Arc<dyn Source>);
+        let id = id.into();
+        let source = Arc::new(source) as Arc<dyn Source>;
+        self.mounts.remove(&id);
+        self.unwatched.remove(&id);
+        self.watch_source(&id, &*source);
+        self.sources.insert(id, source);
+    }
+
+    /// Mounts a source on top of the sources already added with the same id.
+    ///
+    /// Assets are loaded from the source with the highest priority containing them,
+    /// which allows patches or mods to override some assets of the base archive.
+    /// A source added with `add_source` has priority 0, and among sources of the same priority
+    /// the last one mounted is searched first.
+    ///
+    /// The sources are combined in a `Layered` source, naming each layer after its priority.
+    pub fn add_source_with_priority<I, S>(&mut self, id: I, source: S, priority: i32)
+    where
+        I: Into<String>,
+        S: Source,
+    {
+        let id = id.into();
+        let sources = &self.sources;
+        let mounts = self.mounts.entry(id.clone()).or_insert_with(|| {
+            sources
+                .get(&id)
+                .map(|source| (0, source.clone()))
+                .into_iter()
+                .collect()
+        });
+
+        let index = mounts
+            .iter()
+            .position(|(p, _)| *p <= priority)
+            .unwrap_or_else(|| mounts.len());
+        let source = Arc::new(source) as Arc<dyn Source>;
+        mounts.insert(index, (priority, source.clone()));
+
+        let mut layered = Layered::new();
+        for (priority, source) in mounts.iter() {
+            layered.add_shared_layer(format!("priority {}", priority), source.clone());
+        }
+        let layered = Arc::new(layered) as Arc<dyn Source>;
+        self.unwatched.remove(&id);
+        self.watch_source(&id, &*layered);
+        self.sources.insert(id, layered);
     }
 
     /// Set the default source of the `Loader`.
@@ -73,6 +262,104 @@ impl Loader {
         self.hot_reload = value;
     }
 
+    /// Loads the assets requested with `load_cached` through the given cache, preferring the
+    /// preprocessed data it holds over importing the asset files.
+    pub fn set_cache(&mut self, cache: BinaryCache) {
+        self.cache = Some(Arc::new(cache));
+    }
+
+    /// Returns the cache set with `set_cache`.
+    pub fn cache(&self) -> Option<&BinaryCache> {
+        self.cache.as_ref().map(|cache| &**cache)
+    }
+
+    /// Returns the graph of the assets loaded on behalf of other assets.
+    ///
+    /// Every asset loaded while another asset is processed, like the sub assets of a `Prefab`,
+    /// is recorded as a dependency of that asset.
+    pub fn dependencies(&self) -> &Dependencies {
+        &self.dependencies
+    }
+
+    /// Remembers the format and options an `AssetPrefab` loads the asset with.
+    pub(crate) fn remember_format<A, F>(&self, name: &str, format: F, options: F::Options)
+    where
+        A: Asset,
+        F: Format<A>,
+    {
+        self.prefab_formats.lock().insert(
+            (TypeId::of::<(A, F)>(), name.to_string()),
+            Box::new((format, options)),
+        );
+    }
+
+    /// Returns the format and options an `AssetPrefab` loaded the asset with, if any.
+    pub(crate) fn remembered_format<A, F>(&self, name: &str) -> Option<(F, F::Options)>
+    where
+        A: Asset,
+        F: Format<A> + Clone,
+        F::Options: Clone,
+    {
+        self.prefab_formats
+            .lock()
+            .get(&(TypeId::of::<(A, F)>(), name.to_string()))
+            .and_then(|remembered| remembered.downcast_ref::<(F, F::Options)>())
+            .cloned()
+    }
+
+    /// Watches the sources, sending the paths of the changed assets on `changes`.
+    /// The sources added afterwards are watched as well.
+    ///
+    /// Used by the `HotReloadSystem` if the strategy was created with `HotReloadStrategy::when_changed`.
+    pub(crate) fn watch(&mut self, changes: Sender<String>) {
+        self.unwatched.clear();
+        self.changes = Some(changes);
+        let sources: Vec<_> = self
+            .sources
+            .iter()
+            .map(|(id, source)| (id.clone(), source.clone()))
+            .collect();
+        for (id, source) in sources {
+            self.watch_source(&id, &*source);
+        }
+    }
+
+    /// Returns the assets which have to be polled, because their sources can't watch them.
+    pub(crate) fn polled(&self) -> Polled {
+        let unwatched = &self.unwatched;
+        Polled {
+            all: self.changes.is_none(),
+            unwatched: unwatched.clone(),
+            watched: self
+                .sources
+                .iter()
+                .filter(|(id, _)| !unwatched.contains(*id))
+                .map(|(id, source)| (id.clone(), source.clone()))
+                .collect(),
+        }
+    }
+
+    fn watch_source(&mut self, id: &str, source: &dyn Source) {
+        let changes = match self.changes {
+            Some(ref changes) => changes.clone(),
+            None => return,
+        };
+        match source.watch(changes) {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Source {:?} can't be watched, its assets are polled", id);
+                self.unwatched.insert(id.to_string());
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to watch source {:?}, its assets are polled: {}",
+                    id, e
+                );
+                self.unwatched.insert(id.to_string());
+            }
+        }
+    }
+
     /// Loads an asset with a given format from the default (directory) source.
     /// If you want to load from a custom source instead, use `load_from`.
     ///
@@ -99,6 +386,10 @@ impl Loader {
     /// Loads an asset with a given id and format from a custom source.
     /// The actual work is done in a worker thread, thus this method immediately returns a handle.
     ///
+    /// The asset is loaded with priority 0, see `load_from_with_priority`. If every handle to the
+    /// asset is dropped before it is loaded, the load is cancelled, unless the storage has a
+    /// memory budget (see `AssetStorage::set_memory_budget`).
+    ///
     /// ## Parameters
     ///
     /// * `name`: this is just an identifier for the asset, most likely a file name e.g.
@@ -117,7 +408,7 @@ impl Loader {
         format: F,
         options: F::Options,
         source: &S,
-        mut progress: P,
+        progress: P,
         storage: &AssetStorage<A>,
     ) -> Handle<A>
     where
@@ -127,21 +418,275 @@ impl Loader {
         P: Progress,
         S: AsRef<str> + Eq + Hash + ?Sized,
         String: Borrow<S>,
+    {
+        self.load_from_with_priority(name, format, options, source, 0, progress, storage)
+    }
+
+    /// Loads an asset like `load`, with the given priority.
+    ///
+    /// See `load_from_with_priority` for more information.
+    pub fn load_with_priority<A, F, N, P>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        priority: i32,
+        progress: P,
+        storage: &AssetStorage<A>,
+    ) -> Handle<A>
+    where
+        A: Asset,
+        F: Format<A>,
+        N: Into<String>,
+        P: Progress,
+    {
+        self.load_from_with_priority::<A, F, _, _, _>(
+            name, format, options, "", priority, progress, storage,
+        )
+    }
+
+    /// Loads an asset like `load_from`, with the given priority.
+    ///
+    /// Waiting loads are started from the highest priority, and in the order they were requested
+    /// among the same priority. The priority of a waiting load can be changed with `set_priority`,
+    /// for example to load the assets close to the player first.
+    #[allow(clippy::too_many_arguments)]
+    pub fn load_from_with_priority<A, F, N, P, S>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        source: &S,
+        priority: i32,
+        progress: P,
+        storage: &AssetStorage<A>,
+    ) -> Handle<A>
+    where
+        A: Asset,
+        F: Format<A> + 'static,
+        N: Into<String>,
+        P: Progress,
+        S: AsRef<str> + Eq + Hash + ?Sized,
+        String: Borrow<S>,
+    {
+        self.load_into(
+            name,
+            format,
+            options,
+            source.as_ref(),
+            priority,
+            progress,
+            &storage.queue(),
+        )
+    }
+
+    /// Changes the priority of an asset which is still waiting to be loaded.
+    ///
+    /// Returns `false` if the asset isn't waiting anymore.
+    pub fn set_priority<A>(&self, handle: &Handle<A>, priority: i32) -> bool
+    where
+        A: Asset,
+    {
+        let asset = (TypeId::of::<A>(), handle.id());
+        let mut pending = self.pending.lock();
+        let mut loads = mem::replace(&mut pending.loads, BinaryHeap::new()).into_vec();
+        let found = match loads.iter_mut().find(|load| load.asset == asset) {
+            Some(load) => {
+                load.priority = priority;
+                true
+            }
+            None => false,
+        };
+        pending.loads = loads.into();
+        found
+    }
+
+    /// Loads an asset like `load`, through the `BinaryCache` set with `set_cache`.
+    ///
+    /// The cached data of the asset is used as long as the asset file is unchanged, otherwise the
+    /// asset is imported with `format` and cached. Without a cache, this is the same as `load`.
+    pub fn load_cached<A, F, N, P>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        progress: P,
+        storage: &AssetStorage<A>,
+    ) -> Handle<A>
+    where
+        A: Asset,
+        A::Data: Serialize + DeserializeOwned,
+        F: Format<A> + Clone + Sync,
+        F::Options: Serialize + Clone + Sync,
+        N: Into<String>,
+        P: Progress,
+    {
+        self.load_cached_from::<A, F, _, _, _>(name, format, options, "", progress, storage)
+    }
+
+    /// Loads an asset like `load_cached`, with the given priority.
+    ///
+    /// See `load_from_with_priority` for more information.
+    pub fn load_cached_with_priority<A, F, N, P>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        priority: i32,
+        progress: P,
+        storage: &AssetStorage<A>,
+    ) -> Handle<A>
+    where
+        A: Asset,
+        A::Data: Serialize + DeserializeOwned,
+        F: Format<A> + Clone + Sync,
+        F::Options: Serialize + Clone + Sync,
+        N: Into<String>,
+        P: Progress,
+    {
+        self.load_cached_from_with_priority::<A, F, _, _, _>(
+            name, format, options, "", priority, progress, storage,
+        )
+    }
+
+    /// Loads an asset like `load_from`, through the `BinaryCache` set with `set_cache`.
+    ///
+    /// See `load_cached` for more information.
+    pub fn load_cached_from<A, F, N, P, S>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        source: &S,
+        progress: P,
+        storage: &AssetStorage<A>,
+    ) -> Handle<A>
+    where
+        A: Asset,
+        A::Data: Serialize + DeserializeOwned,
+        F: Format<A> + Clone + Sync,
+        F::Options: Serialize + Clone + Sync,
+        N: Into<String>,
+        P: Progress,
+        S: AsRef<str> + Eq + Hash + ?Sized,
+        String: Borrow<S>,
+    {
+        self.load_cached_from_with_priority(name, format, options, source, 0, progress, storage)
+    }
+
+    /// Loads an asset like `load_cached_from`, with the given priority.
+    ///
+    /// See `load_from_with_priority` for more information.
+    #[allow(clippy::too_many_arguments)]
+    pub fn load_cached_from_with_priority<A, F, N, P, S>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        source: &S,
+        priority: i32,
+        progress: P,
+        storage: &AssetStorage<A>,
+    ) -> Handle<A>
+    where
+        A: Asset,
+        A::Data: Serialize + DeserializeOwned,
+        F: Format<A> + Clone + Sync,
+        F::Options: Serialize + Clone + Sync,
+        N: Into<String>,
+        P: Progress,
+        S: AsRef<str> + Eq + Hash + ?Sized,
+        String: Borrow<S>,
+    {
+        let cache = match self.cache {
+            Some(ref cache) => cache.clone(),
+            None => {
+                return self.load_from_with_priority(
+                    name, format, options, source, priority, progress, storage,
+                );
+            }
+        };
+        self.load_with(
+            name,
+            F::NAME,
+            source.as_ref(),
+            priority,
+            progress,
+            &storage.queue(),
+            move |name, source, hot_reload| {
+                binary_cache::import(&cache, format, name, source, options, hot_reload)
+            },
+        )
+    }
+
+    /// Loads an asset through the queue of its storage, which doesn't borrow the storage.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn load_into<A, F, N, P>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        source: &str,
+        priority: i32,
+        progress: P,
+        queue: &AssetQueue<A>,
+    ) -> Handle<A>
+    where
+        A: Asset,
+        F: Format<A> + 'static,
+        N: Into<String>,
+        P: Progress,
+    {
+        self.load_with(
+            name,
+            F::NAME,
+            source,
+            priority,
+            progress,
+            queue,
+            move |name, source, hot_reload| format.import(name, source, options, hot_reload),
+        )
+    }
+
+    // Imports the asset on the pool with `import`, given the name, the source and whether to
+    // create a reload object.
+    #[allow(clippy::too_many_arguments)]
+    fn load_with<A, N, P, I>(
+        &self,
+        name: N,
+        format_name: &'static str,
+        source: &str,
+        priority: i32,
+        mut progress: P,
+        queue: &AssetQueue<A>,
+        import: I,
+    ) -> Handle<A>
+    where
+        A: Asset,
+        N: Into<String>,
+        P: Progress,
+        I: FnOnce(String, Arc<dyn Source>, bool) -> Result<FormatValue<A>, amethyst_error::Error>
+            + Send
+            + 'static,
     {
         #[cfg(feature = "profiler")]
         profile_scope!("load_asset_from");
         use crate::progress::Tracker;
 
         let name = name.into();
-        let source = source.as_ref();
 
-        let format_name = F::NAME;
         let source_name = match source {
             "" => "[default source]",
             other => other,
         };
 
-        let handle = storage.allocate();
+        let handle = queue.allocate();
+
+        if let Some(dependent) = processing::asset() {
+            debug!("Asset {:?} is loaded on behalf of {}", name, dependent);
+            self.dependencies
+                .add(&dependent, &AssetKey::new(source, A::NAME, &name));
+        }
 
         debug!(
             "{:?}: Loading asset {:?} with format {:?} from source {:?} (handle id: {:?})",
@@ -153,30 +698,71 @@ impl Loader {
         );
 
         progress.add_assets(1);
-        let tracker = progress.create_tracker();
+        let mut tracker = progress.create_tracker();
+        tracker.requested(A::NAME, &name);
 
+        let source_id = source.to_string();
         let source = self.source(source);
         let handle_clone = handle.clone();
-        let processed = storage.processed.clone();
+        let queue = queue.clone();
 
         let hot_reload = self.hot_reload;
+        let dependencies = self.dependencies.clone();
 
         let cl = move || {
             #[cfg(feature = "profiler")]
             profile_scope!("load_asset_from_worker");
-            let data = format
-                .import(name.clone(), source, options, hot_reload)
-                .with_context(|_| Error::Format(F::NAME));
-            let tracker = Box::new(tracker) as Box<dyn Tracker>;
+            let mut tracker = Box::new(tracker) as Box<dyn Tracker>;
+            if queue.cancels_unused() && handle.is_unique() {
+                debug!(
+                    "{:?}: Loading asset {:?} (handle id: {:?}) was cancelled, its handle was dropped",
+                    A::NAME,
+                    name,
+                    handle,
+                );
+                queue.release(handle);
+                tracker.cancelled();
+                return;
+            }
 
-            processed.push(Processed::NewAsset {
+            let source = Arc::new(CountingSource {
+                source,
+                bytes: AtomicUsize::new(0),
+            });
+            let data = import(name.clone(), source.clone(), hot_reload)
+                .with_context(|_| Error::Format(format_name));
+            if data.is_ok() {
+                tracker.imported(source.bytes.load(AtomicOrdering::Relaxed));
+            }
+
+            queue.processed.push(Processed::NewAsset {
                 data,
                 handle,
                 name,
+                source: source_id,
                 tracker,
+                dependencies: Some(dependencies),
             });
         };
-        self.pool.spawn(cl);
+
+        {
+            let mut pending = self.pending.lock();
+            pending.requested += 1;
+            let load = PendingLoad {
+                priority,
+                order: pending.requested,
+                asset: (TypeId::of::<A>(), handle_clone.id()),
+                load: Box::new(cl),
+            };
+            pending.loads.push(load);
+        }
+        let pending = self.pending.clone();
+        self.pool.spawn(move || {
+            let load = pending.lock().loads.pop();
+            if let Some(load) = load {
+                load.load.run();
+            }
+        });
 
         handle_clone
     }
@@ -192,15 +778,20 @@ impl Loader {
         A: Asset,
         P: Progress,
     {
+        use crate::progress::Tracker;
+
         progress.add_assets(1);
-        let tracker = progress.create_tracker();
+        let mut tracker = progress.create_tracker();
+        tracker.requested(A::NAME, "<Data>");
         let tracker = Box::new(tracker);
         let handle = storage.allocate();
         storage.processed.push(Processed::NewAsset {
             data: Ok(FormatValue::data(data)),
             handle: handle.clone(),
             name: "<Data>".into(),
+            source: String::new(),
             tracker,
+            dependencies: Some(self.dependencies.clone()),
         });
 
         handle
@@ -213,3 +804,200 @@ impl Loader {
             .clone()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env,
+        fs::{self, File},
+        path::PathBuf,
+        process,
+        sync::{mpsc, Arc},
+        thread,
+        time::Duration,
+    };
+
+    use rayon::ThreadPoolBuilder;
+
+    use amethyst_core::specs::prelude::VecStorage;
+
+    use crate::{
+        source::{Archive, Memory},
+        storage::Processed,
+        Asset, AssetStorage, Cache, Handle, ProcessingState, RonFormat,
+    };
+
+    use super::Loader;
+
+    struct Text;
+
+    impl Asset for Text {
+        const NAME: &'static str = "Text";
+        type Data = String;
+        type HandleStorage = VecStorage<Handle<Self>>;
+    }
+
+    #[derive(Clone)]
+    struct Note(String);
+
+    impl Asset for Note {
+        const NAME: &'static str = "Note";
+        type Data = String;
+        type HandleStorage = VecStorage<Handle<Self>>;
+
+        fn memory_size(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    fn pak_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("amethyst_{}_{}.pak", name, process::id()))
+    }
+
+    fn temp_pak(name: &str, assets: Vec<(&str, &str)>) -> Archive {
+        let path = pak_path(name);
+        let file = File::create(&path).expect("Failed to create pak");
+        Archive::write_pak(file, assets).expect("Failed to write pak");
+        Archive::open(path).expect("Failed to open pak")
+    }
+
+    #[test]
+    fn mounted_sources_override_by_priority() {
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let mut loader = Loader::with_default_source(
+            temp_pak("base", vec![("a", "base"), ("b", "base"), ("c", "base")]),
+            pool,
+        );
+        loader.add_source_with_priority("", temp_pak("mod", vec![("a", "mod")]), 10);
+        loader.add_source_with_priority(
+            "",
+            temp_pak("patch", vec![("a", "patch"), ("b", "patch")]),
+            1,
+        );
+
+        let source = loader.source("");
+        assert_eq!(source.load("a").unwrap(), b"mod".to_vec());
+        assert_eq!(source.load("b").unwrap(), b"patch".to_vec());
+        assert_eq!(source.load("c").unwrap(), b"base".to_vec());
+        assert!(source.load("d").is_err());
+        assert!(source.modified("c").is_ok());
+
+        // Adding a source replaces the mounted ones.
+        loader.add_source("", temp_pak("replaced", vec![("d", "replaced")]));
+        let source = loader.source("");
+        assert!(source.load("a").is_err());
+        assert_eq!(source.load("d").unwrap(), b"replaced".to_vec());
+        for name in &["base", "mod", "patch", "replaced"] {
+            fs::remove_file(pak_path(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn unwatched_sources_are_polled() {
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let memory = Memory::new();
+        let mut loader = Loader::with_default_source(memory.clone(), pool);
+        assert!(loader.polled().polls("", "asset"));
+
+        let (tx, rx) = mpsc::channel();
+        loader.watch(tx);
+        assert!(!loader.polled().polls("", "asset"));
+        memory.insert("asset", "data");
+        assert_eq!(rx.try_recv(), Ok("asset".to_string()));
+
+        // Only the assets of the sources which can't be watched are polled.
+        loader.add_source("archive", temp_pak("unwatched", vec![("a", "archive")]));
+        assert!(loader.polled().polls("archive", "a"));
+        assert!(!loader.polled().polls("", "asset"));
+
+        // Mounting a watched source on top of the archive leaves the archive's assets polled.
+        loader.add_source_with_priority("archive", Memory::new().with_asset("b", "mod"), 1);
+        assert!(loader.polled().polls("archive", "a"));
+        assert!(!loader.polled().polls("archive", "b"));
+
+        loader.add_source("archive", Memory::new());
+        assert!(!loader.polled().polls("archive", "a"));
+        fs::remove_file(pak_path("unwatched")).unwrap();
+    }
+
+    #[test]
+    fn loads_run_by_priority_unless_cancelled() {
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let memory = Memory::new();
+        for name in &["a", "b", "c", "d"] {
+            memory.insert(*name, format!("{:?}", name));
+        }
+        let loader = Loader::with_default_source(memory, pool.clone());
+        let storage = AssetStorage::<Text>::new();
+
+        // Keep the only thread busy until every load is requested.
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        pool.spawn(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started.recv().unwrap();
+
+        let a = loader.load("a", RonFormat, (), (), &storage);
+        let b = loader.load_with_priority("b", RonFormat, (), 5, (), &storage);
+        let c = loader.load("c", RonFormat, (), (), &storage);
+        let d = loader.load_with_priority("d", RonFormat, (), 10, (), &storage);
+        assert!(loader.set_priority(&c, 7));
+        drop(d);
+        release.send(()).unwrap();
+
+        let mut loaded = Vec::new();
+        for _ in 0..1000 {
+            match storage.processed.try_pop() {
+                Some(Processed::NewAsset { name, .. }) => loaded.push(name),
+                Some(_) => {}
+                None if loaded.len() == 3 => break,
+                None => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+        assert_eq!(loaded, vec!["c", "b", "a"]);
+        assert!(!loader.set_priority(&a, 1));
+        drop((a, b, c));
+    }
+
+    #[test]
+    fn loads_kept_for_the_memory_budget_are_not_cancelled() {
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let memory = Memory::new().with_asset("note", "\"kept\"");
+        let loader = Loader::with_default_source(memory, pool.clone());
+        let mut storage = AssetStorage::<Note>::new();
+        storage.set_memory_budget(Some(100));
+        let mut cache = Cache::new();
+
+        // The handle is dropped before the only thread runs the load.
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        pool.spawn(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started.recv().unwrap();
+        let handle = loader.load("note", RonFormat, (), (), &storage);
+        cache.insert("note", &handle);
+        drop(handle);
+        release.send(()).unwrap();
+
+        for _ in 0..1000 {
+            if !storage.processed.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        storage.process(
+            |text| Ok(ProcessingState::Loaded(Note(text))),
+            0,
+            &pool,
+            None,
+        );
+        let handle = cache
+            .get("note")
+            .expect("The load of an asset held by a `Cache` was cancelled");
+        assert_eq!(storage.get(&handle).map(|note| &*note.0), Some("kept"));
+    }
+}
@@ -37,6 +37,18 @@ where
             Ok(false)
         }
     }
+
+    fn extract_from_entity(
+        entity: Entity,
+        system_data: &mut Self::SystemData,
+        entities: &[Entity],
+    ) -> Result<Option<Self>, Error> {
+        Ok(Some(T::extract_from_entity(entity, system_data, entities)?))
+    }
+
+    fn remove_from_entity(entity: Entity, system_data: &mut Self::SystemData) -> Result<(), Error> {
+        T::remove_from_entity(entity, system_data)
+    }
 }
 
 impl<'a> PrefabData<'a> for GlobalTransform {
@@ -52,6 +64,19 @@ impl<'a> PrefabData<'a> for GlobalTransform {
         storage.insert(entity, self.clone()).map(|_| ())?;
         Ok(())
     }
+
+    fn extract_from_entity(
+        entity: Entity,
+        storage: &mut Self::SystemData,
+        _: &[Entity],
+    ) -> Result<Option<Self>, Error> {
+        Ok(storage.get(entity).cloned())
+    }
+
+    fn remove_from_entity(entity: Entity, storage: &mut Self::SystemData) -> Result<(), Error> {
+        storage.remove(entity);
+        Ok(())
+    }
 }
 
 impl<'a> PrefabData<'a> for Transform {
@@ -71,6 +96,20 @@ impl<'a> PrefabData<'a> for Transform {
         storages.0.insert(entity, self.clone()).map(|_| ())?;
         Ok(())
     }
+
+    fn extract_from_entity(
+        entity: Entity,
+        storages: &mut Self::SystemData,
+        _: &[Entity],
+    ) -> Result<Option<Self>, Error> {
+        Ok(storages.0.get(entity).cloned())
+    }
+
+    fn remove_from_entity(entity: Entity, storages: &mut Self::SystemData) -> Result<(), Error> {
+        storages.0.remove(entity);
+        storages.1.remove(entity);
+        Ok(())
+    }
 }
 
 impl<'a> PrefabData<'a> for Named {
@@ -86,6 +125,19 @@ impl<'a> PrefabData<'a> for Named {
         storages.0.insert(entity, self.clone()).map(|_| ())?;
         Ok(())
     }
+
+    fn extract_from_entity(
+        entity: Entity,
+        storages: &mut Self::SystemData,
+        _: &[Entity],
+    ) -> Result<Option<Self>, Error> {
+        Ok(storages.0.get(entity).cloned())
+    }
+
+    fn remove_from_entity(entity: Entity, storages: &mut Self::SystemData) -> Result<(), Error> {
+        storages.0.remove(entity);
+        Ok(())
+    }
 }
 
 macro_rules! impl_data {
@@ -127,6 +179,33 @@ macro_rules! impl_data {
                 )*
                 Ok(ret)
             }
+
+            fn extract_from_entity(
+                entity: Entity,
+                system_data: &mut Self::SystemData,
+                entities: &[Entity],
+            ) -> Result<Option<Self>, Error> {
+                #![allow(unused_variables)]
+                Ok(Some((
+                    $(
+                        match $ty::extract_from_entity(entity, &mut system_data.$i, entities)? {
+                            Some(data) => data,
+                            None => return Ok(None),
+                        },
+                    )*
+                )))
+            }
+
+            fn remove_from_entity(
+                entity: Entity,
+                system_data: &mut Self::SystemData,
+            ) -> Result<(), Error> {
+                #![allow(unused_variables)]
+                $(
+                    $ty::remove_from_entity(entity, &mut system_data.$i)?;
+                )*
+                Ok(())
+            }
         }
     };
 }
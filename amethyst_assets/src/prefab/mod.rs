@@ -1,14 +1,23 @@
-use std::marker::PhantomData;
+use std::{collections::HashMap, fmt, marker::PhantomData};
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::DeserializeOwned, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer,
+};
 use shred_derive::SystemData;
 
-use amethyst_core::specs::prelude::{
-    Component, DenseVecStorage, Entity, FlaggedStorage, Read, ReadExpect, SystemData, WriteStorage,
+use amethyst_core::{
+    specs::prelude::{
+        Component, DenseVecStorage, Entities, Entity, FlaggedStorage, Join, Read, ReadExpect,
+        ReadStorage, SystemData, WriteStorage,
+    },
+    Parent,
 };
-use amethyst_error::Error;
+use amethyst_error::{format_err, Error};
 
-use crate::{Asset, AssetStorage, Format, Handle, Loader, Progress, ProgressCounter};
+use crate::{
+    processing, progress, storage::AssetQueue, Asset, AssetKey, AssetStorage, Format, Handle,
+    Loader, Progress, ProgressCounter, RonFormat,
+};
 
 pub use self::system::PrefabLoaderSystem;
 
@@ -65,6 +74,50 @@ pub trait PrefabData<'a> {
     ) -> Result<bool, Error> {
         Ok(false)
     }
+
+    /// Extract the prefab data from the components of the given `Entity`, the reverse of
+    /// `add_to_entity`. This is used to capture existing entities with `Prefab::capture`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `entity`: `Entity` to extract the data from
+    /// - `system_data`: `SystemData` needed to do the extraction
+    /// - `entities`: All the `Entity`s captured in the same prefab, in prefab order, for
+    ///               components linking to other entities.
+    ///
+    /// ### Returns
+    ///
+    /// - `Err(error)` - if an `Error` occurs
+    /// - `Ok(None)` - if the `Entity` doesn't have the data, this is the default implementation
+    /// - `Ok(Some(data))` - the data extracted from the `Entity`
+    fn extract_from_entity(
+        _entity: Entity,
+        _system_data: &mut Self::SystemData,
+        _entities: &[Entity],
+    ) -> Result<Option<Self>, Error>
+    where
+        Self: Sized,
+    {
+        Ok(None)
+    }
+
+    /// Remove the components added by `add_to_entity` from the given `Entity`, whatever the data
+    /// was. This is used to apply hot reloaded prefabs again to their instances, see
+    /// `PrefabLoaderSystem::with_instance_reload`.
+    ///
+    /// The default implementation removes nothing, so the components of the fields removed from a
+    /// reloaded prefab are left on the entities.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `entity`: `Entity` to remove the components from
+    /// - `system_data`: `SystemData` needed to do the removal
+    fn remove_from_entity(_entity: Entity, _system_data: &mut Self::SystemData) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        Ok(())
+    }
 }
 
 /// Main `Prefab` structure, containing all data loaded in a single prefab.
@@ -76,6 +129,9 @@ pub trait PrefabData<'a> {
 ///
 /// The recommended way of loading resources is to place them on the main `Entity`.
 ///
+/// An entry can also reference another prefab with a `PrefabReference`, to build variants of a
+/// prefab without duplicating it.
+///
 /// ### Example:
 ///
 /// If the prefab contains 3 new entities `A`, `B` and `C`, and the main `Entity` that the `Handle`
@@ -97,6 +153,7 @@ pub trait PrefabData<'a> {
 ///
 /// - `T`: `PrefabData`
 #[derive(Default, Deserialize, Serialize)]
+#[serde(bound(deserialize = "T: DeserializeOwned + Send + Sync + 'static"))]
 pub struct Prefab<T> {
     #[serde(skip)]
     tag: Option<u64>,
@@ -110,11 +167,37 @@ pub struct Prefab<T> {
 /// ### Type parameters:
 ///
 /// - `T`: `PrefabData`
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize)]
 #[serde(default)]
+#[serde(bound(deserialize = "T: DeserializeOwned + Send + Sync + 'static"))]
 pub struct PrefabEntity<T> {
     parent: Option<usize>,
     data: Option<T>,
+    prefab: Option<PrefabReference<T>>,
+}
+
+// The referenced prefab is left out of human readable formats when there is none, binary formats
+// like the `BinaryCache` need every field.
+impl<T> Serialize for PrefabEntity<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let with_prefab = self.prefab.is_some() || !serializer.is_human_readable();
+        let mut state =
+            serializer.serialize_struct("PrefabEntity", if with_prefab { 3 } else { 2 })?;
+        state.serialize_field("parent", &self.parent)?;
+        state.serialize_field("data", &self.data)?;
+        if with_prefab {
+            state.serialize_field("prefab", &self.prefab)?;
+        } else {
+            state.skip_field("prefab")?;
+        }
+        state.end()
+    }
 }
 
 impl<T> Default for PrefabEntity<T> {
@@ -126,7 +209,11 @@ impl<T> Default for PrefabEntity<T> {
 impl<T> PrefabEntity<T> {
     /// New prefab entity
     pub fn new(parent: Option<usize>, data: Option<T>) -> Self {
-        PrefabEntity { parent, data }
+        PrefabEntity {
+            parent,
+            data,
+            prefab: None,
+        }
     }
 
     /// Set parent index
@@ -139,6 +226,16 @@ impl<T> PrefabEntity<T> {
         self.data = Some(data);
     }
 
+    /// Set the prefab instantiated on the entity, see `PrefabReference`
+    pub fn set_prefab(&mut self, prefab: PrefabReference<T>) {
+        self.prefab = Some(prefab);
+    }
+
+    /// Get immutable access to the referenced prefab
+    pub fn prefab(&self) -> Option<&PrefabReference<T>> {
+        self.prefab.as_ref()
+    }
+
     /// Get immutable access to the data
     pub fn data(&self) -> Option<&T> {
         self.data.as_ref()
@@ -159,7 +256,8 @@ impl<T> PrefabEntity<T> {
         self.data.get_or_insert_with(T::default)
     }
 
-    /// Trigger sub asset loading for the prefab entity
+    /// Trigger sub asset loading for the prefab entity, and for the overrides of its referenced
+    /// prefab
     pub fn load_sub_assets<'a>(
         &mut self,
         progress: &mut ProgressCounter,
@@ -168,11 +266,229 @@ impl<T> PrefabEntity<T> {
     where
         T: PrefabData<'a>,
     {
+        let mut ret = false;
         if let Some(ref mut data) = self.data {
-            data.load_sub_assets(progress, system_data)
+            ret |= data.load_sub_assets(progress, system_data)?;
+        }
+        if let Some(ref mut prefab) = self.prefab {
+            for o in &mut prefab.overrides {
+                ret |= o.data.load_sub_assets(progress, system_data)?;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+// Loads a referenced prefab from a source into the queue of the prefab storage.
+type LoadPrefab<T> =
+    fn(&str, &str, &Loader, &mut ProgressCounter, &AssetQueue<Prefab<T>>) -> Handle<Prefab<T>>;
+
+/// Reference from a `PrefabEntity` to another prefab file, instantiated on the entity.
+///
+/// The main entity of the referenced prefab is the referencing entity itself, and its other
+/// entities are created along with the entities of the referencing prefab. The referencing entity
+/// gets the referenced data first and then its own data.
+///
+/// The overrides then patch selected entities of the referenced prefab: their data is added after
+/// the referenced data, so it only replaces the components it sets. With a `PrefabData` derived
+/// for a struct of `Option`s, the fields left to `None` keep the referenced components.
+///
+/// Referenced prefabs are loaded as sub assets of the referencing prefab, from the source it was
+/// loaded from unless another `source` is given, and can reference other prefabs in turn.
+/// They are always loaded with `RonFormat`, the format of the referencing prefab being unknown
+/// once it is loaded. A prefab referencing itself, directly or not, fails to load.
+///
+/// ### Example:
+///
+/// ```ron,ignore
+/// Prefab(
+///     entities: [
+///         (
+///             prefab: Some((
+///                 file: "prefab/tree.ron",
+///                 overrides: [
+///                     (entity: 1, data: (transform: Some((translation: (0.0, 2.0, 0.0))))),
+///                 ],
+///             )),
+///         ),
+///     ],
+/// )
+/// ```
+pub struct PrefabReference<T> {
+    file: String,
+    source: Option<String>,
+    overrides: Vec<PrefabOverride<T>>,
+    handle: Option<Handle<Prefab<T>>>,
+    load_fn: LoadPrefab<T>,
+}
+
+/// Data patching an entity of a referenced prefab, see `PrefabReference`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PrefabOverride<T> {
+    /// Index of the patched entity in the referenced prefab
+    pub entity: usize,
+    /// Data added to the entity after the referenced data
+    pub data: T,
+}
+
+impl<T> PrefabReference<T> {
+    /// Reference the prefab file with the given name, without overrides
+    pub fn new<N>(file: N) -> Self
+    where
+        N: Into<String>,
+        T: DeserializeOwned + Send + Sync + 'static,
+    {
+        PrefabReference {
+            file: file.into(),
+            source: None,
+            overrides: Vec::new(),
+            handle: None,
+            load_fn: load_referenced::<T>,
+        }
+    }
+
+    /// Load the referenced prefab from the source with the given id, instead of the source of the
+    /// referencing prefab
+    pub fn with_source<S>(mut self, source: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Add an override for the entity with the given index in the referenced prefab
+    pub fn with_override(mut self, entity: usize, data: T) -> Self {
+        self.overrides.push(PrefabOverride { entity, data });
+        self
+    }
+
+    /// Name of the referenced prefab file
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// Id of the source the referenced prefab is loaded from, if set with `with_source`
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_ref().map(String::as_str)
+    }
+
+    /// Get immutable access to the overrides
+    pub fn overrides(&self) -> &[PrefabOverride<T>] {
+        &self.overrides
+    }
+
+    /// Handle of the referenced prefab, once its loading has been triggered
+    pub fn handle(&self) -> Option<&Handle<Prefab<T>>> {
+        self.handle.as_ref()
+    }
+
+    // Starts loading the referenced prefab on behalf of the prefab being processed.
+    fn load(
+        &mut self,
+        loader: &Loader,
+        progress: &mut ProgressCounter,
+        queue: &AssetQueue<Prefab<T>>,
+    ) -> Result<(), Error>
+    where
+        T: Send + Sync + 'static,
+    {
+        let source = self
+            .source
+            .clone()
+            .or_else(processing::source)
+            .unwrap_or_default();
+        if let Some(dependent) = processing::asset() {
+            let referenced = AssetKey::new(&source, Prefab::<T>::NAME, &self.file);
+            if !loader.dependencies().add(&dependent, &referenced) {
+                return Err(format_err!(
+                    "Prefab {} references {}, which references it back",
+                    dependent,
+                    referenced
+                ));
+            }
+        }
+        self.handle = Some((self.load_fn)(&self.file, &source, loader, progress, queue));
+        Ok(())
+    }
+}
+
+fn load_referenced<T>(
+    file: &str,
+    source: &str,
+    loader: &Loader,
+    progress: &mut ProgressCounter,
+    queue: &AssetQueue<Prefab<T>>,
+) -> Handle<Prefab<T>>
+where
+    T: DeserializeOwned + Send + Sync + 'static,
+{
+    loader.load_into(file, RonFormat, (), source, 0, progress, queue)
+}
+
+impl<T> fmt::Debug for PrefabReference<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrefabReference")
+            .field("file", &self.file)
+            .field("source", &self.source)
+            .field("overrides", &self.overrides)
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+impl<T> Serialize for PrefabReference<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // Like the referenced prefab of `PrefabEntity`, the source is only left out of human
+        // readable formats.
+        let with_source = self.source.is_some() || !serializer.is_human_readable();
+        let mut state =
+            serializer.serialize_struct("PrefabReference", if with_source { 3 } else { 2 })?;
+        state.serialize_field("file", &self.file)?;
+        if with_source {
+            state.serialize_field("source", &self.source)?;
         } else {
-            Ok(false)
+            state.skip_field("source")?;
         }
+        state.serialize_field("overrides", &self.overrides)?;
+        state.end()
+    }
+}
+
+// The loading function can't be deserialized, it is picked from `T` instead.
+impl<'de, T> Deserialize<'de> for PrefabReference<T>
+where
+    T: DeserializeOwned + Send + Sync + 'static,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(rename = "PrefabReference")]
+        struct Data<T> {
+            file: String,
+            #[serde(default)]
+            source: Option<String>,
+            #[serde(default)]
+            overrides: Vec<PrefabOverride<T>>,
+        }
+
+        let data = Data::<T>::deserialize(deserializer)?;
+        Ok(PrefabReference {
+            source: data.source,
+            overrides: data.overrides,
+            ..PrefabReference::new(data.file)
+        })
     }
 }
 
@@ -266,7 +582,7 @@ impl<T> Prefab<T> {
         T: PrefabData<'a>,
     {
         let mut ret = false;
-        let mut progress = ProgressCounter::default();
+        let mut progress = self.counter.take().unwrap_or_else(progress::nested_counter);
         for entity in &mut self.entities {
             if entity.load_sub_assets(&mut progress, system_data)? {
                 ret = true;
@@ -275,6 +591,86 @@ impl<T> Prefab<T> {
         self.counter = Some(progress);
         Ok(ret)
     }
+
+    /// Trigger loading of the referenced prefabs, tracked along with the sub assets.
+    pub(crate) fn load_references(
+        &mut self,
+        loader: &Loader,
+        queue: &AssetQueue<Prefab<T>>,
+    ) -> Result<bool, Error>
+    where
+        T: Send + Sync + 'static,
+    {
+        let mut ret = false;
+        let mut progress = self.counter.take().unwrap_or_else(progress::nested_counter);
+        for entity in &mut self.entities {
+            if let Some(ref mut prefab) = entity.prefab {
+                prefab.load(loader, &mut progress, queue)?;
+                ret = true;
+            }
+        }
+        self.counter = Some(progress);
+        Ok(ret)
+    }
+
+    /// Capture an existing `Entity` and its descendants into a prefab, following the `Parent`
+    /// links. The given `Entity` becomes the main entity of the prefab.
+    ///
+    /// The data of every entity is extracted with `PrefabData::extract_from_entity`, and the
+    /// prefab can then be written out with `RonFormat::export`.
+    ///
+    /// ### Example:
+    ///
+    /// ```rust,ignore
+    /// let prefab = world.exec(
+    ///     |(entities, parents, mut data): (
+    ///         Entities<'_>,
+    ///         ReadStorage<'_, Parent>,
+    ///         <MyPrefabData as PrefabData<'_>>::SystemData,
+    ///     )| Prefab::<MyPrefabData>::capture(root, &entities, &parents, &mut data),
+    /// )?;
+    /// let ron = RonFormat.export(&prefab)?;
+    /// ```
+    pub fn capture<'a>(
+        root: Entity,
+        entities: &Entities<'a>,
+        parents: &ReadStorage<'a, Parent>,
+        system_data: &mut <T as PrefabData<'a>>::SystemData,
+    ) -> Result<Self, Error>
+    where
+        T: PrefabData<'a>,
+    {
+        let mut children: HashMap<Entity, Vec<Entity>> = HashMap::new();
+        for (entity, parent) in (&**entities, parents).join() {
+            children.entry(parent.entity).or_default().push(entity);
+        }
+
+        // Breadth first, so parents always come before their children.
+        let mut captured = vec![root];
+        let mut captured_parents = vec![None];
+        let mut next = 0;
+        while next < captured.len() {
+            if let Some(mut entity_children) = children.remove(&captured[next]) {
+                entity_children.sort_by_key(Entity::id);
+                for child in entity_children {
+                    captured.push(child);
+                    captured_parents.push(Some(next));
+                }
+            }
+            next += 1;
+        }
+
+        let mut prefab_entities = Vec::with_capacity(captured.len());
+        for (entity, parent) in captured.iter().zip(captured_parents) {
+            let data = T::extract_from_entity(*entity, system_data, &captured)?;
+            prefab_entities.push(PrefabEntity::new(parent, data));
+        }
+        Ok(Prefab {
+            tag: None,
+            entities: prefab_entities,
+            counter: None,
+        })
+    }
 }
 
 /// Tag placed on entities created by the prefab system.
@@ -374,6 +770,10 @@ where
         system_data: &mut Self::SystemData,
     ) -> Result<bool, Error> {
         let handle = if let AssetPrefab::File(ref name, ref format, ref options) = *self {
+            // So the entities can be captured with the file again.
+            system_data
+                .0
+                .remember_format::<A, F>(name, format.clone(), options.clone());
             Some(system_data.0.load(
                 name.as_ref(),
                 format.clone(),
@@ -391,6 +791,31 @@ where
             Ok(false)
         }
     }
+
+    /// Extracts the file the asset was loaded from, if it was loaded by an `AssetPrefab`.
+    /// Otherwise the `Handle` is extracted, which can't be serialized.
+    fn extract_from_entity(
+        entity: Entity,
+        system_data: &mut Self::SystemData,
+        _: &[Entity],
+    ) -> Result<Option<Self>, Error> {
+        let (ref loader, ref handles, ref storage) = *system_data;
+        let handle = match handles.get(entity) {
+            Some(handle) => handle.clone(),
+            None => return Ok(None),
+        };
+        let file = storage.name(&handle).and_then(|name| {
+            loader
+                .remembered_format::<A, F>(name)
+                .map(|(format, options)| AssetPrefab::File(name.to_string(), format, options))
+        });
+        Ok(Some(file.unwrap_or(AssetPrefab::Handle(handle))))
+    }
+
+    fn remove_from_entity(entity: Entity, system_data: &mut Self::SystemData) -> Result<(), Error> {
+        system_data.1.remove(entity);
+        Ok(())
+    }
 }
 
 /// Helper structure for loading prefabs.
@@ -434,6 +859,71 @@ where
             .load(name, format, options, progress, &self.storage)
     }
 
+    /// Load prefab from source with the given priority, see `Loader::load_from_with_priority`
+    pub fn load_with_priority<F, N, P>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        priority: i32,
+        progress: P,
+    ) -> Handle<Prefab<T>>
+    where
+        F: Format<Prefab<T>>,
+        N: Into<String>,
+        P: Progress,
+    {
+        self.loader
+            .load_with_priority(name, format, options, priority, progress, &self.storage)
+    }
+
+    /// Load prefab from source, through the `BinaryCache` of the `Loader`, see
+    /// `Loader::load_cached`
+    pub fn load_cached<F, N, P>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        progress: P,
+    ) -> Handle<Prefab<T>>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Format<Prefab<T>> + Clone + Sync,
+        F::Options: Serialize + Clone + Sync,
+        N: Into<String>,
+        P: Progress,
+    {
+        self.loader
+            .load_cached(name, format, options, progress, &self.storage)
+    }
+
+    /// Load prefab from source through the `BinaryCache` of the `Loader`, with the given priority,
+    /// see `Loader::load_cached_with_priority`
+    pub fn load_cached_with_priority<F, N, P>(
+        &self,
+        name: N,
+        format: F,
+        options: F::Options,
+        priority: i32,
+        progress: P,
+    ) -> Handle<Prefab<T>>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Format<Prefab<T>> + Clone + Sync,
+        F::Options: Serialize + Clone + Sync,
+        N: Into<String>,
+        P: Progress,
+    {
+        self.loader.load_cached_with_priority(
+            name,
+            format,
+            options,
+            priority,
+            progress,
+            &self.storage,
+        )
+    }
+
     /// Load prefab from explicit data
     pub fn load_from_data<P>(&self, data: Prefab<T>, progress: P) -> Handle<Prefab<T>>
     where
@@ -445,21 +935,25 @@ where
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{sync::Arc, thread, time::Duration};
 
     use rayon::ThreadPoolBuilder;
 
     use amethyst_core::{
         specs::{Builder, RunNow, World},
-        GlobalTransform, Time, Transform,
+        GlobalTransform, Named, Parent, Time, Transform,
     };
 
-    use crate::Loader;
+    use crate::{HotReloadStrategy, HotReloadSystem, Loader, Memory, RonFormat};
 
     use super::*;
 
     type MyPrefab = Transform;
 
+    type NestedPrefab = AssetPrefab<Prefab<MyPrefab>, RonFormat>;
+
+    type PatchedPrefab = (Option<Transform>, Option<Named>);
+
     #[test]
     fn test_prefab_load() {
         let mut world = World::new();
@@ -488,4 +982,467 @@ mod tests {
             .get(root_entity)
             .is_some());
     }
+
+    #[test]
+    fn sub_assets_are_recorded_as_dependencies() {
+        let mut world = World::new();
+        let pool = Arc::new(ThreadPoolBuilder::default().build().unwrap());
+        world.add_resource(pool.clone());
+        let source = Memory::new()
+            .with_asset(
+                "outer.ron",
+                r#"(entities: [(data: Some(File("inner.ron", (), ())))])"#,
+            )
+            .with_asset("inner.ron", "(entities: [()])");
+        world.add_resource(Loader::with_default_source(source, pool));
+        world.add_resource(Time::default());
+        let mut system = PrefabLoaderSystem::<NestedPrefab>::default();
+        RunNow::setup(&mut system, &mut world.res);
+
+        let handle = world.exec(|loader: PrefabLoader<'_, NestedPrefab>| {
+            loader.load("outer.ron", RonFormat, (), ())
+        });
+        world.create_entity().with(handle).build();
+
+        let dependencies = world.read_resource::<Loader>().dependencies().clone();
+        let outer = AssetKey::new("", "PREFAB", "outer.ron");
+        let inner = AssetKey::new("", "PREFAB", "inner.ron");
+        for _ in 0..500 {
+            system.run_now(&world.res);
+            if !dependencies.dependencies_of(&outer).is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(dependencies.dependencies_of(&outer), vec![inner.clone()]);
+        assert_eq!(dependencies.dependents_of(&inner), vec![outer]);
+    }
+
+    #[test]
+    fn asset_prefabs_are_captured_as_files() {
+        let mut world = World::new();
+        let pool = Arc::new(ThreadPoolBuilder::default().build().unwrap());
+        world.add_resource(pool.clone());
+        let source = Memory::new()
+            .with_asset(
+                "outer.ron",
+                r#"(entities: [(data: Some(File("inner.ron", (), ())))])"#,
+            )
+            .with_asset("inner.ron", "(entities: [()])");
+        world.add_resource(Loader::with_default_source(source, pool));
+        world.add_resource(Time::default());
+        let mut outer_system = PrefabLoaderSystem::<NestedPrefab>::default();
+        RunNow::setup(&mut outer_system, &mut world.res);
+        let mut inner_system = PrefabLoaderSystem::<MyPrefab>::default();
+        RunNow::setup(&mut inner_system, &mut world.res);
+
+        let handle = world.exec(|loader: PrefabLoader<'_, NestedPrefab>| {
+            loader.load("outer.ron", RonFormat, (), ())
+        });
+        let root = world.create_entity().with(handle).build();
+        for _ in 0..500 {
+            outer_system.run_now(&world.res);
+            inner_system.run_now(&world.res);
+            let loaded = world
+                .read_storage::<Handle<Prefab<MyPrefab>>>()
+                .get(root)
+                .map_or(false, |inner| {
+                    world
+                        .read_resource::<AssetStorage<Prefab<MyPrefab>>>()
+                        .get(inner)
+                        .is_some()
+                });
+            if loaded {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let prefab = world
+            .exec(
+                |(entities, parents, mut data): (
+                    Entities<'_>,
+                    ReadStorage<'_, Parent>,
+                    <NestedPrefab as PrefabData<'_>>::SystemData,
+                )| {
+                    Prefab::<NestedPrefab>::capture(root, &entities, &parents, &mut data)
+                },
+            )
+            .unwrap();
+        match prefab.entities[0].data() {
+            Some(AssetPrefab::File(name, RonFormat, ())) => assert_eq!(name, "inner.ron"),
+            _ => panic!("The asset prefab was not captured as a file"),
+        }
+    }
+
+    #[test]
+    fn referenced_prefabs_are_instantiated_with_overrides() {
+        let mut world = World::new();
+        let pool = Arc::new(ThreadPoolBuilder::default().build().unwrap());
+        world.add_resource(pool.clone());
+        let source = Memory::new()
+            .with_asset(
+                "base.ron",
+                r#"(entities: [
+                    (data: Some((Some((translation: (1.0, 0.0, 0.0))), Some((name: "base"))))),
+                    (
+                        parent: Some(0),
+                        data: Some((Some((translation: (2.0, 0.0, 0.0))), Some((name: "child")))),
+                    ),
+                ])"#,
+            )
+            .with_asset(
+                "variant.ron",
+                r#"(entities: [(
+                    data: Some((None, Some((name: "variant")))),
+                    prefab: Some((
+                        file: "base.ron",
+                        overrides: [(entity: 1, data: (None, Some((name: "patched"))))],
+                    )),
+                )])"#,
+            );
+        world.add_resource(Loader::with_default_source(source, pool));
+        world.add_resource(Time::default());
+        let mut system = PrefabLoaderSystem::<PatchedPrefab>::default();
+        RunNow::setup(&mut system, &mut world.res);
+
+        let handle = world.exec(|loader: PrefabLoader<'_, PatchedPrefab>| {
+            loader.load("variant.ron", RonFormat, (), ())
+        });
+        let root = world.create_entity().with(handle).build();
+        for _ in 0..500 {
+            system.run_now(&world.res);
+            if world.read_storage::<Named>().get(root).is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let names = world.read_storage::<Named>();
+        let transforms = world.read_storage::<Transform>();
+        assert_eq!(names.get(root).map(|n| &*n.name), Some("variant"));
+        assert_eq!(transforms.get(root).map(|t| t.translation().x), Some(1.0));
+        let children: Vec<_> = (&world.entities(), &world.read_storage::<Parent>())
+            .join()
+            .filter(|(_, parent)| parent.entity == root)
+            .map(|(child, _)| child)
+            .collect();
+        assert_eq!(children.len(), 1);
+        assert_eq!(names.get(children[0]).map(|n| &*n.name), Some("patched"));
+        assert_eq!(
+            transforms.get(children[0]).map(|t| t.translation().x),
+            Some(2.0)
+        );
+    }
+
+    #[test]
+    fn referenced_prefabs_are_loaded_from_the_referencing_source() {
+        let mut world = World::new();
+        let pool = Arc::new(ThreadPoolBuilder::default().build().unwrap());
+        world.add_resource(pool.clone());
+        let source = Memory::new()
+            .with_asset(
+                "base.ron",
+                r#"(entities: [(data: Some((Some((translation: (1.0, 0.0, 0.0))), None)))])"#,
+            )
+            .with_asset(
+                "variant.ron",
+                r#"(entities: [(
+                    data: Some((None, Some((name: "variant")))),
+                    prefab: Some((file: "base.ron")),
+                )])"#,
+            );
+        // Nothing is found in the default source.
+        let mut loader = Loader::with_default_source(Memory::new(), pool);
+        loader.add_source("mods", source);
+        world.add_resource(loader);
+        world.add_resource(Time::default());
+        let mut system = PrefabLoaderSystem::<PatchedPrefab>::default();
+        RunNow::setup(&mut system, &mut world.res);
+
+        let handle = world.read_resource::<Loader>().load_from(
+            "variant.ron",
+            RonFormat,
+            (),
+            "mods",
+            (),
+            &world.read_resource::<AssetStorage<Prefab<PatchedPrefab>>>(),
+        );
+        let root = world.create_entity().with(handle).build();
+        for _ in 0..500 {
+            system.run_now(&world.res);
+            if world.read_storage::<Named>().get(root).is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(
+            world.read_storage::<Named>().get(root).map(|n| &*n.name),
+            Some("variant")
+        );
+        assert_eq!(
+            world
+                .read_storage::<Transform>()
+                .get(root)
+                .map(|t| t.translation().x),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn mods_can_reference_the_base_prefab_with_the_same_name() {
+        let mut world = World::new();
+        let pool = Arc::new(ThreadPoolBuilder::default().build().unwrap());
+        world.add_resource(pool.clone());
+        let base = Memory::new().with_asset(
+            "tree.ron",
+            r#"(entities: [(data: Some((Some((translation: (1.0, 0.0, 0.0))), None)))])"#,
+        );
+        let mods = Memory::new().with_asset(
+            "tree.ron",
+            r#"(entities: [(
+                data: Some((None, Some((name: "modded tree")))),
+                prefab: Some((file: "tree.ron", source: Some(""))),
+            )])"#,
+        );
+        let mut loader = Loader::with_default_source(base, pool);
+        loader.add_source("mods", mods);
+        world.add_resource(loader);
+        world.add_resource(Time::default());
+        let mut system = PrefabLoaderSystem::<PatchedPrefab>::default();
+        RunNow::setup(&mut system, &mut world.res);
+
+        let handle = world.read_resource::<Loader>().load_from(
+            "tree.ron",
+            RonFormat,
+            (),
+            "mods",
+            (),
+            &world.read_resource::<AssetStorage<Prefab<PatchedPrefab>>>(),
+        );
+        let root = world.create_entity().with(handle).build();
+        for _ in 0..500 {
+            system.run_now(&world.res);
+            if world.read_storage::<Named>().get(root).is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // The reference isn't taken for a cycle, the base prefab is another asset.
+        assert_eq!(
+            world.read_storage::<Named>().get(root).map(|n| &*n.name),
+            Some("modded tree")
+        );
+        assert_eq!(
+            world
+                .read_storage::<Transform>()
+                .get(root)
+                .map(|t| t.translation().x),
+            Some(1.0)
+        );
+        let dependencies = world.read_resource::<Loader>().dependencies().clone();
+        assert_eq!(
+            dependencies.dependencies_of(&AssetKey::new("mods", "PREFAB", "tree.ron")),
+            vec![AssetKey::new("", "PREFAB", "tree.ron")]
+        );
+    }
+
+    #[test]
+    fn reloaded_prefabs_are_applied_to_their_instances() {
+        let mut world = World::new();
+        let pool = Arc::new(ThreadPoolBuilder::default().build().unwrap());
+        world.add_resource(pool.clone());
+        let source = Memory::new().with_asset(
+            "tree.ron",
+            r#"(entities: [
+                (data: Some((name: "tree"))),
+                (parent: Some(0), data: Some((name: "branch"))),
+                (parent: Some(1), data: Some((name: "leaf"))),
+            ])"#,
+        );
+        world.add_resource(Loader::with_default_source(source.clone(), pool));
+        world.add_resource(Time::default());
+        let mut hot_reload = HotReloadSystem::new(HotReloadStrategy::when_triggered());
+        RunNow::setup(&mut hot_reload, &mut world.res);
+        let mut system = PrefabLoaderSystem::<Named>::default().with_instance_reload();
+        RunNow::setup(&mut system, &mut world.res);
+
+        let handle = world
+            .exec(|loader: PrefabLoader<'_, Named>| loader.load("tree.ron", RonFormat, (), ()));
+        let root = world.create_entity().with(handle).build();
+        let mut run_until = |world: &mut World, name: &str| {
+            for _ in 0..500 {
+                world.write_resource::<Time>().increment_frame_number();
+                hot_reload.run_now(&world.res);
+                system.run_now(&world.res);
+                world.maintain();
+                if world.read_storage::<Named>().join().any(|n| n.name == name) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("No entity named {:?}", name);
+        };
+        run_until(&mut world, "leaf");
+        let named = |world: &World, name: &str| {
+            let names = world.read_storage::<Named>();
+            (&world.entities(), &names)
+                .join()
+                .find(|(_, n)| n.name == name)
+                .map(|(entity, _)| entity)
+                .unwrap()
+        };
+        let branch = named(&world, "branch");
+        let leaf = named(&world, "leaf");
+
+        source.insert(
+            "tree.ron",
+            r#"(entities: [
+                (data: Some((name: "tree"))),
+                (parent: Some(0), data: Some((name: "bough"))),
+                (parent: Some(1)),
+            ])"#,
+        );
+        world.write_resource::<HotReloadStrategy>().trigger();
+        run_until(&mut world, "bough");
+
+        let names = world.read_storage::<Named>();
+        assert!(world.entities().is_alive(branch));
+        assert!(world.entities().is_alive(leaf));
+        assert_eq!(names.get(leaf), None);
+        assert_eq!(names.get(branch).map(|n| &*n.name), Some("bough"));
+        assert_eq!(
+            world.read_storage::<Parent>().get(branch).map(|p| p.entity),
+            Some(root)
+        );
+        assert_eq!(
+            names.join().map(|n| n.name.to_string()).collect::<Vec<_>>(),
+            vec!["tree", "bough"]
+        );
+    }
+
+    #[test]
+    fn reloaded_prefabs_match_entities_by_prefab_index() {
+        let mut world = World::new();
+        let pool = Arc::new(ThreadPoolBuilder::default().build().unwrap());
+        world.add_resource(pool.clone());
+        let source = Memory::new()
+            .with_asset(
+                "branch.ron",
+                r#"(entities: [
+                    (data: Some((name: "branch"))),
+                    (parent: Some(0), data: Some((name: "twig"))),
+                ])"#,
+            )
+            .with_asset(
+                "tree.ron",
+                r#"(entities: [
+                    (data: Some((name: "tree"))),
+                    (parent: Some(0), prefab: Some((file: "branch.ron"))),
+                    (parent: Some(0), data: Some((name: "leaf"))),
+                ])"#,
+            );
+        world.add_resource(Loader::with_default_source(source.clone(), pool));
+        world.add_resource(Time::default());
+        let mut hot_reload = HotReloadSystem::new(HotReloadStrategy::when_triggered());
+        RunNow::setup(&mut hot_reload, &mut world.res);
+        let mut system = PrefabLoaderSystem::<Named>::default().with_instance_reload();
+        RunNow::setup(&mut system, &mut world.res);
+
+        let handle = world
+            .exec(|loader: PrefabLoader<'_, Named>| loader.load("tree.ron", RonFormat, (), ()));
+        world.create_entity().with(handle).build();
+        let mut run_until = |world: &mut World, name: &str| {
+            for _ in 0..500 {
+                world.write_resource::<Time>().increment_frame_number();
+                hot_reload.run_now(&world.res);
+                system.run_now(&world.res);
+                world.maintain();
+                if world.read_storage::<Named>().join().any(|n| n.name == name) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("No entity named {:?}", name);
+        };
+        run_until(&mut world, "leaf");
+        let named = |world: &World, name: &str| {
+            let names = world.read_storage::<Named>();
+            (&world.entities(), &names)
+                .join()
+                .find(|(_, n)| n.name == name)
+                .map(|(entity, _)| entity)
+                .unwrap()
+        };
+        let branch = named(&world, "branch");
+        let twig = named(&world, "twig");
+        let leaf = named(&world, "leaf");
+
+        // The entities of the referenced prefab no longer come before the leaf.
+        source.insert(
+            "tree.ron",
+            r#"(entities: [
+                (data: Some((name: "tree"))),
+                (parent: Some(0), data: Some((name: "bough"))),
+                (parent: Some(0), data: Some((name: "leaf"))),
+            ])"#,
+        );
+        world.write_resource::<HotReloadStrategy>().trigger();
+        run_until(&mut world, "bough");
+
+        assert_eq!(named(&world, "bough"), branch);
+        assert_eq!(named(&world, "leaf"), leaf);
+        assert!(!world.entities().is_alive(twig));
+    }
+
+    #[test]
+    fn entities_are_captured_into_a_prefab() {
+        let mut world = World::new();
+        world.register::<Transform>();
+        world.register::<GlobalTransform>();
+        world.register::<Parent>();
+
+        let mut moved = Transform::default();
+        moved.set_x(1.0);
+        let root = world.create_entity().with(Transform::default()).build();
+        let child = world
+            .create_entity()
+            .with(moved.clone())
+            .with(Parent { entity: root })
+            .build();
+        world.create_entity().with(Parent { entity: child }).build();
+        world.create_entity().with(Transform::default()).build();
+
+        let prefab = world
+            .exec(
+                |(entities, parents, mut data): (
+                    Entities<'_>,
+                    ReadStorage<'_, Parent>,
+                    <MyPrefab as PrefabData<'_>>::SystemData,
+                )| {
+                    Prefab::<MyPrefab>::capture(root, &entities, &parents, &mut data)
+                },
+            )
+            .expect("Failed to capture the prefab");
+
+        let ron = RonFormat
+            .export(&prefab)
+            .expect("Failed to export the prefab");
+        let loaded: Prefab<MyPrefab> = ron::de::from_str(&ron).expect("Failed to load the prefab");
+        for prefab in &[prefab, loaded] {
+            assert_eq!(
+                prefab.entities().map(|e| e.parent).collect::<Vec<_>>(),
+                vec![None, Some(0), Some(1)]
+            );
+            assert_eq!(
+                prefab
+                    .entities()
+                    .map(PrefabEntity::data)
+                    .collect::<Vec<_>>(),
+                vec![Some(&Transform::default()), Some(&moved), None]
+            );
+        }
+    }
 }
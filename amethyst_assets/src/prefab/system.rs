@@ -1,6 +1,7 @@
-use std::{marker::PhantomData, ops::Deref};
+use std::{marker::PhantomData, mem, ops::Deref};
 
-use log::error;
+use fnv::FnvHashMap;
+use log::{debug, error};
 
 use amethyst_core::{
     specs::{
@@ -11,37 +12,67 @@ use amethyst_core::{
 };
 use amethyst_error::{format_err, Error, ResultExt};
 
-use crate::{AssetStorage, Completion, Handle, HotReloadStrategy, ProcessingState};
+use crate::{AssetStorage, Completion, Handle, HotReloadStrategy, Loader, ProcessingState};
 
 use super::{Prefab, PrefabData, PrefabTag};
 
 /// System that load `Prefab`s for `PrefabData` `T`.
 ///
+/// By default, hot reloading a `Prefab` only affects the entities instantiated from it afterwards,
+/// see `with_instance_reload` to update the existing instances too.
+///
 /// ### Type parameters:
 ///
 /// - `T`: `PrefabData`
 pub struct PrefabLoaderSystem<T> {
     _m: PhantomData<T>,
-    entities: Vec<Entity>,
     finished: Vec<Entity>,
     to_process: BitSet,
     insert_reader: Option<ReaderId<ComponentEvent>>,
     next_tag: u64,
+    reload_instances: bool,
+    instances: FnvHashMap<Entity, Spawned<T>>,
+}
+
+// Entities instantiated from a prefab, by main `Entity`, kept to apply the prefab again when it is
+// hot reloaded.
+struct Spawned<T> {
+    handle: Handle<Prefab<T>>,
+    tag: u64,
+    // every created entity, by prefab index, see `Instance::reused`.
+    entities: FnvHashMap<Vec<usize>, Entity>,
 }
 
 impl<T> Default for PrefabLoaderSystem<T> {
     fn default() -> Self {
         PrefabLoaderSystem {
             _m: PhantomData,
-            entities: Vec::default(),
             finished: Vec::default(),
             to_process: BitSet::default(),
             insert_reader: None,
             next_tag: 0,
+            reload_instances: false,
+            instances: FnvHashMap::default(),
         }
     }
 }
 
+impl<T> PrefabLoaderSystem<T> {
+    /// Apply hot reloaded `Prefab`s to the entity hierarchies already instantiated from them.
+    ///
+    /// The existing entities are matched with the entries of the reloaded prefab by their index in
+    /// the prefab, and in the prefabs it references, so they keep their identity. The components
+    /// of the prefab data are removed from them with `PrefabData::remove_from_entity` before the
+    /// reloaded data is added again, so the components of the fields removed from the prefab are
+    /// removed too, unless the `PrefabData` keeps the default implementation which leaves them.
+    /// Entities are created for the entries added to the prefab, and the entities of removed
+    /// entries are deleted.
+    pub fn with_instance_reload(mut self) -> Self {
+        self.reload_instances = true;
+        self
+    }
+}
+
 impl<'a, T> System<'a> for PrefabLoaderSystem<T>
 where
     T: PrefabData<'a> + Send + Sync + 'static,
@@ -52,6 +83,7 @@ where
         ReadStorage<'a, Handle<Prefab<T>>>,
         Read<'a, Time>,
         ReadExpect<'a, ArcThreadPool>,
+        ReadExpect<'a, Loader>,
         Option<Read<'a, HotReloadStrategy>>,
         WriteStorage<'a, Parent>,
         WriteStorage<'a, PrefabTag<T>>,
@@ -65,21 +97,26 @@ where
             prefab_handles,
             time,
             pool,
+            loader,
             strategy,
             mut parents,
             mut tags,
             mut prefab_system_data,
         ) = data;
         let strategy = strategy.as_ref().map(Deref::deref);
+        let queue = prefab_storage.queue();
         prefab_storage.process(
             |mut d| {
                 d.tag = Some(self.next_tag);
                 self.next_tag += 1;
                 if !d.loading() {
-                    if !d
+                    let references = d.load_references(&loader, &queue).with_context(|_| {
+                        format_err!("Failed starting referenced prefab loading")
+                    })?;
+                    let sub_assets = d
                         .load_sub_assets(&mut prefab_system_data)
-                        .with_context(|_| format_err!("Failed starting sub asset loading"))?
-                    {
+                        .with_context(|_| format_err!("Failed starting sub asset loading"))?;
+                    if !references && !sub_assets {
                         return Ok(ProcessingState::Loaded(d));
                     }
                 }
@@ -106,47 +143,87 @@ where
                     self.to_process.add(*id);
                 }
             });
+        if self.reload_instances {
+            // instances whose main entity is gone or got another prefab are no longer updated.
+            self.instances.retain(|root, spawned| {
+                entities.is_alive(*root) && prefab_handles.get(*root) == Some(&spawned.handle)
+            });
+            for (root_entity, spawned) in &mut self.instances {
+                let prefab = match prefab_storage.get(&spawned.handle) {
+                    Some(prefab) => prefab,
+                    None => continue,
+                };
+                let tag = prefab
+                    .tag
+                    .expect("Unreachable: Every loaded prefab should have a `PrefabTag`");
+                if tag == spawned.tag {
+                    continue;
+                }
+                debug!(
+                    "Applying the reloaded prefab again to the entities of {:?}",
+                    root_entity
+                );
+                let mut instance = Instance {
+                    tag,
+                    entities: &entities,
+                    parents: &mut parents,
+                    tags: &mut tags,
+                    reload: true,
+                    reused: mem::replace(&mut spawned.entities, FnvHashMap::default()),
+                    created: FnvHashMap::default(),
+                };
+                instance.create(
+                    prefab,
+                    *root_entity,
+                    &[],
+                    &prefab_storage,
+                    &mut prefab_system_data,
+                );
+                for (_, removed) in instance.reused {
+                    if !entities.is_alive(removed) {
+                        continue;
+                    }
+                    if let Err(e) = entities.delete(removed) {
+                        error!("Failed deleting removed prefab entity: {:?}", e);
+                    }
+                }
+                spawned.entities = instance.created;
+                spawned.tag = tag;
+            }
+        }
+
         self.finished.clear();
         for (root_entity, handle, _) in (&*entities, &prefab_handles, &self.to_process).join() {
             if let Some(prefab) = prefab_storage.get(handle) {
                 self.finished.push(root_entity);
-                // create entities
-                self.entities.clear();
-                self.entities.push(root_entity);
-                for entity_data in prefab.entities.iter().skip(1) {
-                    let new_entity = entities.create();
-                    self.entities.push(new_entity);
-                    if let Some(parent) = entity_data.parent {
-                        parents
-                            .insert(
-                                new_entity,
-                                Parent {
-                                    entity: self.entities[parent],
-                                },
-                            )
-                            .expect("Unable to insert `Parent` for prefab");
-                    }
-                    tags.insert(
-                        new_entity,
-                        PrefabTag::new(
-                            prefab.tag.expect(
-                                "Unreachable: Every loaded prefab should have a `PrefabTag`",
-                            ),
-                        ),
-                    )
-                    .expect("Unable to insert `PrefabTag` for prefab entity");
-                }
-                // create components
-                for (index, entity_data) in prefab.entities.iter().enumerate() {
-                    if let Some(ref prefab_data) = &entity_data.data {
-                        prefab_data
-                            .add_to_entity(
-                                self.entities[index],
-                                &mut prefab_system_data,
-                                &self.entities,
-                            )
-                            .expect("Unable to add prefab system data to entity");
-                    }
+                let tag = prefab
+                    .tag
+                    .expect("Unreachable: Every loaded prefab should have a `PrefabTag`");
+                let mut instance = Instance {
+                    tag,
+                    entities: &entities,
+                    parents: &mut parents,
+                    tags: &mut tags,
+                    reload: false,
+                    reused: FnvHashMap::default(),
+                    created: FnvHashMap::default(),
+                };
+                instance.create(
+                    prefab,
+                    root_entity,
+                    &[],
+                    &prefab_storage,
+                    &mut prefab_system_data,
+                );
+                if self.reload_instances {
+                    self.instances.insert(
+                        root_entity,
+                        Spawned {
+                            handle: handle.clone(),
+                            tag,
+                            entities: instance.created,
+                        },
+                    );
                 }
             }
         }
@@ -162,3 +239,119 @@ where
         self.insert_reader = Some(WriteStorage::<Handle<Prefab<T>>>::fetch(&res).register_reader());
     }
 }
+
+// Storages the entities of a prefab instance are created in.
+struct Instance<'s, 'a, T>
+where
+    T: Send + Sync + 'static,
+{
+    tag: u64,
+    entities: &'s Entities<'a>,
+    parents: &'s mut WriteStorage<'a, Parent>,
+    tags: &'s mut WriteStorage<'a, PrefabTag<T>>,
+    // whether the prefab is applied again to a previous instance.
+    reload: bool,
+    // entities of a previous instance, used before creating new ones if they are still alive,
+    // by prefab index: the index of the entry in the prefab, preceded by the indices of the
+    // entries referencing the prefab it belongs to.
+    reused: FnvHashMap<Vec<usize>, Entity>,
+    // every entity used by the instance besides the main one, by prefab index.
+    created: FnvHashMap<Vec<usize>, Entity>,
+}
+
+impl<'s, 'a, T> Instance<'s, 'a, T>
+where
+    T: PrefabData<'a> + Send + Sync + 'static,
+{
+    // Creates the entities of `prefab` on `root`, along with the entities of the prefabs it
+    // references, and returns them in prefab order. `path` is the prefab index of `root`.
+    fn create(
+        &mut self,
+        prefab: &Prefab<T>,
+        root: Entity,
+        path: &[usize],
+        storage: &AssetStorage<Prefab<T>>,
+        system_data: &mut T::SystemData,
+    ) -> Vec<Entity> {
+        // create entities
+        let mut created = vec![root];
+        for (index, entity_data) in prefab.entities.iter().enumerate().skip(1) {
+            let mut entity_path = path.to_vec();
+            entity_path.push(index);
+            let entities = self.entities;
+            let new_entity = match self.reused.remove(&entity_path) {
+                Some(entity) if entities.is_alive(entity) => entity,
+                _ => entities.create(),
+            };
+            self.created.insert(entity_path, new_entity);
+            created.push(new_entity);
+            if let Some(parent) = entity_data.parent {
+                self.parents
+                    .insert(
+                        new_entity,
+                        Parent {
+                            entity: created[parent],
+                        },
+                    )
+                    .expect("Unable to insert `Parent` for prefab");
+            } else {
+                self.parents.remove(new_entity);
+            }
+            self.tags
+                .insert(new_entity, PrefabTag::new(self.tag))
+                .expect("Unable to insert `PrefabTag` for prefab entity");
+        }
+        // a reloaded prefab replaces the data it added before, fields it no longer has included
+        if self.reload {
+            for entity in &created {
+                T::remove_from_entity(*entity, system_data)
+                    .expect("Unable to remove prefab system data from entity");
+            }
+        }
+        // create components, the referenced prefabs first so the entity data patches them
+        for (index, entity_data) in prefab.entities.iter().enumerate() {
+            let referenced = match entity_data.prefab {
+                Some(ref reference) => {
+                    let referenced_prefab = reference
+                        .handle()
+                        .and_then(|handle| storage.get(handle))
+                        .expect("Unreachable: Referenced prefabs are loaded first");
+                    let mut referenced_path = path.to_vec();
+                    referenced_path.push(index);
+                    let referenced_entities = self.create(
+                        referenced_prefab,
+                        created[index],
+                        &referenced_path,
+                        storage,
+                        system_data,
+                    );
+                    Some((reference, referenced_entities))
+                }
+                None => None,
+            };
+            if let Some(ref prefab_data) = &entity_data.data {
+                prefab_data
+                    .add_to_entity(created[index], system_data, &created)
+                    .expect("Unable to add prefab system data to entity");
+            }
+            if let Some((reference, referenced_entities)) = referenced {
+                for o in reference.overrides() {
+                    match referenced_entities.get(o.entity) {
+                        Some(entity) => {
+                            o.data
+                                .add_to_entity(*entity, system_data, &referenced_entities)
+                                .expect("Unable to add prefab override data to entity");
+                        }
+                        None => error!(
+                            "Override of entity {} is out of the {} entities of prefab {:?}",
+                            o.entity,
+                            referenced_entities.len(),
+                            reference.file()
+                        ),
+                    }
+                }
+            }
+        }
+        created
+    }
+}
@@ -0,0 +1,97 @@
+//! The assets being processed on the current thread, for the loads they start meanwhile.
+
+use std::cell::RefCell;
+
+use crate::{dependencies::AssetKey, progress::ProgressCounter};
+
+thread_local! {
+    // the assets being processed on this thread, the last one being the innermost.
+    static PROCESSING: RefCell<Vec<Processed>> = RefCell::new(Vec::new());
+}
+
+struct Processed {
+    asset: AssetKey,
+    counter: Option<ProgressCounter>,
+}
+
+// Pops the asset from the processed ones when dropped, even if processing it panicked.
+struct ProcessingGuard;
+
+impl Drop for ProcessingGuard {
+    fn drop(&mut self) {
+        PROCESSING.with(|processing| processing.borrow_mut().pop());
+    }
+}
+
+/// Calls `f` while the given asset, tracked with `counter`, is processed on this thread.
+///
+/// The assets loaded meanwhile are recorded as its dependencies, referenced prefabs are loaded
+/// from its source, and the counters created with `progress::nested_counter` are nested in
+/// `counter`.
+pub(crate) fn scope<F, R>(asset: AssetKey, counter: Option<ProgressCounter>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    PROCESSING.with(|processing| processing.borrow_mut().push(Processed { asset, counter }));
+    let _guard = ProcessingGuard;
+    f()
+}
+
+/// Returns the asset processed on this thread, if any.
+pub(crate) fn asset() -> Option<AssetKey> {
+    PROCESSING.with(|processing| processing.borrow().last().map(|p| p.asset.clone()))
+}
+
+/// Returns the id of the source the asset processed on this thread was loaded from, if any.
+pub(crate) fn source() -> Option<String> {
+    PROCESSING.with(|processing| processing.borrow().last().map(|p| p.asset.source.clone()))
+}
+
+/// Returns the counter tracking the asset processed on this thread, if any.
+pub(crate) fn counter() -> Option<ProgressCounter> {
+    PROCESSING.with(|processing| {
+        processing
+            .borrow()
+            .last()
+            .and_then(|p| p.counter.as_ref().map(ProgressCounter::shared))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic;
+
+    use crate::{dependencies::AssetKey, progress::ProgressCounter};
+
+    use super::{asset, counter, scope, source};
+
+    #[test]
+    fn processed_assets_are_scoped() {
+        assert_eq!(asset(), None);
+        let outer = AssetKey::new("", "Prefab", "outer");
+        let inner = AssetKey::new("mods", "Prefab", "inner");
+        scope(outer.clone(), Some(ProgressCounter::new()), || {
+            scope(inner.clone(), None, || {
+                assert_eq!(asset(), Some(inner));
+                assert_eq!(source(), Some("mods".into()));
+                assert!(counter().is_none());
+            });
+            assert_eq!(asset(), Some(outer));
+            assert_eq!(source(), Some("".into()));
+            assert!(counter().is_some());
+        });
+        assert_eq!(asset(), None);
+        assert_eq!(source(), None);
+    }
+
+    #[test]
+    fn processed_asset_is_popped_on_panic() {
+        let result = panic::catch_unwind(|| {
+            scope(AssetKey::new("", "Prefab", "panicking"), None, || {
+                panic!("processing")
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(asset(), None);
+    }
+}
@@ -1,12 +1,18 @@
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use amethyst_error::Error;
 use log::error;
 use parking_lot::Mutex;
 
+use crate::processing;
+
 /// Completion status, returned by `ProgressCounter::complete`.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Completion {
@@ -43,12 +49,69 @@ impl Progress for () {
 
 /// A progress tracker which is passed to the `Loader`
 /// in order to check how many assets are loaded.
+///
+/// Besides the counts, it records every tracked asset, with its size and loading time, see
+/// `assets`. The counters created while one of its assets is processed, like the one tracking
+/// the sub assets of a `Prefab`, are nested in it: every count, `complete`, `errors` and the
+/// asset lists include the assets of the nested counters.
+///
+/// The records and the nested counters are kept until the counter is dropped, so a counter is
+/// meant for a single loading phase, like the loading of a level: create a new counter for every
+/// phase instead of passing the same one to every load of the game.
 #[derive(Default)]
 pub struct ProgressCounter {
     errors: Arc<Mutex<Vec<AssetErrorMeta>>>,
-    num_assets: usize,
+    num_assets: Arc<AtomicUsize>,
     num_failed: Arc<AtomicUsize>,
     num_loading: Arc<AtomicUsize>,
+    records: Arc<Mutex<Records>>,
+}
+
+// The assets tracked by a counter, and the counters nested in it, kept as long as the counter.
+#[derive(Default)]
+struct Records {
+    assets: Vec<AssetRecord>,
+    nested: Vec<ProgressCounter>,
+}
+
+struct AssetRecord {
+    asset_type_name: &'static str,
+    asset_name: String,
+    bytes: Option<usize>,
+    requested: Instant,
+    finished: Option<(Instant, bool)>,
+    // whether the load was cancelled, the record is then skipped.
+    cancelled: bool,
+}
+
+/// Progress of a single asset, returned by `ProgressCounter::assets`.
+#[derive(Clone, Debug)]
+pub struct AssetProgress {
+    /// The type of the asset
+    pub asset_type_name: &'static str,
+    /// The name the asset is loaded with
+    pub asset_name: String,
+    /// Number of bytes read to import the asset, `None` until it is imported
+    pub bytes: Option<usize>,
+    /// Whether the asset is loading, loaded or failed
+    pub completion: Completion,
+    /// Time spent loading the asset, up to now if it is still loading
+    pub duration: Duration,
+}
+
+/// Progress of the assets of a type, returned by `ProgressCounter::by_type`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TypeProgress {
+    /// Number of tracked assets
+    pub num_assets: usize,
+    /// Number of assets still loading
+    pub num_loading: usize,
+    /// Number of assets which failed
+    pub num_failed: usize,
+    /// Number of bytes read for the finished assets
+    pub bytes_loaded: usize,
+    /// Total time spent loading the finished assets
+    pub duration: Duration,
 }
 
 impl ProgressCounter {
@@ -57,40 +120,41 @@ impl ProgressCounter {
         Default::default()
     }
 
-    /// Removes all errors and returns them.
+    /// Removes all errors, including those of the nested counters, and returns them.
     pub fn errors(&self) -> Vec<AssetErrorMeta> {
-        let mut lock = self.errors.lock();
-        let rv = lock.drain(..).collect();
-
-        rv
+        let mut errors: Vec<_> = self.errors.lock().drain(..).collect();
+        for nested in self.nested() {
+            errors.extend(nested.errors());
+        }
+        errors
     }
 
-    /// Returns the number of assets this struct is tracking.
+    /// Returns the number of assets this struct is tracking, including those of the nested
+    /// counters.
     pub fn num_assets(&self) -> usize {
-        self.num_assets
+        self.total(&|counter| counter.num_assets.load(Ordering::Relaxed))
     }
 
-    /// Returns the number of assets that have failed.
+    /// Returns the number of assets that have failed, including those of the nested counters.
     pub fn num_failed(&self) -> usize {
-        self.num_failed.load(Ordering::Relaxed)
+        self.total(&|counter| counter.num_failed.load(Ordering::Relaxed))
     }
 
-    /// Returns the number of assets that are still loading.
+    /// Returns the number of assets that are still loading, including those of the nested
+    /// counters.
     pub fn num_loading(&self) -> usize {
-        self.num_loading.load(Ordering::Relaxed)
+        self.total(&|counter| counter.num_loading.load(Ordering::Relaxed))
     }
 
-    /// Returns the number of assets this struct is tracking.
+    /// Returns the number of assets that are finished, including those of the nested counters.
     pub fn num_finished(&self) -> usize {
-        self.num_assets - self.num_loading()
+        self.num_assets() - self.num_loading()
     }
 
-    /// Returns `Completion::Complete` if all tracked assets are finished.
+    /// Returns `Completion::Complete` if all tracked assets are finished, including those of the
+    /// nested counters.
     pub fn complete(&self) -> Completion {
-        match (
-            self.num_failed.load(Ordering::Relaxed),
-            self.num_loading.load(Ordering::Relaxed),
-        ) {
+        match (self.num_failed(), self.num_loading()) {
             (0, 0) => Completion::Complete,
             (0, _) => Completion::Loading,
             (_, _) => Completion::Failed,
@@ -101,13 +165,164 @@ impl ProgressCounter {
     pub fn is_complete(&self) -> bool {
         self.complete() == Completion::Complete
     }
+
+    /// Returns the tracked assets, including those of the nested counters, in the order they
+    /// were requested.
+    ///
+    /// Sorting them by `duration` helps finding slow loads.
+    pub fn assets(&self) -> Vec<AssetProgress> {
+        let now = Instant::now();
+        let mut assets = Vec::new();
+        collect(&self.records, &mut |record| {
+            let (completion, duration) = match record.finished {
+                None => (Completion::Loading, now - record.requested),
+                Some((finished, false)) => (Completion::Complete, finished - record.requested),
+                Some((finished, true)) => (Completion::Failed, finished - record.requested),
+            };
+            assets.push(AssetProgress {
+                asset_type_name: record.asset_type_name,
+                asset_name: record.asset_name.clone(),
+                bytes: record.bytes,
+                completion,
+                duration,
+            });
+        });
+        assets
+    }
+
+    /// Returns the assets still loading, including those of the nested counters.
+    pub fn loading_assets(&self) -> Vec<AssetProgress> {
+        self.assets()
+            .into_iter()
+            .filter(|asset| asset.completion == Completion::Loading)
+            .collect()
+    }
+
+    /// Returns the progress of the tracked assets by asset type, including those of the nested
+    /// counters.
+    pub fn by_type(&self) -> BTreeMap<&'static str, TypeProgress> {
+        let mut types = BTreeMap::<_, TypeProgress>::new();
+        for asset in self.assets() {
+            let progress = types.entry(asset.asset_type_name).or_default();
+            progress.num_assets += 1;
+            match asset.completion {
+                Completion::Loading => progress.num_loading += 1,
+                Completion::Failed => progress.num_failed += 1,
+                Completion::Complete => {}
+            }
+            if asset.completion != Completion::Loading {
+                progress.bytes_loaded += asset.bytes.unwrap_or(0);
+                progress.duration += asset.duration;
+            }
+        }
+        types
+    }
+
+    /// Returns the number of bytes read for the finished assets, including those of the nested
+    /// counters.
+    pub fn bytes_loaded(&self) -> usize {
+        self.assets()
+            .iter()
+            .filter(|asset| asset.completion != Completion::Loading)
+            .filter_map(|asset| asset.bytes)
+            .sum()
+    }
+
+    /// Returns the progress as the fraction of the bytes of the tracked assets which are loaded,
+    /// from `0.0` to `1.0`, including the assets of the nested counters.
+    ///
+    /// The size of an asset is only known once it is imported, until then it is estimated as the
+    /// average size of the imported assets.
+    pub fn fraction(&self) -> f32 {
+        let assets = self.assets();
+        let sizes: Vec<usize> = assets.iter().filter_map(|asset| asset.bytes).collect();
+        let estimate = match sizes.len() {
+            0 => 1.0,
+            n => (sizes.iter().sum::<usize>() as f64 / n as f64).max(1.0),
+        };
+
+        let (mut loaded, mut total) = (0.0, 0.0);
+        for asset in &assets {
+            let size = asset.bytes.map_or(estimate, |bytes| bytes as f64);
+            total += size;
+            if asset.completion != Completion::Loading {
+                loaded += size;
+            }
+        }
+        if total > 0.0 {
+            (loaded / total) as f32
+        } else {
+            1.0
+        }
+    }
+
+    /// Returns another counter sharing the counts and records of this one.
+    pub(crate) fn shared(&self) -> Self {
+        ProgressCounter {
+            errors: self.errors.clone(),
+            num_assets: self.num_assets.clone(),
+            num_failed: self.num_failed.clone(),
+            num_loading: self.num_loading.clone(),
+            records: self.records.clone(),
+        }
+    }
+
+    // The nested counters, cloned so their records aren't visited under the lock.
+    fn nested(&self) -> Vec<ProgressCounter> {
+        self.records
+            .lock()
+            .nested
+            .iter()
+            .map(Self::shared)
+            .collect()
+    }
+
+    // Sums a count of this counter and of its nested counters.
+    fn total(&self, count: &dyn Fn(&ProgressCounter) -> usize) -> usize {
+        count(self)
+            + self
+                .nested()
+                .iter()
+                .map(|nested| nested.total(count))
+                .sum::<usize>()
+    }
+}
+
+// Visits the records of a counter and of its nested counters.
+fn collect<F>(records: &Arc<Mutex<Records>>, f: &mut F)
+where
+    F: FnMut(&AssetRecord),
+{
+    let nested = {
+        let records = records.lock();
+        for record in records.assets.iter().filter(|record| !record.cancelled) {
+            f(record);
+        }
+        records
+            .nested
+            .iter()
+            .map(ProgressCounter::shared)
+            .collect::<Vec<_>>()
+    };
+    for counter in &nested {
+        collect(&counter.records, f);
+    }
+}
+
+/// Creates a counter nested in the counter of the asset being processed, if any.
+pub(crate) fn nested_counter() -> ProgressCounter {
+    let counter = ProgressCounter::new();
+    if let Some(parent) = processing::counter() {
+        parent.records.lock().nested.push(counter.shared());
+    }
+    counter
 }
 
 impl<'a> Progress for &'a mut ProgressCounter {
     type Tracker = ProgressCounterTracker;
 
     fn add_assets(&mut self, num: usize) {
-        self.num_assets += num;
+        self.num_assets.fetch_add(num, Ordering::Relaxed);
     }
 
     fn create_tracker(self) -> Self::Tracker {
@@ -118,8 +333,11 @@ impl<'a> Progress for &'a mut ProgressCounter {
 
         ProgressCounterTracker {
             errors,
+            num_assets: self.num_assets.clone(),
             num_failed,
             num_loading,
+            records: self.records.clone(),
+            record: None,
         }
     }
 }
@@ -128,12 +346,62 @@ impl<'a> Progress for &'a mut ProgressCounter {
 #[derive(Default)]
 pub struct ProgressCounterTracker {
     errors: Arc<Mutex<Vec<AssetErrorMeta>>>,
+    num_assets: Arc<AtomicUsize>,
     num_failed: Arc<AtomicUsize>,
     num_loading: Arc<AtomicUsize>,
+    records: Arc<Mutex<Records>>,
+    // index of the record of the tracked asset.
+    record: Option<usize>,
+}
+
+impl ProgressCounterTracker {
+    fn finish(&self, failed: bool) {
+        if let Some(index) = self.record {
+            self.records.lock().assets[index].finished = Some((Instant::now(), failed));
+        }
+    }
 }
 
 impl Tracker for ProgressCounterTracker {
+    fn requested(&mut self, asset_type_name: &'static str, asset_name: &str) {
+        let mut records = self.records.lock();
+        self.record = Some(records.assets.len());
+        records.assets.push(AssetRecord {
+            asset_type_name,
+            asset_name: asset_name.to_string(),
+            bytes: None,
+            requested: Instant::now(),
+            finished: None,
+            cancelled: false,
+        });
+    }
+
+    fn imported(&mut self, bytes: usize) {
+        if let Some(index) = self.record {
+            self.records.lock().assets[index].bytes = Some(bytes);
+        }
+    }
+
+    fn counter(&self) -> Option<ProgressCounter> {
+        Some(ProgressCounter {
+            errors: self.errors.clone(),
+            num_assets: self.num_assets.clone(),
+            num_failed: self.num_failed.clone(),
+            num_loading: self.num_loading.clone(),
+            records: self.records.clone(),
+        })
+    }
+
     fn success(self: Box<Self>) {
+        self.finish(false);
+        self.num_loading.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn cancelled(self: Box<Self>) {
+        if let Some(index) = self.record {
+            self.records.lock().assets[index].cancelled = true;
+        }
+        self.num_assets.fetch_sub(1, Ordering::Relaxed);
         self.num_loading.fetch_sub(1, Ordering::Relaxed);
     }
 
@@ -145,6 +413,7 @@ impl Tracker for ProgressCounterTracker {
         error: Error,
     ) {
         show_error(handle_id, asset_type_name, &asset_name, &error);
+        self.finish(true);
         self.errors.lock().push(AssetErrorMeta {
             error,
             handle_id,
@@ -166,9 +435,33 @@ pub struct AssetErrorMeta {
 /// The `Tracker` trait which will be used by the loader to report
 /// back to `Progress`.
 pub trait Tracker: Send + 'static {
+    /// Called by the `Loader` once the tracker is created, with the asset it tracks.
+    fn requested(&mut self, asset_type_name: &'static str, asset_name: &str) {
+        let _ = (asset_type_name, asset_name);
+    }
+
+    /// Called once the asset is imported, with the number of bytes read from its source.
+    fn imported(&mut self, bytes: usize) {
+        let _ = bytes;
+    }
+
+    /// Returns the counter the tracker reports to, if any.
+    ///
+    /// The counters created while the asset is processed, like the one tracking the sub assets
+    /// of a `Prefab`, are nested in it.
+    fn counter(&self) -> Option<ProgressCounter> {
+        None
+    }
+
     // TODO: maybe add handles as parameters?
     /// Called if the asset could be imported.
     fn success(self: Box<Self>);
+    /// Called if the load was cancelled because the asset wasn't used anymore.
+    ///
+    /// Reports a success by default.
+    fn cancelled(self: Box<Self>) {
+        self.success();
+    }
     /// Called if the asset couldn't be imported to an error.
     fn fail(
         self: Box<Self>,
@@ -204,3 +497,88 @@ fn show_error(handle_id: u32, asset_type_name: &'static str, asset_name: &String
         .for_each(|e| err_out.push_str(&format!("\r\ncaused by: {:?}", e)));
     error!("{}", err_out);
 }
+
+#[cfg(test)]
+mod tests {
+    use amethyst_error::format_err;
+
+    use crate::{dependencies::AssetKey, processing};
+
+    use super::*;
+
+    fn track(
+        mut counter: &mut ProgressCounter,
+        asset_type_name: &'static str,
+        asset_name: &str,
+    ) -> Box<ProgressCounterTracker> {
+        counter.add_assets(1);
+        let mut tracker = counter.create_tracker();
+        tracker.requested(asset_type_name, asset_name);
+        Box::new(tracker)
+    }
+
+    #[test]
+    fn nested_counters_are_aggregated() {
+        let mut counter = ProgressCounter::new();
+        let mut mesh = track(&mut counter, "Mesh", "mesh.obj");
+        let prefab = track(&mut counter, "Prefab", "scene.ron");
+        mesh.imported(300);
+        mesh.success();
+
+        // Sub assets loaded while the prefab is processed.
+        let scene = AssetKey::new("", "Prefab", "scene.ron");
+        let mut nested = processing::scope(scene, prefab.counter(), nested_counter);
+        let mut texture = track(&mut nested, "Texture", "texture.png");
+        let other = track(&mut nested, "Texture", "other.png");
+        texture.imported(100);
+        texture.success();
+
+        assert_eq!(counter.num_assets(), 4);
+        assert_eq!(counter.num_loading(), 2);
+        assert_eq!(nested.num_assets(), 2);
+        let loading: Vec<_> = counter
+            .loading_assets()
+            .into_iter()
+            .map(|asset| asset.asset_name)
+            .collect();
+        assert_eq!(loading, vec!["scene.ron", "other.png"]);
+        assert_eq!(counter.bytes_loaded(), 400);
+        // The sizes still unknown are estimated as the average of 300 and 100 bytes.
+        assert!((counter.fraction() - 0.5).abs() < 1e-6);
+
+        other.fail(0, "Texture", "other.png".into(), format_err!("Missing"));
+        prefab.success();
+        let textures = &counter.by_type()["Texture"];
+        assert_eq!(textures.num_assets, 2);
+        assert_eq!(textures.num_loading, 0);
+        assert_eq!(textures.num_failed, 1);
+        assert_eq!(textures.bytes_loaded, 100);
+        assert!((counter.fraction() - 1.0).abs() < 1e-6);
+        assert_eq!(counter.complete(), Completion::Failed);
+        assert_eq!(nested.complete(), Completion::Failed);
+        let errors = counter.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].asset_name, "other.png");
+        assert!(nested.errors().is_empty());
+    }
+
+    #[test]
+    fn cancelled_loads_are_not_counted() {
+        let mut counter = ProgressCounter::new();
+        let mesh = track(&mut counter, "Mesh", "mesh.obj");
+        let texture = track(&mut counter, "Texture", "texture.png");
+        mesh.success();
+        texture.cancelled();
+
+        assert_eq!(counter.num_assets(), 1);
+        assert_eq!(counter.num_loading(), 0);
+        assert_eq!(counter.num_finished(), 1);
+        assert_eq!(counter.complete(), Completion::Complete);
+        let assets: Vec<_> = counter
+            .assets()
+            .into_iter()
+            .map(|asset| asset.asset_name)
+            .collect();
+        assert_eq!(assets, vec!["mesh.obj"]);
+    }
+}
@@ -1,9 +1,17 @@
 //! Defines the `Reload` trait.
 
-use std::{sync::Arc, time::Instant};
+use std::{
+    collections::HashSet,
+    hash::Hash,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::Instant,
+};
 
 use amethyst_core::{
-    specs::prelude::{DispatcherBuilder, Read, Resources, System, Write},
+    specs::prelude::{DispatcherBuilder, Read, ReadExpect, Resources, System, Write},
     SystemBundle, Time,
 };
 use amethyst_error::Error;
@@ -11,7 +19,7 @@ use amethyst_error::Error;
 #[cfg(feature = "profiler")]
 use thread_profiler::profile_scope;
 
-use crate::{Asset, Format, FormatValue, Loader, Source};
+use crate::{loader::Polled, Asset, AssetKey, Dependencies, Format, FormatValue, Loader, Source};
 
 /// This bundle activates hot reload for the `Loader`,
 /// adds a `HotReloadStrategy` and the `HotReloadSystem`.
@@ -48,42 +56,68 @@ impl<'a, 'b> SystemBundle<'a, 'b> for HotReloadBundle {
 /// world.add_resource(HotReloadStrategy::every(2));
 /// # }
 /// ```
+///
+/// Whatever the strategy, the assets depending on a reloaded asset are reloaded the frame after,
+/// see `Dependencies`.
 #[derive(Clone)]
 pub struct HotReloadStrategy {
     inner: HotReloadStrategyInner,
+    // dependents of the reloaded assets.
+    cascade: Staged<AssetKey>,
+    dependencies: Option<Dependencies>,
 }
 
 impl HotReloadStrategy {
+    fn new(inner: HotReloadStrategyInner) -> Self {
+        HotReloadStrategy {
+            inner,
+            cascade: Staged::default(),
+            dependencies: None,
+        }
+    }
+
     /// Causes hot reloads every `n` seconds.
     pub fn every(n: u8) -> Self {
         use std::u64::MAX;
 
-        HotReloadStrategy {
-            inner: HotReloadStrategyInner::Every {
-                interval: n,
-                last: Instant::now(),
-                frame_number: MAX,
-            },
-        }
+        HotReloadStrategy::new(HotReloadStrategyInner::Every {
+            interval: n,
+            last: Instant::now(),
+            frame_number: MAX,
+        })
+    }
+
+    /// Reloads the assets the frame after they changed, using the notifications of the OS
+    /// instead of checking every asset.
+    ///
+    /// Only the sources supporting it are watched, like the `Directory` and `Memory` sources.
+    /// The assets of the other sources, or of the layers of a `Layered` source which can't be
+    /// watched, are checked every `poll_interval` seconds instead, like with `every`.
+    pub fn when_changed(poll_interval: u8) -> Self {
+        use std::u64::MAX;
+
+        HotReloadStrategy::new(HotReloadStrategyInner::Watch {
+            changed: Staged::default(),
+            polled: None,
+            interval: poll_interval,
+            last: Instant::now(),
+            frame_number: MAX,
+        })
     }
 
     /// This allows to use `trigger` for hot reloading.
     pub fn when_triggered() -> Self {
         use std::u64::MAX;
 
-        HotReloadStrategy {
-            inner: HotReloadStrategyInner::Trigger {
-                triggered: false,
-                frame_number: MAX,
-            },
-        }
+        HotReloadStrategy::new(HotReloadStrategyInner::Trigger {
+            triggered: false,
+            frame_number: MAX,
+        })
     }
 
     /// Never do any hot-reloading.
     pub fn never() -> Self {
-        HotReloadStrategy {
-            inner: HotReloadStrategyInner::Never,
-        }
+        HotReloadStrategy::new(HotReloadStrategyInner::Never)
     }
 
     /// The frame after calling this, all changed assets will be reloaded.
@@ -104,9 +138,84 @@ impl HotReloadStrategy {
         match self.inner {
             HotReloadStrategyInner::Every { frame_number, .. } => frame_number == current_frame,
             HotReloadStrategyInner::Trigger { frame_number, .. } => frame_number == current_frame,
+            HotReloadStrategyInner::Watch { frame_number, .. } => frame_number == current_frame,
             HotReloadStrategyInner::Never => false,
         }
     }
+
+    /// Crate-internal method returning `true` if the asset with the given name, loaded from the
+    /// source with the given id, is checked for a reload when `needs_reload` returns `true`.
+    pub(crate) fn polls(&self, source: &str, name: &str) -> bool {
+        match self.inner {
+            HotReloadStrategyInner::Watch { ref polled, .. } => polled
+                .as_ref()
+                .map_or(true, |polled| polled.polls(source, name)),
+            _ => true,
+        }
+    }
+
+    /// Crate-internal method returning the paths of the changed assets
+    /// which have to be checked for a reload in the current frame.
+    pub(crate) fn changed_assets(&self, current_frame: u64) -> Option<&HashSet<String>> {
+        match self.inner {
+            HotReloadStrategyInner::Watch { ref changed, .. } => changed.get(current_frame),
+            _ => None,
+        }
+    }
+
+    /// Crate-internal method returning the assets which have to be reloaded in the current frame
+    /// because an asset they depend on was reloaded.
+    pub(crate) fn cascaded_assets(&self, current_frame: u64) -> Option<&HashSet<AssetKey>> {
+        self.cascade.get(current_frame)
+    }
+
+    /// Crate-internal method returning the dependencies recorded by the `Loader`.
+    pub(crate) fn dependencies(&self) -> Option<&Dependencies> {
+        self.dependencies.as_ref()
+    }
+}
+
+// Assets to check the frame after they are staged.
+#[derive(Clone)]
+struct Staged<T> {
+    assets: HashSet<T>,
+    frame_number: u64,
+}
+
+impl<T: Eq + Hash> Staged<T> {
+    fn get(&self, current_frame: u64) -> Option<&HashSet<T>> {
+        if self.frame_number == current_frame && !self.assets.is_empty() {
+            Some(&self.assets)
+        } else {
+            None
+        }
+    }
+
+    // Stages the assets returned by `incoming` for the next frame. They are left where they are
+    // if the previously staged ones may not have been checked yet.
+    fn stage<F, I>(&mut self, current_frame: u64, incoming: F)
+    where
+        F: FnOnce() -> I,
+        I: IntoIterator<Item = T>,
+    {
+        if self.frame_number != std::u64::MAX && self.frame_number >= current_frame {
+            return;
+        }
+        self.assets.clear();
+        self.assets.extend(incoming());
+        if !self.assets.is_empty() {
+            self.frame_number = current_frame + 1;
+        }
+    }
+}
+
+impl<T> Default for Staged<T> {
+    fn default() -> Self {
+        Staged {
+            assets: HashSet::new(),
+            frame_number: std::u64::MAX,
+        }
+    }
 }
 
 impl Default for HotReloadStrategy {
@@ -126,12 +235,22 @@ enum HotReloadStrategyInner {
         triggered: bool,
         frame_number: u64,
     },
+    Watch {
+        changed: Staged<String>,
+        // polling of the assets which can't be watched.
+        polled: Option<Polled>,
+        interval: u8,
+        last: Instant,
+        frame_number: u64,
+    },
     Never,
 }
 
 /// System for updating `HotReloadStrategy`.
 pub struct HotReloadSystem {
     initial_strategy: HotReloadStrategy,
+    // receives the changed assets if the strategy was created with `when_changed`.
+    changes: Option<Receiver<String>>,
 }
 
 impl HotReloadSystem {
@@ -139,14 +258,26 @@ impl HotReloadSystem {
     pub fn new(strategy: HotReloadStrategy) -> Self {
         HotReloadSystem {
             initial_strategy: strategy,
+            changes: None,
         }
     }
 }
 
 impl<'a> System<'a> for HotReloadSystem {
-    type SystemData = (Read<'a, Time>, Write<'a, HotReloadStrategy>);
+    type SystemData = (
+        Read<'a, Time>,
+        ReadExpect<'a, Loader>,
+        Write<'a, HotReloadStrategy>,
+    );
+
+    fn run(&mut self, (time, loader, mut strategy): Self::SystemData) {
+        let strategy = &mut *strategy;
+        if let Some(ref dependencies) = strategy.dependencies {
+            strategy
+                .cascade
+                .stage(time.frame_number(), || dependencies.take_pending());
+        }
 
-    fn run(&mut self, (time, mut strategy): Self::SystemData) {
         match strategy.inner {
             HotReloadStrategyInner::Trigger {
                 ref mut triggered,
@@ -167,6 +298,24 @@ impl<'a> System<'a> for HotReloadSystem {
                     *last = Instant::now();
                 }
             }
+            HotReloadStrategyInner::Watch {
+                ref mut changed,
+                ref mut polled,
+                interval,
+                ref mut last,
+                ref mut frame_number,
+            } => {
+                let frame = time.frame_number();
+                if let Some(ref changes) = self.changes {
+                    changed.stage(frame, || changes.try_iter());
+                }
+
+                if last.elapsed().as_secs() > u64::from(interval) {
+                    *polled = Some(loader.polled());
+                    *frame_number = frame + 1;
+                    *last = Instant::now();
+                }
+            }
             HotReloadStrategyInner::Never => {}
         }
     }
@@ -174,8 +323,19 @@ impl<'a> System<'a> for HotReloadSystem {
     fn setup(&mut self, res: &mut Resources) {
         use amethyst_core::specs::prelude::SystemData;
         Self::SystemData::setup(res);
-        res.insert(self.initial_strategy.clone());
-        res.fetch_mut::<Loader>().set_hot_reload(true);
+
+        let mut strategy = self.initial_strategy.clone();
+        {
+            let mut loader = res.fetch_mut::<Loader>();
+            loader.set_hot_reload(true);
+            strategy.dependencies = Some(loader.dependencies().clone());
+            if let HotReloadStrategyInner::Watch { .. } = strategy.inner {
+                let (tx, rx) = mpsc::channel();
+                loader.watch(tx);
+                self.changes = Some(rx);
+            }
+        }
+        res.insert(strategy);
     }
 }
 
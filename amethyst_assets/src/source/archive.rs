@@ -0,0 +1,410 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use parking_lot::Mutex;
+
+#[cfg(feature = "profiler")]
+use thread_profiler::profile_scope;
+
+use amethyst_error::{format_err, Error, ResultExt};
+
+use crate::{
+    error,
+    source::{dir::modified_secs, Source},
+};
+
+const PAK_MAGIC: [u8; 4] = *b"APAK";
+const PAK_VERSION: u32 = 1;
+#[cfg(feature = "zip")]
+const ZIP_MAGIC: [u8; 2] = *b"PK";
+
+/// Archive source, reading assets out of a single file.
+///
+/// Two kinds of archives are supported:
+///
+/// * amethyst paks, an index of the assets followed by their bytes, written with `Archive::write_pak`.
+/// * zip archives, if the `zip` feature is enabled.
+///
+/// The kind is detected from the content of the file.
+/// Every asset reports the modification time of the archive itself, and the archive is read again
+/// when it changes, so hot reloading works by replacing the whole archive.
+///
+/// To mount several archives on top of each other, use `Loader::add_source_with_priority`.
+pub struct Archive {
+    path: PathBuf,
+    opened: Mutex<Opened>,
+}
+
+// The archive as read at a given modification time.
+struct Opened {
+    modified: u64,
+    reader: Reader,
+}
+
+enum Reader {
+    // offset and size of every asset, by path.
+    Pak(HashMap<String, (u64, u64)>),
+    #[cfg(feature = "zip")]
+    Zip(zip::ZipArchive<File>),
+}
+
+impl Archive {
+    /// Opens the archive at the given path, reading its index.
+    pub fn open<P>(path: P) -> Result<Self, Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        let opened = Opened::read(&path)?;
+        Ok(Archive {
+            path,
+            opened: Mutex::new(opened),
+        })
+    }
+
+    /// Writes an amethyst pak containing the given assets, by path.
+    ///
+    /// The paths should always use `/` as separator.
+    pub fn write_pak<W, I, N, D>(mut writer: W, assets: I) -> Result<(), Error>
+    where
+        W: Write,
+        I: IntoIterator<Item = (N, D)>,
+        N: Into<String>,
+        D: AsRef<[u8]>,
+    {
+        let assets: Vec<(String, D)> = assets
+            .into_iter()
+            .map(|(path, data)| (path.into(), data))
+            .collect();
+
+        let header_size = assets
+            .iter()
+            .fold(12, |size, (path, _)| size + 4 + path.len() as u64 + 16);
+
+        let mut header = PAK_MAGIC.to_vec();
+        push_u32(&mut header, PAK_VERSION);
+        push_u32(&mut header, assets.len() as u32);
+        let mut offset = header_size;
+        for (path, data) in &assets {
+            let size = data.as_ref().len() as u64;
+            push_u32(&mut header, path.len() as u32);
+            header.extend_from_slice(path.as_bytes());
+            push_u64(&mut header, offset);
+            push_u64(&mut header, size);
+            offset += size;
+        }
+
+        writer
+            .write_all(&header)
+            .with_context(|_| format_err!("Failed to write the pak index"))?;
+        for (path, data) in &assets {
+            writer
+                .write_all(data.as_ref())
+                .with_context(|_| format_err!("Failed to write {:?} in the pak", path))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the paths of the assets in the archive.
+    pub fn paths(&self) -> Result<Vec<String>, Error> {
+        self.with_reader(|reader, _| match reader {
+            Reader::Pak(index) => Ok(index.keys().cloned().collect()),
+            #[cfg(feature = "zip")]
+            Reader::Zip(archive) => Ok((0..archive.len())
+                .filter_map(|i| archive.by_index(i).ok().map(|file| file.name().to_string()))
+                .collect()),
+        })
+    }
+
+    // Calls `f` with the reader and the modification time of the archive,
+    // reading the archive again if it changed.
+    fn with_reader<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Reader, u64) -> Result<T, Error>,
+    {
+        let mut opened = self.opened.lock();
+        if modified_secs(&self.path)? != opened.modified {
+            *opened = Opened::read(&self.path)?;
+        }
+        let modified = opened.modified;
+        f(&mut opened.reader, modified)
+    }
+
+    fn not_found(&self, path: &str) -> Error {
+        format_err!("No asset {:?} in archive {:?}", path, self.path)
+    }
+}
+
+impl Opened {
+    fn read(path: &Path) -> Result<Self, Error> {
+        let modified = modified_secs(path)?;
+        let mut file = File::open(path)
+            .with_context(|_| format_err!("Failed to open archive {:?}", path))
+            .with_context(|_| error::Error::Source)?;
+        let len = archive_len(&file)
+            .with_context(|_| format_err!("Failed to read archive {:?}", path))
+            .with_context(|_| error::Error::Source)?;
+
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic)
+            .with_context(|_| format_err!("Failed to read archive {:?}", path))
+            .with_context(|_| error::Error::Source)?;
+        file.seek(SeekFrom::Start(0))
+            .with_context(|_| format_err!("Failed to read archive {:?}", path))?;
+
+        let reader = if magic == PAK_MAGIC {
+            Reader::Pak(
+                read_pak_index(file, len)
+                    .with_context(|_| format_err!("Invalid pak {:?}", path))
+                    .with_context(|_| error::Error::Source)?,
+            )
+        } else {
+            open_zip(file, &magic).with_context(|_| format_err!("Invalid archive {:?}", path))?
+        };
+
+        Ok(Opened { modified, reader })
+    }
+}
+
+#[cfg(feature = "zip")]
+fn open_zip(file: File, magic: &[u8; 4]) -> Result<Reader, Error> {
+    if magic[..2] != ZIP_MAGIC {
+        return Err(format_err!("Unknown archive kind"));
+    }
+    Ok(Reader::Zip(zip::ZipArchive::new(file)?))
+}
+
+#[cfg(not(feature = "zip"))]
+fn open_zip(_: File, _: &[u8; 4]) -> Result<Reader, Error> {
+    Err(format_err!(
+        "Unknown archive kind, zip archives need the `zip` feature"
+    ))
+}
+
+// Reads the index of a pak of `len` bytes, checking that the assets are within the pak.
+fn read_pak_index(file: File, len: u64) -> Result<HashMap<String, (u64, u64)>, Error> {
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    let version = read_u32(&mut reader)?;
+    if version != PAK_VERSION {
+        return Err(format_err!("Unsupported pak version {}", version));
+    }
+
+    let count = read_u32(&mut reader)?;
+    // Every entry takes at least 20 bytes of the index.
+    check_bounds(12, u64::from(count) * 20, len)?;
+    let mut index = HashMap::with_capacity(count as usize);
+    for _ in 0..count {
+        let path_len = read_u32(&mut reader)?;
+        check_bounds(12, u64::from(path_len), len)?;
+        let mut path = vec![0; path_len as usize];
+        reader.read_exact(&mut path)?;
+        let path = String::from_utf8(path)?;
+        let offset = read_u64(&mut reader)?;
+        let size = read_u64(&mut reader)?;
+        check_bounds(offset, size, len)
+            .with_context(|_| format_err!("Invalid entry for {:?}", path))?;
+        index.insert(path, (offset, size));
+    }
+    Ok(index)
+}
+
+fn archive_len(file: &File) -> Result<u64, Error> {
+    Ok(file.metadata()?.len())
+}
+
+// Checks that the `size` bytes at `offset` are within an archive of `len` bytes.
+fn check_bounds(offset: u64, size: u64, len: u64) -> Result<(), Error> {
+    match offset.checked_add(size) {
+        Some(end) if end <= len => Ok(()),
+        _ => Err(format_err!(
+            "{} bytes at offset {} are out of the {} bytes of the archive",
+            size,
+            offset,
+            len
+        )),
+    }
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend((0..4).map(|i| (value >> (i * 8)) as u8));
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend((0..8).map(|i| (value >> (i * 8)) as u8));
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Error> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | u32::from(*byte)))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | u64::from(*byte)))
+}
+
+impl Source for Archive {
+    fn modified(&self, path: &str) -> Result<u64, Error> {
+        #[cfg(feature = "profiler")]
+        profile_scope!("archive_modified_asset");
+
+        self.with_reader(|reader, modified| {
+            let found = match reader {
+                Reader::Pak(index) => index.contains_key(path),
+                #[cfg(feature = "zip")]
+                Reader::Zip(archive) => archive.by_name(path).is_ok(),
+            };
+            if found {
+                Ok(modified)
+            } else {
+                Err(self.not_found(path))
+            }
+        })
+    }
+
+    fn load(&self, path: &str) -> Result<Vec<u8>, Error> {
+        self.load_with_metadata(path).map(|(bytes, _)| bytes)
+    }
+
+    fn load_with_metadata(&self, path: &str) -> Result<(Vec<u8>, u64), Error> {
+        #[cfg(feature = "profiler")]
+        profile_scope!("archive_load_asset");
+
+        self.with_reader(|reader, modified| {
+            let bytes = match reader {
+                Reader::Pak(index) => {
+                    let (offset, size) = *index
+                        .get(path)
+                        .ok_or_else(|| self.not_found(path))
+                        .with_context(|_| error::Error::Source)?;
+                    let mut file = File::open(&self.path)
+                        .with_context(|_| format_err!("Failed to open archive {:?}", self.path))
+                        .with_context(|_| error::Error::Source)?;
+                    // The archive may have been truncated since its index was read.
+                    archive_len(&file)
+                        .and_then(|len| check_bounds(offset, size, len))
+                        .with_context(|_| format_err!("Failed to read {:?} in archive", path))
+                        .with_context(|_| error::Error::Source)?;
+                    let mut bytes = vec![0; size as usize];
+                    file.seek(SeekFrom::Start(offset))
+                        .and_then(|_| file.read_exact(&mut bytes))
+                        .with_context(|_| format_err!("Failed to read {:?} in archive", path))
+                        .with_context(|_| error::Error::Source)?;
+                    bytes
+                }
+                #[cfg(feature = "zip")]
+                Reader::Zip(archive) => {
+                    let mut file = archive
+                        .by_name(path)
+                        .with_context(|_| self.not_found(path))
+                        .with_context(|_| error::Error::Source)?;
+                    let mut bytes = Vec::with_capacity(file.size() as usize);
+                    file.read_to_end(&mut bytes)
+                        .with_context(|_| format_err!("Failed to read {:?} in archive", path))
+                        .with_context(|_| error::Error::Source)?;
+                    bytes
+                }
+            };
+            Ok((bytes, modified))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env,
+        fs::{self, File, OpenOptions},
+        path::PathBuf,
+        process,
+    };
+
+    use crate::source::Source;
+
+    use super::{push_u32, push_u64, Archive, PAK_MAGIC, PAK_VERSION};
+
+    fn temp_pak(name: &str, assets: Vec<(&str, &[u8])>) -> PathBuf {
+        let path = env::temp_dir().join(format!("amethyst_{}_{}.pak", name, process::id()));
+        let file = File::create(&path).expect("Failed to create pak");
+        Archive::write_pak(file, assets).expect("Failed to write pak");
+        path
+    }
+
+    #[test]
+    fn loads_asset_from_pak() {
+        let path = temp_pak(
+            "loads_asset_from_pak",
+            vec![("subdir/asset", &b"data"[..]), ("other", &b""[..])],
+        );
+        let archive = Archive::open(&path).expect("Failed to open pak");
+
+        assert_eq!(b"data".to_vec(), archive.load("subdir/asset").unwrap());
+        assert_eq!(Vec::<u8>::new(), archive.load("other").unwrap());
+        assert!(archive.load("missing").is_err());
+        assert!(archive.modified("missing").is_err());
+
+        let (bytes, modified) = archive.load_with_metadata("subdir/asset").unwrap();
+        assert_eq!(b"data".to_vec(), bytes);
+        assert_eq!(modified, archive.modified("other").unwrap());
+
+        let mut paths = archive.paths().unwrap();
+        paths.sort();
+        assert_eq!(paths, vec!["other".to_string(), "subdir/asset".to_string()]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_archives() {
+        let path = env::temp_dir().join(format!("amethyst_unknown_{}.pak", process::id()));
+        fs::write(&path, b"not an archive").unwrap();
+        assert!(Archive::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_assets_out_of_the_pak() {
+        let path = env::temp_dir().join(format!("amethyst_out_of_bounds_{}.pak", process::id()));
+        let mut pak = PAK_MAGIC.to_vec();
+        push_u32(&mut pak, PAK_VERSION);
+        push_u32(&mut pak, 1);
+        push_u32(&mut pak, 1);
+        pak.push(b'a');
+        push_u64(&mut pak, 29);
+        push_u64(&mut pak, u64::max_value());
+        fs::write(&path, &pak).unwrap();
+        assert!(Archive::open(&path).is_err());
+
+        // A path longer than the pak.
+        pak.truncate(12);
+        push_u32(&mut pak, u32::max_value());
+        pak.extend_from_slice(&[0; 40]);
+        fs::write(&path, &pak).unwrap();
+        assert!(Archive::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+
+        // A pak truncated after its index was read.
+        let path = temp_pak("truncated", vec![("asset", &b"data"[..])]);
+        let archive = Archive::open(&path).expect("Failed to open pak");
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_len(20))
+            .unwrap();
+        assert!(archive.load("asset").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
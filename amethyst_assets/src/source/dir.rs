@@ -1,9 +1,16 @@
 use std::{
+    fmt,
     fs::File,
-    path::{Path, PathBuf},
-    time::UNIX_EPOCH,
+    path::{Component, Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, UNIX_EPOCH},
 };
 
+use log::warn;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::Mutex;
+
 #[cfg(feature = "profiler")]
 use thread_profiler::profile_scope;
 
@@ -17,9 +24,18 @@ use crate::{error, source::Source};
 /// inside the `Loader`, which is automatically used when you call
 /// `load`. In case you want another, second, directory for assets,
 /// you can instantiate one yourself, too. Please use `Loader::load_from` then.
-#[derive(Debug)]
+///
+/// The directory can be watched for changes using the notifications of the OS.
 pub struct Directory {
     loc: PathBuf,
+    // kept alive while the directory is watched.
+    watcher: Mutex<Option<RecommendedWatcher>>,
+}
+
+impl fmt::Debug for Directory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Directory").field("loc", &self.loc).finish()
+    }
 }
 
 impl Directory {
@@ -28,7 +44,10 @@ impl Directory {
     where
         P: Into<PathBuf>,
     {
-        Directory { loc: loc.into() }
+        Directory {
+            loc: loc.into(),
+            watcher: Mutex::new(None),
+        }
     }
 
     fn path(&self, s_path: &str) -> PathBuf {
@@ -39,23 +58,27 @@ impl Directory {
     }
 }
 
+/// Returns the modification time of a file as seconds since `UNIX_EPOCH`.
+pub(super) fn modified_secs(path: &Path) -> Result<u64, Error> {
+    use std::fs::metadata;
+
+    metadata(path)
+        .with_context(|_| format_err!("Failed to fetch metadata for {:?}", path))?
+        .modified()
+        .with_context(|_| format_err!("Could not get modification time"))?
+        .duration_since(UNIX_EPOCH)
+        .with_context(|_| {
+            format_err!("Anomalies with the system clock caused `duration_since` to fail")
+        })
+        .map(|d| d.as_secs())
+}
+
 impl Source for Directory {
     fn modified(&self, path: &str) -> Result<u64, Error> {
         #[cfg(feature = "profiler")]
         profile_scope!("dir_modified_asset");
-        use std::fs::metadata;
 
-        let path = self.path(path);
-
-        metadata(&path)
-            .with_context(|_| format_err!("Failed to fetch metadata for {:?}", path))?
-            .modified()
-            .with_context(|_| format_err!("Could not get modification time"))?
-            .duration_since(UNIX_EPOCH)
-            .with_context(|_| {
-                format_err!("Anomalies with the system clock caused `duration_since` to fail")
-            })
-            .map(|d| d.as_secs())
+        modified_secs(&self.path(path))
     }
 
     fn load(&self, path: &str) -> Result<Vec<u8>, Error> {
@@ -75,15 +98,73 @@ impl Source for Directory {
 
         Ok(v)
     }
+
+    fn watch(&self, changes: Sender<String>) -> Result<bool, Error> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::watcher(tx, Duration::from_millis(50))
+            .with_context(|_| format_err!("Failed to create a watcher"))?;
+        watcher
+            .watch(&self.loc, RecursiveMode::Recursive)
+            .with_context(|_| format_err!("Failed to watch directory {:?}", self.loc))?;
+
+        // The events may carry the canonical paths.
+        let roots = vec![
+            self.loc.clone(),
+            self.loc.canonicalize().unwrap_or_else(|_| self.loc.clone()),
+        ];
+        thread::spawn(move || {
+            // stops once the watcher is dropped.
+            for event in rx.iter() {
+                let paths = match event {
+                    DebouncedEvent::Create(path)
+                    | DebouncedEvent::Write(path)
+                    | DebouncedEvent::Remove(path) => vec![path],
+                    DebouncedEvent::Rename(from, to) => vec![from, to],
+                    DebouncedEvent::Error(e, path) => {
+                        warn!("Failed to watch {:?}: {}", path, e);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                for path in paths.iter().filter_map(|path| asset_path(&roots, path)) {
+                    if changes.send(path).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        *self.watcher.lock() = Some(watcher);
+        Ok(true)
+    }
+}
+
+// Returns the path of an asset relative to one of the roots, using `/` as separator.
+fn asset_path(roots: &[PathBuf], path: &Path) -> Option<String> {
+    let relative = roots.iter().find_map(|root| path.strip_prefix(root).ok())?;
+    let components: Option<Vec<&str>> = relative
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect();
+    Some(components?.join("/"))
 }
 
 #[cfg(test)]
 mod test {
-    use std::path::Path;
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+        process,
+        sync::mpsc,
+        time::Duration,
+    };
 
     use crate::source::Source;
 
-    use super::Directory;
+    use super::{asset_path, Directory};
 
     #[test]
     fn loads_asset_from_assets_directory() {
@@ -116,4 +197,32 @@ mod test {
                 .expect("Failed to load tests/assets/subdir/asset")
         );
     }
+
+    #[test]
+    fn asset_paths_are_relative_to_the_directory() {
+        let roots = vec![PathBuf::from("assets")];
+        assert_eq!(
+            asset_path(&roots, &Path::new("assets").join("subdir").join("asset")),
+            Some("subdir/asset".to_string())
+        );
+        assert_eq!(asset_path(&roots, Path::new("elsewhere/asset")), None);
+    }
+
+    #[test]
+    fn reports_changed_assets() {
+        let dir = env::temp_dir().join(format!("amethyst_watch_{}", process::id()));
+        fs::create_dir_all(dir.join("subdir")).unwrap();
+        fs::write(dir.join("subdir/asset"), b"data").unwrap();
+
+        let directory = Directory::new(&dir);
+        let (tx, rx) = mpsc::channel();
+        assert!(directory.watch(tx).expect("Failed to watch the directory"));
+
+        fs::write(dir.join("subdir/asset"), b"changed").unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)),
+            Ok("subdir/asset".to_string())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap,
+    sync::{mpsc::Sender, Arc},
+};
+
+use log::{debug, warn};
+use parking_lot::Mutex;
+
+#[cfg(feature = "profiler")]
+use thread_profiler::profile_scope;
+
+use amethyst_error::{format_err, Error, ResultExt};
+
+use crate::{error, source::Source};
+
+/// Layered source, searching a stack of sources in priority order.
+///
+/// Every asset is served by the first layer containing it, so upper layers override the assets of
+/// the lower ones, e.g. a mod directory on top of a patch archive on top of the base directory.
+///
+/// The modification time of an asset increases whenever another layer starts serving it,
+/// so hot reloading picks up assets added to or removed from an upper layer.
+///
+/// The layered source is watched if any of its layers can be watched. The assets which the
+/// layers that can't be watched serve, or could start serving, are still polled.
+pub struct Layered {
+    layers: Vec<(String, Arc<dyn Source>)>,
+    // whether each layer is watched, the layers added since the last `watch` aren't.
+    watched: Mutex<Vec<bool>>,
+    // layer and its modification time, and the modification time reported for every asset.
+    reported: Mutex<HashMap<String, (usize, u64, u64)>>,
+}
+
+impl Layered {
+    /// Creates a layered source without layers.
+    pub fn new() -> Self {
+        Layered {
+            layers: Vec::new(),
+            watched: Mutex::new(Vec::new()),
+            reported: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a layer below the layers already added.
+    /// The name identifies the layer when debugging.
+    pub fn with_layer<N, S>(mut self, name: N, source: S) -> Self
+    where
+        N: Into<String>,
+        S: Source,
+    {
+        self.add_layer(name, source);
+        self
+    }
+
+    /// Adds a layer below the layers already added.
+    /// The name identifies the layer when debugging.
+    pub fn add_layer<N, S>(&mut self, name: N, source: S)
+    where
+        N: Into<String>,
+        S: Source,
+    {
+        self.add_shared_layer(name, Arc::new(source));
+    }
+
+    pub(crate) fn add_shared_layer<N>(&mut self, name: N, source: Arc<dyn Source>)
+    where
+        N: Into<String>,
+    {
+        self.layers.push((name.into(), source));
+        self.reported.lock().clear();
+    }
+
+    /// Returns the names of the layers, from the first searched to the last.
+    pub fn layers(&self) -> impl Iterator<Item = &str> {
+        self.layers.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Returns the name of the layer serving the given asset, if any layer contains it.
+    pub fn layer_of(&self, path: &str) -> Option<&str> {
+        self.find(path, |source| source.modified(path))
+            .ok()
+            .map(|(layer, _)| self.layers[layer].0.as_str())
+    }
+
+    // Returns the index of the first layer `f` succeeds for, along with its result.
+    fn find<F, T>(&self, path: &str, f: F) -> Result<(usize, T), Error>
+    where
+        F: Fn(&dyn Source) -> Result<T, Error>,
+    {
+        let mut last_error = None;
+        for (layer, (_, source)) in self.layers.iter().enumerate() {
+            match f(&**source) {
+                Ok(value) => return Ok((layer, value)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        let cause = last_error.unwrap_or_else(|| format_err!("The layered source has no layer"));
+        Err(cause)
+            .with_context(|_| format_err!("No layer contains {:?}", path))
+            .with_context(|_| error::Error::Source)
+    }
+
+    // Returns the modification time to report for an asset served by `layer`,
+    // increasing it whenever the layer or its modification time changes.
+    fn report(&self, path: &str, layer: usize, modified: u64) -> u64 {
+        let mut reported = self.reported.lock();
+        let entry = reported
+            .entry(path.to_string())
+            .or_insert((layer, modified, modified));
+        if entry.0 != layer || entry.1 != modified {
+            *entry = (layer, modified, modified.max(entry.2 + 1));
+        }
+        entry.2
+    }
+}
+
+impl Default for Layered {
+    fn default() -> Self {
+        Layered::new()
+    }
+}
+
+impl Source for Layered {
+    fn modified(&self, path: &str) -> Result<u64, Error> {
+        #[cfg(feature = "profiler")]
+        profile_scope!("layered_modified_asset");
+
+        let (layer, modified) = self.find(path, |source| source.modified(path))?;
+        Ok(self.report(path, layer, modified))
+    }
+
+    fn load(&self, path: &str) -> Result<Vec<u8>, Error> {
+        self.load_with_metadata(path).map(|(bytes, _)| bytes)
+    }
+
+    fn load_with_metadata(&self, path: &str) -> Result<(Vec<u8>, u64), Error> {
+        #[cfg(feature = "profiler")]
+        profile_scope!("layered_load_asset");
+
+        let (layer, (bytes, modified)) =
+            self.find(path, |source| source.load_with_metadata(path))?;
+        debug!(
+            "Asset {:?} served by layer {:?}",
+            path, self.layers[layer].0
+        );
+        Ok((bytes, self.report(path, layer, modified)))
+    }
+
+    fn watch(&self, changes: Sender<String>) -> Result<bool, Error> {
+        let watched: Vec<bool> = self
+            .layers
+            .iter()
+            .map(|(name, source)| match source.watch(changes.clone()) {
+                Ok(true) => true,
+                Ok(false) => {
+                    debug!("Layer {:?} can't be watched, its assets are polled", name);
+                    false
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to watch layer {:?}, its assets are polled: {}",
+                        name, e
+                    );
+                    false
+                }
+            })
+            .collect();
+        let any_watched = watched.contains(&true);
+        *self.watched.lock() = watched;
+        Ok(any_watched)
+    }
+
+    fn needs_polling(&self, path: &str) -> bool {
+        // The asset changes if the layer serving it changes, or if a layer above starts serving it.
+        let layers = self
+            .find(path, |source| source.modified(path))
+            .map_or(self.layers.len(), |(layer, _)| layer + 1);
+        let watched = self.watched.lock();
+        (0..layers).any(|layer| !watched.get(layer).cloned().unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc,
+        },
+    };
+
+    use amethyst_error::{format_err, Error};
+
+    use crate::source::{Directory, Memory, Source};
+
+    use super::Layered;
+
+    // A source containing a single asset, which can be removed.
+    struct Single {
+        path: &'static str,
+        data: &'static str,
+        modified: u64,
+        present: Arc<AtomicBool>,
+    }
+
+    impl Single {
+        fn new(path: &'static str, data: &'static str, modified: u64) -> Self {
+            Single {
+                path,
+                data,
+                modified,
+                present: Arc::new(AtomicBool::new(true)),
+            }
+        }
+    }
+
+    impl Source for Single {
+        fn modified(&self, path: &str) -> Result<u64, Error> {
+            if path == self.path && self.present.load(Ordering::SeqCst) {
+                Ok(self.modified)
+            } else {
+                Err(format_err!("No asset {:?}", path))
+            }
+        }
+
+        fn load(&self, path: &str) -> Result<Vec<u8>, Error> {
+            self.modified(path).map(|_| self.data.as_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn upper_layers_override_lower_ones() {
+        let test_assets_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/assets");
+        let layered = Layered::new()
+            .with_layer("mod", Single::new("subdir/asset", "modded", 1))
+            .with_layer("base", Directory::new(test_assets_dir));
+
+        assert_eq!(layered.layers().collect::<Vec<_>>(), vec!["mod", "base"]);
+        assert_eq!(layered.load("subdir/asset").unwrap(), b"modded".to_vec());
+        assert_eq!(layered.layer_of("subdir/asset"), Some("mod"));
+        assert_eq!(layered.layer_of("missing"), None);
+        assert!(layered.load("missing").is_err());
+    }
+
+    #[test]
+    fn assets_of_unwatched_layers_are_polled() {
+        let (tx, _rx) = mpsc::channel();
+        let layered = Layered::new()
+            .with_layer("patch", Single::new("patched", "patch", 1))
+            .with_layer("mod", Memory::new().with_asset("modded", "mod"))
+            .with_layer("base", Single::new("base", "base", 1));
+        assert!(layered.watch(tx.clone()).unwrap());
+
+        // Every asset could be overridden by the patch, which isn't watched.
+        assert!(layered.needs_polling("modded"));
+
+        let layered = Layered::new()
+            .with_layer("mod", Memory::new().with_asset("modded", "mod"))
+            .with_layer("base", Single::new("base", "base", 1));
+        assert!(layered.watch(tx.clone()).unwrap());
+        assert!(!layered.needs_polling("modded"));
+        assert!(layered.needs_polling("base"));
+        assert!(layered.needs_polling("missing"));
+
+        let layered = Layered::new().with_layer("base", Single::new("base", "base", 1));
+        assert!(!layered.watch(tx).unwrap());
+    }
+
+    #[test]
+    fn modification_time_increases_when_layer_changes() {
+        let patch = Single::new("asset", "patch", 100);
+        let present = patch.present.clone();
+        let layered = Layered::new()
+            .with_layer("patch", patch)
+            .with_layer("base", Single::new("asset", "base", 50));
+        assert_eq!(layered.modified("asset").unwrap(), 100);
+
+        // The base asset is older than the patch, but is served again once the patch is removed.
+        present.store(false, Ordering::SeqCst);
+        assert_eq!(layered.modified("asset").unwrap(), 101);
+        assert_eq!(layered.modified("asset").unwrap(), 101);
+        assert_eq!(layered.load("asset").unwrap(), b"base".to_vec());
+        assert_eq!(layered.layer_of("asset"), Some("base"));
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    sync::{mpsc::Sender, Arc},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::{Mutex, RwLock};
+
+#[cfg(feature = "profiler")]
+use thread_profiler::profile_scope;
+
+use amethyst_error::{format_err, Error, ResultExt};
+
+use crate::{error, source::Source};
+
+/// In-memory source, for tests and procedurally generated assets.
+///
+/// Clones share the same assets, so you can keep one to change the assets after handing the
+/// source to the `Loader`. Every write bumps the modification time of the asset,
+/// which makes hot reloading pick it up.
+#[derive(Clone, Debug, Default)]
+pub struct Memory {
+    inner: Arc<RwLock<Inner>>,
+    // notified of every write, behind a `Mutex` as senders can't be shared between threads.
+    watcher: Arc<Mutex<Option<Sender<String>>>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // bytes and modification time, by path.
+    assets: HashMap<String, (Vec<u8>, u64)>,
+    // the latest modification time given to an asset.
+    last_modified: u64,
+}
+
+impl Memory {
+    /// Creates an empty in-memory source.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds an asset, see `insert`.
+    pub fn with_asset<P, D>(self, path: P, data: D) -> Self
+    where
+        P: Into<String>,
+        D: Into<Vec<u8>>,
+    {
+        self.insert(path, data);
+        self
+    }
+
+    /// Adds or replaces the asset at the given path.
+    ///
+    /// The modification time is the current time, or one second after the previous write if it
+    /// happened during the same second, so it always increases.
+    pub fn insert<P, D>(&self, path: P, data: D)
+    where
+        P: Into<String>,
+        D: Into<Vec<u8>>,
+    {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let path = path.into();
+        {
+            let mut inner = self.inner.write();
+            let modified = now.max(inner.last_modified + 1);
+            inner.last_modified = modified;
+            inner.assets.insert(path.clone(), (data.into(), modified));
+        }
+        self.notify(&path);
+    }
+
+    /// Removes the asset at the given path, returning its bytes.
+    pub fn remove(&self, path: &str) -> Option<Vec<u8>> {
+        let removed = self
+            .inner
+            .write()
+            .assets
+            .remove(path)
+            .map(|(bytes, _)| bytes);
+        if removed.is_some() {
+            self.notify(path);
+        }
+        removed
+    }
+
+    /// Returns `true` if there is an asset at the given path.
+    pub fn contains(&self, path: &str) -> bool {
+        self.inner.read().assets.contains_key(path)
+    }
+
+    /// Returns the paths of the assets.
+    pub fn paths(&self) -> Vec<String> {
+        self.inner.read().assets.keys().cloned().collect()
+    }
+
+    fn notify(&self, path: &str) {
+        let mut watcher = self.watcher.lock();
+        let hung_up = watcher
+            .as_ref()
+            .map_or(false, |watcher| watcher.send(path.to_string()).is_err());
+        if hung_up {
+            *watcher = None;
+        }
+    }
+}
+
+impl Source for Memory {
+    fn modified(&self, path: &str) -> Result<u64, Error> {
+        #[cfg(feature = "profiler")]
+        profile_scope!("memory_modified_asset");
+
+        self.inner
+            .read()
+            .assets
+            .get(path)
+            .map(|&(_, modified)| modified)
+            .ok_or_else(|| format_err!("No asset {:?} in memory", path))
+            .with_context(|_| error::Error::Source)
+    }
+
+    fn load(&self, path: &str) -> Result<Vec<u8>, Error> {
+        #[cfg(feature = "profiler")]
+        profile_scope!("memory_load_asset");
+
+        self.load_with_metadata(path).map(|(bytes, _)| bytes)
+    }
+
+    fn load_with_metadata(&self, path: &str) -> Result<(Vec<u8>, u64), Error> {
+        self.inner
+            .read()
+            .assets
+            .get(path)
+            .cloned()
+            .ok_or_else(|| format_err!("No asset {:?} in memory", path))
+            .with_context(|_| error::Error::Source)
+    }
+
+    // Replaces the sender given previously, the source is watched by one `Loader` at a time.
+    fn watch(&self, changes: Sender<String>) -> Result<bool, Error> {
+        *self.watcher.lock() = Some(changes);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use crate::source::Source;
+
+    use super::Memory;
+
+    #[test]
+    fn writes_are_visible_to_clones() {
+        let memory = Memory::new().with_asset("subdir/asset", "data");
+        let source = memory.clone();
+        assert_eq!(b"data".to_vec(), source.load("subdir/asset").unwrap());
+        assert!(source.load("missing").is_err());
+
+        let modified = source.modified("subdir/asset").unwrap();
+        memory.insert("subdir/asset", vec![1, 2, 3]);
+        let (bytes, reloaded) = source.load_with_metadata("subdir/asset").unwrap();
+        assert_eq!(vec![1, 2, 3], bytes);
+        assert!(reloaded > modified);
+
+        assert_eq!(Some(vec![1, 2, 3]), memory.remove("subdir/asset"));
+        assert!(!source.contains("subdir/asset"));
+        assert!(source.modified("subdir/asset").is_err());
+    }
+
+    #[test]
+    fn writes_are_watched() {
+        let memory = Memory::new();
+        let (tx, rx) = mpsc::channel();
+        assert!(memory.watch(tx).unwrap());
+
+        memory.insert("asset", "data");
+        memory.remove("asset");
+        memory.remove("missing");
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec!["asset", "asset"]);
+
+        // Watching again replaces the previous sender.
+        let (other_tx, other_rx) = mpsc::channel();
+        assert!(memory.watch(other_tx).unwrap());
+        memory.insert("asset", "data");
+        assert_eq!(rx.try_iter().count(), 0);
+        assert_eq!(other_rx.try_iter().collect::<Vec<_>>(), vec!["asset"]);
+    }
+}
@@ -1,11 +1,16 @@
+use std::sync::mpsc::Sender;
+
 use amethyst_error::Error;
 
-pub use self::dir::Directory;
+pub use self::{archive::Archive, dir::Directory, layered::Layered, memory::Memory};
 
 #[cfg(feature = "profiler")]
 use thread_profiler::profile_scope;
 
+mod archive;
 mod dir;
+mod layered;
+mod memory;
 
 /// A trait for asset sources, which provides
 /// methods for loading bytes.
@@ -32,4 +37,22 @@ pub trait Source: Send + Sync + 'static {
 
         Ok((b, m))
     }
+
+    /// Starts sending the paths of the assets which changed on `changes`, if the source supports it.
+    ///
+    /// Returns `false` if the source can't watch its assets, in which case `modified` is polled.
+    /// The default implementation returns `false`.
+    fn watch(&self, changes: Sender<String>) -> Result<bool, Error> {
+        let _ = changes;
+        Ok(false)
+    }
+
+    /// Returns `true` if the asset at `path` has to be polled although `watch` returned `true`,
+    /// because the source only watches some of its assets.
+    ///
+    /// The default implementation returns `false`.
+    fn needs_polling(&self, path: &str) -> bool {
+        let _ = path;
+        false
+    }
 }
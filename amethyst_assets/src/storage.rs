@@ -1,15 +1,16 @@
 use std::{
     marker::PhantomData,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Mutex, Weak,
     },
 };
 
 use crossbeam::queue::MsQueue;
 use derivative::Derivative;
+use fnv::FnvHashMap;
 use hibitset::BitSet;
-use log::{debug, error, trace, warn};
+use log::{debug, error, trace};
 use rayon::ThreadPool;
 
 use amethyst_core::{
@@ -23,7 +24,8 @@ use amethyst_error::{Error, ResultExt};
 
 use crate::{
     asset::{Asset, FormatValue},
-    error,
+    dependencies::{AssetKey, Dependencies},
+    error, processing,
     progress::Tracker,
     reload::{HotReloadStrategy, Reload},
 };
@@ -47,11 +49,95 @@ pub struct AssetStorage<A: Asset> {
     assets: VecStorage<A>,
     bitset: BitSet,
     handles: Vec<Handle<A>>,
-    handle_alloc: Allocator,
+    handle_alloc: Arc<Allocator>,
     pub(crate) processed: Arc<MsQueue<Processed<A>>>,
     reloads: Vec<(WeakHandle<A>, Box<dyn Reload<A>>)>,
-    unused_handles: MsQueue<Handle<A>>,
+    unused_handles: Arc<MsQueue<Handle<A>>>,
     requeue: Mutex<Vec<Processed<A>>>,
+    memory: MemoryUsage,
+    memory_budget: Option<usize>,
+    // whether loads whose handles are all dropped are cancelled, shared with the `AssetQueue`s.
+    cancel_unused: Arc<AtomicBool>,
+    // names the assets were loaded with, by handle id.
+    names: FnvHashMap<u32, String>,
+    // ids of the sources the assets were loaded from, by handle id.
+    sources: FnvHashMap<u32, String>,
+    // frame since which the assets kept for the memory budget are unused, by handle id.
+    unused_since: FnvHashMap<u32, u64>,
+    // the dependencies recorded by the `Loader`, which forget the freed assets.
+    dependencies: Option<Dependencies>,
+}
+
+/// Allocates handles and queues loaded data for an `AssetStorage`, without borrowing it,
+/// so assets can be loaded into a storage while it is processed.
+pub(crate) struct AssetQueue<A: Asset> {
+    handle_alloc: Arc<Allocator>,
+    unused_handles: Arc<MsQueue<Handle<A>>>,
+    pub(crate) processed: Arc<MsQueue<Processed<A>>>,
+    cancel_unused: Arc<AtomicBool>,
+}
+
+impl<A: Asset> AssetQueue<A> {
+    /// Allocate a new handle.
+    pub(crate) fn allocate(&self) -> Handle<A> {
+        self.unused_handles.try_pop().unwrap_or_else(|| Handle {
+            id: Arc::new(self.handle_alloc.next_id() as u32),
+            marker: PhantomData,
+        })
+    }
+
+    /// Returns `true` if the loads whose handles are all dropped are cancelled, which they are
+    /// unless the storage has a memory budget.
+    pub(crate) fn cancels_unused(&self) -> bool {
+        self.cancel_unused.load(Ordering::Relaxed)
+    }
+
+    /// Gives back the id of a handle whose asset was never stored, once the handle is unique.
+    pub(crate) fn release(&self, handle: Handle<A>) {
+        release(&self.unused_handles, handle);
+    }
+}
+
+impl<A: Asset> Clone for AssetQueue<A> {
+    fn clone(&self) -> Self {
+        AssetQueue {
+            handle_alloc: self.handle_alloc.clone(),
+            unused_handles: self.unused_handles.clone(),
+            processed: self.processed.clone(),
+            cancel_unused: self.cancel_unused.clone(),
+        }
+    }
+}
+
+// Can't reuse the handle itself, because otherwise weak handles would still be valid.
+fn release<A: Asset>(unused_handles: &MsQueue<Handle<A>>, handle: Handle<A>) {
+    unused_handles.push(Handle {
+        id: Arc::new(handle.id()),
+        marker: PhantomData,
+    });
+}
+
+// Memory used by the assets, as reported by `Asset::memory_size`.
+#[derive(Default)]
+struct MemoryUsage {
+    sizes: FnvHashMap<u32, usize>,
+    total: usize,
+}
+
+impl MemoryUsage {
+    fn set(&mut self, id: u32, size: usize) {
+        self.remove(id);
+        if size != 0 {
+            self.sizes.insert(id, size);
+            self.total += size;
+        }
+    }
+
+    fn remove(&mut self, id: u32) {
+        if let Some(size) = self.sizes.remove(&id) {
+            self.total -= size;
+        }
+    }
 }
 
 /// Returned by processor systems, describes the loading state of the asset.
@@ -73,16 +159,16 @@ impl<A: Asset> AssetStorage<A> {
 
     /// Allocate a new handle.
     pub(crate) fn allocate(&self) -> Handle<A> {
-        self.unused_handles
-            .try_pop()
-            .unwrap_or_else(|| self.allocate_new())
+        self.queue().allocate()
     }
 
-    fn allocate_new(&self) -> Handle<A> {
-        let id = self.handle_alloc.next_id() as u32;
-        Handle {
-            id: Arc::new(id),
-            marker: PhantomData,
+    /// Returns a queue allocating handles of this storage.
+    pub(crate) fn queue(&self) -> AssetQueue<A> {
+        AssetQueue {
+            handle_alloc: self.handle_alloc.clone(),
+            unused_handles: self.unused_handles.clone(),
+            processed: self.processed.clone(),
+            cancel_unused: self.cancel_unused.clone(),
         }
     }
 
@@ -101,6 +187,7 @@ impl<A: Asset> AssetStorage<A> {
             let id = h.id();
             self.bitset.add(id);
             self.handles.push(h.clone());
+            self.memory.set(id, asset.memory_size());
 
             unsafe {
                 self.assets.insert(id, asset);
@@ -130,6 +217,63 @@ impl<A: Asset> AssetStorage<A> {
         }
     }
 
+    /// Unloads the asset of the handle, returning it.
+    ///
+    /// The asset is removed even if handles to it remain, `get` returns `None` for them afterwards.
+    /// Its handle id is only reused once all of them are dropped.
+    /// An asset which is still loading is not affected.
+    pub fn unload(&mut self, handle: &Handle<A>) -> Option<A> {
+        let id = handle.id();
+        if !self.bitset.remove(id) {
+            return None;
+        }
+
+        debug!("{:?}: Unloading asset (handle id: {:?})", A::NAME, handle);
+        self.memory.remove(id);
+        self.unused_since.remove(&id);
+        self.forget(id);
+        self.reloads
+            .retain(|&(ref weak, _)| weak.upgrade().map_or(false, |h| h.id() != id));
+        Some(unsafe { self.assets.remove(id) })
+    }
+
+    /// Returns the name the asset was loaded with, which is `"<Data>"` for assets loaded from
+    /// data with `Loader::load_from_data`.
+    pub fn name(&self, handle: &Handle<A>) -> Option<&str> {
+        self.names.get(&handle.id()).map(String::as_str)
+    }
+
+    /// Returns the id of the source the asset was loaded from, which is empty for the default
+    /// source.
+    pub fn source(&self, handle: &Handle<A>) -> Option<&str> {
+        self.sources.get(&handle.id()).map(String::as_str)
+    }
+
+    /// Returns the memory used by the assets, in bytes, as reported by `Asset::memory_size`.
+    pub fn memory_usage(&self) -> usize {
+        self.memory.total
+    }
+
+    /// Returns the memory budget, if any.
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.memory_budget
+    }
+
+    /// Sets the memory budget for the assets, in bytes.
+    ///
+    /// Without budget, an asset is freed as soon as all its handles are dropped. With a budget,
+    /// unused assets with a memory size are kept around, so they can be retrieved through the
+    /// `WeakHandle`s of a `Cache`. When the memory used goes above the budget, the least recently
+    /// used ones are freed until it fits again. Assets which are still used are never freed.
+    ///
+    /// With a budget, loads are no longer cancelled when all their handles are dropped, so the
+    /// assets still reach the `Cache`s holding them.
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.memory_budget = budget;
+        self.cancel_unused
+            .store(budget.is_none(), Ordering::Relaxed);
+    }
+
     /// Process finished asset data and maintain the storage.
     pub fn process<F>(
         &mut self,
@@ -161,11 +305,17 @@ impl<A: Asset> AssetStorage<A> {
                 .requeue
                 .get_mut()
                 .expect("The mutex of `requeue` in `AssetStorage` was poisoned");
+            let cancel_unused = self.memory_budget.is_none();
             while let Some(processed) = self.processed.try_pop() {
                 let assets = &mut self.assets;
                 let bitset = &mut self.bitset;
                 let handles = &mut self.handles;
                 let reloads = &mut self.reloads;
+                let memory = &mut self.memory;
+                let names = &mut self.names;
+                let sources = &mut self.sources;
+                let storage_dependencies = &mut self.dependencies;
+                let unused_handles = &self.unused_handles;
 
                 let f = &mut f;
                 let (reload_obj, handle) = match processed {
@@ -173,11 +323,39 @@ impl<A: Asset> AssetStorage<A> {
                         data,
                         handle,
                         name,
+                        source,
                         tracker,
+                        dependencies,
                     } => {
+                        if dependencies.is_some() {
+                            *storage_dependencies = dependencies;
+                        }
+
+                        // The asset isn't used by anything anymore, so it isn't processed,
+                        // unless it is kept for the memory budget.
+                        // https://github.com/amethyst/amethyst/issues/628
+                        if cancel_unused && handle.is_unique() {
+                            debug!(
+                                "{:?}: Asset {:?} (handle id: {:?}) was cancelled, its handle was dropped",
+                                A::NAME,
+                                name,
+                                handle,
+                            );
+                            release(unused_handles, handle);
+                            tracker.cancelled();
+                            continue;
+                        }
+
                         let (asset, reload_obj) = match data
                             .map(|FormatValue { data, reload }| (data, reload))
-                            .and_then(|(d, rel)| f(d).map(|a| (a, rel)))
+                            .and_then(|(d, rel)| {
+                                processing::scope(
+                                    AssetKey::new(&source, A::NAME, &name),
+                                    tracker.counter(),
+                                    || f(d),
+                                )
+                                .map(|a| (a, rel))
+                            })
                             .with_context(|_| error::Error::Asset(name.clone()))
                         {
                             Ok((ProcessingState::Loaded(x), r)) => {
@@ -187,23 +365,7 @@ impl<A: Asset> AssetStorage<A> {
                                         name,
                                         handle,
                                     );
-                                // Add a warning if a handle is unique (i.e. asset does not
-                                // need to be loaded as it is not used by anything)
-                                // https://github.com/amethyst/amethyst/issues/628
-                                if handle.is_unique() {
-                                    warn!(
-                                        "Loading unnecessary asset. Handle {} is unique ",
-                                        handle.id()
-                                    );
-                                    tracker.fail(
-                                        handle.id(),
-                                        A::NAME,
-                                        name,
-                                        Error::from(error::Error::UnusedHandle),
-                                    );
-                                } else {
-                                    tracker.success();
-                                }
+                                tracker.success();
 
                                 (x, r)
                             }
@@ -218,7 +380,9 @@ impl<A: Asset> AssetStorage<A> {
                                     data: Ok(FormatValue { data: x, reload: r }),
                                     handle,
                                     name,
+                                    source,
                                     tracker,
+                                    dependencies: None,
                                 });
                                 continue;
                             }
@@ -239,6 +403,9 @@ impl<A: Asset> AssetStorage<A> {
                         let id = handle.id();
                         bitset.add(id);
                         handles.push(handle.clone());
+                        memory.set(id, asset.memory_size());
+                        names.insert(id, name);
+                        sources.insert(id, source);
 
                         // NOTE: the loader has to ensure that a handle will be used
                         // together with a `Data` only once.
@@ -252,11 +419,19 @@ impl<A: Asset> AssetStorage<A> {
                         data,
                         handle,
                         name,
+                        source,
                         old_reload,
                     } => {
                         let (asset, reload_obj) = match data
                             .map(|FormatValue { data, reload }| (data, reload))
-                            .and_then(|(d, rel)| f(d).map(|a| (a, rel)))
+                            .and_then(|(d, rel)| {
+                                processing::scope(
+                                    AssetKey::new(&source, A::NAME, &name),
+                                    None,
+                                    || f(d),
+                                )
+                                .map(|a| (a, rel))
+                            })
                             .with_context(|_| error::Error::Asset(name.clone()))
                         {
                             Ok((ProcessingState::Loaded(x), r)) => (x, r),
@@ -271,6 +446,7 @@ impl<A: Asset> AssetStorage<A> {
                                     data: Ok(FormatValue { data: x, reload: r }),
                                     handle,
                                     name,
+                                    source,
                                     old_reload,
                                 });
                                 continue;
@@ -292,15 +468,25 @@ impl<A: Asset> AssetStorage<A> {
                         };
 
                         let id = handle.id();
-                        assert!(
-                            bitset.contains(id),
-                            "Expected handle {:?} to be valid, but the asset storage says otherwise",
-                            handle,
-                        );
+                        if !bitset.contains(id) {
+                            debug!(
+                                "{:?}: Asset {:?} (handle id: {:?}) was unloaded during its hot-reload",
+                                A::NAME,
+                                name,
+                                handle,
+                            );
+                            continue;
+                        }
+                        memory.set(id, asset.memory_size());
                         unsafe {
                             let old = assets.get_mut(id);
                             *old = asset;
                         }
+                        if let Some(dependencies) =
+                            strategy.and_then(HotReloadStrategy::dependencies)
+                        {
+                            dependencies.reloaded(&AssetKey::new(&source, A::NAME, &name));
+                        }
 
                         (reload_obj, handle)
                     }
@@ -318,46 +504,126 @@ impl<A: Asset> AssetStorage<A> {
         }
 
         let mut count = 0;
-        let mut skip = 0;
-        while let Some(i) = self.handles.iter().skip(skip).position(Handle::is_unique) {
-            count += 1;
-            // Re-normalize index
-            let i = skip + i;
-            skip = i;
-            let handle = self.handles.swap_remove(i);
-            let id = handle.id();
-            unsafe {
-                drop_fn(self.assets.remove(id));
+        let mut i = 0;
+        while i < self.handles.len() {
+            let id = self.handles[i].id();
+            if !self.handles[i].is_unique() {
+                self.unused_since.remove(&id);
+                i += 1;
+            } else if self.memory_budget.is_some() && self.memory.sizes.contains_key(&id) {
+                // Kept around until the budget is exceeded.
+                self.unused_since.entry(id).or_insert(frame_number);
+                i += 1;
+            } else {
+                self.free(i, &mut drop_fn);
+                count += 1;
             }
-            self.bitset.remove(id);
+        }
 
-            // Can't reuse old handle here, because otherwise weak handles would still be valid.
-            // TODO: maybe just store u32?
-            self.unused_handles.push(Handle {
-                id: Arc::new(id),
-                marker: PhantomData,
-            });
+        if let Some(budget) = self.memory_budget {
+            while self.memory.total > budget {
+                let lru = self
+                    .unused_since
+                    .iter()
+                    .min_by_key(|&(id, since)| (*since, *id))
+                    .map(|(id, _)| *id);
+                let index = match lru {
+                    Some(id) => self
+                        .handles
+                        .iter()
+                        .position(|handle| handle.id() == id)
+                        .expect("Unreachable: unused assets have a handle"),
+                    None => break,
+                };
+                self.free(index, &mut drop_fn);
+                count += 1;
+            }
         }
         if count != 0 {
             debug!("{:?}: Freed {} handle ids", A::NAME, count,);
         }
 
-        if strategy
-            .map(|s| s.needs_reload(frame_number))
-            .unwrap_or(false)
-        {
+        let strategy = match strategy {
+            Some(strategy) => strategy,
+            None => return,
+        };
+        let poll = strategy.needs_reload(frame_number);
+        let changed = strategy.changed_assets(frame_number);
+        let cascaded = strategy.cascaded_assets(frame_number);
+        if poll || changed.is_some() || cascaded.is_some() {
             trace!("{:?}: Testing for asset reloads..", A::NAME);
-            self.hot_reload(pool);
+            self.hot_reload(pool, strategy.dependencies(), |source, rel| {
+                let name = rel.name();
+                // Dependents are reloaded even though their own file didn't change.
+                if cascaded.map_or(false, |cascaded| {
+                    cascaded.contains(&AssetKey::new(source, A::NAME, &name))
+                }) {
+                    return true;
+                }
+                ((poll && strategy.polls(source, &name))
+                    || changed.map_or(false, |changed| changed.contains(&name)))
+                    && rel.needs_reload()
+            });
+        }
+    }
+
+    // Frees the asset of the handle at `index` in `handles`, and its handle id.
+    fn free<D>(&mut self, index: usize, drop_fn: &mut D)
+    where
+        D: FnMut(A),
+    {
+        let handle = self.handles.swap_remove(index);
+        let id = handle.id();
+        self.memory.remove(id);
+        self.unused_since.remove(&id);
+        self.forget(id);
+        if self.bitset.remove(id) {
+            unsafe {
+                drop_fn(self.assets.remove(id));
+            }
+        }
+
+        release(&self.unused_handles, handle);
+    }
+
+    // Forgets the name and source of the asset, and its dependencies unless it is loaded again
+    // from that source with that name.
+    fn forget(&mut self, id: u32) {
+        let source = self.sources.remove(&id).unwrap_or_default();
+        let name = match self.names.remove(&id) {
+            Some(name) => name,
+            None => return,
+        };
+        if let Some(ref dependencies) = self.dependencies {
+            let names = &self.names;
+            let loaded_again = self.sources.iter().any(|(other, other_source)| {
+                *other_source == source && names.get(other) == Some(&name)
+            });
+            if !loaded_again {
+                dependencies.remove(&AssetKey::new(&source, A::NAME, &name));
+            }
         }
     }
 
-    fn hot_reload(&mut self, pool: &ThreadPool) {
+    // Reloads the assets for which `needs_reload` returns `true`, given the id of the source they
+    // were loaded from.
+    fn hot_reload<F>(
+        &mut self,
+        pool: &ThreadPool,
+        dependencies: Option<&Dependencies>,
+        needs_reload: F,
+    ) where
+        F: Fn(&str, &dyn Reload<A>) -> bool,
+    {
         self.reloads.retain(|&(ref handle, _)| !handle.is_dead());
-        while let Some(p) = self
-            .reloads
-            .iter()
-            .position(|&(_, ref rel)| rel.needs_reload())
-        {
+        let sources = &self.sources;
+        while let Some(p) = self.reloads.iter().position(|&(ref handle, ref rel)| {
+            let source = handle
+                .upgrade()
+                .and_then(|handle| sources.get(&handle.id()))
+                .map_or("", String::as_str);
+            needs_reload(source, &**rel)
+        }) {
             let (handle, rel): (WeakHandle<_>, Box<dyn Reload<_>>) = self.reloads.swap_remove(p);
 
             let name = rel.name();
@@ -373,6 +639,13 @@ impl<A: Asset> AssetStorage<A> {
             );
 
             if let Some(handle) = handle {
+                let source = sources.get(&handle.id()).cloned().unwrap_or_default();
+
+                // The dependencies are recorded again while the asset is processed.
+                if let Some(dependencies) = dependencies {
+                    dependencies.clear(&AssetKey::new(&source, A::NAME, &name));
+                }
+
                 let processed = self.processed.clone();
                 pool.spawn(move || {
                     let old_reload = rel.clone();
@@ -381,6 +654,7 @@ impl<A: Asset> AssetStorage<A> {
                     let p = Processed::HotReload {
                         data,
                         name,
+                        source,
                         handle,
                         old_reload,
                     };
@@ -400,8 +674,15 @@ impl<A: Asset> Default for AssetStorage<A> {
             handle_alloc: Default::default(),
             processed: Arc::new(MsQueue::new()),
             reloads: Default::default(),
-            unused_handles: MsQueue::new(),
+            unused_handles: Arc::new(MsQueue::new()),
             requeue: Mutex::new(Vec::default()),
+            memory: Default::default(),
+            memory_budget: None,
+            cancel_unused: Arc::new(AtomicBool::new(true)),
+            names: Default::default(),
+            sources: Default::default(),
+            unused_since: Default::default(),
+            dependencies: None,
         }
     }
 }
@@ -491,7 +772,7 @@ impl<A> Handle<A> {
     }
 
     /// Returns `true` if this is the only handle to the asset its pointing at.
-    fn is_unique(&self) -> bool {
+    pub(crate) fn is_unique(&self) -> bool {
         Arc::strong_count(&self.id) == 1
     }
 }
@@ -508,12 +789,17 @@ pub(crate) enum Processed<A: Asset> {
         data: Result<FormatValue<A>, Error>,
         handle: Handle<A>,
         name: String,
+        // id of the source the asset is loaded from.
+        source: String,
         tracker: Box<dyn Tracker>,
+        // the dependencies the `Loader` records the asset in.
+        dependencies: Option<Dependencies>,
     },
     HotReload {
         data: Result<FormatValue<A>, Error>,
         handle: Handle<A>,
         name: String,
+        source: String,
         old_reload: Box<dyn Reload<A>>,
     },
 }
@@ -543,3 +829,128 @@ impl<A> WeakHandle<A> {
         self.upgrade().is_none()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use rayon::{ThreadPool, ThreadPoolBuilder};
+
+    use amethyst_core::specs::prelude::VecStorage;
+
+    use crate::Cache;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct Blob(usize);
+
+    impl Asset for Blob {
+        const NAME: &'static str = "BLOB";
+        type Data = Self;
+        type HandleStorage = VecStorage<Handle<Self>>;
+
+        fn memory_size(&self) -> usize {
+            self.0
+        }
+    }
+
+    fn load(storage: &AssetStorage<Blob>, size: usize) -> Handle<Blob> {
+        let handle = storage.allocate();
+        storage.processed.push(Processed::NewAsset {
+            data: Ok(FormatValue::data(Blob(size))),
+            handle: handle.clone(),
+            name: format!("blob {}", size),
+            source: String::new(),
+            tracker: Box::new(()),
+            dependencies: None,
+        });
+        handle
+    }
+
+    fn process(storage: &mut AssetStorage<Blob>, frame_number: u64, pool: &ThreadPool) {
+        storage.process(
+            |blob| Ok(ProcessingState::Loaded(blob)),
+            frame_number,
+            pool,
+            None,
+        );
+    }
+
+    #[test]
+    fn unused_assets_are_evicted_over_budget() {
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut storage = AssetStorage::<Blob>::new();
+        storage.set_memory_budget(Some(10));
+        let mut cache = Cache::new();
+
+        let a = load(&storage, 6);
+        let b = load(&storage, 4);
+        let c = load(&storage, 5);
+        cache.insert("a", &a);
+        cache.insert("b", &b);
+        cache.insert("c", &c);
+        process(&mut storage, 0, &pool);
+        // Over budget, but every asset is used.
+        assert_eq!(storage.memory_usage(), 15);
+
+        drop(a);
+        process(&mut storage, 1, &pool);
+        assert!(cache.get("a").is_none());
+        assert_eq!(storage.memory_usage(), 9);
+
+        drop(b);
+        process(&mut storage, 2, &pool);
+        let b = cache
+            .get("b")
+            .expect("Unused assets are kept within the budget");
+        assert_eq!(storage.get(&b).map(|blob| blob.0), Some(4));
+
+        assert_eq!(storage.unload(&b).map(|blob| blob.0), Some(4));
+        assert!(storage.get(&b).is_none());
+        assert!(storage.unload(&b).is_none());
+        assert_eq!(storage.memory_usage(), 5);
+    }
+    #[test]
+    fn freed_assets_are_forgotten_by_the_dependencies() {
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut storage = AssetStorage::<Blob>::new();
+        let dependencies = Dependencies::new();
+        let level = AssetKey::new("", "Prefab", "level");
+        let (blob, modded) = (
+            AssetKey::new("", "BLOB", "blob"),
+            AssetKey::new("mods", "BLOB", "blob"),
+        );
+        dependencies.add(&level, &blob);
+        dependencies.add(&level, &modded);
+
+        // The same name is loaded from the default source once and from the mods twice.
+        let mut handles = Vec::new();
+        for &(size, source) in &[(1, ""), (2, "mods"), (3, "mods")] {
+            let handle = storage.allocate();
+            storage.processed.push(Processed::NewAsset {
+                data: Ok(FormatValue::data(Blob(size))),
+                handle: handle.clone(),
+                name: "blob".into(),
+                source: source.into(),
+                tracker: Box::new(()),
+                dependencies: Some(dependencies.clone()),
+            });
+            handles.push(handle);
+        }
+        process(&mut storage, 0, &pool);
+        assert_eq!(
+            dependencies.dependencies_of(&level),
+            vec![blob.clone(), modded.clone()]
+        );
+
+        storage.unload(&handles[1]);
+        assert_eq!(
+            dependencies.dependencies_of(&level),
+            vec![blob.clone(), modded.clone()]
+        );
+        storage.unload(&handles[2]);
+        assert_eq!(dependencies.dependencies_of(&level), vec![blob.clone()]);
+        handles.clear();
+        process(&mut storage, 1, &pool);
+        assert!(dependencies.dependents_of(&blob).is_empty());
+    }
+}
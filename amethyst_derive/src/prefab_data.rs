@@ -28,6 +28,18 @@ fn impl_prefab_data_component(ast: &DeriveInput) -> TokenStream {
                 system_data.insert(entity, self.clone()).map(|_| ())?;
                 Ok(())
             }
+
+            fn extract_from_entity(entity: Entity,
+                                   system_data: &mut Self::SystemData,
+                                   _: &[Entity]) -> ::std::result::Result<Option<Self>, Error> {
+                Ok(system_data.get(entity).cloned())
+            }
+
+            fn remove_from_entity(entity: Entity,
+                                  system_data: &mut Self::SystemData) -> ::std::result::Result<(), Error> {
+                system_data.remove(entity);
+                Ok(())
+            }
         }
     }
 }
@@ -72,6 +84,38 @@ fn impl_prefab_data_aggregate(ast: &DeriveInput) -> TokenStream {
         }
     });
 
+    let extracts = (0..data.len()).map(|n| {
+        let (ty, name, is_component) = &data[n];
+        let extracted = if *is_component {
+            quote! {
+                system_data.#n.get(entity).cloned()
+            }
+        } else {
+            quote! {
+                <#ty as PrefabData<'pfd>>::extract_from_entity(entity, &mut system_data.#n, entities)?
+            }
+        };
+        quote! {
+            #name: match #extracted {
+                Some(data) => data,
+                None => return Ok(None),
+            },
+        }
+    });
+
+    let removes = (0..data.len()).map(|n| {
+        let (ty, _, is_component) = &data[n];
+        if *is_component {
+            quote! {
+                system_data.#n.remove(entity);
+            }
+        } else {
+            quote! {
+                <#ty as PrefabData<'pfd>>::remove_from_entity(entity, &mut system_data.#n)?;
+            }
+        }
+    });
+
     let (_, ty_generics, where_clause) = ast.generics.split_for_impl();
     let lf_tokens = gen_def_lt_tokens(&ast.generics);
     let ty_tokens = gen_def_ty_params(&ast.generics);
@@ -98,6 +142,20 @@ fn impl_prefab_data_aggregate(ast: &DeriveInput) -> TokenStream {
                 #(#subs)*
                 Ok(ret)
             }
+
+            fn extract_from_entity(entity: Entity,
+                                   system_data: &mut Self::SystemData,
+                                   entities: &[Entity]) -> ::std::result::Result<Option<Self>, Error> {
+                Ok(Some(#base {
+                    #(#extracts)*
+                }))
+            }
+
+            fn remove_from_entity(entity: Entity,
+                                  system_data: &mut Self::SystemData) -> ::std::result::Result<(), Error> {
+                #(#removes)*
+                Ok(())
+            }
         }
     }
 }
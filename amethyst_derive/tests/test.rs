@@ -3,7 +3,10 @@ use amethyst_derive::{EventReader, PrefabData};
 use amethyst_assets::{PrefabData, ProgressCounter};
 use amethyst_core::{
     shrev::{EventChannel, ReaderId},
-    specs::{Component, DenseVecStorage, Entity, Read, Resources, SystemData, WriteStorage},
+    specs::{
+        Builder, Component, DenseVecStorage, Entity, Read, Resources, SystemData, World,
+        WriteStorage,
+    },
     EventReader,
 };
 use amethyst_error::Error;
@@ -59,3 +62,35 @@ pub struct Outer {
     #[prefab(Component)]
     external: External,
 }
+
+#[test]
+fn extract_from_entity_reads_the_fields() {
+    let mut world = World::new();
+    world.register::<Stuff<u32>>();
+    world.register::<External>();
+    let entity = world
+        .create_entity()
+        .with(Stuff { inner: 3u32 })
+        .with(External { inner: 4 })
+        .build();
+    let empty = world.create_entity().build();
+
+    let mut data =
+        <<OuterPrefab<u32> as PrefabData<'_>>::SystemData as SystemData<'_>>::fetch(&world.res);
+    let outer = OuterPrefab::<u32>::extract_from_entity(entity, &mut data, &[entity])
+        .unwrap()
+        .expect("The fields are extracted from the components");
+    assert_eq!(outer.inner.inner, 3);
+    // A missing field means there is nothing to extract.
+    assert!(
+        OuterPrefab::<u32>::extract_from_entity(empty, &mut data, &[empty])
+            .unwrap()
+            .is_none()
+    );
+
+    let mut data = <<Outer as PrefabData<'_>>::SystemData as SystemData<'_>>::fetch(&world.res);
+    let outer = Outer::extract_from_entity(entity, &mut data, &[entity])
+        .unwrap()
+        .expect("The component is extracted");
+    assert_eq!(outer.external.inner, 4);
+}
@@ -1,11 +1,19 @@
-use std::net::SocketAddr;
+use std::{marker::PhantomData, net::SocketAddr};
 
 use serde::{de::DeserializeOwned, Serialize};
 
 use amethyst_core::{bundle::SystemBundle, shred::DispatcherBuilder};
 use amethyst_error::{Error, ResultExt};
 
-use crate::{filter::NetFilter, server::ServerConfig, NetSocketSystem};
+use crate::{
+    codec::PayloadFormat,
+    filter::NetFilter,
+    lifecycle::ConnectionPolicy,
+    prediction::{Predicted, PredictionSystem},
+    replication::{ComponentReplicationSystem, EntityReplicationSystem, ReplicatedComponent},
+    server::ServerConfig,
+    NetSocketSystem,
+};
 
 /// A convenience bundle to create the infrastructure needed to send and receive network messages.
 pub struct NetworkBundle<T> {
@@ -14,6 +22,12 @@ pub struct NetworkBundle<T> {
 
     /// The filters applied on received network events.
     filters: Vec<Box<dyn NetFilter<T>>>,
+
+    /// The admission policy used in server mode, if enabled.
+    server_policy: Option<Box<dyn ConnectionPolicy>>,
+
+    /// The format of the payloads, the default one if not set.
+    format: Option<PayloadFormat<T>>,
 }
 
 impl<T> NetworkBundle<T> {
@@ -29,10 +43,41 @@ impl<T> NetworkBundle<T> {
         let config = ServerConfig {
             udp_recv_addr: receive_addr,
             udp_send_addr: send_addr,
-            max_throughput: 5000,
+            ..Default::default()
         };
 
-        NetworkBundle { config, filters }
+        NetworkBundle {
+            config,
+            filters,
+            server_policy: None,
+            format: None,
+        }
+    }
+
+    /// Runs the networking system in server mode.
+    ///
+    /// Clients sending a `NetEvent::Connect` are accepted or refused using `policy`
+    /// and the configured maximal amount of clients, and get their own `NetConnection` entity.
+    pub fn with_server_mode<P>(mut self, policy: P) -> Self
+    where
+        P: ConnectionPolicy + 'static,
+    {
+        self.server_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Sets the codec, compression and protocol version used for the payloads sent over the network.
+    ///
+    /// Defaults to `bincode` without compression and protocol version 0.
+    pub fn with_payload_format(mut self, format: PayloadFormat<T>) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Sets the maximal amount of clients that can be connected at the same time in server mode.
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.config.max_clients = max_clients;
+        self
     }
 }
 
@@ -42,11 +87,119 @@ where
 {
     /// Build the networking bundle by adding the networking system to the application.
     fn build(self, builder: &mut DispatcherBuilder<'_, '_>) -> Result<(), Error> {
-        let socket_system = NetSocketSystem::<T>::new(self.config, self.filters)
+        let mut socket_system = NetSocketSystem::<T>::new(self.config, self.filters)
             .with_context(|_| Error::from_string("Failed to open network system."))?;
 
+        if let Some(policy) = self.server_policy {
+            socket_system = socket_system.with_server_mode(policy);
+        }
+        if let Some(format) = self.format {
+            socket_system = socket_system.with_payload_format(format);
+        }
+
         builder.add(socket_system, "net_socket", &[]);
 
         Ok(())
     }
 }
+
+/// Bundle adding the systems replicating entities and components over the network.
+///
+/// Will add `EntityReplicationSystem<T>` with the name `entity_replication`,
+/// a `ComponentReplicationSystem<C, T>` named `<C::NAME>_replication` for every registered component,
+/// and a `PredictionSystem<C, T>` named `<C::NAME>_prediction` for every registered predicted component.
+pub struct ReplicationBundle<'a, T> {
+    is_server: bool,
+    dep: &'a [&'a str],
+    // name of the system to add, with the function adding it.
+    components: Vec<(String, fn(&mut DispatcherBuilder<'_, '_>, bool, &str))>,
+    _pd: PhantomData<T>,
+}
+
+impl<'a, T> ReplicationBundle<'a, T>
+where
+    T: Send + Sync + 'static,
+{
+    /// Creates a new replication bundle.
+    /// The server is authoritative, clients only author changes to the entities they own.
+    pub fn new(is_server: bool) -> Self {
+        ReplicationBundle {
+            is_server,
+            dep: &[],
+            components: Vec::new(),
+            _pd: PhantomData,
+        }
+    }
+
+    /// Set dependencies for the `EntityReplicationSystem`, like the `net_socket` system.
+    pub fn with_dep(mut self, dep: &'a [&'a str]) -> Self {
+        self.dep = dep;
+        self
+    }
+
+    /// Replicates the component `C`.
+    pub fn with_component<C>(mut self) -> Self
+    where
+        C: ReplicatedComponent,
+    {
+        self.components.push((
+            format!("{}_replication", C::NAME),
+            add_component_replication::<C, T>,
+        ));
+        self
+    }
+
+    /// Predicts the component `C` on the clients owning it, and reconciles it with the server.
+    pub fn with_predicted_component<C>(mut self) -> Self
+    where
+        C: Predicted,
+    {
+        self.components
+            .push((format!("{}_prediction", C::NAME), add_prediction::<C, T>));
+        self
+    }
+}
+
+fn add_component_replication<C, T>(
+    builder: &mut DispatcherBuilder<'_, '_>,
+    is_server: bool,
+    name: &str,
+) where
+    C: ReplicatedComponent,
+    T: Send + Sync + 'static,
+{
+    builder.add(
+        ComponentReplicationSystem::<C, T>::new(is_server),
+        name,
+        &["entity_replication"],
+    );
+}
+
+fn add_prediction<C, T>(builder: &mut DispatcherBuilder<'_, '_>, is_server: bool, name: &str)
+where
+    C: Predicted,
+    T: Send + Sync + 'static,
+{
+    builder.add(
+        PredictionSystem::<C, T>::new(is_server),
+        name,
+        &["entity_replication"],
+    );
+}
+
+impl<'a, 'b, 'c, T> SystemBundle<'a, 'b> for ReplicationBundle<'c, T>
+where
+    T: Send + Sync + 'static,
+{
+    fn build(self, builder: &mut DispatcherBuilder<'a, 'b>) -> Result<(), Error> {
+        builder.add(
+            EntityReplicationSystem::<T>::new(self.is_server),
+            "entity_replication",
+            self.dep,
+        );
+        for (name, add) in self.components {
+            add(builder, self.is_server, &name);
+        }
+        Ok(())
+    }
+}
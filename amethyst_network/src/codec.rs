@@ -0,0 +1,428 @@
+//! Encoding of `NetEvent`s into the payloads sent over the network.
+//!
+//! Every payload starts with a small header:
+//!
+//! | bytes | content                                   |
+//! |-------|-------------------------------------------|
+//! | 2     | the magic bytes `AN`                      |
+//! | 2     | the protocol version, big endian          |
+//! | 1     | flags: compression and handshake layout   |
+//!
+//! `NetEvent::Connect` and `NetEvent::ConnectionRefused` use a fixed layout independent of the codec
+//! and the protocol version, so builds that can't understand each other can still refuse each other.
+
+#[cfg(feature = "zstd")]
+use std::io::Read;
+
+use bincode::{deserialize, serialize};
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    server::MAX_FRAME_SIZE,
+    NetEvent,
+};
+
+const MAGIC: [u8; 2] = *b"AN";
+const HEADER_SIZE: usize = 5;
+
+// The lowest two bits of the flags hold the compression algorithm.
+const COMPRESSION_MASK: u8 = 0b0000_0011;
+const COMPRESSION_NONE: u8 = 0;
+#[cfg(feature = "lz4")]
+const COMPRESSION_LZ4: u8 = 1;
+#[cfg(feature = "zstd")]
+const COMPRESSION_ZSTD: u8 = 2;
+// The body is a `NetEvent::Connect`: a 16 bytes uuid followed by the receive port, big endian.
+const FLAG_HANDSHAKE: u8 = 0b0000_0100;
+// The body is the utf-8 reason of a `NetEvent::ConnectionRefused`.
+const FLAG_REFUSAL: u8 = 0b0000_1000;
+
+/// Turns `NetEvent`s into bytes and back.
+///
+/// Implement it to use your own format, for example a compact bit-packer for your custom events.
+pub trait NetCodec<T>: Send + Sync {
+    /// Encodes an event.
+    fn encode(&self, event: &NetEvent<T>) -> Result<Vec<u8>>;
+
+    /// Decodes an event encoded with `encode`.
+    fn decode(&self, data: &[u8]) -> Result<NetEvent<T>>;
+}
+
+/// Encodes events using `bincode`. This is the default codec.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BincodeCodec;
+
+impl<T> NetCodec<T> for BincodeCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(&self, event: &NetEvent<T>) -> Result<Vec<u8>> {
+        Ok(serialize(event)?)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<NetEvent<T>> {
+        Ok(deserialize(data)?)
+    }
+}
+
+/// Encodes events using MessagePack.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl<T> NetCodec<T> for MsgPackCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(&self, event: &NetEvent<T>) -> Result<Vec<u8>> {
+        rmp_serde::to_vec(event).map_err(|e| Error::Codec(e.to_string()))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<NetEvent<T>> {
+        rmp_serde::from_read_ref(data).map_err(|e| Error::Codec(e.to_string()))
+    }
+}
+
+/// The compression applied on encoded events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// The payloads are sent as they are.
+    None,
+    /// The payloads are compressed with LZ4, favoring speed.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// The payloads are compressed with zstd at the given level, favoring size.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+    fn flag(self) -> u8 {
+        match self {
+            Compression::None => COMPRESSION_NONE,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => COMPRESSION_LZ4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => COMPRESSION_ZSTD,
+        }
+    }
+
+    fn compress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4::block::compress(&data, None, true)?),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Ok(zstd::encode_all(&data[..], level)?),
+        }
+    }
+}
+
+// Decompresses a body compressed with the algorithm of the given flag.
+// Fails without allocating more than `max_size` bytes if the body decompresses to more than that.
+fn decompress(flag: u8, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    match flag {
+        COMPRESSION_NONE => Ok(data.to_vec()),
+        #[cfg(feature = "lz4")]
+        COMPRESSION_LZ4 => {
+            // The block is prefixed with its decompressed size, little endian.
+            if data.len() < 4 {
+                return Err(Error::InvalidHeader);
+            }
+            let size = data[..4]
+                .iter()
+                .rev()
+                .fold(0, |size, byte| size << 8 | *byte as usize);
+            if size > max_size {
+                return Err(Error::PayloadTooLarge(max_size));
+            }
+            Ok(lz4::block::decompress(&data[4..], Some(size as i32))?)
+        }
+        #[cfg(feature = "zstd")]
+        COMPRESSION_ZSTD => {
+            let mut body = Vec::new();
+            zstd::stream::Decoder::new(data)?
+                .take((max_size as u64).saturating_add(1))
+                .read_to_end(&mut body)?;
+            if body.len() > max_size {
+                return Err(Error::PayloadTooLarge(max_size));
+            }
+            Ok(body)
+        }
+        flag => Err(Error::UnsupportedCompression(flag)),
+    }
+}
+
+/// The format of the payloads sent over the network: the codec, the compression and the protocol version.
+///
+/// Both ends need the same format. Payloads with another protocol version are rejected, and a client
+/// connecting with another version is refused with a `NetEvent::ConnectionRefused`.
+/// Bump the version whenever your events or your codec change.
+pub struct PayloadFormat<T> {
+    codec: Box<dyn NetCodec<T>>,
+    compression: Compression,
+    compression_threshold: usize,
+    max_size: usize,
+    version: u16,
+}
+
+impl<T> PayloadFormat<T> {
+    /// Creates a format using the given codec, without compression and with protocol version 0.
+    pub fn new<C>(codec: C) -> Self
+    where
+        C: NetCodec<T> + 'static,
+    {
+        PayloadFormat {
+            codec: Box::new(codec),
+            compression: Compression::None,
+            compression_threshold: 0,
+            max_size: MAX_FRAME_SIZE,
+            version: 0,
+        }
+    }
+
+    /// Compresses the encoded events larger than `threshold` bytes.
+    pub fn with_compression(mut self, compression: Compression, threshold: usize) -> Self {
+        self.compression = compression;
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Sets the maximal size received payloads may decompress to, the other end picks the size the buffer is allocated with.
+    /// This value is by default `MAX_FRAME_SIZE`, the largest payload sent over TCP.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Sets the protocol version written in every payload.
+    pub fn with_version(mut self, version: u16) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// The protocol version written in every payload.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Encodes an event into a payload.
+    pub fn encode(&self, event: &NetEvent<T>) -> Result<Vec<u8>> {
+        match *event {
+            NetEvent::Connect {
+                ref client_uuid,
+                receive_port,
+            } => {
+                let mut payload = self.header(FLAG_HANDSHAKE);
+                payload.extend_from_slice(client_uuid.as_bytes());
+                payload.extend_from_slice(&[(receive_port >> 8) as u8, receive_port as u8]);
+                Ok(payload)
+            }
+            NetEvent::ConnectionRefused { ref reason } => {
+                let mut payload = self.header(FLAG_REFUSAL);
+                payload.extend_from_slice(reason.as_bytes());
+                Ok(payload)
+            }
+            ref event => {
+                let body = self.codec.encode(event)?;
+                let (flags, body) = if body.len() > self.compression_threshold {
+                    (self.compression.flag(), self.compression.compress(body)?)
+                } else {
+                    (COMPRESSION_NONE, body)
+                };
+                let mut payload = self.header(flags);
+                payload.extend(body);
+                Ok(payload)
+            }
+        }
+    }
+
+    /// Decodes a payload into an event.
+    ///
+    /// Fails with `Error::VersionMismatch` if the payload has been encoded with another protocol version.
+    pub fn decode(&self, data: &[u8]) -> Result<NetEvent<T>> {
+        if data.len() < HEADER_SIZE || data[..2] != MAGIC {
+            return Err(Error::InvalidHeader);
+        }
+        let version = u16::from(data[2]) << 8 | u16::from(data[3]);
+        let flags = data[4];
+        let body = &data[HEADER_SIZE..];
+
+        if flags & FLAG_REFUSAL != 0 {
+            let reason = String::from_utf8_lossy(body).into_owned();
+            return Ok(NetEvent::ConnectionRefused { reason });
+        }
+
+        if flags & FLAG_HANDSHAKE != 0 {
+            if body.len() != 18 {
+                return Err(Error::InvalidHeader);
+            }
+            let receive_port = u16::from(body[16]) << 8 | u16::from(body[17]);
+            if version != self.version {
+                return Err(Error::VersionMismatch {
+                    expected: self.version,
+                    found: version,
+                    receive_port: Some(receive_port),
+                });
+            }
+            let client_uuid = Uuid::from_slice(&body[..16]).map_err(|_| Error::InvalidHeader)?;
+            return Ok(NetEvent::Connect {
+                client_uuid,
+                receive_port,
+            });
+        }
+
+        if version != self.version {
+            return Err(Error::VersionMismatch {
+                expected: self.version,
+                found: version,
+                receive_port: None,
+            });
+        }
+        let body = decompress(flags & COMPRESSION_MASK, body, self.max_size)?;
+        self.codec.decode(&body)
+    }
+
+    fn header(&self, flags: u8) -> Vec<u8> {
+        vec![
+            MAGIC[0],
+            MAGIC[1],
+            (self.version >> 8) as u8,
+            self.version as u8,
+            flags,
+        ]
+    }
+}
+
+impl<T> Default for PayloadFormat<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn default() -> Self {
+        PayloadFormat::new(BincodeCodec)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::*;
+
+    // A codec packing a single byte as the custom event.
+    struct BytePacker;
+
+    impl NetCodec<u8> for BytePacker {
+        fn encode(&self, event: &NetEvent<u8>) -> Result<Vec<u8>> {
+            match *event {
+                NetEvent::Custom(byte) => Ok(vec![byte]),
+                _ => Err(Error::Codec("only custom events are packed".to_string())),
+            }
+        }
+
+        fn decode(&self, data: &[u8]) -> Result<NetEvent<u8>> {
+            match data {
+                [byte] => Ok(NetEvent::Custom(*byte)),
+                _ => Err(Error::Codec("expected a single byte".to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn custom_codec_round_trip() {
+        let format = PayloadFormat::new(BytePacker).with_version(3);
+        let payload = format.encode(&NetEvent::Custom(42)).unwrap();
+        assert_eq!(payload, vec![b'A', b'N', 0, 3, 0, 42]);
+        assert_eq!(format.decode(&payload).unwrap(), NetEvent::Custom(42));
+        assert!(format.decode(&[1, 2, 3, 4, 5, 6]).is_err());
+    }
+
+    #[test]
+    fn mismatched_version_is_rejected() {
+        let old = PayloadFormat::<()>::default().with_version(1);
+        let new = PayloadFormat::<()>::default().with_version(2);
+
+        let text = old
+            .encode(&NetEvent::TextMessage {
+                msg: "hello".to_string(),
+            })
+            .unwrap();
+        match new.decode(&text) {
+            Err(Error::VersionMismatch {
+                expected: 2,
+                found: 1,
+                receive_port: None,
+            }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // Handshakes can be read by any version to be refused properly.
+        let connect = old
+            .encode(&NetEvent::Connect {
+                client_uuid: Uuid::new_v4(),
+                receive_port: 3457,
+            })
+            .unwrap();
+        match new.decode(&connect) {
+            Err(Error::VersionMismatch {
+                receive_port: Some(3457),
+                ..
+            }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        let refusal = NetEvent::ConnectionRefused {
+            reason: "Outdated".to_string(),
+        };
+        assert_eq!(old.decode(&new.encode(&refusal).unwrap()).unwrap(), refusal);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn large_payloads_are_compressed() {
+        let format = PayloadFormat::<()>::default().with_compression(Compression::Lz4, 64);
+        let event = NetEvent::TextMessage {
+            msg: "a".repeat(1000),
+        };
+        let payload = format.encode(&event).unwrap();
+        assert!(payload.len() < 1000);
+        assert_eq!(format.decode(&payload).unwrap(), event);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn oversized_lz4_prefix_is_rejected() {
+        let format = PayloadFormat::<()>::default();
+        // Announces a body of almost 2 GiB.
+        let payload = vec![b'A', b'N', 0, 0, COMPRESSION_LZ4, 0xff, 0xff, 0xff, 0x7f, 0];
+        match format.decode(&payload) {
+            Err(Error::PayloadTooLarge(MAX_FRAME_SIZE)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_bodies_are_capped() {
+        let format = PayloadFormat::<()>::default().with_compression(Compression::Zstd(3), 64);
+        let event = NetEvent::TextMessage {
+            msg: "a".repeat(1000),
+        };
+        let payload = format.encode(&event).unwrap();
+        assert_eq!(format.decode(&payload).unwrap(), event);
+
+        let small = PayloadFormat::<()>::default().with_max_size(100);
+        match small.decode(&payload) {
+            Err(Error::PayloadTooLarge(100)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -7,7 +7,7 @@ use uuid::Uuid;
 
 use amethyst_core::specs::{Component, VecStorage};
 
-use crate::NetEvent;
+use crate::{NetEvent, NetPacket};
 
 // TODO: Think about relationship between NetConnection and NetIdentity.
 
@@ -19,17 +19,20 @@ pub struct NetConnection<E: 'static> {
     pub target_receiver: SocketAddr,
     /// The target remote socket address who is sending packets to us.
     pub target_sender: SocketAddr,
+    /// The target remote TCP listener, used to open a stream when sending `DeliveryRequirement::Tcp` packets.
+    /// It is not needed if the other end opened the stream.
+    pub target_tcp: Option<SocketAddr>,
     /// The state of the connection.
     pub state: ConnectionState,
-    /// The buffer of events to be sent.
+    /// The buffer of events to be sent, along with their delivery requirement.
     #[serde(skip)]
-    pub send_buffer: EventChannel<NetEvent<E>>,
+    pub send_buffer: EventChannel<NetPacket<E>>,
     /// The buffer of events that have been received.
     #[serde(skip)]
     pub receive_buffer: EventChannel<NetEvent<E>>,
     /// Private. Used by `NetSocketSystem` to be able to immediately send events upon receiving a new NetConnection.
     #[serde(skip)]
-    send_reader: ReaderId<NetEvent<E>>,
+    send_reader: ReaderId<NetPacket<E>>,
 }
 
 impl<E: Send + Sync + 'static> NetConnection<E> {
@@ -41,6 +44,7 @@ impl<E: Send + Sync + 'static> NetConnection<E> {
         NetConnection {
             target_receiver,
             target_sender,
+            target_tcp: None,
             state: ConnectionState::Connecting,
             send_buffer,
             receive_buffer: EventChannel::<NetEvent<E>>::new(),
@@ -48,6 +52,18 @@ impl<E: Send + Sync + 'static> NetConnection<E> {
         }
     }
 
+    /// Sets the TCP listener of the other end, see `target_tcp`.
+    pub fn with_tcp(mut self, target_tcp: SocketAddr) -> Self {
+        self.target_tcp = Some(target_tcp);
+        self
+    }
+
+    /// Queues an event for sending using its default delivery requirement.
+    /// Use `send_buffer` directly with a `NetPacket` to pick the delivery requirement yourself.
+    pub fn queue(&mut self, event: NetEvent<E>) {
+        self.send_buffer.single_write(NetPacket::from(event));
+    }
+
     /// Function used ONLY by NetSocketSystem.
     /// Since most users will want to both create the connection and send messages on the same frame,
     /// we need a way to read those. Since the NetSocketSystem runs after the creation of the NetConnection,
@@ -55,7 +71,7 @@ impl<E: Send + Sync + 'static> NetConnection<E> {
     ///
     /// The downside of this is that you are forced to take NetConnection mutably inside of NetSocketSystem.
     /// If someone finds a better solution, please open a PR.
-    pub fn send_buffer_early_read(&mut self) -> EventIterator<'_, NetEvent<E>> {
+    pub fn send_buffer_early_read(&mut self) -> EventIterator<'_, NetPacket<E>> {
         self.send_buffer.read(&mut self.send_reader)
     }
 }
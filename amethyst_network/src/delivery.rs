@@ -0,0 +1,175 @@
+//! Delivery guarantees that can be requested for outgoing network events.
+
+use std::net::SocketAddr;
+
+use laminar::{DeliveryMethod, Packet};
+use serde::{Deserialize, Serialize};
+
+/// The delivery guarantee requested for a single outgoing `NetEvent`.
+///
+/// `NetSocketSystem` maps every requirement onto the matching laminar packet type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DeliveryRequirement {
+    /// The packet may be dropped, duplicated or arrive out of order.
+    /// This is basically a bare UDP packet.
+    Unreliable,
+    /// The packet may be dropped, but packets older than the newest received one are discarded.
+    /// Useful for state that is continuously updated, like positions.
+    UnreliableSequenced,
+    /// The packet will arrive, but not necessarily in the order it was sent.
+    ReliableUnordered,
+    /// The packet will arrive, and in the order it was sent.
+    /// Useful for chat messages, handshakes and gameplay commands.
+    ReliableOrdered,
+    /// The packet is sent over the TCP stream of the connection, reliably and in order.
+    /// Useful for large payloads like level downloads.
+    ///
+    /// Falls back to `ReliableOrdered` over UDP if TCP is not enabled in the `ServerConfig`,
+    /// or if there is no stream with the other end and `NetConnection::target_tcp` is not set.
+    Tcp,
+}
+
+impl DeliveryRequirement {
+    /// Returns `true` if packets sent with this requirement are guaranteed to arrive.
+    pub fn is_reliable(self) -> bool {
+        match self {
+            DeliveryRequirement::ReliableUnordered
+            | DeliveryRequirement::ReliableOrdered
+            | DeliveryRequirement::Tcp => true,
+            DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced => false,
+        }
+    }
+
+    /// Returns the laminar delivery method matching this requirement.
+    /// This is the UDP fallback for `Tcp`.
+    pub fn delivery_method(self) -> DeliveryMethod {
+        match self {
+            DeliveryRequirement::Unreliable => DeliveryMethod::UnreliableUnordered,
+            DeliveryRequirement::UnreliableSequenced => DeliveryMethod::SequencedUnordered,
+            DeliveryRequirement::ReliableUnordered => DeliveryMethod::ReliableUnordered,
+            DeliveryRequirement::ReliableOrdered | DeliveryRequirement::Tcp => {
+                DeliveryMethod::ReliableOrdered
+            }
+        }
+    }
+
+    /// Builds the laminar `Packet` carrying `payload` to `addr` with this requirement.
+    pub fn packet(self, addr: SocketAddr, payload: Vec<u8>) -> Packet {
+        Packet::new(addr, payload.into_boxed_slice(), self.delivery_method())
+    }
+}
+
+impl Default for DeliveryRequirement {
+    fn default() -> Self {
+        DeliveryRequirement::Unreliable
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use uuid::Uuid;
+
+    use crate::{
+        send_event,
+        server::{ServerConfig, UdpReceiver, UdpSender},
+        NetEvent, NetPacket, PayloadFormat, ServerSocketEvent,
+    };
+
+    use super::DeliveryRequirement;
+
+    /// A lossy link forwarding the datagrams received at `from` to `to`: every third datagram
+    /// is lost on the way, whatever its delivery method.
+    fn lossy_link(from: SocketAddr, to: SocketAddr) {
+        let socket = UdpSocket::bind(from).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1500];
+            let mut count = 0;
+            while let Ok((len, _)) = socket.recv_from(&mut buffer) {
+                count += 1;
+                if count % 3 != 0 {
+                    let _ = socket.send_to(&buffer[..len], to);
+                }
+            }
+        });
+    }
+
+    fn message(id: usize) -> NetEvent<()> {
+        NetEvent::TextMessage {
+            msg: id.to_string(),
+        }
+    }
+
+    #[test]
+    fn handshake_events_are_reliable_by_default() {
+        let connect = NetPacket::<()>::from(NetEvent::Connect {
+            client_uuid: Uuid::nil(),
+            receive_port: 0,
+        });
+        assert_eq!(connect.delivery, DeliveryRequirement::ReliableOrdered);
+
+        let message = NetPacket::<()>::from(NetEvent::TextMessage {
+            msg: "hi".to_string(),
+        });
+        assert_eq!(message.delivery, DeliveryRequirement::Unreliable);
+    }
+
+    #[test]
+    fn reliable_events_survive_lossy_link() {
+        let link: SocketAddr = "127.0.0.1:21301".parse().unwrap();
+        let receive: SocketAddr = "127.0.0.1:21302".parse().unwrap();
+        let send: SocketAddr = "127.0.0.1:21303".parse().unwrap();
+        let config = ServerConfig::default();
+        let receiver = UdpReceiver::run(receive, &config).unwrap();
+        let sender = UdpSender::run(send).unwrap();
+        lossy_link(link, receive);
+
+        let format = PayloadFormat::default();
+        for id in 0..30 {
+            let ordered = NetPacket::reliable_ordered(message(id));
+            send_event(ordered, link, &format, sender.get_sender());
+            let unordered = NetPacket::reliable_unordered(message(100 + id));
+            send_event(unordered, link, &format, sender.get_sender());
+        }
+
+        // Unreliable traffic keeps going, so laminar gets the chance to resend the lost packets.
+        let (mut ordered, mut unordered) = (Vec::new(), Vec::new());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while (ordered.len() < 30 || unordered.len() < 30) && Instant::now() < deadline {
+            send_event(
+                NetPacket::unreliable(message(1000)),
+                link,
+                &format,
+                sender.get_sender(),
+            );
+            thread::sleep(Duration::from_millis(10));
+            for event in receiver.try_iter() {
+                let packet = match event {
+                    ServerSocketEvent::Packet(packet) => packet,
+                    _ => continue,
+                };
+                let id = match format.decode(packet.payload()).unwrap() {
+                    NetEvent::TextMessage { msg } => msg.parse::<usize>().unwrap(),
+                    _ => continue,
+                };
+                match id {
+                    0..=99 => ordered.push(id),
+                    100..=999 if !unordered.contains(&id) => unordered.push(id),
+                    _ => {}
+                }
+            }
+        }
+
+        assert_eq!(ordered, (0..30).collect::<Vec<_>>());
+        unordered.sort();
+        assert_eq!(unordered, (100..130).collect::<Vec<_>>());
+    }
+}
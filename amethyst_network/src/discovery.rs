@@ -0,0 +1,383 @@
+//! Discovery of the servers running on the local network.
+//!
+//! Servers periodically advertise their `ServerInfo` on a broadcast or multicast address with the
+//! `ServerAdvertiseSystem`. Clients listen on that address with the `ServerDiscoverySystem`,
+//! which keeps the `DiscoveredServers` resource up to date.
+//!
+//! Clients can also query the servers on another address, which they answer right away,
+//! so the list is filled without waiting for the next advertisements.
+//!
+//! The ports are bound with `SO_REUSEADDR`, so several clients and servers can run on the same
+//! machine. The datagrams sent to a broadcast or multicast address reach all of them, but a
+//! datagram sent to a unicast address only reaches one of the sockets sharing its port.
+//!
+//! The discovery doesn't go through a `Host` and its `UdpReceiver` and `UdpSender`, which use the
+//! laminar `UdpSocket`: laminar binds the socket itself, so `SO_REUSEADDR` and `SO_BROADCAST`
+//! can't be set on it beforehand, and it prefixes every datagram with its own header and tracks
+//! a connection for every address it hears from, which every client on the network would then
+//! have with every advertising server. The advertisements are plain datagrams instead, read on a
+//! thread behind the same `ReceiveHandler` as the `Host` uses.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use bincode::{deserialize, serialize};
+use laminar::{DeliveryMethod, Packet};
+use log::{error, warn};
+use net2::UdpBuilder;
+use serde::{Deserialize, Serialize};
+
+use amethyst_core::specs::{Read, Resources, System, SystemData, Write};
+
+use crate::{
+    error::{Error, Result},
+    server::{ReceiveHandler, ServerSocketEvent},
+};
+
+const MAGIC: [u8; 4] = *b"ANDS";
+// The whole datagram a client sends to query the servers.
+const QUERY: [u8; 4] = *b"ANDQ";
+// Advertisements are small, anything larger is not meant for us.
+const MAX_ADVERTISEMENT_SIZE: usize = 1024;
+// The port is shared, so a datagram waking up the listening thread could reach another socket.
+// The thread checks if it should stop at this interval instead.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The information a server advertises on the local network.
+///
+/// Insert it as a resource on the server and keep it up to date, for example when a player joins.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The name of the server.
+    pub name: String,
+    /// The amount of connected players.
+    pub players: u32,
+    /// The maximal amount of players.
+    pub max_players: u32,
+    /// The map being played.
+    pub map: String,
+    /// The version of the game, so clients can hide incompatible servers.
+    pub version: u16,
+    /// The port the server receives packets on, the `udp_recv_addr` of its `ServerConfig`.
+    pub port: u16,
+}
+
+impl ServerInfo {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut payload = MAGIC.to_vec();
+        payload.extend(serialize(self)?);
+        Ok(payload)
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < MAGIC.len() || data[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidHeader);
+        }
+        Ok(deserialize(&data[MAGIC.len()..])?)
+    }
+}
+
+/// The configuration of the discovery, shared by servers and clients.
+#[derive(Clone, Debug)]
+pub struct DiscoveryConfig {
+    /// The broadcast or multicast address servers advertise on, and clients listen on.
+    /// This value is by default `255.255.255.255:3460`.
+    pub addr: SocketAddr,
+    /// The broadcast or multicast address clients send their queries to, and servers listen on.
+    /// This value is by default `255.255.255.255:3461`.
+    pub query_addr: SocketAddr,
+    /// The interval at which servers advertise themselves.
+    /// This value is by default one second.
+    pub interval: Duration,
+    /// Time after which a server that stopped advertising itself is removed from the `DiscoveredServers`.
+    /// This value is by default five seconds.
+    pub expiry: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            addr: "255.255.255.255:3460".parse().unwrap(),
+            query_addr: "255.255.255.255:3461".parse().unwrap(),
+            interval: Duration::from_secs(1),
+            expiry: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A server found on the local network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredServer {
+    /// The address to connect to.
+    pub addr: SocketAddr,
+    /// The last information advertised by the server.
+    pub info: ServerInfo,
+    /// When the server advertised itself or answered a query for the last time.
+    pub last_seen: Instant,
+}
+
+/// Resource listing the servers found on the local network, kept up to date by the `ServerDiscoverySystem`.
+#[derive(Debug, Default)]
+pub struct DiscoveredServers {
+    servers: HashMap<SocketAddr, DiscoveredServer>,
+    query_requested: bool,
+}
+
+impl DiscoveredServers {
+    /// Queries the servers on the next run of the `ServerDiscoverySystem`,
+    /// to update the list without waiting for their next advertisements.
+    pub fn refresh(&mut self) {
+        self.query_requested = true;
+    }
+
+    /// Returns the server receiving on the given address.
+    pub fn get(&self, addr: &SocketAddr) -> Option<&DiscoveredServer> {
+        self.servers.get(addr)
+    }
+
+    /// Iterates over the discovered servers, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &DiscoveredServer> {
+        self.servers.values()
+    }
+
+    /// Returns the amount of discovered servers.
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Returns `true` if no server has been discovered.
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    // Lists the server advertised by `data`, received from `ip`.
+    fn insert(&mut self, ip: IpAddr, data: &[u8]) {
+        // Other applications may use the same port, their packets are ignored.
+        let info = match ServerInfo::decode(data) {
+            Ok(info) => info,
+            Err(_) => return,
+        };
+        let addr = SocketAddr::new(ip, info.port);
+        self.servers.insert(
+            addr,
+            DiscoveredServer {
+                addr,
+                info,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Periodically advertises the `ServerInfo` resource on the local network,
+/// and answers the queries of the clients.
+pub struct ServerAdvertiseSystem {
+    socket: UdpSocket,
+    // receives the queries, without blocking.
+    queries: UdpSocket,
+    addr: SocketAddr,
+    interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl ServerAdvertiseSystem {
+    /// Creates a new `ServerAdvertiseSystem` advertising on the address of the config,
+    /// and listening to the queries on its query address.
+    pub fn new(config: &DiscoveryConfig) -> Result<Self> {
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified(&config.addr), 0))?;
+        if config.addr.is_ipv4() {
+            socket.set_broadcast(true)?;
+        }
+        let queries = bind_shared(&config.query_addr)?;
+        queries.set_nonblocking(true)?;
+        Ok(ServerAdvertiseSystem {
+            socket,
+            queries,
+            addr: config.addr,
+            interval: config.interval,
+            last_sent: None,
+        })
+    }
+
+    fn send(&self, info: &ServerInfo, addr: SocketAddr) {
+        let result = info
+            .encode()
+            .and_then(|payload| Ok(self.socket.send_to(&payload, addr)?));
+        if let Err(e) = result {
+            error!("Failed to advertise the server. Reason: {:?}", e);
+        }
+    }
+}
+
+impl<'a> System<'a> for ServerAdvertiseSystem {
+    type SystemData = Read<'a, ServerInfo>;
+
+    fn run(&mut self, info: Self::SystemData) {
+        let mut buffer = [0; MAX_ADVERTISEMENT_SIZE];
+        loop {
+            match self.queries.recv_from(&mut buffer) {
+                // The answer goes straight to the client.
+                Ok((len, from)) if buffer[..len] == QUERY => self.send(&info, from),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to receive a query. Reason: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        if self
+            .last_sent
+            .map_or(false, |sent| sent.elapsed() < self.interval)
+        {
+            return;
+        }
+        self.last_sent = Some(Instant::now());
+        self.send(&info, self.addr);
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+    }
+}
+
+/// Listens to the servers advertising themselves on the local network,
+/// and lists them in the `DiscoveredServers` resource.
+///
+/// The servers are queried once it is created, and again on `DiscoveredServers::refresh`.
+pub struct ServerDiscoverySystem {
+    receiver: ReceiveHandler,
+    // sends the queries, and receives the answers without blocking.
+    query_socket: UdpSocket,
+    query_addr: SocketAddr,
+    expiry: Duration,
+}
+
+impl ServerDiscoverySystem {
+    /// Creates a new `ServerDiscoverySystem` listening on the address of the config,
+    /// receiving the advertisements on its own thread.
+    pub fn new(config: &DiscoveryConfig) -> Result<Self> {
+        let socket = bind_shared(&config.addr)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let query_socket = UdpSocket::bind(SocketAddr::new(unspecified(&config.query_addr), 0))?;
+        if config.query_addr.is_ipv4() {
+            query_socket.set_broadcast(true)?;
+        }
+        query_socket.set_nonblocking(true)?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let (tx, rx) = mpsc::channel();
+        let thread_handle = thread::spawn(move || {
+            let mut buffer = [0; MAX_ADVERTISEMENT_SIZE];
+            while thread_running.load(Ordering::SeqCst) {
+                match socket.recv_from(&mut buffer) {
+                    Ok((len, from)) => {
+                        let packet = Packet::new(
+                            from,
+                            buffer[..len].to_vec().into_boxed_slice(),
+                            DeliveryMethod::UnreliableUnordered,
+                        );
+                        if let Err(e) = tx.send(ServerSocketEvent::Packet(packet)) {
+                            error!("Send channel error. Reason: {:?}", e);
+                        }
+                    }
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut => {}
+                    Err(e) => warn!("Failed to receive an advertisement. Reason: {:?}", e),
+                }
+            }
+        });
+
+        let system = ServerDiscoverySystem {
+            receiver: ReceiveHandler::new(rx, thread_handle, running),
+            query_socket,
+            query_addr: config.query_addr,
+            expiry: config.expiry,
+        };
+        system.query();
+        Ok(system)
+    }
+
+    fn query(&self) {
+        if let Err(e) = self.query_socket.send_to(&QUERY, self.query_addr) {
+            error!("Failed to query the servers. Reason: {:?}", e);
+        }
+    }
+}
+
+impl<'a> System<'a> for ServerDiscoverySystem {
+    type SystemData = Write<'a, DiscoveredServers>;
+
+    fn run(&mut self, mut discovered: Self::SystemData) {
+        if discovered.query_requested {
+            discovered.query_requested = false;
+            self.query();
+        }
+
+        for event in self.receiver.try_iter() {
+            if let ServerSocketEvent::Packet(packet) = event {
+                discovered.insert(packet.addr().ip(), packet.payload());
+            }
+        }
+
+        let mut buffer = [0; MAX_ADVERTISEMENT_SIZE];
+        loop {
+            match self.query_socket.recv_from(&mut buffer) {
+                Ok((len, from)) => discovered.insert(from.ip(), &buffer[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to receive an answer to a query. Reason: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        let expiry = self.expiry;
+        discovered
+            .servers
+            .retain(|_, server| server.last_seen.elapsed() < expiry);
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+    }
+}
+
+// Binds a socket receiving what is sent to `addr`, sharing its port with the other sockets
+// bound the same way.
+fn bind_shared(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => UdpBuilder::new_v4()?,
+        SocketAddr::V6(_) => UdpBuilder::new_v6()?,
+    };
+    builder.reuse_address(true)?;
+    let socket = builder.bind(SocketAddr::new(unspecified(addr), addr.port()))?;
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_multicast() => {
+            socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?
+        }
+        IpAddr::V6(ip) if ip.is_multicast() => socket.join_multicast_v6(&ip, 0)?,
+        _ => {}
+    }
+    Ok(socket)
+}
+
+// The unspecified address of the same family as `addr`.
+fn unspecified(addr: &SocketAddr) -> IpAddr {
+    match *addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
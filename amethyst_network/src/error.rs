@@ -25,6 +25,41 @@ pub enum Error {
     /// Error that could occur when sending an `ServerSocketEvent` to some channel.
     #[error(display = "Channel send error occurred")]
     ChannelSendError(#[cause] mpsc::SendError<ServerSocketEvent>),
+    /// Error that could occur when scheduling a payload on the TCP-sending thread.
+    #[error(display = "TCP channel send error occurred")]
+    TcpChannelSendError,
+    /// Error that occurs when using TCP while it is not enabled in the `ServerConfig`.
+    #[error(display = "TCP is not enabled")]
+    TcpDisabled,
+    /// Error that could occur in a `NetCodec` when encoding or decoding an event.
+    #[error(display = "Codec error occurred: {}", _0)]
+    Codec(String),
+    /// Error that occurs when a received payload does not start with a valid header.
+    #[error(display = "The payload does not start with a valid header")]
+    InvalidHeader,
+    /// Error that occurs when a received payload is compressed with an algorithm not enabled in this build.
+    #[error(
+        display = "The payload is compressed with an unsupported algorithm: {}",
+        _0
+    )]
+    UnsupportedCompression(u8),
+    /// Error that occurs when a received payload decompresses to more than the maximal size of the `PayloadFormat`.
+    #[error(display = "The payload decompresses to more than {} bytes", _0)]
+    PayloadTooLarge(usize),
+    /// Error that occurs when a received payload has been encoded with another protocol version.
+    #[error(
+        display = "Protocol version mismatch, expected {} but found {}",
+        expected,
+        found
+    )]
+    VersionMismatch {
+        /// The protocol version of this end.
+        expected: u16,
+        /// The protocol version of the payload.
+        found: u16,
+        /// The port the other end receives on, if the payload is a `NetEvent::Connect`.
+        receive_port: Option<u16>,
+    },
     #[error(display = "Some error has occurred")]
     #[doc(hidden)]
     __Nonexhaustive,
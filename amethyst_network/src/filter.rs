@@ -1,19 +1,95 @@
-//! The network filter base trait
+//! The network filter base trait and the stock filters.
 
-use std::{marker::PhantomData, net::SocketAddr};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    marker::PhantomData,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use amethyst_core::timing::duration_to_secs;
 
 use crate::NetEvent;
 
+/// Information about a received event handed to every `NetFilter`.
+#[derive(Debug)]
+pub struct FilterContext<'a> {
+    /// The addresses the peers with an established connection are sending from.
+    pub connected: &'a HashSet<SocketAddr>,
+    /// The size in bytes of the received payload.
+    pub payload_size: usize,
+    /// The instant at which the event is filtered.
+    pub now: Instant,
+}
+
+/// The reason why a filter dropped a received event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DropReason {
+    /// The source is not connected.
+    NotConnected,
+    /// The source is sending too many events.
+    RateLimited,
+    /// The source address is not allowed.
+    AddressDenied,
+    /// The received payload is too large.
+    PayloadTooLarge {
+        /// The size of the payload.
+        size: usize,
+        /// The maximal size allowed.
+        max: usize,
+    },
+    /// A custom reason given by a user-defined filter.
+    Other(String),
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DropReason::NotConnected => write!(f, "the source is not connected"),
+            DropReason::RateLimited => write!(f, "the source exceeded its rate limit"),
+            DropReason::AddressDenied => write!(f, "the source address is not allowed"),
+            DropReason::PayloadTooLarge { size, max } => {
+                write!(
+                    f,
+                    "the payload size {} exceeds the maximum of {}",
+                    size, max
+                )
+            }
+            DropReason::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Emitted by `NetSocketSystem` in the `EventChannel<FilteredEvent>` resource
+/// every time a filter drops a received event.
+/// Servers can use it to log or ban abusive peers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilteredEvent {
+    /// The address the dropped event came from.
+    pub source: SocketAddr,
+    /// Why the event was dropped.
+    pub reason: DropReason,
+}
+
 /// Network filter base trait providing an event filtering interface.
 pub trait NetFilter<T>: Send + Sync
 where
     T: PartialEq,
 {
     /// Check if the event is allowed to pass through this filter.
-    fn allow(&mut self, source: &SocketAddr, event: &NetEvent<T>) -> bool;
+    /// Returns the reason why the event has to be dropped otherwise.
+    fn allow(
+        &mut self,
+        source: &SocketAddr,
+        event: &NetEvent<T>,
+        context: &FilterContext<'_>,
+    ) -> Result<(), DropReason>;
 }
 
 /// A filter that checks if the incoming event is from a connected client.
+/// The handshake events (`Connect`, `Connected` and `ConnectionRefused`) are always allowed.
 pub struct FilterConnected<T> {
     _pd: PhantomData<T>,
 }
@@ -25,17 +101,333 @@ impl<T> FilterConnected<T> {
     }
 }
 
+impl<T> Default for FilterConnected<T> {
+    fn default() -> Self {
+        FilterConnected::new()
+    }
+}
+
 impl<T> NetFilter<T> for FilterConnected<T>
 where
     T: PartialEq + Send + Sync,
 {
     /// Checks if the event is from a connected client.
-    /// Note: This is not usable currently.
-    fn allow(&mut self, _source: &SocketAddr, event: &NetEvent<T>) -> bool {
+    fn allow(
+        &mut self,
+        source: &SocketAddr,
+        event: &NetEvent<T>,
+        context: &FilterContext<'_>,
+    ) -> Result<(), DropReason> {
         match event {
-            NetEvent::Connect { .. } => true,
-            NetEvent::Connected { .. } => true,
-            _ => false,
+            NetEvent::Connect { .. }
+            | NetEvent::Connected { .. }
+            | NetEvent::ConnectionRefused { .. } => Ok(()),
+            _ if context.connected.contains(source) => Ok(()),
+            _ => Err(DropReason::NotConnected),
+        }
+    }
+}
+
+/// A filter limiting the amount of events each address can send, using a token bucket.
+///
+/// Every address gets a bucket holding up to `burst` tokens, refilled at `per_second` tokens per second.
+/// Each received event costs one token, and is dropped if the bucket is empty.
+pub struct FilterRateLimit {
+    burst: f32,
+    per_second: f32,
+    buckets: HashMap<SocketAddr, (f32, Instant)>,
+    last_sweep: Option<Instant>,
+}
+
+// Interval in seconds at which full buckets are forgotten, so they do not grow forever.
+const SWEEP_INTERVAL_SECS: u64 = 10;
+
+impl FilterRateLimit {
+    /// Creates a rate limit allowing `per_second` events per second with bursts of up to `burst` events.
+    pub fn new(per_second: f32, burst: f32) -> Self {
+        FilterRateLimit {
+            burst,
+            per_second,
+            buckets: HashMap::new(),
+            last_sweep: None,
+        }
+    }
+
+    // Returns the amount of tokens in a bucket at `now`.
+    fn refill(&self, tokens: f32, last: Instant, now: Instant) -> f32 {
+        refill(tokens, last, now, self.per_second, self.burst)
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        let (per_second, burst) = (self.per_second, self.burst);
+        self.buckets
+            .retain(|_, (tokens, last)| refill(*tokens, *last, now, per_second, burst) < burst);
+        self.last_sweep = Some(now);
+    }
+}
+
+fn refill(tokens: f32, last: Instant, now: Instant, per_second: f32, burst: f32) -> f32 {
+    let elapsed = duration_to_secs(now.duration_since(last));
+    (tokens + elapsed * per_second).min(burst)
+}
+
+impl<T> NetFilter<T> for FilterRateLimit
+where
+    T: PartialEq,
+{
+    fn allow(
+        &mut self,
+        source: &SocketAddr,
+        _: &NetEvent<T>,
+        context: &FilterContext<'_>,
+    ) -> Result<(), DropReason> {
+        let now = context.now;
+        match self.last_sweep {
+            Some(last) if now.duration_since(last) < Duration::from_secs(SWEEP_INTERVAL_SECS) => {}
+            _ => self.sweep(now),
+        }
+
+        let (tokens, last) = self
+            .buckets
+            .get(source)
+            .cloned()
+            .unwrap_or((self.burst, now));
+        let tokens = self.refill(tokens, last, now);
+
+        if tokens >= 1.0 {
+            self.buckets.insert(*source, (tokens - 1.0, now));
+            Ok(())
+        } else {
+            self.buckets.insert(*source, (tokens, now));
+            Err(DropReason::RateLimited)
+        }
+    }
+}
+
+/// A list of ip addresses shared between a `FilterIp` and the game.
+///
+/// Cloning it gives another handle to the same list, so it can for instance be inserted as a resource
+/// to ban peers reported in `FilteredEvent`s while the filter is running.
+#[derive(Debug, Clone, Default)]
+pub struct IpList {
+    ips: Arc<RwLock<HashSet<IpAddr>>>,
+}
+
+impl IpList {
+    /// Creates an empty list.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds an address to the list. Returns `false` if it was already present.
+    pub fn insert(&self, ip: IpAddr) -> bool {
+        self.ips.write().expect("IpList lock poisoned").insert(ip)
+    }
+
+    /// Removes an address from the list. Returns `false` if it was not present.
+    pub fn remove(&self, ip: &IpAddr) -> bool {
+        self.ips.write().expect("IpList lock poisoned").remove(ip)
+    }
+
+    /// Checks if the list contains an address.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.ips.read().expect("IpList lock poisoned").contains(ip)
+    }
+}
+
+/// A filter dropping events based on the ip address of their source.
+pub struct FilterIp {
+    list: IpList,
+    allow_list: bool,
+}
+
+impl FilterIp {
+    /// Only allows events coming from the addresses in `list`.
+    pub fn allow(list: IpList) -> Self {
+        FilterIp {
+            list,
+            allow_list: true,
+        }
+    }
+
+    /// Drops events coming from the addresses in `list`.
+    pub fn deny(list: IpList) -> Self {
+        FilterIp {
+            list,
+            allow_list: false,
+        }
+    }
+
+    /// Returns a handle to the list of addresses used by this filter.
+    pub fn list(&self) -> IpList {
+        self.list.clone()
+    }
+}
+
+impl<T> NetFilter<T> for FilterIp
+where
+    T: PartialEq,
+{
+    fn allow(
+        &mut self,
+        source: &SocketAddr,
+        _: &NetEvent<T>,
+        _: &FilterContext<'_>,
+    ) -> Result<(), DropReason> {
+        if self.list.contains(&source.ip()) == self.allow_list {
+            Ok(())
+        } else {
+            Err(DropReason::AddressDenied)
         }
     }
 }
+
+/// A filter dropping events whose payload is larger than a maximum size in bytes.
+pub struct FilterPayloadSize {
+    max: usize,
+}
+
+impl FilterPayloadSize {
+    /// Creates a filter dropping payloads larger than `max` bytes.
+    pub fn new(max: usize) -> Self {
+        FilterPayloadSize { max }
+    }
+}
+
+impl<T> NetFilter<T> for FilterPayloadSize
+where
+    T: PartialEq,
+{
+    fn allow(
+        &mut self,
+        _: &SocketAddr,
+        _: &NetEvent<T>,
+        context: &FilterContext<'_>,
+    ) -> Result<(), DropReason> {
+        if context.payload_size <= self.max {
+            Ok(())
+        } else {
+            Err(DropReason::PayloadTooLarge {
+                size: context.payload_size,
+                max: self.max,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashSet,
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    fn message() -> NetEvent<()> {
+        NetEvent::TextMessage {
+            msg: "hello".to_string(),
+        }
+    }
+
+    fn context(connected: &HashSet<SocketAddr>, now: Instant) -> FilterContext<'_> {
+        FilterContext {
+            connected,
+            payload_size: 10,
+            now,
+        }
+    }
+
+    #[test]
+    fn connected_filter_consults_peers() {
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let stranger: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let mut connected = HashSet::new();
+        connected.insert(peer);
+        let ctx = context(&connected, Instant::now());
+
+        let mut filter = FilterConnected::<()>::new();
+        assert_eq!(filter.allow(&peer, &message(), &ctx), Ok(()));
+        assert_eq!(
+            filter.allow(&stranger, &message(), &ctx),
+            Err(DropReason::NotConnected)
+        );
+        let connect = NetEvent::Connect {
+            client_uuid: uuid::Uuid::nil(),
+            receive_port: 0,
+        };
+        assert_eq!(filter.allow(&stranger, &connect, &ctx), Ok(()));
+    }
+
+    #[test]
+    fn rate_limit_refills_over_time() {
+        let source: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let connected = HashSet::new();
+        let start = Instant::now();
+        let mut filter = FilterRateLimit::new(2.0, 2.0);
+
+        let ctx = context(&connected, start);
+        assert_eq!(
+            NetFilter::<()>::allow(&mut filter, &source, &message(), &ctx),
+            Ok(())
+        );
+        assert_eq!(
+            NetFilter::<()>::allow(&mut filter, &source, &message(), &ctx),
+            Ok(())
+        );
+        assert_eq!(
+            NetFilter::<()>::allow(&mut filter, &source, &message(), &ctx),
+            Err(DropReason::RateLimited)
+        );
+
+        let ctx = context(&connected, start + Duration::from_millis(500));
+        assert_eq!(
+            NetFilter::<()>::allow(&mut filter, &source, &message(), &ctx),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn ip_lists_are_shared() {
+        let source: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let connected = HashSet::new();
+        let ctx = context(&connected, Instant::now());
+
+        let mut deny = FilterIp::deny(IpList::new());
+        assert_eq!(
+            NetFilter::<()>::allow(&mut deny, &source, &message(), &ctx),
+            Ok(())
+        );
+        deny.list().insert(source.ip());
+        assert_eq!(
+            NetFilter::<()>::allow(&mut deny, &source, &message(), &ctx),
+            Err(DropReason::AddressDenied)
+        );
+
+        let list = IpList::new();
+        let mut allow = FilterIp::allow(list.clone());
+        assert_eq!(
+            NetFilter::<()>::allow(&mut allow, &source, &message(), &ctx),
+            Err(DropReason::AddressDenied)
+        );
+        list.insert(source.ip());
+        assert_eq!(
+            NetFilter::<()>::allow(&mut allow, &source, &message(), &ctx),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn payload_size_is_limited() {
+        let source: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let connected = HashSet::new();
+        let ctx = context(&connected, Instant::now());
+
+        let mut filter = FilterPayloadSize::new(4);
+        assert_eq!(
+            NetFilter::<()>::allow(&mut filter, &source, &message(), &ctx),
+            Err(DropReason::PayloadTooLarge { size: 10, max: 4 })
+        );
+    }
+}
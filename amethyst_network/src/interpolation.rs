@@ -0,0 +1,296 @@
+//! Smooth rendering of remote entities from timestamped `Transform` snapshots.
+//!
+//! Snapshots arrive at an irregular pace because of jitter. Instead of applying them as they come,
+//! they are buffered and the entity is rendered slightly in the past, where there is usually a
+//! snapshot on both sides of the rendered time to interpolate between.
+//! The result is written in the regular `Transform`, so the `TransformSystem` and rendering
+//! don't need to know about it.
+
+use std::{collections::VecDeque, time::Duration};
+
+use amethyst_core::{
+    nalgebra::Vector3,
+    specs::{
+        Component, DenseVecStorage, Entities, Join, Read, Resources, System, SystemData,
+        WriteStorage,
+    },
+    timing::duration_to_secs_f64,
+    Time, Transform,
+};
+
+// Amount of snapshots kept per entity before the oldest ones are dropped.
+const MAX_SNAPSHOTS: usize = 64;
+
+/// The snapshots of the `Transform` of a remote entity, added to the entities to interpolate.
+///
+/// Push the snapshots with the time they were taken at on the sending side, in seconds.
+/// The `TransformInterpolationSystem` then writes the interpolated `Transform` of the entity.
+#[derive(Debug, Clone, Default)]
+pub struct InterpolatedTransform {
+    // snapshots pushed since the last run, not timed against the local clock yet.
+    incoming: Vec<(f64, Transform)>,
+    // snapshots ordered by their timestamp.
+    snapshots: VecDeque<(f64, Transform)>,
+    // smallest difference between the local arrival time and the timestamp of a snapshot.
+    clock_offset: Option<f64>,
+}
+
+impl InterpolatedTransform {
+    /// Creates an empty snapshot buffer.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a snapshot taken at `timestamp` seconds on the sending side.
+    ///
+    /// Snapshots can be pushed out of order, the timestamps only have to come from the same clock.
+    pub fn push(&mut self, timestamp: f64, transform: Transform) {
+        self.incoming.push((timestamp, transform));
+    }
+
+    /// The amount of buffered snapshots.
+    pub fn len(&self) -> usize {
+        self.incoming.len() + self.snapshots.len()
+    }
+
+    /// Returns `true` if there is no buffered snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops every snapshot, for example when the entity has been teleported.
+    ///
+    /// The `Transform` is left as it is until the next snapshot.
+    pub fn clear(&mut self) {
+        self.incoming.clear();
+        self.snapshots.clear();
+    }
+
+    // Moves the incoming snapshots into the buffer, arrived at `now` on the local clock.
+    fn receive(&mut self, now: f64) {
+        for (timestamp, transform) in self.incoming.drain(..) {
+            let offset = now - timestamp;
+            self.clock_offset = Some(self.clock_offset.map_or(offset, |o| o.min(offset)));
+
+            let index = self
+                .snapshots
+                .iter()
+                .position(|(t, _)| *t >= timestamp)
+                .unwrap_or_else(|| self.snapshots.len());
+            if self.snapshots.get(index).map(|(t, _)| *t) == Some(timestamp) {
+                // A duplicate of a snapshot we already have.
+                continue;
+            }
+            self.snapshots.insert(index, (timestamp, transform));
+        }
+
+        while self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // Computes the `Transform` at `now` on the local clock, rendered `delay` seconds in the past.
+    // It is extrapolated up to `max_extrapolation` seconds past the newest snapshot.
+    fn sample(&mut self, now: f64, delay: f64, max_extrapolation: f64) -> Option<Transform> {
+        let render_time = now - self.clock_offset? - delay;
+
+        // Keep the last snapshot before the rendered time, and the one before it to extrapolate.
+        while self.snapshots.len() > 2 && self.snapshots[1].0 <= render_time {
+            self.snapshots.pop_front();
+        }
+
+        let (from_time, from) = self.snapshots.front()?;
+        let (to_time, to) = match self.snapshots.get(1) {
+            Some(to) if render_time > *from_time => to,
+            _ => return Some(from.clone()),
+        };
+
+        let span = to_time - from_time;
+        let factor = if render_time <= *to_time {
+            (render_time - from_time) / span
+        } else {
+            1.0 + (render_time - to_time).min(max_extrapolation) / span
+        };
+        Some(interpolate(from, to, factor as f32))
+    }
+}
+
+impl Component for InterpolatedTransform {
+    type Storage = DenseVecStorage<Self>;
+}
+
+// Interpolates between two transforms, extrapolating when `factor` is greater than 1.
+fn interpolate(from: &Transform, to: &Transform, factor: f32) -> Transform {
+    let lerp = |a: &Vector3<f32>, b: &Vector3<f32>| a + (b - a) * factor;
+
+    let mut result = to.clone();
+    *result.translation_mut() = lerp(from.translation(), to.translation());
+    *result.scale_mut() = lerp(from.scale(), to.scale());
+    // The rotation is not extrapolated, and the interpolation isn't defined between opposite rotations.
+    if factor < 1.0 {
+        if let Some(rotation) = from.rotation().try_slerp(to.rotation(), factor, 1.0e-6) {
+            *result.rotation_mut() = rotation;
+        }
+    }
+    result
+}
+
+/// Writes the interpolated `Transform` of every entity with an `InterpolatedTransform`.
+///
+/// Entities are rendered `delay` in the past, which should be a bit more than the time between
+/// two snapshots plus the expected jitter.
+/// When the newest snapshot is older than the rendered time, the movement is extrapolated for up
+/// to the configured duration, and the entity stops there until the next snapshot.
+///
+/// It has to run before the `TransformSystem`.
+pub struct TransformInterpolationSystem {
+    delay: f64,
+    max_extrapolation: f64,
+}
+
+impl TransformInterpolationSystem {
+    /// Creates a new `TransformInterpolationSystem` rendering entities `delay` in the past, without extrapolation.
+    pub fn new(delay: Duration) -> Self {
+        TransformInterpolationSystem {
+            delay: duration_to_secs_f64(delay),
+            max_extrapolation: 0.0,
+        }
+    }
+
+    /// Extrapolates the movement for up to `max` past the newest snapshot.
+    pub fn with_extrapolation(mut self, max: Duration) -> Self {
+        self.max_extrapolation = duration_to_secs_f64(max);
+        self
+    }
+}
+
+impl Default for TransformInterpolationSystem {
+    fn default() -> Self {
+        TransformInterpolationSystem::new(Duration::from_millis(100))
+    }
+}
+
+impl<'a> System<'a> for TransformInterpolationSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        WriteStorage<'a, InterpolatedTransform>,
+        WriteStorage<'a, Transform>,
+    );
+
+    fn run(&mut self, (entities, time, mut interpolated, mut transforms): Self::SystemData) {
+        let now = time.absolute_time_seconds();
+
+        for (entity, interpolated) in (&*entities, &mut interpolated).join() {
+            interpolated.receive(now);
+            let transform = match interpolated.sample(now, self.delay, self.max_extrapolation) {
+                Some(transform) => transform,
+                None => continue,
+            };
+
+            match transforms.get_mut(entity) {
+                Some(current) => *current = transform,
+                None => {
+                    // The entity is alive, since it has been joined.
+                    let _ = transforms.insert(entity, transform);
+                }
+            }
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amethyst_core::specs::{Builder, Entity, RunNow, World};
+
+    use super::*;
+
+    fn at(x: f32) -> Transform {
+        let mut transform = Transform::default();
+        transform.set_xyz(x, 0.0, 0.0);
+        transform
+    }
+
+    fn assert_x(world: &World, entity: Entity, expected: f32) {
+        let x = world
+            .read_storage::<Transform>()
+            .get(entity)
+            .unwrap()
+            .translation()
+            .x;
+        assert!((x - expected).abs() < 1.0e-3, "{} != {}", x, expected);
+    }
+
+    fn advance(world: &mut World, system: &mut TransformInterpolationSystem, secs: f32) {
+        world.write_resource::<Time>().set_delta_seconds(secs);
+        system.run_now(&world.res);
+    }
+
+    #[test]
+    fn snapshots_are_interpolated_with_delay() {
+        let mut world = World::new();
+        let mut system = TransformInterpolationSystem::new(Duration::from_millis(100));
+        System::setup(&mut system, &mut world.res);
+        let entity = world
+            .create_entity()
+            .with(InterpolatedTransform::new())
+            .build();
+
+        // The sender clock is 5 seconds ahead, snapshots are sent every 100ms.
+        {
+            let mut storage = world.write_storage::<InterpolatedTransform>();
+            let snapshots = storage.get_mut(entity).unwrap();
+            snapshots.push(5.0, at(0.0));
+        }
+        system.run_now(&world.res);
+        assert_x(&world, entity, 0.0);
+
+        // The second snapshot arrives late, which doesn't change the rendered time.
+        advance(&mut world, &mut system, 0.15);
+        world
+            .write_storage::<InterpolatedTransform>()
+            .get_mut(entity)
+            .unwrap()
+            .push(5.1, at(10.0));
+        system.run_now(&world.res);
+        assert_x(&world, entity, 5.0);
+
+        // Past the newest snapshot without extrapolation, the entity stays on it.
+        advance(&mut world, &mut system, 0.2);
+        assert_x(&world, entity, 10.0);
+    }
+
+    #[test]
+    fn extrapolation_is_limited() {
+        let mut world = World::new();
+        let mut system = TransformInterpolationSystem::new(Duration::from_millis(0))
+            .with_extrapolation(Duration::from_millis(50));
+        System::setup(&mut system, &mut world.res);
+        let mut snapshots = InterpolatedTransform::new();
+        snapshots.push(0.1, at(10.0));
+        snapshots.push(0.0, at(0.0));
+        snapshots.push(0.1, at(10.0));
+        let entity = world.create_entity().with(snapshots).build();
+
+        system.run_now(&world.res);
+        assert_x(&world, entity, 10.0);
+        assert_eq!(
+            world
+                .read_storage::<InterpolatedTransform>()
+                .get(entity)
+                .unwrap()
+                .len(),
+            2
+        );
+
+        advance(&mut world, &mut system, 0.02);
+        assert_x(&world, entity, 12.0);
+
+        advance(&mut world, &mut system, 1.0);
+        assert_x(&world, entity, 15.0);
+    }
+}
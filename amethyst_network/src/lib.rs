@@ -3,58 +3,70 @@
 #![warn(missing_docs, rust_2018_idioms, rust_2018_compatibility)]
 
 pub use crate::{
-    bundle::NetworkBundle,
+    bundle::{NetworkBundle, ReplicationBundle},
+    codec::{BincodeCodec, Compression, NetCodec, PayloadFormat},
     connection::{ConnectionState, NetConnection, NetIdentity},
-    error::Result,
-    filter::{FilterConnected, NetFilter},
-    net_event::NetEvent,
+    delivery::DeliveryRequirement,
+    discovery::{
+        DiscoveredServer, DiscoveredServers, DiscoveryConfig, ServerAdvertiseSystem,
+        ServerDiscoverySystem, ServerInfo,
+    },
+    error::{Error, Result},
+    filter::{
+        DropReason, FilterConnected, FilterContext, FilterIp, FilterPayloadSize, FilterRateLimit,
+        FilteredEvent, IpList, NetFilter,
+    },
+    interpolation::{InterpolatedTransform, TransformInterpolationSystem},
+    lifecycle::{AcceptAll, ConnectionPolicy, NetConnectionEvent},
+    net_event::{NetEvent, NetPacket},
     network_socket::NetSocketSystem,
-    server::{Host, ServerConfig, ServerSocketEvent},
+    prediction::{Predicted, Prediction, PredictionSystem},
+    replication::{
+        ComponentReplicationSystem, EntityReplicationSystem, NetEntityMap, ReplicatedComponent,
+        ReplicatedEntity,
+    },
+    server::{Host, NetworkSimulation, ServerConfig, ServerSocketEvent},
+    stats::NetConnectionStats,
 };
 
+#[cfg(feature = "msgpack")]
+pub use crate::codec::MsgPackCodec;
+
 use std::{net::SocketAddr, sync::mpsc::SyncSender};
 
-use bincode::{deserialize, serialize};
-use laminar::Packet;
 use log::error;
-use serde::{de::DeserializeOwned, Serialize};
 
 mod bundle;
+mod codec;
 mod connection;
+mod delivery;
+mod discovery;
 mod error;
 mod filter;
+mod interpolation;
+mod lifecycle;
 mod net_event;
 mod network_socket;
+mod prediction;
+mod replication;
 mod server;
+mod stats;
 mod test;
 
 /// Sends an event to the target NetConnection using the provided network Socket.
+/// The event is encoded with `format` and wrapped in the laminar `Packet` type matching its `DeliveryRequirement`.
 /// The socket has to be bound.
-pub fn send_event<T>(event: NetEvent<T>, addr: SocketAddr, sender: &SyncSender<ServerSocketEvent>)
-where
-    T: Serialize,
-{
-    let ser = serialize(&event);
-    match ser {
-        Ok(s) => {
-            let slice = s.as_slice();
-            // send an unreliable `Packet` from laminar which is basically just a bare UDP packet.
-            match sender.send(ServerSocketEvent::Packet(Packet::unreliable(
-                addr,
-                slice.to_owned(),
-            ))) {
-                Ok(_qty) => {}
-                Err(e) => error!("Failed to send data to network socket: {}", e),
-            }
-        }
-        Err(e) => error!("Failed to serialize the event: {}", e),
+pub fn send_event<T>(
+    packet: NetPacket<T>,
+    addr: SocketAddr,
+    format: &PayloadFormat<T>,
+    sender: &SyncSender<ServerSocketEvent>,
+) {
+    match format.encode(&packet.content) {
+        Ok(s) => match sender.send(ServerSocketEvent::Packet(packet.delivery.packet(addr, s))) {
+            Ok(_qty) => {}
+            Err(e) => error!("Failed to send data to network socket: {}", e),
+        },
+        Err(e) => error!("Failed to encode the event: {}", e),
     }
 }
-
-// Attempts to deserialize an event from the raw byte data.
-fn deserialize_event<T>(data: &[u8]) -> Result<NetEvent<T>>
-where
-    T: DeserializeOwned,
-{
-    Ok(deserialize::<NetEvent<T>>(data)?)
-}
@@ -0,0 +1,65 @@
+//! Connection lifecycle handling: admission of new clients and the events emitted to game code.
+
+use std::net::SocketAddr;
+
+use uuid::Uuid;
+
+use amethyst_core::specs::Entity;
+
+/// Decides whether a client asking to connect is allowed to join the server.
+///
+/// The maximum client limit from `ServerConfig` is checked by `NetSocketSystem` before the policy is consulted.
+pub trait ConnectionPolicy: Send + Sync {
+    /// Checks if the client at `addr` with the given uuid may connect.
+    /// `connected_clients` is the number of clients currently connected.
+    ///
+    /// Returns the reason sent back to the client in a `NetEvent::ConnectionRefused` if it is refused.
+    fn admit(
+        &mut self,
+        addr: &SocketAddr,
+        client_uuid: &Uuid,
+        connected_clients: usize,
+    ) -> std::result::Result<(), String>;
+}
+
+/// A connection policy that accepts every client, as long as the server is not full.
+#[derive(Debug, Default, Clone)]
+pub struct AcceptAll;
+
+impl ConnectionPolicy for AcceptAll {
+    fn admit(&mut self, _: &SocketAddr, _: &Uuid, _: usize) -> std::result::Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Lifecycle events emitted by `NetSocketSystem` into the `EventChannel<NetConnectionEvent>` resource.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetConnectionEvent {
+    /// A connection has been established.
+    /// On the server, `entity` is the newly spawned connection entity of the client.
+    Connected {
+        /// The entity holding the `NetConnection`.
+        entity: Entity,
+        /// The address the remote end is sending from.
+        addr: SocketAddr,
+        /// The uuid of the remote end.
+        uuid: Uuid,
+    },
+    /// A connection attempt has been refused.
+    Refused {
+        /// The address the refused end is sending from.
+        addr: SocketAddr,
+        /// The reason of the refusal.
+        reason: String,
+    },
+    /// A connection has been closed.
+    /// On the server, `entity` has already been deleted when this event is read.
+    Disconnected {
+        /// The entity that held the `NetConnection`.
+        entity: Entity,
+        /// The address the remote end was sending from.
+        addr: SocketAddr,
+        /// The reason of the disconnection.
+        reason: String,
+    },
+}
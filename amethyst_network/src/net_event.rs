@@ -5,14 +5,18 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::delivery::DeliveryRequirement;
+
 /// The basic network events shipped with amethyst.
-// TODO: Add CreateEntity,RemoveEntity,UpdateEntity once specs 0.11 is released
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NetEvent<T> {
     /// Ask to connect to the server.
     Connect {
         /// The client uuid.
         client_uuid: Uuid,
+        /// The port on which the client receives packets.
+        /// The server combines it with the ip the request came from to reply to the client.
+        receive_port: u16,
     },
     /// Reply to the client that the connection has been accepted.
     Connected {
@@ -39,6 +43,60 @@ pub enum NetEvent<T> {
         /// The message.
         msg: String,
     },
+    /// Keep-alive probe sent periodically by `NetSocketSystem`.
+    /// It is answered automatically and never written to `NetConnection::receive_buffer`.
+    Ping {
+        /// The sequence number of the heartbeat.
+        sequence: u32,
+    },
+    /// The answer to a `Ping`, carrying the same sequence number.
+    Pong {
+        /// The sequence number of the answered heartbeat.
+        sequence: u32,
+    },
+    /// Create a replicated entity on the remote end.
+    CreateEntity {
+        /// The id identifying the entity on every machine.
+        net_id: Uuid,
+        /// The uuid of the `NetIdentity` owning the entity.
+        owner: Uuid,
+    },
+    /// Change a replicated component of an entity.
+    UpdateEntity {
+        /// The id identifying the entity on every machine.
+        net_id: Uuid,
+        /// The name of the replicated component type.
+        component: String,
+        /// The serialized component, or `None` if the component has been removed.
+        data: Option<Vec<u8>>,
+    },
+    /// Remove a replicated entity on the remote end.
+    RemoveEntity {
+        /// The id identifying the entity on every machine.
+        net_id: Uuid,
+    },
+    /// An input command for a predicted component, sent by the client owning the entity.
+    Input {
+        /// The id identifying the entity on every machine.
+        net_id: Uuid,
+        /// The name of the predicted component type.
+        component: String,
+        /// The frame number the input has been applied at on the client.
+        tick: u64,
+        /// The serialized input.
+        data: Vec<u8>,
+    },
+    /// The authoritative state of a predicted component, sent by the server to the owner of the entity.
+    Snapshot {
+        /// The id identifying the entity on every machine.
+        net_id: Uuid,
+        /// The name of the predicted component type.
+        component: String,
+        /// The tick of the last input applied to this state.
+        tick: u64,
+        /// The serialized component.
+        data: Vec<u8>,
+    },
     /// A user-defined type containing more network event types.
     Custom(T),
 }
@@ -52,4 +110,74 @@ impl<T> NetEvent<T> {
             None
         }
     }
+
+    /// The delivery requirement used when this event is queued without an explicit one.
+    ///
+    /// Handshake, disconnection, replication and prediction events must not get lost, so they are sent reliably and in order.
+    /// Everything else defaults to unreliable delivery.
+    pub fn default_delivery(&self) -> DeliveryRequirement {
+        match self {
+            NetEvent::Connect { .. }
+            | NetEvent::Connected { .. }
+            | NetEvent::ConnectionRefused { .. }
+            | NetEvent::Disconnect { .. }
+            | NetEvent::Disconnected { .. }
+            | NetEvent::CreateEntity { .. }
+            | NetEvent::UpdateEntity { .. }
+            | NetEvent::RemoveEntity { .. }
+            | NetEvent::Input { .. }
+            | NetEvent::Snapshot { .. } => DeliveryRequirement::ReliableOrdered,
+            _ => DeliveryRequirement::Unreliable,
+        }
+    }
+}
+
+/// An outgoing network event together with the delivery guarantee it should be sent with.
+/// This is what gets pushed onto `NetConnection::send_buffer`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetPacket<T> {
+    /// The delivery guarantee requested for this event.
+    pub delivery: DeliveryRequirement,
+    /// The event to send.
+    pub content: NetEvent<T>,
+}
+
+impl<T> NetPacket<T> {
+    /// Creates a new packet sending `content` with the given delivery requirement.
+    pub fn new(content: NetEvent<T>, delivery: DeliveryRequirement) -> Self {
+        NetPacket { delivery, content }
+    }
+
+    /// Creates a packet which may be dropped, duplicated or reordered.
+    pub fn unreliable(content: NetEvent<T>) -> Self {
+        NetPacket::new(content, DeliveryRequirement::Unreliable)
+    }
+
+    /// Creates a packet which may be dropped, and is discarded if a newer one already arrived.
+    pub fn unreliable_sequenced(content: NetEvent<T>) -> Self {
+        NetPacket::new(content, DeliveryRequirement::UnreliableSequenced)
+    }
+
+    /// Creates a packet which is guaranteed to arrive, in any order.
+    pub fn reliable_unordered(content: NetEvent<T>) -> Self {
+        NetPacket::new(content, DeliveryRequirement::ReliableUnordered)
+    }
+
+    /// Creates a packet which is guaranteed to arrive, in the order it was sent.
+    pub fn reliable_ordered(content: NetEvent<T>) -> Self {
+        NetPacket::new(content, DeliveryRequirement::ReliableOrdered)
+    }
+
+    /// Creates a packet sent over the TCP stream of the connection.
+    pub fn tcp(content: NetEvent<T>) -> Self {
+        NetPacket::new(content, DeliveryRequirement::Tcp)
+    }
+}
+
+impl<T> From<NetEvent<T>> for NetPacket<T> {
+    /// Wraps the event using its `default_delivery`.
+    fn from(content: NetEvent<T>) -> Self {
+        let delivery = content.default_delivery();
+        NetPacket { delivery, content }
+    }
 }
@@ -2,32 +2,46 @@
 
 use std::{
     clone::Clone,
+    collections::HashSet,
     net::SocketAddr,
     sync::{
-        mpsc::{self, Receiver, Sender},
+        mpsc::{self, Sender},
         Arc, Mutex,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::Instant,
 };
 
-use amethyst_core::specs::{Join, Resources, System, SystemData, WriteStorage};
+use amethyst_core::specs::{
+    Entities, Entity, Join, Read, Resources, System, SystemData, Write, WriteStorage,
+};
 
 use laminar::Packet;
-use log::{error, warn};
+use log::{debug, error, info, warn};
 use serde::{de::DeserializeOwned, Serialize};
+use shrev::EventChannel;
+use uuid::Uuid;
 
 use super::{
-    deserialize_event,
-    error::Result,
-    send_event,
-    server::{Host, ReceiveHandler, SendHandler, ServerConfig, ServerSocketEvent},
-    ConnectionState, NetConnection, NetEvent, NetFilter,
+    codec::PayloadFormat,
+    error::{Error, Result},
+    filter::{FilterContext, FilteredEvent},
+    lifecycle::{ConnectionPolicy, NetConnectionEvent},
+    server::{Host, ReceiveHandler, SendHandler, ServerConfig, ServerSocketEvent, TcpSendHandler},
+    ConnectionState, DeliveryRequirement, NetConnection, NetConnectionStats, NetEvent, NetFilter,
+    NetIdentity, NetPacket,
 };
 
-enum InternalSocketEvent<E> {
-    SendEvents {
+enum InternalSocketEvent {
+    SendPayloads {
+        // address the UDP packets are sent to.
         target: SocketAddr,
-        events: Vec<NetEvent<E>>,
+        // address the other end sends from, identifying its TCP stream.
+        peer: SocketAddr,
+        // address to open a TCP stream to if there is none with `peer` yet.
+        tcp_target: Option<SocketAddr>,
+        // encoded events, with their delivery requirement.
+        payloads: Vec<(DeliveryRequirement, Vec<u8>)>,
     },
     Stop,
 }
@@ -42,8 +56,13 @@ enum InternalSocketEvent<E> {
 ///
 /// If both a connection (Connect or Connected) event is received at the same time as another event from the same connection,
 /// only the connection event will be considered and rest will be filtered out.
-// TODO: add Unchecked Event type list. Those events will be let pass the client connected filter (Example: NetEvent::Connect).
-// Current behaviour: hardcoded passthrough of Connect and Connected events.
+///
+/// In server mode, clients sending a `NetEvent::Connect` are run through the `ConnectionPolicy`.
+/// Accepted clients get a new entity with a `NetConnection` and a `NetIdentity`, and are removed again once disconnected.
+/// Lifecycle changes are written to the `EventChannel<NetConnectionEvent>` resource.
+///
+/// Every received event goes through the `filters` first. Dropped events are reported
+/// in the `EventChannel<FilteredEvent>` resource along with the reason they were dropped.
 pub struct NetSocketSystem<E: 'static>
 where
     E: PartialEq,
@@ -51,15 +70,53 @@ where
     /// The list of filters applied on the events received.
     pub filters: Vec<Box<dyn NetFilter<E>>>,
     // sender on which you can queue packets to send to some endpoint.
-    transport_sender: Sender<InternalSocketEvent<E>>,
-    // receiver from which you can read received packets.
-    transport_receiver: Receiver<Packet>,
+    transport_sender: Sender<InternalSocketEvent>,
+    // thread handing the encoded events to the sockets.
+    send_thread: Option<JoinHandle<()>>,
+    // the sockets, dropped after the sending thread is stopped.
+    host: Host,
     config: ServerConfig,
+    format: PayloadFormat<E>,
+    // admission policy, only set when running in server mode.
+    server_policy: Option<Box<dyn ConnectionPolicy>>,
+}
+
+impl<E> NetSocketSystem<E>
+where
+    E: PartialEq,
+{
+    /// Stops the network threads and waits for them to finish.
+    /// Events queued before are still sent. Calling this more than once has no effect.
+    ///
+    /// This is done automatically when the system is disposed or dropped,
+    /// after which the sockets are closed and their addresses can be bound again.
+    pub fn stop(&mut self) {
+        if let Some(send_thread) = self.send_thread.take() {
+            // The sending thread is the only one reading the channel, it may already be gone.
+            let _ = self.transport_sender.send(InternalSocketEvent::Stop);
+            if send_thread.join().is_err() {
+                error!("The network sending thread panicked");
+            }
+            for receiver in self.receive_handles() {
+                receiver
+                    .lock()
+                    .expect("Receive handler lock poisoned")
+                    .stop();
+            }
+        }
+    }
+
+    // The handles of the threads receiving packets.
+    fn receive_handles(&self) -> Vec<Arc<Mutex<ReceiveHandler>>> {
+        let mut handles = vec![self.host.udp_receive_handle()];
+        handles.extend(self.host.tcp_receive_handle());
+        handles
+    }
 }
 
 impl<E> NetSocketSystem<E>
 where
-    E: Serialize + PartialEq + Send + 'static,
+    E: Serialize + DeserializeOwned + PartialEq + Send + 'static,
 {
     /// Creates a `NetSocketSystem` and binds the Socket on the ip and port added in parameters.
     pub fn new(config: ServerConfig, filters: Vec<Box<dyn NetFilter<E>>>) -> Result<Self> {
@@ -68,32 +125,141 @@ where
             warn!("Using a port below 1024, this will require root permission and should not be done.");
         }
 
-        let server = Host::run(&config)?;
+        let host = Host::run(&config)?;
 
-        let udp_send_handle = server.udp_send_handle();
-        let udp_receive_handle = server.udp_receive_handle();
-
-        let server_sender = NetSocketSystem::<E>::start_sending(udp_send_handle);
-        let server_receiver = NetSocketSystem::<E>::start_receiving(udp_receive_handle);
+        let (transport_sender, send_thread) =
+            NetSocketSystem::<E>::start_sending(host.udp_send_handle(), host.tcp_send_handle());
 
         Ok(NetSocketSystem {
             filters,
-            transport_sender: server_sender,
-            transport_receiver: server_receiver,
+            transport_sender,
+            send_thread: Some(send_thread),
+            host,
             config,
+            format: PayloadFormat::default(),
+            server_policy: None,
         })
     }
 
-    /// Start a thread to send all queued packets.
-    fn start_sending(sender: Arc<SendHandler>) -> Sender<InternalSocketEvent<E>> {
+    /// Sets the format used to encode and decode the events, `bincode` without compression by default.
+    pub fn with_payload_format(mut self, format: PayloadFormat<E>) -> Self {
+        self.format = format;
+        self
+    }
+
+    // Takes at most `max_throughput` received packets, UDP ones first.
+    // Packets left over are read on the next run.
+    fn receive_packets(&self) -> Vec<Packet> {
+        let max_throughput = self.config.max_throughput as usize;
+        let mut packets = Vec::new();
+
+        for receiver in self.receive_handles() {
+            let receiver = receiver.lock().expect("Receive handler lock poisoned");
+            while packets.len() < max_throughput {
+                match receiver.receiver.try_recv() {
+                    Ok(ServerSocketEvent::Packet(packet)) => packets.push(packet),
+                    Ok(ServerSocketEvent::Error(error)) => error!("{:?}", error),
+                    Ok(_) => error!("Event not supported"),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        packets
+    }
+
+    /// Runs this system in server mode, admitting new clients with the given policy.
+    pub fn with_server_mode(mut self, policy: Box<dyn ConnectionPolicy>) -> Self {
+        self.server_policy = Some(policy);
+        self
+    }
+
+    /// Returns `true` if this system accepts new clients.
+    pub fn is_server(&self) -> bool {
+        self.server_policy.is_some()
+    }
+
+    // Encodes the events with the payload format, skipping the ones failing to encode.
+    fn encode(&self, events: &[NetPacket<E>]) -> Vec<(DeliveryRequirement, Vec<u8>)> {
+        events
+            .iter()
+            .filter_map(|packet| match self.format.encode(&packet.content) {
+                Ok(payload) => Some((packet.delivery, payload)),
+                Err(e) => {
+                    error!("Failed to encode the event: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Queues events for sending to the given addresses, outside of any `NetConnection`.
+    fn send_direct(&self, target: SocketAddr, peer: SocketAddr, events: Vec<NetPacket<E>>) {
+        self.send_payloads(target, peer, None, self.encode(&events));
+    }
+
+    // Queues encoded events for the sending thread, which is gone once the system is stopped.
+    fn send_payloads(
+        &self,
+        target: SocketAddr,
+        peer: SocketAddr,
+        tcp_target: Option<SocketAddr>,
+        payloads: Vec<(DeliveryRequirement, Vec<u8>)>,
+    ) {
+        let event = InternalSocketEvent::SendPayloads {
+            target,
+            peer,
+            tcp_target,
+            payloads,
+        };
+        if self.transport_sender.send(event).is_err() {
+            warn!(
+                "The network threads are stopped, dropped the events sent to {}",
+                target
+            );
+        }
+    }
+
+    /// Start a thread to send all queued packets, until it receives `InternalSocketEvent::Stop`.
+    ///
+    /// Packets requiring `DeliveryRequirement::Tcp` go over TCP if it is enabled and
+    /// a stream with the other end exists or can be opened, and over UDP otherwise.
+    fn start_sending(
+        sender: Arc<SendHandler>,
+        tcp_sender: Option<Arc<TcpSendHandler>>,
+    ) -> (Sender<InternalSocketEvent>, JoinHandle<()>) {
         let (tx, send_queue) = mpsc::channel();
 
-        thread::spawn(move || loop {
-            for control_event in send_queue.try_iter() {
+        let thread_handle = thread::spawn(move || {
+            // Blocks until there is something to send.
+            for control_event in send_queue.iter() {
                 match control_event {
-                    InternalSocketEvent::SendEvents { target, events } => {
-                        for ev in events {
-                            send_event(ev, target, &sender.get_sender());
+                    InternalSocketEvent::SendPayloads {
+                        target,
+                        peer,
+                        tcp_target,
+                        payloads,
+                    } => {
+                        for (delivery, payload) in payloads {
+                            let tcp = match tcp_sender {
+                                Some(ref tcp) if delivery == DeliveryRequirement::Tcp => {
+                                    if tcp_target.is_some() || tcp.has_stream(&peer) {
+                                        Some(tcp)
+                                    } else {
+                                        None
+                                    }
+                                }
+                                _ => None,
+                            };
+                            let result = match tcp {
+                                Some(tcp) => tcp.send(peer, tcp_target, payload),
+                                None => sender.send(ServerSocketEvent::Packet(
+                                    delivery.packet(target, payload),
+                                )),
+                            };
+                            if let Err(e) = result {
+                                error!("Failed to send data to network socket: {}", e);
+                            }
                         }
                     }
                     InternalSocketEvent::Stop => {
@@ -103,96 +269,384 @@ where
             }
         });
 
-        tx
-    }
-
-    /// Starts a thread which receives incoming packets and sends them onto the 'Receiver' channel.
-    fn start_receiving(receiver: Arc<Mutex<ReceiveHandler>>) -> Receiver<Packet> {
-        let (receive_queue, rx) = mpsc::channel();
-
-        thread::spawn(move || {
-            // take note that this lock will be there as long this thread lives.
-            // We only have one receiver, if one tries to have two receivers the program should panic.
-            // Why is there one receiver? This is because how a channel works. Once we read the messages it will be removed from the channel.
-            match receiver.try_lock() {
-                Ok(receiver) => loop {
-                    for event in receiver.iter() {
-                        match event {
-                            ServerSocketEvent::Packet(packet) => {
-                                if let Err(error) = receive_queue.send(packet.clone()) {
-                                    error!(
-                                        "`NetworkSocketSystem` was dropped. Reason: {:?}",
-                                        error
-                                    );
-                                    break;
-                                }
-                            }
-                            ServerSocketEvent::Error(error) => error!("{:?}", error),
-                            _ => error!("Event not supported"),
-                        }
-                    }
-                },
-                Err(_) => {
-                    panic!("Two packet receivers can't run at the same time");
-                }
+        (tx, thread_handle)
+    }
+}
+
+impl<E> NetSocketSystem<E>
+where
+    E: Send + Sync + Serialize + Clone + DeserializeOwned + PartialEq + 'static,
+{
+    // Handles a `NetEvent::Connect` received from an address without a `NetConnection`.
+    // Returns `true` if the client has been accepted.
+    fn handle_connect_request(
+        &mut self,
+        addr: SocketAddr,
+        client_uuid: Uuid,
+        receive_port: u16,
+        data: &mut LifecycleData<'_, '_, E>,
+    ) -> bool {
+        let reply_addr = SocketAddr::new(addr.ip(), receive_port);
+        let connected_clients = (&*data.connections)
+            .join()
+            .filter(|c| c.state == ConnectionState::Connected)
+            .count();
+
+        let admission = if connected_clients >= self.config.max_clients {
+            Err("The server is full.".to_string())
+        } else {
+            match self.server_policy {
+                Some(ref mut policy) => policy.admit(&addr, &client_uuid, connected_clients),
+                None => Err("Not accepting connections.".to_string()),
             }
-        });
+        };
+
+        match admission {
+            Ok(()) => {
+                let mut connection = NetConnection::new(reply_addr, addr);
+                connection.state = ConnectionState::Connected;
+                connection.queue(NetEvent::Connected {
+                    server_uuid: data.local_identity.uuid,
+                });
+
+                let entity = data
+                    .entities
+                    .build_entity()
+                    .with(connection, &mut *data.connections)
+                    .with(NetIdentity { uuid: client_uuid }, &mut *data.identities)
+                    .build();
 
-        rx
+                info!("Client {} connected from {}", client_uuid, addr);
+                data.lifecycle_events
+                    .single_write(NetConnectionEvent::Connected {
+                        entity,
+                        addr,
+                        uuid: client_uuid,
+                    });
+                true
+            }
+            Err(reason) => {
+                self.refuse(addr, reply_addr, reason, data);
+                false
+            }
+        }
     }
+
+    // Refuses a client connecting with another protocol version.
+    fn refuse_version(
+        &self,
+        addr: SocketAddr,
+        version: u16,
+        receive_port: u16,
+        data: &mut LifecycleData<'_, '_, E>,
+    ) {
+        let reason = format!(
+            "Protocol version mismatch, the server runs version {} but the client runs version {}.",
+            self.format.version(),
+            version
+        );
+        let reply_addr = SocketAddr::new(addr.ip(), receive_port);
+        self.refuse(addr, reply_addr, reason, data);
+    }
+
+    // Sends a `NetEvent::ConnectionRefused` to a client asking to connect.
+    fn refuse(
+        &self,
+        addr: SocketAddr,
+        reply_addr: SocketAddr,
+        reason: String,
+        data: &mut LifecycleData<'_, '_, E>,
+    ) {
+        info!("Refused connection from {}. Reason: {}", addr, reason);
+        self.send_direct(
+            reply_addr,
+            addr,
+            vec![NetPacket::from(NetEvent::ConnectionRefused {
+                reason: reason.clone(),
+            })],
+        );
+        data.lifecycle_events
+            .single_write(NetConnectionEvent::Refused { addr, reason });
+    }
+
+    // Applies the state changes caused by a lifecycle event received on an existing connection.
+    fn handle_connection_event(
+        &self,
+        entity: Entity,
+        event: &NetEvent<E>,
+        data: &mut LifecycleData<'_, '_, E>,
+    ) {
+        let connection = match data.connections.get_mut(entity) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let addr = connection.target_sender;
+
+        match event {
+            NetEvent::Connected { server_uuid } => {
+                if connection.state == ConnectionState::Connecting {
+                    connection.state = ConnectionState::Connected;
+                    data.identities
+                        .insert(entity, NetIdentity { uuid: *server_uuid })
+                        .expect("Unreachable: the connection entity is alive");
+                    data.lifecycle_events
+                        .single_write(NetConnectionEvent::Connected {
+                            entity,
+                            addr,
+                            uuid: *server_uuid,
+                        });
+                }
+            }
+            NetEvent::ConnectionRefused { reason } => {
+                connection.state = ConnectionState::Disconnected;
+                data.lifecycle_events
+                    .single_write(NetConnectionEvent::Refused {
+                        addr,
+                        reason: reason.clone(),
+                    });
+            }
+            NetEvent::Disconnect { reason } => {
+                connection.state = ConnectionState::Disconnected;
+                if self.is_server() {
+                    data.entities
+                        .delete(entity)
+                        .expect("Unreachable: the connection entity is alive");
+                }
+                data.lifecycle_events
+                    .single_write(NetConnectionEvent::Disconnected {
+                        entity,
+                        addr,
+                        reason: reason.clone(),
+                    });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The resources `NetSocketSystem` needs to manage the connection lifecycle.
+struct LifecycleData<'r, 'a, E: 'static> {
+    entities: &'r Entities<'a>,
+    connections: &'r mut WriteStorage<'a, NetConnection<E>>,
+    identities: &'r mut WriteStorage<'a, NetIdentity>,
+    local_identity: &'r NetIdentity,
+    lifecycle_events: &'r mut EventChannel<NetConnectionEvent>,
 }
 
 impl<'a, E> System<'a> for NetSocketSystem<E>
 where
     E: Send + Sync + Serialize + Clone + DeserializeOwned + PartialEq + 'static,
 {
-    type SystemData = (WriteStorage<'a, NetConnection<E>>);
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, NetConnection<E>>,
+        WriteStorage<'a, NetConnectionStats>,
+        WriteStorage<'a, NetIdentity>,
+        Read<'a, NetIdentity>,
+        Write<'a, EventChannel<NetConnectionEvent>>,
+        Write<'a, EventChannel<FilteredEvent>>,
+    );
 
-    fn run(&mut self, mut net_connections: Self::SystemData) {
-        for net_connection in (&mut net_connections).join() {
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut net_connections,
+            mut stats,
+            mut identities,
+            local_identity,
+            mut lifecycle_events,
+            mut filtered_events,
+        ): Self::SystemData,
+    ) {
+        let now = Instant::now();
+
+        // Start tracking the statistics of new connections.
+        let untracked: Vec<Entity> = (&*entities, &net_connections, !&stats)
+            .join()
+            .map(|(entity, _, _)| entity)
+            .collect();
+        for entity in untracked {
+            stats
+                .insert(entity, NetConnectionStats::new(now))
+                .expect("Unreachable: the connection entity is alive");
+        }
+
+        for (entity, net_connection, stats) in (&*entities, &mut net_connections, &mut stats).join()
+        {
             let target = net_connection.target_receiver;
+            let peer = net_connection.target_sender;
+            let tcp_target = net_connection.target_tcp;
+
+            if net_connection.state == ConnectionState::Connected {
+                if let Some(timeout) = self.config.idle_timeout {
+                    if stats.is_timed_out(now, timeout) {
+                        warn!("Connection to {} timed out", net_connection.target_sender);
+                        net_connection.state = ConnectionState::Disconnected;
+                        if self.is_server() {
+                            entities
+                                .delete(entity)
+                                .expect("Unreachable: the connection entity is alive");
+                        }
+                        lifecycle_events.single_write(NetConnectionEvent::Disconnected {
+                            entity,
+                            addr: net_connection.target_sender,
+                            reason: "Connection timed out.".to_string(),
+                        });
+                        continue;
+                    }
+                }
+
+                if let Some(interval) = self.config.heartbeat_interval {
+                    if let Some(sequence) = stats.heartbeat(now, interval) {
+                        net_connection
+                            .send_buffer
+                            .single_write(NetPacket::unreliable(NetEvent::Ping { sequence }));
+                    }
+                }
+            }
 
             if net_connection.state == ConnectionState::Connected
                 || net_connection.state == ConnectionState::Connecting
             {
-                self.transport_sender
-                    .send(InternalSocketEvent::SendEvents {
-                        target,
-                        events: net_connection.send_buffer_early_read().cloned().collect(),
-                    })
-                    .expect("Unreachable: Channel will be alive until a stop event is sent");
+                let events: Vec<_> = net_connection.send_buffer_early_read().cloned().collect();
+                let payloads = self.encode(&events);
+                for (_, payload) in &payloads {
+                    stats.record_sent(payload.len() as u64);
+                }
+                self.send_payloads(target, peer, tcp_target, payloads);
             } else if net_connection.state == ConnectionState::Disconnected {
-                self.transport_sender
-                    .send(InternalSocketEvent::Stop)
-                    .expect("Already sent a stop event to the channel");
+                if self.is_server() {
+                    // Flush what is left, like a `Disconnected` notification, then drop the client.
+                    let events: Vec<_> = net_connection.send_buffer_early_read().cloned().collect();
+                    let reason = events
+                        .iter()
+                        .filter_map(|packet| match packet.content {
+                            NetEvent::Disconnected { ref reason } => Some(reason.clone()),
+                            _ => None,
+                        })
+                        .last()
+                        .unwrap_or_else(|| "Disconnected by the server.".to_string());
+                    self.send_direct(target, peer, events);
+
+                    entities
+                        .delete(entity)
+                        .expect("Unreachable: the connection entity is alive");
+                    lifecycle_events.single_write(NetConnectionEvent::Disconnected {
+                        entity,
+                        addr: net_connection.target_sender,
+                        reason,
+                    });
+                }
+                // Nothing is sent anymore on a connection the client closed.
             }
         }
 
-        for (counter, raw_event) in self.transport_receiver.try_iter().enumerate() {
+        let mut data = LifecycleData {
+            entities: &entities,
+            connections: &mut net_connections,
+            identities: &mut identities,
+            local_identity: &*local_identity,
+            lifecycle_events: &mut *lifecycle_events,
+        };
+
+        // eventually some congestion prevention should be done.
+        let raw_events = self.receive_packets();
+
+        let mut connected: HashSet<SocketAddr> = (&*data.connections)
+            .join()
+            .filter(|c| c.state == ConnectionState::Connected)
+            .map(|c| c.target_sender)
+            .collect();
+
+        for raw_event in raw_events {
+            let addr = raw_event.addr();
+            let event = match self.format.decode(raw_event.payload()) {
+                Ok(ev) => ev,
+                Err(Error::VersionMismatch {
+                    found,
+                    receive_port: Some(receive_port),
+                    ..
+                }) if self.is_server() => {
+                    self.refuse_version(addr, found, receive_port, &mut data);
+                    continue;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to deserialize an incoming network event: {} From source: {:?}",
+                        e, addr
+                    );
+                    continue;
+                }
+            };
+
+            let context = FilterContext {
+                connected: &connected,
+                payload_size: raw_event.payload().len(),
+                now,
+            };
+            let dropped = self
+                .filters
+                .iter_mut()
+                .filter_map(|filter| filter.allow(&addr, &event, &context).err())
+                .next();
+            if let Some(reason) = dropped {
+                debug!("Dropped an event from {}: {}", addr, reason);
+                filtered_events.single_write(FilteredEvent {
+                    source: addr,
+                    reason,
+                });
+                continue;
+            }
+
             // Get the NetConnection from the source
-            for net_connection in (&mut net_connections).join() {
-                if net_connection.target_sender == raw_event.addr() {
-                    // Get the event
-                    match deserialize_event::<E>(raw_event.payload()) {
-                        Ok(ev) => {
-                            net_connection.receive_buffer.single_write(ev);
+            let known = (&*data.entities, &*data.connections)
+                .join()
+                .find(|(_, c)| c.target_sender == addr)
+                .map(|(entity, _)| entity);
+
+            match (known, event) {
+                (Some(entity), event) => {
+                    if let Some(stats) = stats.get_mut(entity) {
+                        stats.record_received(raw_event.payload().len() as u64, now);
+                    }
+
+                    match event {
+                        NetEvent::Ping { sequence } => {
+                            if let Some(net_connection) = data.connections.get_mut(entity) {
+                                net_connection.queue(NetEvent::Pong { sequence });
+                            }
+                        }
+                        NetEvent::Pong { sequence } => {
+                            if let Some(stats) = stats.get_mut(entity) {
+                                stats.record_pong(sequence, now);
+                            }
+                        }
+                        event => {
+                            self.handle_connection_event(entity, &event, &mut data);
+                            if let Some(net_connection) = data.connections.get_mut(entity) {
+                                net_connection.receive_buffer.single_write(event);
+                            }
                         }
-                        Err(e) => error!(
-                            "Failed to deserialize an incoming network event: {} From source: {:?}",
-                            e,
-                            raw_event.addr()
-                        ),
                     }
-                } else {
-                    warn!("Received packet from unknown source");
-                }
-            }
 
-            // this will prevent our system to be stuck in the iterator.
-            // After 10000 packets we will continue and leave the other packets for the next run.
-            // eventually some congestion prevention should be done.
-            if counter >= self.config.max_throughput as usize {
-                break;
+                    match data.connections.get(entity) {
+                        Some(c) if c.state == ConnectionState::Connected => connected.insert(addr),
+                        _ => connected.remove(&addr),
+                    };
+                }
+                (
+                    None,
+                    NetEvent::Connect {
+                        client_uuid,
+                        receive_port,
+                    },
+                ) if self.is_server() => {
+                    if self.handle_connect_request(addr, client_uuid, receive_port, &mut data) {
+                        connected.insert(addr);
+                    }
+                }
+                (None, _) => warn!("Received packet from unknown source: {:?}", addr),
             }
         }
     }
@@ -200,4 +654,17 @@ where
     fn setup(&mut self, res: &mut Resources) {
         Self::SystemData::setup(res);
     }
+
+    fn dispose(mut self, _: &mut Resources) {
+        self.stop();
+    }
+}
+
+impl<E> Drop for NetSocketSystem<E>
+where
+    E: PartialEq,
+{
+    fn drop(&mut self) {
+        self.stop();
+    }
 }
@@ -0,0 +1,627 @@
+//! Client-side prediction of the components of owned entities, reconciled with the server.
+//!
+//! The client applies its inputs to its own entities right away and sends them to the server,
+//! tagged with the frame number they were made at. The server applies them in order and answers
+//! with a snapshot of the authoritative state, along with the tick of the last input it applied.
+//! The client then rewinds to that snapshot and replays the inputs the server has not applied yet.
+//! The other clients get the snapshots too, and apply them as they are.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use bincode::{deserialize, serialize};
+use log::{error, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use shrev::ReaderId;
+use uuid::Uuid;
+
+use amethyst_core::{
+    specs::{
+        Component, DenseVecStorage, Entities, Entity, Join, Read, ReadStorage, Resources, System,
+        SystemData, WriteStorage,
+    },
+    Time,
+};
+
+use crate::{
+    ConnectionState, NetConnection, NetEntityMap, NetEvent, NetIdentity, NetPacket,
+    ReplicatedComponent, ReplicatedEntity,
+};
+
+// Amount of unacknowledged inputs kept before the oldest ones are dropped.
+const MAX_PENDING_INPUTS: usize = 256;
+
+/// A component whose changes are driven by inputs, and predicted by the client owning its entity.
+///
+/// Register it with `ReplicationBundle::with_predicted_component`.
+/// Do not also register it with `ReplicationBundle::with_component`, since the server only
+/// accepts inputs for it, not changes. Its state reaches the clients not owning the entity in
+/// snapshots as well.
+pub trait Predicted: ReplicatedComponent {
+    /// The input command changing the component.
+    type Input: Clone + Serialize + DeserializeOwned + Send + Sync + 'static;
+
+    /// Advances the component by one input.
+    ///
+    /// It has to be deterministic: the client and the server must end up with the same state
+    /// when applying the same inputs.
+    fn step(&mut self, input: &Self::Input);
+}
+
+/// The inputs of a predicted component on the client, added next to the component on owned entities.
+///
+/// Set the input of the current frame with `set_input`, it is applied and sent by the `PredictionSystem`.
+#[derive(Debug, Clone)]
+pub struct Prediction<C: Predicted> {
+    next: Option<C::Input>,
+    // inputs applied locally but not acknowledged by the server yet, with their tick.
+    pending: VecDeque<(u64, C::Input)>,
+    last_acknowledged: Option<u64>,
+    corrections: u64,
+}
+
+impl<C: Predicted> Prediction<C> {
+    /// Creates an empty input buffer.
+    pub fn new() -> Self {
+        Prediction {
+            next: None,
+            pending: VecDeque::new(),
+            last_acknowledged: None,
+            corrections: 0,
+        }
+    }
+
+    /// Sets the input applied this frame. Only the last input set during a frame is applied.
+    pub fn set_input(&mut self, input: C::Input) {
+        self.next = Some(input);
+    }
+
+    /// The amount of inputs which have not been acknowledged by the server yet.
+    pub fn pending_inputs(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The tick of the last input applied by the server.
+    pub fn last_acknowledged(&self) -> Option<u64> {
+        self.last_acknowledged
+    }
+
+    /// The amount of times the predicted state differed from the reconciled one.
+    pub fn corrections(&self) -> u64 {
+        self.corrections
+    }
+
+    // Rewinds to the authoritative `state` after the input `tick`, and replays the newer inputs.
+    // Returns the reconciled state.
+    fn reconcile(&mut self, tick: u64, mut state: C) -> C {
+        self.pending.retain(|(pending, _)| *pending > tick);
+        for (_, input) in &self.pending {
+            state.step(input);
+        }
+        self.last_acknowledged = Some(tick);
+        state
+    }
+}
+
+impl<C: Predicted> Default for Prediction<C> {
+    fn default() -> Self {
+        Prediction::new()
+    }
+}
+
+impl<C: Predicted> Component for Prediction<C> {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Predicts and reconciles a `Predicted` component on the client,
+/// and applies the inputs of its owners on the server.
+///
+/// On the client, inputs are tagged with `Time::frame_number` when they are applied.
+/// On the server, the owner of an entity gets a `NetEvent::Snapshot` whenever one of its inputs
+/// has been applied or the component changed otherwise. The other connections get one whenever
+/// the component changed, which their client applies without prediction.
+///
+/// It has to run after the `EntityReplicationSystem`.
+pub struct PredictionSystem<C, E: 'static> {
+    is_server: bool,
+    // tick of the last input applied on the server, by network id.
+    applied: HashMap<Uuid, u64>,
+    // last state sent to each connection on the server, by network id.
+    sent: HashMap<Entity, HashMap<Uuid, C>>,
+    readers: HashMap<Entity, ReaderId<NetEvent<E>>>,
+}
+
+impl<C, E> PredictionSystem<C, E> {
+    /// Creates a new `PredictionSystem`, acting as the authoritative server if `is_server` is set.
+    pub fn new(is_server: bool) -> Self {
+        PredictionSystem {
+            is_server,
+            applied: HashMap::new(),
+            sent: HashMap::new(),
+            readers: HashMap::new(),
+        }
+    }
+}
+
+impl<'a, C, E> System<'a> for PredictionSystem<C, E>
+where
+    C: Predicted,
+    E: Send + Sync + 'static,
+{
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        WriteStorage<'a, NetConnection<E>>,
+        ReadStorage<'a, NetIdentity>,
+        ReadStorage<'a, ReplicatedEntity>,
+        WriteStorage<'a, C>,
+        WriteStorage<'a, Prediction<C>>,
+        Read<'a, NetIdentity>,
+        Read<'a, NetEntityMap>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            mut connections,
+            identities,
+            replicated,
+            mut components,
+            mut predictions,
+            local_identity,
+            map,
+        ): Self::SystemData,
+    ) {
+        if self.is_server {
+            for (conn_entity, connection) in (&*entities, &mut connections).join() {
+                let reader = self
+                    .readers
+                    .entry(conn_entity)
+                    .or_insert_with(|| connection.receive_buffer.register_reader());
+                let sender = identities.get(conn_entity).map(|identity| identity.uuid);
+
+                // network ids of the entities moved by this connection.
+                let mut moved = HashSet::new();
+                for event in connection.receive_buffer.read(reader) {
+                    let (net_id, tick, data) = match *event {
+                        NetEvent::Input {
+                            ref net_id,
+                            ref component,
+                            tick,
+                            ref data,
+                        } if component == C::NAME => (net_id, tick, data),
+                        _ => continue,
+                    };
+                    let entity = match map.get(net_id) {
+                        Some(entity) => entity,
+                        None => continue,
+                    };
+                    if sender.is_none() || sender != replicated.get(entity).map(|r| r.owner) {
+                        warn!(
+                            "Refused an input for `{}` from a connection not owning the entity",
+                            C::NAME
+                        );
+                        continue;
+                    }
+                    if self
+                        .applied
+                        .get(net_id)
+                        .map_or(false, |applied| tick <= *applied)
+                    {
+                        continue;
+                    }
+                    let component = match components.get_mut(entity) {
+                        Some(component) => component,
+                        None => continue,
+                    };
+
+                    match deserialize::<C::Input>(data) {
+                        Ok(input) => {
+                            component.step(&input);
+                            self.applied.insert(*net_id, tick);
+                            moved.insert(*net_id);
+                        }
+                        Err(e) => error!("Failed to deserialize an input for `{}`: {}", C::NAME, e),
+                    }
+                }
+
+                if connection.state != ConnectionState::Connected {
+                    continue;
+                }
+                let sent = self.sent.entry(conn_entity).or_insert_with(HashMap::new);
+                for (replicated, component) in (&replicated, &components).join() {
+                    let owned = sender == Some(replicated.owner);
+                    let tick = match self.applied.get(&replicated.net_id) {
+                        Some(tick) => *tick,
+                        // The owner has nothing to reconcile before its first input.
+                        None if owned => continue,
+                        None => 0,
+                    };
+                    let acknowledge = owned && moved.contains(&replicated.net_id);
+                    if !acknowledge && sent.get(&replicated.net_id) == Some(component) {
+                        continue;
+                    }
+
+                    match serialize(component) {
+                        Ok(data) => {
+                            connection
+                                .send_buffer
+                                .single_write(NetPacket::reliable_ordered(NetEvent::Snapshot {
+                                    net_id: replicated.net_id,
+                                    component: C::NAME.to_string(),
+                                    tick,
+                                    data,
+                                }));
+                            sent.insert(replicated.net_id, component.clone());
+                        }
+                        Err(e) => error!("Failed to serialize a snapshot of `{}`: {}", C::NAME, e),
+                    }
+                }
+            }
+
+            self.applied.retain(|net_id, _| map.get(net_id).is_some());
+            self.sent.retain(|entity, sent| {
+                sent.retain(|net_id, _| map.get(net_id).is_some());
+                connections.get(*entity).is_some()
+            });
+        } else {
+            // Latest snapshot received for every network id.
+            let mut snapshots: HashMap<Uuid, (u64, C)> = HashMap::new();
+            for (conn_entity, connection) in (&*entities, &mut connections).join() {
+                let reader = self
+                    .readers
+                    .entry(conn_entity)
+                    .or_insert_with(|| connection.receive_buffer.register_reader());
+
+                for event in connection.receive_buffer.read(reader) {
+                    let (net_id, tick, data) = match *event {
+                        NetEvent::Snapshot {
+                            net_id,
+                            ref component,
+                            tick,
+                            ref data,
+                        } if component == C::NAME => (net_id, tick, data),
+                        _ => continue,
+                    };
+                    if snapshots
+                        .get(&net_id)
+                        .map_or(false, |(latest, _)| *latest > tick)
+                    {
+                        continue;
+                    }
+                    match deserialize::<C>(data) {
+                        Ok(state) => {
+                            snapshots.insert(net_id, (tick, state));
+                        }
+                        Err(e) => {
+                            error!("Failed to deserialize a snapshot of `{}`: {}", C::NAME, e)
+                        }
+                    }
+                }
+            }
+
+            let tick = time.frame_number();
+            let mut inputs = Vec::new();
+            for (replicated, component, prediction) in
+                (&replicated, &mut components, &mut predictions).join()
+            {
+                if replicated.owner != local_identity.uuid {
+                    continue;
+                }
+
+                if let Some((acknowledged, state)) = snapshots.remove(&replicated.net_id) {
+                    let outdated = prediction
+                        .last_acknowledged
+                        .map_or(false, |last| acknowledged < last);
+                    if !outdated {
+                        let state = prediction.reconcile(acknowledged, state);
+                        if state != *component {
+                            prediction.corrections += 1;
+                            *component = state;
+                        }
+                    }
+                }
+
+                if let Some(input) = prediction.next.take() {
+                    component.step(&input);
+                    match serialize(&input) {
+                        Ok(data) => inputs.push(NetEvent::Input {
+                            net_id: replicated.net_id,
+                            component: C::NAME.to_string(),
+                            tick,
+                            data,
+                        }),
+                        Err(e) => error!("Failed to serialize an input for `{}`: {}", C::NAME, e),
+                    }
+                    prediction.pending.push_back((tick, input));
+                    if prediction.pending.len() > MAX_PENDING_INPUTS {
+                        warn!("Too many inputs for `{}` are not acknowledged", C::NAME);
+                        prediction.pending.pop_front();
+                    }
+                }
+            }
+
+            // The entities predicted by other clients take the authoritative state as it is.
+            for (net_id, (_, state)) in snapshots {
+                if let Some(entity) = map.get(&net_id) {
+                    if let Err(e) = components.insert(entity, state) {
+                        error!("Failed to apply a snapshot of `{}`: {:?}", C::NAME, e);
+                    }
+                }
+            }
+
+            for connection in (&mut connections).join() {
+                if connection.state != ConnectionState::Connected {
+                    continue;
+                }
+                for input in &inputs {
+                    connection
+                        .send_buffer
+                        .single_write(NetPacket::reliable_ordered(input.clone()));
+                }
+            }
+        }
+
+        self.readers
+            .retain(|entity, _| connections.get(*entity).is_some());
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use amethyst_core::{
+        shred::{Dispatcher, DispatcherBuilder},
+        specs::{Builder, Component, Entity, VecStorage, World},
+        SystemBundle, Time,
+    };
+
+    use super::{Predicted, Prediction};
+    use crate::{
+        ConnectionState, NetConnection, NetEvent, NetIdentity, ReplicatedComponent,
+        ReplicatedEntity, ReplicationBundle,
+    };
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Position(i32);
+
+    impl Component for Position {
+        type Storage = VecStorage<Self>;
+    }
+
+    impl ReplicatedComponent for Position {
+        const NAME: &'static str = "position";
+    }
+
+    impl Predicted for Position {
+        type Input = i32;
+
+        fn step(&mut self, input: &i32) {
+            self.0 += input;
+        }
+    }
+
+    // A link delivering the events queued on one end `latency` frames later on the other end.
+    struct SimulatedLink {
+        latency: usize,
+        in_flight: VecDeque<Vec<NetEvent<()>>>,
+    }
+
+    impl SimulatedLink {
+        fn new(latency: usize) -> Self {
+            SimulatedLink {
+                latency,
+                in_flight: VecDeque::new(),
+            }
+        }
+
+        fn step(&mut self, from: &mut World, from_conn: Entity, to: &mut World, to_conn: Entity) {
+            let sent = from
+                .write_storage::<NetConnection<()>>()
+                .get_mut(from_conn)
+                .unwrap()
+                .send_buffer_early_read()
+                .map(|packet| packet.content.clone())
+                .collect();
+            self.in_flight.push_back(sent);
+
+            if self.in_flight.len() > self.latency {
+                let mut storage = to.write_storage::<NetConnection<()>>();
+                let connection = storage.get_mut(to_conn).unwrap();
+                for event in self.in_flight.pop_front().unwrap() {
+                    connection.receive_buffer.single_write(event);
+                }
+            }
+        }
+    }
+
+    fn build(is_server: bool) -> (World, Dispatcher<'static, 'static>) {
+        let mut world = World::new();
+        let mut builder = DispatcherBuilder::new();
+        ReplicationBundle::<()>::new(is_server)
+            .with_predicted_component::<Position>()
+            .build(&mut builder)
+            .unwrap();
+        let mut dispatch = builder.build();
+        dispatch.setup(&mut world.res);
+        (world, dispatch)
+    }
+
+    fn frame(world: &mut World, dispatch: &mut Dispatcher<'_, '_>) {
+        world.write_resource::<Time>().increment_frame_number();
+        dispatch.dispatch(&world.res);
+        world.maintain();
+    }
+
+    #[test]
+    fn inputs_are_predicted_and_reconciled() {
+        let (mut world_sv, mut sv_dispatch) = build(true);
+        let (mut world_cl, mut cl_dispatch) = build(false);
+        let client_uuid = world_cl.read_resource::<NetIdentity>().uuid;
+
+        let mut conn_to_client = NetConnection::<()>::new(
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        );
+        conn_to_client.state = ConnectionState::Connected;
+        let conn_to_client = world_sv
+            .create_entity()
+            .with(conn_to_client)
+            .with(NetIdentity { uuid: client_uuid })
+            .build();
+        let mut conn_to_server = NetConnection::<()>::new(
+            "127.0.0.1:3".parse().unwrap(),
+            "127.0.0.1:4".parse().unwrap(),
+        );
+        conn_to_server.state = ConnectionState::Connected;
+        let conn_to_server = world_cl.create_entity().with(conn_to_server).build();
+
+        // The same player entity on both ends, as replicated by the `EntityReplicationSystem`.
+        let player = ReplicatedEntity::new(client_uuid);
+        let player_sv = world_sv
+            .create_entity()
+            .with(player)
+            .with(Position(0))
+            .build();
+        let player_cl = world_cl
+            .create_entity()
+            .with(player)
+            .with(Position(0))
+            .with(Prediction::<Position>::new())
+            .build();
+
+        let mut uplink = SimulatedLink::new(2);
+        let mut downlink = SimulatedLink::new(2);
+        let position_on =
+            |world: &World, entity| world.read_storage::<Position>().get(entity).unwrap().0;
+
+        for i in 0..20 {
+            if i < 6 {
+                world_cl
+                    .write_storage::<Prediction<Position>>()
+                    .get_mut(player_cl)
+                    .unwrap()
+                    .set_input(3);
+            }
+            if i == 4 {
+                // The server pushes the player back, which the client can't predict.
+                world_sv
+                    .write_storage::<Position>()
+                    .get_mut(player_sv)
+                    .unwrap()
+                    .0 -= 5;
+            }
+
+            frame(&mut world_cl, &mut cl_dispatch);
+            uplink.step(&mut world_cl, conn_to_server, &mut world_sv, conn_to_client);
+            frame(&mut world_sv, &mut sv_dispatch);
+            downlink.step(&mut world_sv, conn_to_client, &mut world_cl, conn_to_server);
+
+            if i == 3 {
+                // The inputs are applied right away on the client, the server lags behind.
+                assert_eq!(position_on(&world_cl, player_cl), 12);
+                assert_eq!(position_on(&world_sv, player_sv), 6);
+            }
+        }
+
+        let predictions = world_cl.read_storage::<Prediction<Position>>();
+        let prediction = predictions.get(player_cl).unwrap();
+        assert_eq!(prediction.pending_inputs(), 0);
+        assert_eq!(prediction.last_acknowledged(), Some(6));
+        // Only the push back had to be corrected.
+        assert_eq!(prediction.corrections(), 1);
+        assert_eq!(position_on(&world_cl, player_cl), 13);
+        assert_eq!(position_on(&world_sv, player_sv), 13);
+    }
+
+    #[test]
+    fn other_clients_receive_the_authoritative_state() {
+        let (mut world_sv, mut sv_dispatch) = build(true);
+        let (mut world_cl, mut cl_dispatch) = build(false);
+        let (mut world_obs, mut obs_dispatch) = build(false);
+        let client_uuid = world_cl.read_resource::<NetIdentity>().uuid;
+        let observer_uuid = world_obs.read_resource::<NetIdentity>().uuid;
+
+        let connect = |world: &mut World, uuid: Option<Uuid>| {
+            let mut connection = NetConnection::<()>::new(
+                "127.0.0.1:1".parse().unwrap(),
+                "127.0.0.1:2".parse().unwrap(),
+            );
+            connection.state = ConnectionState::Connected;
+            let builder = world.create_entity().with(connection);
+            match uuid {
+                Some(uuid) => builder.with(NetIdentity { uuid }).build(),
+                None => builder.build(),
+            }
+        };
+        let conn_to_client = connect(&mut world_sv, Some(client_uuid));
+        let conn_to_observer = connect(&mut world_sv, Some(observer_uuid));
+        let client_to_server = connect(&mut world_cl, None);
+        let observer_to_server = connect(&mut world_obs, None);
+
+        let player = ReplicatedEntity::new(client_uuid);
+        let player_sv = world_sv
+            .create_entity()
+            .with(player)
+            .with(Position(0))
+            .build();
+        let player_cl = world_cl
+            .create_entity()
+            .with(player)
+            .with(Position(0))
+            .with(Prediction::<Position>::new())
+            .build();
+        // As created by the `EntityReplicationSystem`, the component is not replicated.
+        let player_obs = world_obs.create_entity().with(player).build();
+
+        let mut uplink = SimulatedLink::new(1);
+        let mut downlink = SimulatedLink::new(1);
+        let mut observer_link = SimulatedLink::new(1);
+        for i in 0..10 {
+            if i < 4 {
+                world_cl
+                    .write_storage::<Prediction<Position>>()
+                    .get_mut(player_cl)
+                    .unwrap()
+                    .set_input(3);
+            }
+
+            frame(&mut world_cl, &mut cl_dispatch);
+            uplink.step(
+                &mut world_cl,
+                client_to_server,
+                &mut world_sv,
+                conn_to_client,
+            );
+            frame(&mut world_sv, &mut sv_dispatch);
+            downlink.step(
+                &mut world_sv,
+                conn_to_client,
+                &mut world_cl,
+                client_to_server,
+            );
+            observer_link.step(
+                &mut world_sv,
+                conn_to_observer,
+                &mut world_obs,
+                observer_to_server,
+            );
+            frame(&mut world_obs, &mut obs_dispatch);
+        }
+
+        let position_on =
+            |world: &World, entity| world.read_storage::<Position>().get(entity).cloned();
+        assert_eq!(position_on(&world_sv, player_sv), Some(Position(12)));
+        assert_eq!(position_on(&world_cl, player_cl), Some(Position(12)));
+        assert_eq!(position_on(&world_obs, player_obs), Some(Position(12)));
+    }
+}
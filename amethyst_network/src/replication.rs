@@ -0,0 +1,359 @@
+//! Replication of entities and components from the server to its clients.
+//!
+//! The server is authoritative: it sends the creation and removal of every entity marked with
+//! `ReplicatedEntity`, and the changes of every `ReplicatedComponent` registered in the
+//! `ReplicationBundle`, to all connected clients.
+//! A client may only author changes to the components of entities it owns,
+//! which the server then forwards to the other clients.
+
+use std::collections::{HashMap, HashSet};
+
+use bincode::{deserialize, serialize};
+use log::{error, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use shrev::ReaderId;
+use uuid::Uuid;
+
+use amethyst_core::specs::{
+    Component, Entities, Entity, Join, Read, ReadStorage, Resources, System, SystemData,
+    VecStorage, Write, WriteStorage,
+};
+
+use crate::{ConnectionState, NetConnection, NetEvent, NetIdentity, NetPacket};
+
+/// Marks an entity as replicated over the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicatedEntity {
+    /// The id identifying this entity on every machine.
+    pub net_id: Uuid,
+    /// The uuid of the `NetIdentity` allowed to author changes to this entity.
+    pub owner: Uuid,
+}
+
+impl ReplicatedEntity {
+    /// Creates a new replicated entity marker with a fresh network id, owned by `owner`.
+    pub fn new(owner: Uuid) -> Self {
+        ReplicatedEntity {
+            net_id: Uuid::new_v4(),
+            owner,
+        }
+    }
+}
+
+impl Component for ReplicatedEntity {
+    type Storage = VecStorage<Self>;
+}
+
+/// A component whose changes are sent over the network.
+///
+/// Register it with `ReplicationBundle::with_component`.
+pub trait ReplicatedComponent:
+    Component + Clone + PartialEq + Serialize + DeserializeOwned + Send + Sync
+{
+    /// The name identifying this component type in `NetEvent::UpdateEntity`.
+    /// It has to be unique among the replicated components.
+    const NAME: &'static str;
+}
+
+/// Resource mapping network ids of replicated entities to the local entities.
+#[derive(Debug, Default)]
+pub struct NetEntityMap {
+    entities: HashMap<Uuid, Entity>,
+}
+
+impl NetEntityMap {
+    /// Returns the local entity replicating the given network id.
+    pub fn get(&self, net_id: &Uuid) -> Option<Entity> {
+        self.entities.get(net_id).cloned()
+    }
+
+    /// Returns the amount of replicated entities.
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    /// Returns `true` if there is no replicated entity.
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    fn rebuild(
+        &mut self,
+        entities: &Entities<'_>,
+        replicated: &WriteStorage<'_, ReplicatedEntity>,
+    ) {
+        self.entities.clear();
+        for (entity, replicated) in (&**entities, replicated).join() {
+            self.entities.insert(replicated.net_id, entity);
+        }
+    }
+}
+
+/// Sends the creation and removal of replicated entities on the server,
+/// and applies them on the client. It also keeps the `NetEntityMap` resource up to date.
+///
+/// It has to run before the `ComponentReplicationSystem`s.
+pub struct EntityReplicationSystem<E: 'static> {
+    is_server: bool,
+    // network ids of the entities each connection has been told about.
+    known: HashMap<Entity, HashSet<Uuid>>,
+    readers: HashMap<Entity, ReaderId<NetEvent<E>>>,
+}
+
+impl<E> EntityReplicationSystem<E> {
+    /// Creates a new `EntityReplicationSystem`, acting as the authoritative server if `is_server` is set.
+    pub fn new(is_server: bool) -> Self {
+        EntityReplicationSystem {
+            is_server,
+            known: HashMap::new(),
+            readers: HashMap::new(),
+        }
+    }
+}
+
+impl<'a, E> System<'a> for EntityReplicationSystem<E>
+where
+    E: Send + Sync + 'static,
+{
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, NetConnection<E>>,
+        WriteStorage<'a, ReplicatedEntity>,
+        Write<'a, NetEntityMap>,
+    );
+
+    fn run(&mut self, (entities, mut connections, mut replicated, mut map): Self::SystemData) {
+        map.rebuild(&entities, &replicated);
+
+        if self.is_server {
+            for (conn_entity, connection) in (&*entities, &mut connections).join() {
+                if connection.state != ConnectionState::Connected {
+                    continue;
+                }
+
+                let known = self.known.entry(conn_entity).or_insert_with(HashSet::new);
+                for entity in (&replicated).join() {
+                    if known.insert(entity.net_id) {
+                        connection
+                            .send_buffer
+                            .single_write(NetPacket::reliable_ordered(NetEvent::CreateEntity {
+                                net_id: entity.net_id,
+                                owner: entity.owner,
+                            }));
+                    }
+                }
+                known.retain(|net_id| {
+                    let alive = map.get(net_id).is_some();
+                    if !alive {
+                        connection
+                            .send_buffer
+                            .single_write(NetPacket::reliable_ordered(NetEvent::RemoveEntity {
+                                net_id: *net_id,
+                            }));
+                    }
+                    alive
+                });
+            }
+
+            self.known.retain(|entity, _| {
+                connections
+                    .get(*entity)
+                    .map_or(false, |c| c.state == ConnectionState::Connected)
+            });
+        } else {
+            for (conn_entity, connection) in (&*entities, &mut connections).join() {
+                let reader = self
+                    .readers
+                    .entry(conn_entity)
+                    .or_insert_with(|| connection.receive_buffer.register_reader());
+
+                for event in connection.receive_buffer.read(reader) {
+                    match *event {
+                        NetEvent::CreateEntity { net_id, owner } => {
+                            if map.get(&net_id).is_none() {
+                                let entity = entities
+                                    .build_entity()
+                                    .with(ReplicatedEntity { net_id, owner }, &mut replicated)
+                                    .build();
+                                map.entities.insert(net_id, entity);
+                            }
+                        }
+                        NetEvent::RemoveEntity { net_id } => {
+                            if let Some(entity) = map.entities.remove(&net_id) {
+                                if let Err(e) = entities.delete(entity) {
+                                    error!("Failed to delete a replicated entity: {:?}", e);
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            self.readers
+                .retain(|entity, _| connections.get(*entity).is_some());
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+    }
+}
+
+/// Sends the changes of a `ReplicatedComponent` and applies the changes received from the network.
+///
+/// The server sends the changes of every replicated entity to all connected clients,
+/// and only accepts changes coming from the owner of an entity.
+/// A client sends the changes of the entities it owns, and applies the changes of all other entities.
+pub struct ComponentReplicationSystem<C, E: 'static> {
+    is_server: bool,
+    // last values sent to each connection, by network id.
+    sent: HashMap<Entity, HashMap<Uuid, C>>,
+    readers: HashMap<Entity, ReaderId<NetEvent<E>>>,
+}
+
+impl<C, E> ComponentReplicationSystem<C, E> {
+    /// Creates a new `ComponentReplicationSystem`, acting as the authoritative server if `is_server` is set.
+    pub fn new(is_server: bool) -> Self {
+        ComponentReplicationSystem {
+            is_server,
+            sent: HashMap::new(),
+            readers: HashMap::new(),
+        }
+    }
+}
+
+impl<'a, C, E> System<'a> for ComponentReplicationSystem<C, E>
+where
+    C: ReplicatedComponent,
+    E: Send + Sync + 'static,
+{
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, NetConnection<E>>,
+        ReadStorage<'a, NetIdentity>,
+        ReadStorage<'a, ReplicatedEntity>,
+        WriteStorage<'a, C>,
+        Read<'a, NetIdentity>,
+        Read<'a, NetEntityMap>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut connections, identities, replicated, mut components, local_identity, map): Self::SystemData,
+    ) {
+        // Apply the received changes.
+        for (conn_entity, connection) in (&*entities, &mut connections).join() {
+            let reader = self
+                .readers
+                .entry(conn_entity)
+                .or_insert_with(|| connection.receive_buffer.register_reader());
+            let sender = identities.get(conn_entity).map(|identity| identity.uuid);
+
+            for event in connection.receive_buffer.read(reader) {
+                let (net_id, data) = match *event {
+                    NetEvent::UpdateEntity {
+                        ref net_id,
+                        ref component,
+                        ref data,
+                    } if component == C::NAME => (net_id, data),
+                    _ => continue,
+                };
+                let entity = match map.get(net_id) {
+                    Some(entity) => entity,
+                    None => continue,
+                };
+                let owner = replicated.get(entity).map(|r| r.owner);
+
+                if self.is_server {
+                    if sender.is_none() || sender != owner {
+                        warn!(
+                            "Refused a change of `{}` from a connection not owning the entity",
+                            C::NAME
+                        );
+                        continue;
+                    }
+                } else if owner == Some(local_identity.uuid) && components.get(entity).is_some() {
+                    // We are the author of this component, only its initial value comes from the server.
+                    continue;
+                }
+
+                match data {
+                    Some(data) => match deserialize::<C>(data) {
+                        Ok(component) => {
+                            if let Err(e) = components.insert(entity, component) {
+                                error!("Failed to insert a replicated `{}`: {:?}", C::NAME, e);
+                            }
+                        }
+                        Err(e) => error!("Failed to deserialize a replicated `{}`: {}", C::NAME, e),
+                    },
+                    None => {
+                        components.remove(entity);
+                    }
+                }
+            }
+        }
+
+        // Send the local changes.
+        for (conn_entity, connection) in (&*entities, &mut connections).join() {
+            if connection.state != ConnectionState::Connected {
+                continue;
+            }
+
+            let sent = self.sent.entry(conn_entity).or_insert_with(HashMap::new);
+            for (replicated, component) in (&replicated, &components).join() {
+                if !self.is_server && replicated.owner != local_identity.uuid {
+                    continue;
+                }
+                if sent.get(&replicated.net_id) == Some(component) {
+                    continue;
+                }
+
+                match serialize(component) {
+                    Ok(data) => {
+                        connection
+                            .send_buffer
+                            .single_write(NetPacket::reliable_ordered(NetEvent::UpdateEntity {
+                                net_id: replicated.net_id,
+                                component: C::NAME.to_string(),
+                                data: Some(data),
+                            }));
+                        sent.insert(replicated.net_id, component.clone());
+                    }
+                    Err(e) => error!("Failed to serialize a replicated `{}`: {}", C::NAME, e),
+                }
+            }
+
+            sent.retain(|net_id, _| {
+                let entity = match map.get(net_id) {
+                    Some(entity) => entity,
+                    // The entity is gone, which is replicated on its own.
+                    None => return false,
+                };
+                let present = components.get(entity).is_some();
+                if !present {
+                    connection
+                        .send_buffer
+                        .single_write(NetPacket::reliable_ordered(NetEvent::UpdateEntity {
+                            net_id: *net_id,
+                            component: C::NAME.to_string(),
+                            data: None,
+                        }));
+                }
+                present
+            });
+        }
+
+        self.sent.retain(|entity, _| {
+            connections
+                .get(*entity)
+                .map_or(false, |c| c.state == ConnectionState::Connected)
+        });
+        self.readers
+            .retain(|entity, _| connections.get(*entity).is_some());
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+    }
+}
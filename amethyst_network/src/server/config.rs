@@ -1,4 +1,6 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
+
+use crate::server::NetworkSimulation;
 
 #[derive(Clone, Debug)]
 /// The configuration used for the networking system.
@@ -7,10 +9,30 @@ pub struct ServerConfig {
     pub udp_recv_addr: SocketAddr,
     /// Address from which the UDP server will be sending packets.
     pub udp_send_addr: SocketAddr,
+    /// Address at which the TCP listener accepts streams, `None` disables TCP.
+    /// This value is by default `None`.
+    ///
+    /// The port of `udp_send_addr` identifies us on the other end of a stream,
+    /// so it should not be left to the OS when TCP is enabled.
+    pub tcp_addr: Option<SocketAddr>,
     /// Specifies what the maximal packets that could be handled by the server.
     /// This value is meant for preventing some loops to read infinitely long when many packets are send and received.
     /// This value is by default 5000.
     pub max_throughput: u16,
+    /// The maximal amount of clients that can be connected at the same time.
+    /// Only used when `NetSocketSystem` runs in server mode.
+    /// This value is by default 32.
+    pub max_clients: usize,
+    /// Interval at which heartbeats are sent on connected `NetConnection`s.
+    /// They keep the connection alive and are used to measure `NetConnectionStats`.
+    /// `None` disables heartbeats. This value is by default one second.
+    pub heartbeat_interval: Option<Duration>,
+    /// Time after which a connected `NetConnection` that did not receive anything is disconnected.
+    /// `None` disables timeouts. This value is by default ten seconds.
+    pub idle_timeout: Option<Duration>,
+    /// Bad network conditions simulated on the datagrams received over UDP, for testing.
+    /// `None` receives datagrams right away. This value is by default `None`.
+    pub simulation: Option<NetworkSimulation>,
 }
 
 impl Default for ServerConfig {
@@ -19,7 +41,12 @@ impl Default for ServerConfig {
             // by passing in :0 port the OS will give an available port.
             udp_recv_addr: "0.0.0.0:0".parse().unwrap(),
             udp_send_addr: "0.0.0.0:0".parse().unwrap(),
+            tcp_addr: None,
             max_throughput: 5000,
+            max_clients: 32,
+            heartbeat_interval: Some(Duration::from_secs(1)),
+            idle_timeout: Some(Duration::from_secs(10)),
+            simulation: None,
         }
     }
 }
@@ -3,19 +3,32 @@
 /// 1. Sending Data
 /// 2. Receiving Data
 /// 3. Broadcasting
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::server::{
-    ReceiveHandler, SendHandler, ServerConfig, ServerSocketEvent, UdpReceiver, UdpSender,
+    ReceiveHandler, SendHandler, ServerConfig, ServerSocketEvent, TcpSendHandler, TcpTransport,
+    UdpReceiver, UdpSender,
 };
 use laminar::Packet;
-use std::sync::{Arc, Mutex};
+use log::warn;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
 /// 'Host' abstracts TCP and UDP sockets away.
+///
+/// Once the host and all handles obtained from it are dropped, its threads are stopped and joined
+/// and its sockets are closed, so the addresses can be bound again.
 pub struct Host {
     // Handler to access the internals of the UDP receiving thread
     udp_receiver: Arc<Mutex<ReceiveHandler>>,
     // Handler to access the internals of the UDP sender thread
     udp_sender: Arc<SendHandler>,
+    // Handler to access the internals of the TCP sender thread, if enabled.
+    // Declared before the listener so no stream gets opened while the listener is stopping.
+    tcp_sender: Option<Arc<TcpSendHandler>>,
+    // Handler to access the internals of the TCP listener thread, if enabled
+    tcp_receiver: Option<Arc<Mutex<ReceiveHandler>>>,
 }
 
 impl Host {
@@ -32,9 +45,23 @@ impl Host {
         // setup the UDP-sender which will send packets to an certain endpoint.
         let udp_sender = Arc::new(UdpSender::run(config.udp_send_addr)?);
 
+        // setup the TCP-listener and TCP-sender if enabled.
+        let (tcp_receiver, tcp_sender) = match config.tcp_addr {
+            Some(tcp_addr) => {
+                if config.udp_send_addr.port() == 0 {
+                    warn!("TCP is enabled while the port of the UDP-sender is left to the OS, the other end will not be able to identify us.");
+                }
+                let (receiver, sender) = TcpTransport::run(tcp_addr, config.udp_send_addr.port())?;
+                (Some(Arc::new(Mutex::new(receiver))), Some(Arc::new(sender)))
+            }
+            None => (None, None),
+        };
+
         Ok(Host {
             udp_sender,
             udp_receiver,
+            tcp_sender,
+            tcp_receiver,
         })
     }
 
@@ -48,9 +75,28 @@ impl Host {
         self.udp_sender.clone()
     }
 
-    /// Schedule a TCP-packet for sending.
-    pub fn send_tcp(&mut self, _payload: &[u8]) -> Result<()> {
-        unimplemented!()
+    /// Get the handle to the internals of the TCP-listening thread, if TCP is enabled.
+    pub fn tcp_receive_handle(&self) -> Option<Arc<Mutex<ReceiveHandler>>> {
+        self.tcp_receiver.clone()
+    }
+
+    /// Get the handle to the internals of the TCP-sending thread, if TCP is enabled.
+    pub fn tcp_send_handle(&self) -> Option<Arc<TcpSendHandler>> {
+        self.tcp_sender.clone()
+    }
+
+    /// Schedule a TCP-payload for sending to `peer`, identified by the address of its UDP-sender.
+    /// If there is no stream with `peer` yet, one is opened to `connect_to`.
+    pub fn send_tcp(
+        &mut self,
+        peer: SocketAddr,
+        connect_to: Option<SocketAddr>,
+        payload: &[u8],
+    ) -> Result<()> {
+        match self.tcp_sender {
+            Some(ref sender) => sender.send(peer, connect_to, payload.to_vec()),
+            None => Err(Error::TcpDisabled),
+        }
     }
 
     /// Schedule a UDP-packet for sending.
@@ -3,6 +3,8 @@ mod host;
 mod receive_handler;
 mod send_handler;
 mod server_socket_event;
+mod simulation;
+mod tcp;
 mod udp;
 
 pub use self::{
@@ -10,10 +12,13 @@ pub use self::{
     host::Host,
     receive_handler::ReceiveHandler,
     send_handler::SendHandler,
+    simulation::NetworkSimulation,
+    tcp::{read_frame, write_frame, TcpSendHandler, TcpTransport, MAX_FRAME_SIZE},
     udp::{UdpReceiver, UdpSender},
 };
 
 pub use self::server_socket_event::{ClientEvent, ServerSocketEvent};
+
 use std::sync::mpsc::{Receiver, Sender};
 
 /// Can be implemented for the receiving side of a socket.
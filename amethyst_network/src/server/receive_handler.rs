@@ -2,37 +2,90 @@
 //! The `ReceiveHandler` communicates via `mpsc::channel`s.
 
 use crate::server::ServerSocketEvent;
+use log::{error, warn};
 use std::{
-    sync::mpsc::{Iter, Receiver},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Iter, Receiver, TryIter},
+        Arc,
+    },
     thread::JoinHandle,
 };
 
 /// Handler to access the internals of a receiving socket.
+///
+/// The receiving thread is stopped and joined when the handler is dropped.
 pub struct ReceiveHandler {
     /// handle that should be used for reading received packets from a given socket
     pub receiver: Receiver<ServerSocketEvent>,
     /// thread handle to the thread that receives packets on a given socket
-    _thread_handle: JoinHandle<()>,
+    thread_handle: Option<JoinHandle<()>>,
+    /// flag shared with the receiving thread, which stops once it is cleared
+    running: Arc<AtomicBool>,
+    /// unblocks the receiving thread so it notices it has to stop
+    waker: Option<Box<dyn Fn() -> bool + Send>>,
 }
 
 impl ReceiveHandler {
     /// Create a new receive handler by specifying:
-    /// 1: the max throughput in packets a host can read at once.
-    /// 2: handle that should be used for reading received packets from a given socket.
-    /// 3: handle that should be used for reading received packets from a given socket.
+    /// 1: handle that should be used for reading received packets from a given socket.
+    /// 2: thread handle to the thread that receives packets on a given socket.
+    /// 3: flag the receiving thread checks to know if it should keep running.
     pub fn new(
         receiver: Receiver<ServerSocketEvent>,
         thread_handle: JoinHandle<()>,
+        running: Arc<AtomicBool>,
     ) -> ReceiveHandler {
         ReceiveHandler {
             receiver,
-            _thread_handle: thread_handle,
+            thread_handle: Some(thread_handle),
+            running,
+            waker: None,
         }
     }
 
+    /// Sets the function called on stop to unblock the receiving thread, for example
+    /// when it is waiting on a socket.
+    ///
+    /// It returns `false` if the thread could not be woken up, which is then left to finish on its own instead of being joined.
+    pub fn with_waker<F>(mut self, waker: F) -> Self
+    where
+        F: Fn() -> bool + Send + 'static,
+    {
+        self.waker = Some(Box::new(waker));
+        self
+    }
+
     // Returns an iterator that will block waiting for messages from the receiver but which will never panic!.
     // It will return None when the channel has hung up.
     pub fn iter(&self) -> Iter<'_, ServerSocketEvent> {
         self.receiver.iter()
     }
+
+    /// Returns an iterator over the messages already received, without blocking.
+    pub fn try_iter(&self) -> TryIter<'_, ServerSocketEvent> {
+        self.receiver.try_iter()
+    }
+
+    /// Stops the receiving thread and waits for it to finish.
+    /// Calling this more than once has no effect.
+    pub fn stop(&mut self) {
+        if let Some(thread_handle) = self.thread_handle.take() {
+            self.running.store(false, Ordering::SeqCst);
+            let woken = self.waker.as_ref().map_or(true, |waker| waker());
+            if !woken {
+                warn!("Could not wake up the receiving thread, leaving it behind");
+                return;
+            }
+            if thread_handle.join().is_err() {
+                error!("The receiving thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for ReceiveHandler {
+    fn drop(&mut self) {
+        self.stop();
+    }
 }
@@ -2,14 +2,17 @@
 //! The `SendHandler` communicates via `mpsc::channel`s.
 
 use crate::{error::Result, server::ServerSocketEvent};
+use log::error;
 use std::{sync::mpsc::SyncSender, thread::JoinHandle};
 
 /// Handler to access the internals of a sending socket.
+///
+/// The sending thread finishes sending what is queued and is joined when the handler is dropped.
 pub struct SendHandler {
     /// handle that should be used fro reading the received packets on a given socket
-    sender: SyncSender<ServerSocketEvent>,
+    sender: Option<SyncSender<ServerSocketEvent>>,
     /// thread handle to the thread that sends packets
-    _thread_handle: JoinHandle<()>,
+    thread_handle: Option<JoinHandle<()>>,
 }
 
 impl SendHandler {
@@ -21,19 +24,33 @@ impl SendHandler {
         thread_handle: JoinHandle<()>,
     ) -> SendHandler {
         SendHandler {
-            sender,
-            _thread_handle: thread_handle,
+            sender: Some(sender),
+            thread_handle: Some(thread_handle),
         }
     }
 
     /// Send an event on the internal channel, by doing this it will be scheduled for sending.
     pub fn send(&self, event: ServerSocketEvent) -> Result<()> {
-        self.sender.send(event)?;
+        self.get_sender().send(event)?;
         Ok(())
     }
 
     /// Get the sending channel of this `SendHandler` to which you can send  
     pub fn get_sender(&self) -> &SyncSender<ServerSocketEvent> {
-        &self.sender
+        self.sender
+            .as_ref()
+            .expect("Unreachable: the sender is only taken when dropping the handler")
+    }
+}
+
+impl Drop for SendHandler {
+    fn drop(&mut self) {
+        // Hanging up the channel makes the sending thread stop.
+        self.sender.take();
+        if let Some(thread_handle) = self.thread_handle.take() {
+            if thread_handle.join().is_err() {
+                error!("The sending thread panicked");
+            }
+        }
     }
 }
@@ -0,0 +1,386 @@
+//! Simulation of bad network conditions on the datagrams received over UDP, to test netcode locally.
+
+use log::error;
+use rand::{rngs::SmallRng, FromEntropy, Rng, SeedableRng};
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// The largest datagram UDP can carry.
+const MAX_DATAGRAM_SIZE: usize = 65_536;
+
+/// The network conditions simulated on the datagrams a `Host` receives over UDP.
+///
+/// Enable it with `ServerConfig::simulation`. The datagrams are degraded before laminar reads
+/// them, so lost reliable packets are resent and reordered ones are put back in order like on a
+/// real network. Only the datagrams received by this host are affected, so enable it on both ends
+/// to degrade both directions. Datagrams still in flight when the host is dropped are lost.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkSimulation {
+    /// Delay added to every datagram.
+    pub latency: Duration,
+    /// Maximal random delay added on top of `latency`, picked uniformly for every datagram.
+    pub jitter: Duration,
+    /// Probability between 0 and 1 for a datagram to be dropped.
+    pub loss: f32,
+    /// Probability between 0 and 1 for a datagram to be delivered twice.
+    pub duplication: f32,
+    /// Probability between 0 and 1 for a datagram to be held back by `reorder_delay`,
+    /// so the datagrams received right after it arrive first.
+    pub reordering: f32,
+    /// Delay added to the datagrams held back for reordering.
+    /// This value is by default 50 milliseconds.
+    pub reorder_delay: Duration,
+    /// Seed of the random generator, to reproduce the same conditions. `None` seeds it from the OS.
+    pub seed: Option<u64>,
+}
+
+impl Default for NetworkSimulation {
+    fn default() -> Self {
+        NetworkSimulation {
+            latency: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            reorder_delay: Duration::from_millis(50),
+            seed: None,
+        }
+    }
+}
+
+// A datagram waiting for its delivery time.
+struct Delayed {
+    deliver_at: Instant,
+    // keeps the datagrams with the same delivery time in the order they were received.
+    sequence: u64,
+    from: SocketAddr,
+    payload: Vec<u8>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.sequence == other.sequence
+    }
+}
+
+impl Eq for Delayed {}
+
+impl Ord for Delayed {
+    // Reversed, so the `BinaryHeap` pops the earliest delivery first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .deliver_at
+            .cmp(&self.deliver_at)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Holds datagrams back until the simulated network delivers them.
+pub(crate) struct LinkConditioner {
+    simulation: NetworkSimulation,
+    rng: SmallRng,
+    in_flight: BinaryHeap<Delayed>,
+    sequence: u64,
+}
+
+impl LinkConditioner {
+    pub(crate) fn new(simulation: NetworkSimulation) -> Self {
+        let rng = match simulation.seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
+        LinkConditioner {
+            simulation,
+            rng,
+            in_flight: BinaryHeap::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Puts a datagram received from `from` at `now` on the simulated network, unless it gets lost.
+    pub(crate) fn schedule(&mut self, from: SocketAddr, payload: Vec<u8>, now: Instant) {
+        let (loss, duplication) = (self.simulation.loss, self.simulation.duplication);
+        if self.roll(loss) {
+            return;
+        }
+
+        if self.roll(duplication) {
+            self.push(from, payload.clone(), now);
+        }
+        self.push(from, payload, now);
+    }
+
+    /// The time at which the next datagram is delivered.
+    pub(crate) fn next_delivery(&self) -> Option<Instant> {
+        self.in_flight.peek().map(|delayed| delayed.deliver_at)
+    }
+
+    /// Takes the next datagram delivered at or before `now`, with the address it came from.
+    pub(crate) fn pop_delivered(&mut self, now: Instant) -> Option<(SocketAddr, Vec<u8>)> {
+        if self.next_delivery()? > now {
+            return None;
+        }
+        self.in_flight
+            .pop()
+            .map(|delayed| (delayed.from, delayed.payload))
+    }
+
+    fn push(&mut self, from: SocketAddr, payload: Vec<u8>, now: Instant) {
+        let reordering = self.simulation.reordering;
+        let mut delay = self.simulation.latency + self.jitter();
+        if self.roll(reordering) {
+            delay += self.simulation.reorder_delay;
+        }
+
+        self.sequence += 1;
+        self.in_flight.push(Delayed {
+            deliver_at: now + delay,
+            sequence: self.sequence,
+            from,
+            payload,
+        });
+    }
+
+    fn roll(&mut self, probability: f32) -> bool {
+        probability > 0.0 && self.rng.gen::<f32>() < probability
+    }
+
+    fn jitter(&mut self) -> Duration {
+        let jitter = self.simulation.jitter;
+        let max = jitter.as_secs() * 1_000_000_000 + u64::from(jitter.subsec_nanos());
+        if max == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_nanos(self.rng.gen_range(0, max + 1))
+    }
+}
+
+/// A socket receiving the datagrams in place of laminar, and relaying them to the laminar socket
+/// once the simulated network delivers them.
+///
+/// Every peer is relayed from a socket of its own so laminar still tells the peers apart,
+/// and `peer` maps the address laminar saw back to the peer.
+pub(crate) struct ConditionedSocket {
+    local_addr: SocketAddr,
+    // the peers by address of the socket relaying them.
+    peers: Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>,
+    thread_handle: Option<JoinHandle<()>>,
+}
+
+impl ConditionedSocket {
+    /// Binds a socket at `addr` relaying the datagrams to `target` on its own thread,
+    /// until `running` is cleared and the socket is woken up.
+    pub(crate) fn run(
+        addr: SocketAddr,
+        target: SocketAddr,
+        simulation: NetworkSimulation,
+        running: Arc<AtomicBool>,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        let local_addr = socket.local_addr()?;
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+
+        let mut relay = Relay {
+            target,
+            sockets: HashMap::new(),
+            peers: peers.clone(),
+        };
+        let thread_handle = thread::spawn(move || {
+            relay.run(&socket, LinkConditioner::new(simulation), &running);
+            // The receiving thread is blocked on the laminar socket, and must stop as well.
+            if let Err(e) = socket.send_to(&[], target) {
+                error!("Failed to wake up the UDP-receiver. Reason: {:?}", e);
+            }
+        });
+
+        Ok(ConditionedSocket {
+            local_addr,
+            peers,
+            thread_handle: Some(thread_handle),
+        })
+    }
+
+    /// The address the datagrams are received at.
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The peer a datagram relayed from `addr` comes from.
+    pub(crate) fn peer(&self, addr: SocketAddr) -> SocketAddr {
+        self.peers
+            .lock()
+            .expect("Relayed peers lock poisoned")
+            .get(&addr)
+            .cloned()
+            .unwrap_or(addr)
+    }
+}
+
+impl Drop for ConditionedSocket {
+    fn drop(&mut self) {
+        if let Some(thread_handle) = self.thread_handle.take() {
+            if thread_handle.join().is_err() {
+                error!("The UDP-relaying thread panicked");
+            }
+        }
+    }
+}
+
+// The relaying side of a `ConditionedSocket`.
+struct Relay {
+    target: SocketAddr,
+    // the socket relaying each peer.
+    sockets: HashMap<SocketAddr, UdpSocket>,
+    peers: Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>,
+}
+
+impl Relay {
+    // Receives datagrams on `socket`, relaying them as the simulated network delivers them.
+    fn run(&mut self, socket: &UdpSocket, mut conditioner: LinkConditioner, running: &AtomicBool) {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        while running.load(atomic::Ordering::SeqCst) {
+            let now = Instant::now();
+            while let Some((from, payload)) = conditioner.pop_delivered(now) {
+                self.relay(from, &payload);
+            }
+
+            // A zero timeout is refused, so it waits at least a millisecond.
+            let timeout = conditioner
+                .next_delivery()
+                .map(|deliver_at| (deliver_at - now).max(Duration::from_millis(1)));
+            if let Err(e) = socket.set_read_timeout(timeout) {
+                error!("Failed to wait for the next delivery. Reason: {:?}", e);
+            }
+            match socket.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    conditioner.schedule(from, buffer[..len].to_vec(), Instant::now())
+                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => error!("Failed to receive a datagram. Reason: {:?}", e),
+            }
+        }
+    }
+
+    fn relay(&mut self, from: SocketAddr, payload: &[u8]) {
+        if !self.sockets.contains_key(&from) {
+            let socket = match UdpSocket::bind(SocketAddr::new(self.target.ip(), 0)) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("Failed to bind a relay for {}. Reason: {:?}", from, e);
+                    return;
+                }
+            };
+            if let Ok(relay_addr) = socket.local_addr() {
+                self.peers
+                    .lock()
+                    .expect("Relayed peers lock poisoned")
+                    .insert(relay_addr, from);
+            }
+            self.sockets.insert(from, socket);
+        }
+
+        if let Err(e) = self.sockets[&from].send_to(payload, self.target) {
+            error!("Failed to relay a datagram from {}. Reason: {:?}", from, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use super::{LinkConditioner, NetworkSimulation};
+
+    fn schedule(conditioner: &mut LinkConditioner, id: u8, now: Instant) {
+        let from: SocketAddr = "127.0.0.1:21300".parse().unwrap();
+        conditioner.schedule(from, vec![id], now);
+    }
+
+    fn delivered(conditioner: &mut LinkConditioner, now: Instant) -> Vec<u8> {
+        let mut ids = Vec::new();
+        while let Some((_, payload)) = conditioner.pop_delivered(now) {
+            ids.push(payload[0]);
+        }
+        ids
+    }
+
+    #[test]
+    fn packets_are_delayed_and_duplicated() {
+        let mut conditioner = LinkConditioner::new(NetworkSimulation {
+            latency: Duration::from_millis(100),
+            duplication: 1.0,
+            seed: Some(7),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        schedule(&mut conditioner, 1, now);
+        schedule(&mut conditioner, 2, now);
+
+        assert_eq!(
+            conditioner.next_delivery(),
+            Some(now + Duration::from_millis(100))
+        );
+        assert!(delivered(&mut conditioner, now + Duration::from_millis(99)).is_empty());
+        assert_eq!(
+            delivered(&mut conditioner, now + Duration::from_millis(100)),
+            vec![1, 1, 2, 2]
+        );
+        assert_eq!(conditioner.next_delivery(), None);
+    }
+
+    #[test]
+    fn lost_packets_are_never_delivered() {
+        let mut conditioner = LinkConditioner::new(NetworkSimulation {
+            loss: 1.0,
+            seed: Some(7),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        schedule(&mut conditioner, 1, now);
+        assert_eq!(conditioner.next_delivery(), None);
+    }
+
+    #[test]
+    fn jitter_and_reordering_shuffle_packets() {
+        let mut conditioner = LinkConditioner::new(NetworkSimulation {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(20),
+            reordering: 0.2,
+            reorder_delay: Duration::from_millis(50),
+            seed: Some(7),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        for id in 0..100 {
+            schedule(&mut conditioner, id, now);
+        }
+
+        assert!(delivered(&mut conditioner, now + Duration::from_millis(9)).is_empty());
+        let mut ids = delivered(&mut conditioner, now + Duration::from_millis(80));
+        assert_eq!(ids.len(), 100);
+        assert_ne!(ids, (0..100).collect::<Vec<_>>());
+        ids.sort();
+        assert_eq!(ids, (0..100).collect::<Vec<_>>());
+    }
+}
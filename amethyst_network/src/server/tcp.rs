@@ -0,0 +1,534 @@
+//! All TCP related logic for streaming length-prefixed payloads to the other side.
+//!
+//! Every stream starts with the port the opening end sends its UDP packets from.
+//! Both ends identify the stream by the address of the peer's UDP-sender,
+//! so packets received over TCP carry the same address as the ones received over UDP.
+
+use crate::{
+    error::{Error, Result},
+    server::{udp::loopback_if_unspecified, ReceiveHandler, ServerSocketEvent},
+};
+use laminar::{DeliveryMethod, Packet};
+use log::{error, warn};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// The maximal size of a single payload sent or received over TCP.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Time the opening end has to announce its port before the stream is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The open streams by peer, with an id telling a closed stream apart from the one replacing it.
+type Streams = Arc<Mutex<HashMap<SocketAddr, (usize, Arc<TcpStream>)>>>;
+// The reading threads, with a flag set once they are finished.
+type Readers = Arc<Mutex<Vec<(Arc<AtomicBool>, JoinHandle<()>)>>>;
+
+static NEXT_STREAM_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes a payload prefixed with its length.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "payload exceeds the maximal frame size",
+        ));
+    }
+    let len = payload.len() as u32;
+    writer.write_all(&[
+        (len >> 24) as u8,
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+    ])?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Reads a payload prefixed with its length.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = len.iter().fold(0, |len, byte| len << 8 | *byte as usize);
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame exceeds the maximal frame size",
+        ));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// A payload to send, handled by the TCP-sending thread.
+struct TcpCommand {
+    peer: SocketAddr,
+    connect_to: Option<SocketAddr>,
+    payload: Vec<u8>,
+}
+
+/// Handler to access the internals of the TCP-sending thread.
+///
+/// The sending thread finishes sending what is queued and is joined when the handler is dropped.
+pub struct TcpSendHandler {
+    sender: Option<Sender<TcpCommand>>,
+    streams: Streams,
+    /// thread handle to the thread that sends payloads
+    thread_handle: Option<JoinHandle<()>>,
+}
+
+impl TcpSendHandler {
+    /// Schedules a payload for sending to `peer`, identified by the address of its UDP-sender.
+    ///
+    /// If there is no stream with `peer` yet, one is opened to `connect_to`.
+    pub fn send(
+        &self,
+        peer: SocketAddr,
+        connect_to: Option<SocketAddr>,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        self.sender
+            .as_ref()
+            .expect("Unreachable: the sender is only taken when dropping the handler")
+            .send(TcpCommand {
+                peer,
+                connect_to,
+                payload,
+            })
+            .map_err(|_| Error::TcpChannelSendError)
+    }
+
+    /// Checks if there is an open stream with `peer`, identified by the address of its UDP-sender.
+    pub fn has_stream(&self, peer: &SocketAddr) -> bool {
+        self.streams
+            .lock()
+            .expect("TCP streams lock poisoned")
+            .contains_key(peer)
+    }
+}
+
+impl Drop for TcpSendHandler {
+    fn drop(&mut self) {
+        // Hanging up the channel makes the sending thread stop.
+        self.sender.take();
+        if let Some(thread_handle) = self.thread_handle.take() {
+            if thread_handle.join().is_err() {
+                error!("The TCP-sending thread panicked");
+            }
+        }
+    }
+}
+
+/// A TCP transport, wrapper for starting the TCP-listening and TCP-sending threads.
+pub struct TcpTransport;
+
+impl TcpTransport {
+    /// This will run the TCP listener and sender on their own threads.
+    ///
+    /// `udp_send_port` is the port our UDP-sender is bound to, which identifies us on the other end.
+    ///
+    /// Dropping the `ReceiveHandler` closes every stream and joins the listening and reading threads.
+    pub fn run(addr: SocketAddr, udp_send_port: u16) -> Result<(ReceiveHandler, TcpSendHandler)> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let streams: Streams = Arc::new(Mutex::new(HashMap::new()));
+        let readers: Readers = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        // channel used for communicating about received packets.
+        let (tx, rx) = mpsc::channel();
+
+        let listener_streams = streams.clone();
+        let listener_readers = readers.clone();
+        let listener_running = running.clone();
+        let listener_tx = tx.clone();
+        let listener_handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !listener_running.load(Ordering::SeqCst) {
+                    break;
+                }
+                // The handshake is read on the thread of the stream,
+                // so a silent client does not hold back the next ones.
+                match stream {
+                    Ok(stream) => start_reading(
+                        stream,
+                        None,
+                        listener_tx.clone(),
+                        &listener_streams,
+                        &listener_running,
+                        &listener_readers,
+                    ),
+                    Err(e) => warn!("Failed to accept a TCP stream. Reason: {:?}", e),
+                }
+            }
+
+            // The streams have been shut down by the waker, so the reading threads are finishing.
+            let readers: Vec<_> = listener_readers
+                .lock()
+                .expect("TCP readers lock poisoned")
+                .drain(..)
+                .collect();
+            for (_, reader) in readers {
+                if reader.join().is_err() {
+                    error!("A TCP-reading thread panicked");
+                }
+            }
+        });
+
+        let (command_tx, command_rx) = mpsc::channel();
+        let sender_streams = streams.clone();
+        let sender_readers = readers.clone();
+        let sender_running = running.clone();
+        let sender_handle = thread::spawn(move || {
+            start_sending(
+                command_rx,
+                &sender_streams,
+                &sender_readers,
+                &sender_running,
+                udp_send_port,
+                tx,
+            );
+        });
+
+        let waker_streams = streams.clone();
+        let wake_addr =
+            SocketAddr::new(loopback_if_unspecified(local_addr.ip()), local_addr.port());
+        let receive_handler =
+            ReceiveHandler::new(rx, listener_handle, running).with_waker(move || {
+                for (_, (_, stream)) in waker_streams
+                    .lock()
+                    .expect("TCP streams lock poisoned")
+                    .drain()
+                {
+                    // The stream may already be closed by the other end.
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                // The listening thread blocks on accepting, a connection gets it to check if it should stop.
+                match TcpStream::connect(wake_addr) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        error!("Failed to wake up the TCP-listener. Reason: {:?}", e);
+                        false
+                    }
+                }
+            });
+
+        Ok((
+            receive_handler,
+            TcpSendHandler {
+                sender: Some(command_tx),
+                streams,
+                thread_handle: Some(sender_handle),
+            },
+        ))
+    }
+}
+
+// Reads the port announced by the opening end and registers the stream.
+fn accept(
+    stream: &mut TcpStream,
+    streams: &Streams,
+    running: &AtomicBool,
+) -> io::Result<(SocketAddr, usize)> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut port = [0; 2];
+    stream.read_exact(&mut port)?;
+    stream.set_read_timeout(None)?;
+    let port = u16::from(port[0]) << 8 | u16::from(port[1]);
+    let peer = SocketAddr::new(stream.peer_addr()?.ip(), port);
+
+    let (id, _) = register(peer, stream.try_clone()?, streams, running)?;
+    Ok((peer, id))
+}
+
+// Opens a stream to `connect_to` and announces our UDP-sender port.
+fn connect(connect_to: SocketAddr, udp_send_port: u16) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(connect_to)?;
+    stream.set_nodelay(true)?;
+    stream.write_all(&[(udp_send_port >> 8) as u8, udp_send_port as u8])?;
+    Ok(stream)
+}
+
+// Registers the stream with `peer`, closing the previous one, and returns it with its id.
+// No stream is registered anymore once the transport is stopping.
+fn register(
+    peer: SocketAddr,
+    stream: TcpStream,
+    streams: &Streams,
+    running: &AtomicBool,
+) -> io::Result<(usize, Arc<TcpStream>)> {
+    let mut streams = streams.lock().expect("TCP streams lock poisoned");
+    // `running` is cleared before the waker closes the streams, so none is left open.
+    if !running.load(Ordering::SeqCst) {
+        let _ = stream.shutdown(Shutdown::Both);
+        return Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "the TCP transport is stopping",
+        ));
+    }
+    let id = NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed);
+    let stream = Arc::new(stream);
+    if let Some((_, replaced)) = streams.insert(peer, (id, stream.clone())) {
+        // Stops the thread reading the previous stream of this peer.
+        let _ = replaced.shutdown(Shutdown::Both);
+    }
+    Ok((id, stream))
+}
+
+// Closes and forgets the stream with `peer`, unless it was replaced by another one already.
+fn unregister(peer: &SocketAddr, id: usize, streams: &Streams) {
+    let mut streams = streams.lock().expect("TCP streams lock poisoned");
+    if streams
+        .get(peer)
+        .map_or(false, |&(current, _)| current == id)
+    {
+        if let Some((_, stream)) = streams.remove(peer) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// Reads frames from the stream on its own thread until it is closed, then unregisters it.
+// A stream accepted by the listener, without `peer`, first reads the handshake.
+fn start_reading(
+    mut stream: TcpStream,
+    peer: Option<(SocketAddr, usize)>,
+    tx: Sender<ServerSocketEvent>,
+    streams: &Streams,
+    running: &Arc<AtomicBool>,
+    readers: &Readers,
+) {
+    let streams = streams.clone();
+    let running = running.clone();
+    let done = Arc::new(AtomicBool::new(false));
+    let finished = FinishedGuard(done.clone());
+    let reader = thread::spawn(move || {
+        let _finished = finished;
+        let (peer, id) = match peer {
+            Some(peer) => peer,
+            None => match accept(&mut stream, &streams, &running) {
+                Ok(peer) => peer,
+                Err(e) => {
+                    warn!("Failed to accept a TCP stream. Reason: {:?}", e);
+                    return;
+                }
+            },
+        };
+
+        loop {
+            match read_frame(&mut stream) {
+                Ok(payload) => {
+                    let packet = Packet::new(
+                        peer,
+                        payload.into_boxed_slice(),
+                        DeliveryMethod::ReliableOrdered,
+                    );
+                    if let Err(e) = tx.send(ServerSocketEvent::Packet(packet)) {
+                        error!("Send channel error. Reason: {:?}", e);
+                        break;
+                    }
+                }
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::UnexpectedEof {
+                        warn!("TCP stream with {} closed. Reason: {:?}", peer, e);
+                    }
+                    break;
+                }
+            }
+        }
+        unregister(&peer, id, &streams);
+    });
+
+    let mut readers = readers.lock().expect("TCP readers lock poisoned");
+    // The threads of the closed streams are joined, so a long-running server doesn't keep them.
+    let (finished, alive): (Vec<_>, Vec<_>) = readers
+        .drain(..)
+        .partition(|(done, _)| done.load(Ordering::SeqCst));
+    *readers = alive;
+    for (_, reader) in finished {
+        if reader.join().is_err() {
+            error!("A TCP-reading thread panicked");
+        }
+    }
+    readers.push((done, reader));
+}
+
+// Flags a reading thread as finished when dropped, even if it panicked.
+struct FinishedGuard(Arc<AtomicBool>);
+
+impl Drop for FinishedGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+// 1. Receives payloads from the channel containing payloads to send to some peer.
+// 2. Opens a stream to the peer if there is none yet.
+// 3. Writes the payload on the stream.
+//
+// Connecting and writing block, so they are done without holding the streams.
+fn start_sending(
+    rx: Receiver<TcpCommand>,
+    streams: &Streams,
+    readers: &Readers,
+    running: &Arc<AtomicBool>,
+    udp_send_port: u16,
+    tx: Sender<ServerSocketEvent>,
+) {
+    for command in rx.iter() {
+        let TcpCommand {
+            peer,
+            connect_to,
+            payload,
+        } = command;
+
+        let open = streams
+            .lock()
+            .expect("TCP streams lock poisoned")
+            .get(&peer)
+            .cloned();
+        let (id, stream) = match open {
+            Some(open) => open,
+            None => {
+                if !running.load(Ordering::SeqCst) {
+                    // The listener is stopping, no new stream should be opened.
+                    continue;
+                }
+                let opened = connect_to
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotConnected, "no stream with peer")
+                    })
+                    .and_then(|addr| connect(addr, udp_send_port))
+                    .and_then(|stream| {
+                        let reader = stream.try_clone()?;
+                        Ok((reader, register(peer, stream, streams, running)?))
+                    });
+                match opened {
+                    Ok((reader, (id, stream))) => {
+                        let peer = Some((peer, id));
+                        start_reading(reader, peer, tx.clone(), streams, running, readers);
+                        (id, stream)
+                    }
+                    Err(e) => {
+                        error!("Failed to open a TCP stream to {}. Reason: {:?}", peer, e);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = write_frame(&mut &*stream, &payload) {
+            error!(
+                "Something went wrong when trying to send a payload over TCP. Reason: {:?}",
+                e
+            );
+            unregister(&peer, id, streams);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io::Cursor,
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        thread::sleep,
+        time::{Duration, Instant},
+    };
+
+    use crate::server::ServerSocketEvent;
+
+    use super::{read_frame, start_reading, write_frame, Readers, TcpTransport};
+
+    #[test]
+    fn frames_are_length_prefixed() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, b"level data").unwrap();
+        write_frame(&mut buffer, b"").unwrap();
+        assert_eq!(&buffer[..4], &[0, 0, 0, 10]);
+
+        let mut cursor = Cursor::new(buffer);
+        assert_eq!(read_frame(&mut cursor).unwrap(), b"level data".to_vec());
+        assert_eq!(read_frame(&mut cursor).unwrap(), Vec::<u8>::new());
+        assert!(read_frame(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn silent_clients_do_not_block_accepting() {
+        let addr: SocketAddr = "127.0.0.1:21232".parse().unwrap();
+        let (receiver, sender) = TcpTransport::run(addr, 21233).unwrap();
+        let (other_receiver, other_sender) =
+            TcpTransport::run("127.0.0.1:21234".parse().unwrap(), 21235).unwrap();
+
+        // Connects without ever announcing its port.
+        let _silent = TcpStream::connect(addr).unwrap();
+
+        let peer: SocketAddr = "127.0.0.1:21233".parse().unwrap();
+        other_sender
+            .send(peer, Some(addr), b"level".to_vec())
+            .unwrap();
+        match receiver.receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(ServerSocketEvent::Packet(packet)) => {
+                assert_eq!(packet.addr(), "127.0.0.1:21235".parse().unwrap());
+                assert_eq!(packet.payload(), b"level");
+            }
+            _ => panic!("The payload was not received"),
+        }
+
+        // The stream is forgotten once the other end closes it.
+        let other_peer: SocketAddr = "127.0.0.1:21235".parse().unwrap();
+        assert!(sender.has_stream(&other_peer));
+        drop(other_sender);
+        drop(other_receiver);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while sender.has_stream(&other_peer) && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+        assert!(!sender.has_stream(&other_peer));
+    }
+
+    #[test]
+    fn finished_readers_are_joined() {
+        let listener = TcpListener::bind("127.0.0.1:21243").unwrap();
+        let streams = Arc::new(Mutex::new(HashMap::new()));
+        let readers: Readers = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let (tx, _rx) = mpsc::channel();
+
+        for _ in 0..3 {
+            // The client hangs up before the handshake, which ends the reading thread.
+            drop(TcpStream::connect("127.0.0.1:21243").unwrap());
+            let (stream, _) = listener.accept().unwrap();
+            start_reading(stream, None, tx.clone(), &streams, &running, &readers);
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while !readers
+                .lock()
+                .unwrap()
+                .last()
+                .unwrap()
+                .0
+                .load(Ordering::SeqCst)
+                && Instant::now() < deadline
+            {
+                sleep(Duration::from_millis(10));
+            }
+        }
+        assert_eq!(readers.lock().unwrap().len(), 1);
+    }
+}
@@ -3,15 +3,19 @@
 use crate::{
     error::Result,
     server::{
-        ClientEvent, PacketReceiving, PacketSending, ReceiveHandler, SendHandler, ServerConfig,
-        ServerSocketEvent,
+        simulation::ConditionedSocket, ClientEvent, PacketReceiving, PacketSending, ReceiveHandler,
+        SendHandler, ServerConfig, ServerSocketEvent,
     },
 };
-use laminar::{net::UdpSocket, NetworkConfig};
+use laminar::{net::UdpSocket, NetworkConfig, Packet};
 use log::{error, warn};
 use std::{
-    net::SocketAddr,
-    sync::mpsc::{self, Receiver, Sender},
+    net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
     thread,
 };
 
@@ -21,16 +25,45 @@ pub struct UdpReceiver {
     socket: UdpSocket,
     /// The max throughput in packets a host can read at once
     pub max_throughput: usize,
+    // cleared by the `ReceiveHandler` to stop the receiving thread.
+    running: Arc<AtomicBool>,
+    // relays the datagrams to the socket when bad network conditions are simulated.
+    conditioned: Option<ConditionedSocket>,
 }
 
 impl UdpReceiver {
     /// This will run the udp receiver on it's own thread.
+    ///
+    /// The datagrams go through the `NetworkSimulation` of the config, if any, before laminar gets them.
     pub fn run(addr: SocketAddr, config: &ServerConfig) -> Result<ReceiveHandler> {
-        let socket = UdpSocket::bind(&addr, NetworkConfig::default())?;
+        let running = Arc::new(AtomicBool::new(true));
+
+        let (socket, conditioned) = match config.simulation {
+            Some(ref simulation) => {
+                // laminar listens on a port of its own, behind the socket simulating the network.
+                let laminar_addr = SocketAddr::new(loopback_if_unspecified(addr.ip()), 0);
+                let socket = UdpSocket::bind(&laminar_addr, NetworkConfig::default())?;
+                let conditioned = ConditionedSocket::run(
+                    addr,
+                    socket.local_addr()?,
+                    simulation.clone(),
+                    running.clone(),
+                )?;
+                (socket, Some(conditioned))
+            }
+            None => (UdpSocket::bind(&addr, NetworkConfig::default())?, None),
+        };
+        // The port may be chosen by the OS, the waker needs the one actually bound.
+        let local_addr = match conditioned {
+            Some(ref conditioned) => conditioned.local_addr(),
+            None => socket.local_addr()?,
+        };
 
         let mut receiver = UdpReceiver {
             socket,
             max_throughput: config.max_throughput as usize,
+            running: running.clone(),
+            conditioned,
         };
 
         // channel used for communicating about received packets.
@@ -40,7 +73,33 @@ impl UdpReceiver {
             receiver.start_receiving(tx);
         });
 
-        Ok(ReceiveHandler::new(rx, thread_handle))
+        Ok(ReceiveHandler::new(rx, thread_handle, running).with_waker(udp_waker(local_addr)))
+    }
+}
+
+/// Returns a waker for a thread blocking on a UDP-socket bound to `addr`.
+///
+/// An empty packet gets the thread to check if it should stop.
+/// `addr` is the local address of the bound socket, so the port is never left to the OS.
+fn udp_waker(addr: SocketAddr) -> impl Fn() -> bool + Send + 'static {
+    let wake_addr = SocketAddr::new(loopback_if_unspecified(addr.ip()), addr.port());
+    move || {
+        let result = net::UdpSocket::bind(SocketAddr::new(wake_addr.ip(), 0))
+            .and_then(|socket| socket.send_to(&[], wake_addr));
+        if let Err(e) = result {
+            error!("Failed to wake up the UDP-receiver. Reason: {:?}", e);
+            return false;
+        }
+        true
+    }
+}
+
+/// Returns the loopback address instead of the unspecified address, to reach a socket bound to all interfaces.
+pub(crate) fn loopback_if_unspecified(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
     }
 }
 
@@ -50,10 +109,23 @@ impl PacketReceiving for UdpReceiver {
     // 3. Check if there are events from laminar.
     // 3. Notify the receiver with the received data over send channel.
     fn start_receiving(&mut self, tx: Sender<ServerSocketEvent>) {
-        loop {
+        while self.running.load(Ordering::SeqCst) {
             let result = self.socket.recv();
+            if !self.running.load(Ordering::SeqCst) {
+                // woken up to stop.
+                break;
+            }
             match result {
                 Ok(Some(packet)) => {
+                    let packet = match self.conditioned {
+                        // laminar got the packet from the relay socket of the peer.
+                        Some(ref conditioned) => Packet::new(
+                            conditioned.peer(packet.addr()),
+                            packet.payload().to_vec().into_boxed_slice(),
+                            packet.delivery_method(),
+                        ),
+                        None => packet,
+                    };
                     if let Err(e) = tx.send(ServerSocketEvent::Packet(packet)) {
                         error!("Send channel error. Reason: {:?}", e)
                     }
@@ -106,20 +178,15 @@ impl PacketSending for UdpSender {
     // 1. Receives a packets from the channel containing packets to send to some endpoint.
     // 2. Sent the packet to a specific client.
     fn start_sending(&mut self, rx: Receiver<ServerSocketEvent>) {
-        loop {
-            match rx.recv() {
-                Ok(packet) => match packet {
-                    ServerSocketEvent::Packet(packet) => {
-                        if let Err(e) = self.socket.send(&packet) {
-                            error!("Something went wrong when trying to send a packet with UDP socket. Reason: {:?}", e)
-                        }
+        // stops once the `SendHandler` hangs up the channel.
+        for packet in rx.iter() {
+            match packet {
+                ServerSocketEvent::Packet(packet) => {
+                    if let Err(e) = self.socket.send(&packet) {
+                        error!("Something went wrong when trying to send a packet with UDP socket. Reason: {:?}", e)
                     }
-                    _ => warn!("The UDP-sender can only send packets"),
-                },
-                Err(e) => error!(
-                    "Something went wrong when receiving from channel. Reason: {:?}",
-                    e
-                ),
+                }
+                _ => warn!("The UDP-sender can only send packets"),
             }
         }
     }
@@ -0,0 +1,222 @@
+//! Liveness tracking and link statistics of a network connection.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use amethyst_core::specs::{Component, VecStorage};
+
+// Amount of heartbeats taken into account when computing the packet loss.
+const LOSS_WINDOW: usize = 32;
+
+/// Statistics about the link of a `NetConnection`, kept up to date by `NetSocketSystem`.
+/// It is added automatically to every entity having a `NetConnection`.
+///
+/// Round-trip time, jitter and packet loss are measured using the heartbeats
+/// configured with `ServerConfig::heartbeat_interval`.
+#[derive(Debug, Clone)]
+pub struct NetConnectionStats {
+    rtt: Option<Duration>,
+    jitter: Duration,
+    bytes_sent: u64,
+    bytes_received: u64,
+    packets_sent: u64,
+    packets_received: u64,
+    last_received: Instant,
+    last_heartbeat: Option<Instant>,
+    next_sequence: u32,
+    // heartbeats waiting for an answer, with the instant they were sent at.
+    pending: VecDeque<(u32, Instant)>,
+    // whether the latest heartbeats were answered.
+    answered: VecDeque<bool>,
+}
+
+impl NetConnectionStats {
+    /// Creates empty statistics, considering the connection alive at `now`.
+    pub fn new(now: Instant) -> Self {
+        NetConnectionStats {
+            rtt: None,
+            jitter: Duration::from_secs(0),
+            bytes_sent: 0,
+            bytes_received: 0,
+            packets_sent: 0,
+            packets_received: 0,
+            last_received: now,
+            last_heartbeat: None,
+            next_sequence: 0,
+            pending: VecDeque::new(),
+            answered: VecDeque::new(),
+        }
+    }
+
+    /// The smoothed round-trip time, if a heartbeat has been answered yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// The smoothed variation of the round-trip time.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// The fraction of the latest heartbeats which have not been answered, between 0 and 1.
+    pub fn packet_loss(&self) -> f32 {
+        if self.answered.is_empty() {
+            return 0.0;
+        }
+        let lost = self.answered.iter().filter(|answered| !**answered).count();
+        lost as f32 / self.answered.len() as f32
+    }
+
+    /// The amount of payload bytes sent on this connection.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// The amount of payload bytes received on this connection.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// The amount of packets sent on this connection.
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    /// The amount of packets received on this connection.
+    pub fn packets_received(&self) -> u64 {
+        self.packets_received
+    }
+
+    /// The instant at which the last packet was received.
+    pub fn last_received(&self) -> Instant {
+        self.last_received
+    }
+
+    /// Returns `true` if nothing has been received for longer than `timeout`.
+    pub fn is_timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        now.duration_since(self.last_received) > timeout
+    }
+
+    pub(crate) fn record_sent(&mut self, bytes: u64) {
+        self.bytes_sent += bytes;
+        self.packets_sent += 1;
+    }
+
+    pub(crate) fn record_received(&mut self, bytes: u64, now: Instant) {
+        self.bytes_received += bytes;
+        self.packets_received += 1;
+        self.last_received = now;
+    }
+
+    /// Returns the sequence number of the heartbeat to send if one is due.
+    /// Heartbeats still unanswered at that point are counted as lost.
+    pub(crate) fn heartbeat(&mut self, now: Instant, interval: Duration) -> Option<u32> {
+        if let Some(last) = self.last_heartbeat {
+            if now.duration_since(last) < interval {
+                return None;
+            }
+        }
+
+        for _ in self.pending.drain(..) {
+            self.answered.push_back(false);
+        }
+        while self.answered.len() > LOSS_WINDOW {
+            self.answered.pop_front();
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.pending.push_back((sequence, now));
+        self.last_heartbeat = Some(now);
+        Some(sequence)
+    }
+
+    /// Updates the round-trip time and jitter with the answer to a heartbeat.
+    pub(crate) fn record_pong(&mut self, sequence: u32, now: Instant) {
+        let sent = match self.pending.iter().position(|(s, _)| *s == sequence) {
+            Some(index) => self.pending.remove(index).map(|(_, sent)| sent),
+            None => None,
+        };
+        let sample = match sent {
+            Some(sent) => now.duration_since(sent),
+            // unknown or already counted as lost.
+            None => return,
+        };
+
+        self.answered.push_back(true);
+        while self.answered.len() > LOSS_WINDOW {
+            self.answered.pop_front();
+        }
+
+        // Smoothing as done by TCP (RFC 6298).
+        match self.rtt {
+            Some(rtt) => {
+                let deviation = if sample > rtt {
+                    sample - rtt
+                } else {
+                    rtt - sample
+                };
+                self.jitter = self.jitter * 3 / 4 + deviation / 4;
+                self.rtt = Some(rtt * 7 / 8 + sample / 8);
+            }
+            None => {
+                self.jitter = sample / 2;
+                self.rtt = Some(sample);
+            }
+        }
+    }
+}
+
+impl Default for NetConnectionStats {
+    fn default() -> Self {
+        NetConnectionStats::new(Instant::now())
+    }
+}
+
+impl Component for NetConnectionStats {
+    type Storage = VecStorage<Self>;
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::NetConnectionStats;
+
+    #[test]
+    fn rtt_is_measured_from_heartbeats() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(1);
+        let mut stats = NetConnectionStats::new(start);
+
+        let first = stats.heartbeat(start, interval).unwrap();
+        assert_eq!(stats.heartbeat(start + interval / 2, interval), None);
+        stats.record_pong(first, start + Duration::from_millis(100));
+        assert_eq!(stats.rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(stats.jitter(), Duration::from_millis(50));
+
+        let second = stats.heartbeat(start + interval, interval).unwrap();
+        stats.record_pong(second, start + interval + Duration::from_millis(180));
+        assert_eq!(stats.rtt(), Some(Duration::from_millis(110)));
+        assert_eq!(stats.packet_loss(), 0.0);
+    }
+
+    #[test]
+    fn unanswered_heartbeats_count_as_lost() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(1);
+        let mut stats = NetConnectionStats::new(start);
+
+        stats.heartbeat(start, interval).unwrap();
+        let second = stats.heartbeat(start + interval, interval).unwrap();
+        stats.record_pong(second, start + interval + Duration::from_millis(50));
+        assert_eq!(stats.packet_loss(), 0.5);
+        assert!(stats.is_timed_out(start + Duration::from_secs(11), Duration::from_secs(10)));
+
+        stats.record_received(10, start + Duration::from_secs(10));
+        assert!(!stats.is_timed_out(start + Duration::from_secs(11), Duration::from_secs(10)));
+        assert_eq!(stats.bytes_received(), 10);
+    }
+}
@@ -1,13 +1,25 @@
 #[cfg(test)]
 mod test {
-    use std::{net::SocketAddr, thread::sleep, time::Duration};
+    use std::{
+        net::SocketAddr,
+        sync::mpsc::TryRecvError,
+        thread::sleep,
+        time::{Duration, Instant},
+    };
 
     use amethyst_core::{
+        bundle::SystemBundle,
         shred::{Dispatcher, DispatcherBuilder, SystemData},
-        specs::{Builder, Join, World, WriteStorage},
+        specs::{Builder, Component, Entity, Join, RunNow, VecStorage, World, WriteStorage},
     };
 
-    use crate::{server::ServerConfig, *};
+    use serde::{Deserialize, Serialize};
+    use shrev::EventChannel;
+
+    use crate::{
+        server::{NetworkSimulation, ServerConfig, UdpReceiver},
+        *,
+    };
 
     #[test]
     fn single_packet_early() {
@@ -33,7 +45,7 @@ mod test {
             msg: "1".to_string(),
         };
 
-        conn_to_server.send_buffer.single_write(test_event.clone());
+        conn_to_server.queue(test_event.clone());
         world_cl.create_entity().with(conn_to_server).build();
 
         let mut rcv = conn_to_client.receive_buffer.register_reader();
@@ -89,7 +101,7 @@ mod test {
 
             for cmp in (&mut sto).join() {
                 for _i in 0..100 {
-                    cmp.send_buffer.single_write(test_event.clone());
+                    cmp.queue(test_event.clone());
                 }
             }
         }
@@ -102,6 +114,590 @@ mod test {
         assert_eq!(comp.receive_buffer.read(&mut rcv).count(), 100);
     }
 
+    #[test]
+    fn server_mode_handshake() {
+        let server_send: SocketAddr = "127.0.0.1:21208".parse().unwrap();
+        let server_receive: SocketAddr = "127.0.0.1:21209".parse().unwrap();
+        let client_send: SocketAddr = "127.0.0.1:21210".parse().unwrap();
+        let client_receive: SocketAddr = "127.0.0.1:21211".parse().unwrap();
+
+        let (mut world_cl, mut cl_dispatch) = build_one(client_send, client_receive, None);
+        let (mut world_sv, mut sv_dispatch) =
+            build_one(server_send, server_receive, Some(Box::new(AcceptAll)));
+
+        let client_uuid = world_cl.read_resource::<NetIdentity>().uuid;
+        let server_uuid = world_sv.read_resource::<NetIdentity>().uuid;
+        let mut lifecycle_reader = world_sv
+            .write_resource::<EventChannel<NetConnectionEvent>>()
+            .register_reader();
+
+        let mut conn_to_server = NetConnection::<()>::new(server_receive, server_send);
+        conn_to_server.queue(NetEvent::Connect {
+            client_uuid,
+            receive_port: client_receive.port(),
+        });
+        let conn_to_server_entity = world_cl.create_entity().with(conn_to_server).build();
+
+        cl_dispatch.dispatch(&mut world_cl.res);
+        sleep(Duration::from_millis(500));
+        // accept the client, then send the `Connected` reply.
+        sv_dispatch.dispatch(&mut world_sv.res);
+        sv_dispatch.dispatch(&mut world_sv.res);
+        sleep(Duration::from_millis(500));
+        cl_dispatch.dispatch(&mut world_cl.res);
+
+        {
+            let channel = world_sv.read_resource::<EventChannel<NetConnectionEvent>>();
+            let events: Vec<_> = channel.read(&mut lifecycle_reader).cloned().collect();
+            assert_eq!(events.len(), 1);
+            match events[0] {
+                NetConnectionEvent::Connected { uuid, addr, .. } => {
+                    assert_eq!(uuid, client_uuid);
+                    assert_eq!(addr, client_send);
+                }
+                ref other => panic!("Unexpected lifecycle event: {:?}", other),
+            }
+
+            let connections = world_sv.read_storage::<NetConnection<()>>();
+            let identities = world_sv.read_storage::<NetIdentity>();
+            let (conn, identity) = (&connections, &identities).join().next().unwrap();
+            assert_eq!(conn.state, ConnectionState::Connected);
+            assert_eq!(conn.target_receiver, client_receive);
+            assert_eq!(identity.uuid, client_uuid);
+        }
+
+        let connections = world_cl.read_storage::<NetConnection<()>>();
+        let identities = world_cl.read_storage::<NetIdentity>();
+        assert_eq!(
+            connections.get(conn_to_server_entity).unwrap().state,
+            ConnectionState::Connected
+        );
+        assert_eq!(
+            identities.get(conn_to_server_entity).unwrap().uuid,
+            server_uuid
+        );
+    }
+
+    #[test]
+    fn server_mode_refuses_when_full() {
+        let server_send: SocketAddr = "127.0.0.1:21212".parse().unwrap();
+        let server_receive: SocketAddr = "127.0.0.1:21213".parse().unwrap();
+        let client_send: SocketAddr = "127.0.0.1:21214".parse().unwrap();
+        let client_receive: SocketAddr = "127.0.0.1:21215".parse().unwrap();
+
+        let (mut world_cl, mut cl_dispatch) = build_one(client_send, client_receive, None);
+        let mut world_sv = World::new();
+        let mut sv_dispatch = DispatcherBuilder::new()
+            .with(
+                NetSocketSystem::<()>::new(
+                    ServerConfig {
+                        udp_send_addr: server_send,
+                        udp_recv_addr: server_receive,
+                        max_clients: 0,
+                        ..Default::default()
+                    },
+                    Vec::new(),
+                )
+                .unwrap()
+                .with_server_mode(Box::new(AcceptAll)),
+                "s",
+                &[],
+            )
+            .build();
+        sv_dispatch.setup(&mut world_sv.res);
+
+        let mut conn_to_server = NetConnection::<()>::new(server_receive, server_send);
+        conn_to_server.queue(NetEvent::Connect {
+            client_uuid: world_cl.read_resource::<NetIdentity>().uuid,
+            receive_port: client_receive.port(),
+        });
+        let conn_to_server_entity = world_cl.create_entity().with(conn_to_server).build();
+
+        cl_dispatch.dispatch(&mut world_cl.res);
+        sleep(Duration::from_millis(500));
+        sv_dispatch.dispatch(&mut world_sv.res);
+        sleep(Duration::from_millis(500));
+        cl_dispatch.dispatch(&mut world_cl.res);
+
+        assert_eq!(
+            world_sv.read_storage::<NetConnection<()>>().join().count(),
+            0
+        );
+        assert_eq!(
+            world_cl
+                .read_storage::<NetConnection<()>>()
+                .get(conn_to_server_entity)
+                .unwrap()
+                .state,
+            ConnectionState::Disconnected
+        );
+    }
+
+    #[test]
+    fn server_mode_refuses_other_protocol_version() {
+        let server_send: SocketAddr = "127.0.0.1:21226".parse().unwrap();
+        let server_receive: SocketAddr = "127.0.0.1:21227".parse().unwrap();
+        let client_send: SocketAddr = "127.0.0.1:21228".parse().unwrap();
+        let client_receive: SocketAddr = "127.0.0.1:21229".parse().unwrap();
+
+        let build_versioned = |send, receive, version, server_policy: Option<AcceptAll>| {
+            let mut world = World::new();
+            let config = ServerConfig {
+                udp_send_addr: send,
+                udp_recv_addr: receive,
+                ..Default::default()
+            };
+            let mut system = NetSocketSystem::<()>::new(config, Vec::new())
+                .unwrap()
+                .with_payload_format(PayloadFormat::default().with_version(version));
+            if let Some(policy) = server_policy {
+                system = system.with_server_mode(Box::new(policy));
+            }
+            let mut dispatch = DispatcherBuilder::new().with(system, "s", &[]).build();
+            dispatch.setup(&mut world.res);
+            (world, dispatch)
+        };
+        let (mut world_cl, mut cl_dispatch) = build_versioned(client_send, client_receive, 1, None);
+        let (mut world_sv, mut sv_dispatch) =
+            build_versioned(server_send, server_receive, 2, Some(AcceptAll));
+
+        let mut conn_to_server = NetConnection::<()>::new(server_receive, server_send);
+        conn_to_server.queue(NetEvent::Connect {
+            client_uuid: world_cl.read_resource::<NetIdentity>().uuid,
+            receive_port: client_receive.port(),
+        });
+        let mut refusals = conn_to_server.receive_buffer.register_reader();
+        let conn_to_server_entity = world_cl.create_entity().with(conn_to_server).build();
+
+        cl_dispatch.dispatch(&mut world_cl.res);
+        sleep(Duration::from_millis(500));
+        sv_dispatch.dispatch(&mut world_sv.res);
+        sleep(Duration::from_millis(500));
+        cl_dispatch.dispatch(&mut world_cl.res);
+
+        assert_eq!(
+            world_sv.read_storage::<NetConnection<()>>().join().count(),
+            0
+        );
+        let storage = world_cl.read_storage::<NetConnection<()>>();
+        let conn_to_server = storage.get(conn_to_server_entity).unwrap();
+        assert_eq!(conn_to_server.state, ConnectionState::Disconnected);
+        match conn_to_server.receive_buffer.read(&mut refusals).next() {
+            Some(NetEvent::ConnectionRefused { reason }) => assert!(reason.contains("version")),
+            other => panic!("Expected a refusal, got {:?}", other),
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Health(u32);
+
+    impl Component for Health {
+        type Storage = VecStorage<Self>;
+    }
+
+    impl ReplicatedComponent for Health {
+        const NAME: &'static str = "health";
+    }
+
+    #[test]
+    fn replicate_entities_to_client() {
+        let (mut world_sv, mut sv_dispatch) = build_replication(true);
+        let (mut world_cl, mut cl_dispatch) = build_replication(false);
+        let client_uuid = world_cl.read_resource::<NetIdentity>().uuid;
+        let server_uuid = world_sv.read_resource::<NetIdentity>().uuid;
+
+        let addr: SocketAddr = "127.0.0.1:21216".parse().unwrap();
+        let mut conn_to_client = NetConnection::<()>::new(addr, addr);
+        conn_to_client.state = ConnectionState::Connected;
+        let conn_to_client = world_sv
+            .create_entity()
+            .with(conn_to_client)
+            .with(NetIdentity { uuid: client_uuid })
+            .build();
+        let mut conn_to_server = NetConnection::<()>::new(addr, addr);
+        conn_to_server.state = ConnectionState::Connected;
+        let conn_to_server = world_cl.create_entity().with(conn_to_server).build();
+
+        let server_owned = world_sv
+            .create_entity()
+            .with(ReplicatedEntity::new(server_uuid))
+            .with(Health(10))
+            .build();
+        let client_owned = ReplicatedEntity::new(client_uuid);
+        world_sv
+            .create_entity()
+            .with(client_owned)
+            .with(Health(5))
+            .build();
+
+        // Let the client register its readers before anything is received.
+        cl_dispatch.dispatch(&mut world_cl.res);
+        sv_dispatch.dispatch(&mut world_sv.res);
+        transfer(&mut world_sv, conn_to_client, &mut world_cl, conn_to_server);
+        cl_dispatch.dispatch(&mut world_cl.res);
+        world_cl.maintain();
+
+        assert_eq!(world_cl.read_resource::<NetEntityMap>().len(), 2);
+        assert_eq!(health_of(&world_cl, &client_owned.net_id), Some(Health(5)));
+
+        // The client changes the entity it owns, and the entity it does not own.
+        for health in (&mut world_cl.write_storage::<Health>()).join() {
+            health.0 = 1;
+        }
+        cl_dispatch.dispatch(&mut world_cl.res);
+        transfer(&mut world_cl, conn_to_server, &mut world_sv, conn_to_client);
+        sv_dispatch.dispatch(&mut world_sv.res);
+
+        assert_eq!(health_of(&world_sv, &client_owned.net_id), Some(Health(1)));
+        assert_eq!(
+            world_sv.read_storage::<Health>().get(server_owned).cloned(),
+            Some(Health(10))
+        );
+
+        // Removals are replicated as well.
+        world_sv.delete_entity(server_owned).unwrap();
+        world_sv.maintain();
+        sv_dispatch.dispatch(&mut world_sv.res);
+        transfer(&mut world_sv, conn_to_client, &mut world_cl, conn_to_server);
+        cl_dispatch.dispatch(&mut world_cl.res);
+        world_cl.maintain();
+
+        assert_eq!(world_cl.read_resource::<NetEntityMap>().len(), 1);
+        assert_eq!(
+            world_cl.read_storage::<ReplicatedEntity>().join().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn large_packet_over_tcp() {
+        let server_send: SocketAddr = "127.0.0.1:21217".parse().unwrap();
+        let server_receive: SocketAddr = "127.0.0.1:21218".parse().unwrap();
+        let server_tcp: SocketAddr = "127.0.0.1:21219".parse().unwrap();
+        let client_send: SocketAddr = "127.0.0.1:21220".parse().unwrap();
+        let client_receive: SocketAddr = "127.0.0.1:21221".parse().unwrap();
+        let client_tcp: SocketAddr = "127.0.0.1:21222".parse().unwrap();
+
+        let build_tcp = |send, receive, tcp| {
+            let mut world = World::new();
+            let config = ServerConfig {
+                udp_send_addr: send,
+                udp_recv_addr: receive,
+                tcp_addr: Some(tcp),
+                ..Default::default()
+            };
+            let system = NetSocketSystem::<()>::new(config, Vec::new()).unwrap();
+            let mut dispatch = DispatcherBuilder::new().with(system, "s", &[]).build();
+            dispatch.setup(&mut world.res);
+            (world, dispatch)
+        };
+        let (mut world_cl, mut cl_dispatch) = build_tcp(client_send, client_receive, client_tcp);
+        let (mut world_sv, mut sv_dispatch) = build_tcp(server_send, server_receive, server_tcp);
+
+        // Way above what fits in a single UDP packet.
+        let test_event = NetEvent::TextMessage {
+            msg: "a".repeat(200_000),
+        };
+
+        let mut conn_to_server =
+            NetConnection::<()>::new(server_receive, server_send).with_tcp(server_tcp);
+        conn_to_server
+            .send_buffer
+            .single_write(NetPacket::tcp(test_event.clone()));
+        world_cl.create_entity().with(conn_to_server).build();
+
+        let mut conn_to_client = NetConnection::<()>::new(client_receive, client_send);
+        let mut rcv = conn_to_client.receive_buffer.register_reader();
+        let conn_to_client_entity = world_sv.create_entity().with(conn_to_client).build();
+
+        cl_dispatch.dispatch(&mut world_cl.res);
+        sleep(Duration::from_millis(500));
+        sv_dispatch.dispatch(&mut world_sv.res);
+
+        let storage = world_sv.read_storage::<NetConnection<()>>();
+        let comp = storage.get(conn_to_client_entity).unwrap();
+        assert_eq!(comp.receive_buffer.read(&mut rcv).next(), Some(&test_event));
+    }
+
+    #[test]
+    fn sockets_can_be_bound_again_after_drop() {
+        let config = ServerConfig {
+            udp_send_addr: "127.0.0.1:21223".parse().unwrap(),
+            udp_recv_addr: "127.0.0.1:21224".parse().unwrap(),
+            tcp_addr: Some("127.0.0.1:21225".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let mut system = NetSocketSystem::<()>::new(config.clone(), Vec::new()).unwrap();
+        system.stop();
+        // Stopping twice is harmless.
+        system.stop();
+        drop(system);
+
+        let system = NetSocketSystem::<()>::new(config.clone(), Vec::new()).unwrap();
+        drop(system);
+        NetSocketSystem::<()>::new(config, Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn receiver_on_os_chosen_port_is_joined() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut receiver = UdpReceiver::run(addr, &ServerConfig::default()).unwrap();
+        receiver.stop();
+        // The receiving thread is gone, and with it the sending end of the channel.
+        receiver.try_iter().for_each(drop);
+        assert_eq!(
+            receiver.receiver.try_recv().err(),
+            Some(TryRecvError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn events_sent_after_stop_are_dropped() {
+        let config = ServerConfig {
+            udp_send_addr: "127.0.0.1:21236".parse().unwrap(),
+            udp_recv_addr: "127.0.0.1:21237".parse().unwrap(),
+            ..Default::default()
+        };
+        let mut system = NetSocketSystem::<()>::new(config, Vec::new()).unwrap();
+        system.stop();
+
+        let mut world = World::new();
+        let mut dispatch = DispatcherBuilder::new().with(system, "s", &[]).build();
+        dispatch.setup(&mut world.res);
+
+        let addr: SocketAddr = "127.0.0.1:21238".parse().unwrap();
+        let mut conn = NetConnection::<()>::new(addr, addr);
+        conn.state = ConnectionState::Connected;
+        conn.queue(NetEvent::TextMessage {
+            msg: "lost".to_string(),
+        });
+        world.create_entity().with(conn).build();
+        dispatch.dispatch(&mut world.res);
+    }
+
+    #[test]
+    fn reliable_ordered_events_survive_simulated_network() {
+        let server_send: SocketAddr = "127.0.0.1:21239".parse().unwrap();
+        let server_receive: SocketAddr = "127.0.0.1:21240".parse().unwrap();
+        let client_send: SocketAddr = "127.0.0.1:21241".parse().unwrap();
+        let client_receive: SocketAddr = "127.0.0.1:21242".parse().unwrap();
+
+        let mut world_sv = World::new();
+        let config = ServerConfig {
+            udp_send_addr: server_send,
+            udp_recv_addr: server_receive,
+            max_throughput: 10000,
+            simulation: Some(NetworkSimulation {
+                loss: 0.2,
+                reordering: 0.3,
+                reorder_delay: Duration::from_millis(20),
+                seed: Some(7),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let system = NetSocketSystem::<()>::new(config, Vec::new()).unwrap();
+        let mut sv_dispatch = DispatcherBuilder::new().with(system, "s", &[]).build();
+        sv_dispatch.setup(&mut world_sv.res);
+        let (mut world_cl, mut cl_dispatch) = build_one(client_send, client_receive, None);
+
+        let mut conn_to_server = NetConnection::<()>::new(server_receive, server_send);
+        for id in 0..20 {
+            conn_to_server
+                .send_buffer
+                .single_write(NetPacket::reliable_ordered(NetEvent::TextMessage {
+                    msg: id.to_string(),
+                }));
+        }
+        let conn_to_server_entity = world_cl.create_entity().with(conn_to_server).build();
+
+        let mut conn_to_client = NetConnection::<()>::new(client_receive, client_send);
+        let mut rcv = conn_to_client.receive_buffer.register_reader();
+        let conn_to_client_entity = world_sv.create_entity().with(conn_to_client).build();
+
+        // Unreliable traffic keeps going, so laminar gets the chance to resend the lost packets.
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.len() < 20 && Instant::now() < deadline {
+            world_cl
+                .write_storage::<NetConnection<()>>()
+                .get_mut(conn_to_server_entity)
+                .unwrap()
+                .send_buffer
+                .single_write(NetPacket::unreliable(NetEvent::TextMessage {
+                    msg: "filler".to_string(),
+                }));
+            cl_dispatch.dispatch(&mut world_cl.res);
+            sleep(Duration::from_millis(10));
+            sv_dispatch.dispatch(&mut world_sv.res);
+
+            let storage = world_sv.read_storage::<NetConnection<()>>();
+            let comp = storage.get(conn_to_client_entity).unwrap();
+            for event in comp.receive_buffer.read(&mut rcv) {
+                if let NetEvent::TextMessage { msg } = event {
+                    if let Ok(id) = msg.parse::<usize>() {
+                        received.push(id);
+                    }
+                }
+            }
+        }
+
+        assert_eq!(received, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn servers_are_discovered_on_loopback() {
+        let config = DiscoveryConfig {
+            addr: "127.0.0.1:21230".parse().unwrap(),
+            query_addr: "127.0.0.1:21304".parse().unwrap(),
+            interval: Duration::from_millis(0),
+            ..Default::default()
+        };
+        let server_addr: SocketAddr = "127.0.0.1:21231".parse().unwrap();
+
+        let mut world_sv = World::new();
+        let mut advertise = ServerAdvertiseSystem::new(&config).unwrap();
+        advertise.setup(&mut world_sv.res);
+        *world_sv.write_resource::<ServerInfo>() = ServerInfo {
+            name: "LAN party".to_string(),
+            max_players: 8,
+            port: server_addr.port(),
+            ..Default::default()
+        };
+
+        let mut world_cl = World::new();
+        let mut discovery = ServerDiscoverySystem::new(&config).unwrap();
+        discovery.setup(&mut world_cl.res);
+
+        advertise.run_now(&world_sv.res);
+        sleep(Duration::from_millis(200));
+        discovery.run_now(&world_cl.res);
+        {
+            let discovered = world_cl.read_resource::<DiscoveredServers>();
+            assert_eq!(discovered.len(), 1);
+            let server = discovered.get(&server_addr).unwrap();
+            assert_eq!(server.info.name, "LAN party");
+            assert_eq!(server.info.players, 0);
+        }
+
+        // The list is updated by the next advertisements.
+        world_sv.write_resource::<ServerInfo>().players = 3;
+        advertise.run_now(&world_sv.res);
+        sleep(Duration::from_millis(200));
+        discovery.run_now(&world_cl.res);
+        let discovered = world_cl.read_resource::<DiscoveredServers>();
+        assert_eq!(discovered.len(), 1);
+        assert_eq!(discovered.get(&server_addr).unwrap().info.players, 3);
+    }
+
+    #[test]
+    fn servers_answer_discovery_queries() {
+        let config = DiscoveryConfig {
+            addr: "127.0.0.1:21305".parse().unwrap(),
+            query_addr: "127.0.0.1:21306".parse().unwrap(),
+            // Only the first run advertises the server, before the client listens.
+            interval: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let server_addr: SocketAddr = "127.0.0.1:21307".parse().unwrap();
+
+        let mut world_sv = World::new();
+        let mut advertise = ServerAdvertiseSystem::new(&config).unwrap();
+        advertise.setup(&mut world_sv.res);
+        *world_sv.write_resource::<ServerInfo>() = ServerInfo {
+            name: "LAN party".to_string(),
+            port: server_addr.port(),
+            ..Default::default()
+        };
+        advertise.run_now(&world_sv.res);
+
+        // The client queries the servers once created.
+        let mut world_cl = World::new();
+        let mut discovery = ServerDiscoverySystem::new(&config).unwrap();
+        discovery.setup(&mut world_cl.res);
+        sleep(Duration::from_millis(200));
+        advertise.run_now(&world_sv.res);
+        sleep(Duration::from_millis(200));
+        discovery.run_now(&world_cl.res);
+        assert_eq!(
+            world_cl
+                .read_resource::<DiscoveredServers>()
+                .get(&server_addr)
+                .unwrap()
+                .info
+                .name,
+            "LAN party"
+        );
+
+        // And again when asked to.
+        world_sv.write_resource::<ServerInfo>().players = 3;
+        world_cl.write_resource::<DiscoveredServers>().refresh();
+        discovery.run_now(&world_cl.res);
+        sleep(Duration::from_millis(200));
+        advertise.run_now(&world_sv.res);
+        sleep(Duration::from_millis(200));
+        discovery.run_now(&world_cl.res);
+        let discovered = world_cl.read_resource::<DiscoveredServers>();
+        assert_eq!(discovered.len(), 1);
+        assert_eq!(discovered.get(&server_addr).unwrap().info.players, 3);
+    }
+
+    fn health_of(world: &World, net_id: &uuid::Uuid) -> Option<Health> {
+        let entity = world.read_resource::<NetEntityMap>().get(net_id)?;
+        world.read_storage::<Health>().get(entity).cloned()
+    }
+
+    // Moves every event queued on a connection to the receive buffer of the other end.
+    fn transfer(from: &mut World, from_conn: Entity, to: &mut World, to_conn: Entity) {
+        let mut from_storage = from.write_storage::<NetConnection<()>>();
+        let mut to_storage = to.write_storage::<NetConnection<()>>();
+        let to_conn = to_storage.get_mut(to_conn).unwrap();
+        for packet in from_storage
+            .get_mut(from_conn)
+            .unwrap()
+            .send_buffer_early_read()
+        {
+            to_conn.receive_buffer.single_write(packet.content.clone());
+        }
+    }
+
+    fn build_replication<'a, 'b>(is_server: bool) -> (World, Dispatcher<'a, 'b>) {
+        let mut world = World::new();
+        let mut builder = DispatcherBuilder::new();
+        ReplicationBundle::<()>::new(is_server)
+            .with_component::<Health>()
+            .build(&mut builder)
+            .unwrap();
+        let mut dispatch = builder.build();
+        dispatch.setup(&mut world.res);
+        (world, dispatch)
+    }
+
+    fn build_one<'a, 'b>(
+        send: SocketAddr,
+        receive: SocketAddr,
+        server_policy: Option<Box<dyn ConnectionPolicy>>,
+    ) -> (World, Dispatcher<'a, 'b>) {
+        let mut world = World::new();
+        let config = ServerConfig {
+            udp_send_addr: send,
+            udp_recv_addr: receive,
+            max_throughput: 10000,
+            ..Default::default()
+        };
+
+        let mut system = NetSocketSystem::<()>::new(config, Vec::new()).unwrap();
+        if let Some(policy) = server_policy {
+            system = system.with_server_mode(policy);
+        }
+
+        let mut dispatch = DispatcherBuilder::new().with(system, "s", &[]).build();
+        dispatch.setup(&mut world.res);
+
+        (world, dispatch)
+    }
+
     fn build<'a, 'b>(
         server_send: SocketAddr,
         server_receive: SocketAddr,
@@ -116,6 +712,7 @@ mod test {
             udp_send_addr: client_send,
             udp_recv_addr: client_receive,
             max_throughput: 10000,
+            ..Default::default()
         };
 
         // server config
@@ -123,6 +720,7 @@ mod test {
             udp_send_addr: server_send,
             udp_recv_addr: server_receive,
             max_throughput: 10000,
+            ..Default::default()
         };
 
         let mut cl_dispatch = DispatcherBuilder::new()
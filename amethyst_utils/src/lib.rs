@@ -10,6 +10,8 @@ pub mod circular_buffer;
 pub mod fps_counter;
 pub mod ortho_camera;
 pub mod removal;
+#[cfg(feature = "saveload")]
+pub mod saveload;
 pub mod scene;
 pub mod tag;
 pub mod time_destroy;
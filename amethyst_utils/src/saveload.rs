@@ -0,0 +1,946 @@
+//! Save games, writing the state of the marked entities and restoring it in a `World`.
+//!
+//! The entities marked with `SaveMarker` are written with the components of a `SaveComponents`
+//! set, a tuple of `Persistent` components. Loading a save creates new entities with these
+//! components, remapping the `Parent` links between the saved entities, and the asset `Handle`s by
+//! the source and name the assets were loaded with.
+//!
+//! Loading a save keeps the marked entities already in the `World`, unless the `SaveGame` is
+//! created `with_replace`, in which case they are deleted once the save is restored.
+//!
+//! Every save records the version of the `SaveGame` it was written with, saves of older versions
+//! go through the migrations registered with `SaveGame::with_migration` when loaded.
+//!
+//! ### Example:
+//!
+//! ```rust,ignore
+//! let save_game = SaveGame::<(Transform, Parent, Handle<Mesh>, Player)>::new(2)
+//!     .with_migration(1, add_player_health);
+//!
+//! let bytes = save_game.save(&mut world)?;
+//! let entities = save_game.load(&mut world, &bytes)?;
+//! ```
+//!
+//! The `SaveGameBundle` does the same from a system, for the files requested with the
+//! `SaveGameRequests` resource.
+//!
+//! This is not built on `specs::saveload`: its `ConvertSaveload` conversions only get the ids of
+//! the entities, while saving a `Handle` needs the `AssetStorage` to find the asset name and
+//! loading it needs the `Loader`, hence `Persistent` and its `SystemData`. Its `SimpleMarker`s
+//! also need an allocator resource to keep ids unique across saves, whereas a `SaveFile` only
+//! identifies its entities by index, and it streams the components to the serializer, leaving no
+//! whole file for the migrations to rewrite.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    marker::PhantomData,
+    path::PathBuf,
+};
+
+use log::error;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use amethyst_assets::{Asset, AssetStorage, Format, Handle, Loader, PrefabData};
+use amethyst_core::{
+    shrev::EventChannel,
+    specs::prelude::{
+        Component, DispatcherBuilder, Entities, Entity, Join, NullStorage, Read, ReadExpect,
+        Resources, System, SystemData, World, Write, WriteStorage,
+    },
+    Named, Parent, SystemBundle, Transform,
+};
+use amethyst_derive::PrefabData;
+use amethyst_error::{format_err, Error, ResultExt};
+
+/// Marks the entities written to save games.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PrefabData)]
+#[prefab(Component)]
+pub struct SaveMarker;
+
+impl Component for SaveMarker {
+    type Storage = NullStorage<Self>;
+}
+
+/// Component which can be written to save games.
+///
+/// Plain data components can use themselves as `Data`:
+///
+/// ```rust,ignore
+/// impl<'a> Persistent<'a> for Player {
+///     type SystemData = ();
+///     type Data = Self;
+///
+///     fn save(&self, _: &SaveIds, _: &mut ()) -> Result<Self, Error> {
+///         Ok(self.clone())
+///     }
+///
+///     fn load(data: Self, _: &SaveIds, _: &mut ()) -> Result<Self, Error> {
+///         Ok(data)
+///     }
+/// }
+/// ```
+pub trait Persistent<'a>: Component + Sized {
+    /// `SystemData` needed to convert the component
+    type SystemData: SystemData<'a>;
+
+    /// The serializable form of the component
+    type Data: Serialize + DeserializeOwned;
+
+    /// Convert the component to its serializable form.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `ids`: identifiers of the saved entities, for components linking to other entities
+    /// - `system_data`: `SystemData` needed to do the conversion
+    fn save(&self, ids: &SaveIds, system_data: &mut Self::SystemData) -> Result<Self::Data, Error>;
+
+    /// Restore the component from its serializable form.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `data`: the saved data
+    /// - `ids`: the entities created for the save, for components linking to other entities
+    /// - `system_data`: `SystemData` needed to do the conversion
+    fn load(
+        data: Self::Data,
+        ids: &SaveIds,
+        system_data: &mut Self::SystemData,
+    ) -> Result<Self, Error>;
+}
+
+/// Set of `Persistent` components written to save games, implemented for tuples of components.
+pub trait SaveComponents<'a> {
+    /// `SystemData` needed to save and restore the components
+    type SystemData: SystemData<'a>;
+
+    /// The serializable form of the components of an entity
+    type Data: Serialize + DeserializeOwned;
+
+    /// Convert the components of the given entity.
+    fn save(
+        entity: Entity,
+        ids: &SaveIds,
+        system_data: &mut Self::SystemData,
+    ) -> Result<Self::Data, Error>;
+
+    /// Restore the components of the given entity.
+    fn load(
+        entity: Entity,
+        data: Self::Data,
+        ids: &SaveIds,
+        system_data: &mut Self::SystemData,
+    ) -> Result<(), Error>;
+}
+
+macro_rules! impl_save_components {
+    ($($ty:ident : $i:tt),*) => {
+        impl<'a, $($ty),*> SaveComponents<'a> for ($($ty,)*)
+        where
+            $($ty: Persistent<'a>),*
+        {
+            type SystemData = ($((WriteStorage<'a, $ty>, <$ty as Persistent<'a>>::SystemData),)*);
+            type Data = ($(Option<<$ty as Persistent<'a>>::Data>,)*);
+
+            fn save(
+                entity: Entity,
+                ids: &SaveIds,
+                system_data: &mut Self::SystemData,
+            ) -> Result<Self::Data, Error> {
+                Ok(($(
+                    match (system_data.$i).0.get(entity) {
+                        Some(component) => Some(component.save(ids, &mut (system_data.$i).1)?),
+                        None => None,
+                    },
+                )*))
+            }
+
+            fn load(
+                entity: Entity,
+                data: Self::Data,
+                ids: &SaveIds,
+                system_data: &mut Self::SystemData,
+            ) -> Result<(), Error> {
+                $(
+                    if let Some(component) = data.$i {
+                        let component = <$ty as Persistent<'a>>::load(
+                            component,
+                            ids,
+                            &mut (system_data.$i).1,
+                        )?;
+                        (system_data.$i).0.insert(entity, component)?;
+                    }
+                )*
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_save_components!(A:0);
+impl_save_components!(A:0, B:1);
+impl_save_components!(A:0, B:1, C:2);
+impl_save_components!(A:0, B:1, C:2, D:3);
+impl_save_components!(A:0, B:1, C:2, D:3, E:4);
+impl_save_components!(A:0, B:1, C:2, D:3, E:4, F:5);
+impl_save_components!(A:0, B:1, C:2, D:3, E:4, F:5, G:6);
+impl_save_components!(A:0, B:1, C:2, D:3, E:4, F:5, G:6, H:7);
+impl_save_components!(A:0, B:1, C:2, D:3, E:4, F:5, G:6, H:7, I:8);
+impl_save_components!(A:0, B:1, C:2, D:3, E:4, F:5, G:6, H:7, I:8, J:9);
+
+/// Identifiers of the entities in a save game, for the components linking to other entities.
+#[derive(Debug, Default)]
+pub struct SaveIds {
+    entities: Vec<Entity>,
+    ids: HashMap<Entity, u32>,
+}
+
+impl SaveIds {
+    fn new(entities: Vec<Entity>) -> Self {
+        let ids = entities
+            .iter()
+            .enumerate()
+            .map(|(id, entity)| (*entity, id as u32))
+            .collect();
+        SaveIds { entities, ids }
+    }
+
+    /// Returns the identifier of a saved entity.
+    pub fn id(&self, entity: Entity) -> Result<u32, Error> {
+        self.ids.get(&entity).cloned().ok_or_else(|| {
+            format_err!(
+                "Entity {:?} is not saved, it needs a `SaveMarker` to be referenced",
+                entity
+            )
+        })
+    }
+
+    /// Returns the entity restored for an identifier.
+    pub fn entity(&self, id: u32) -> Result<Entity, Error> {
+        self.entities
+            .get(id as usize)
+            .cloned()
+            .ok_or_else(|| format_err!("No entity {} in the save", id))
+    }
+}
+
+/// Encoding of the save games.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaveFormat {
+    /// Human readable Ron, the default
+    Ron,
+    /// Compact binary encoding of bincode
+    Bincode,
+}
+
+impl Default for SaveFormat {
+    fn default() -> Self {
+        SaveFormat::Ron
+    }
+}
+
+impl SaveFormat {
+    /// Encode a value, for example a `SaveFile` in a migration.
+    pub fn encode<T>(self, value: &T) -> Result<Vec<u8>, Error>
+    where
+        T: Serialize,
+    {
+        match self {
+            SaveFormat::Ron => {
+                use ron::ser::{to_string_pretty, PrettyConfig};
+                Ok(to_string_pretty(value, PrettyConfig::default())
+                    .with_context(|_| format_err!("Failed serializing Ron save"))?
+                    .into_bytes())
+            }
+            SaveFormat::Bincode => Ok(bincode::serialize(value)
+                .with_context(|_| format_err!("Failed serializing bincode save"))?),
+        }
+    }
+
+    /// Decode a value, for example a `SaveFile` in a migration.
+    pub fn decode<T>(self, bytes: &[u8]) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            SaveFormat::Ron => {
+                use ron::de::Deserializer;
+                let mut d = Deserializer::from_bytes(bytes)
+                    .with_context(|_| format_err!("Failed deserializing Ron save"))?;
+                let value = T::deserialize(&mut d)
+                    .with_context(|_| format_err!("Failed parsing Ron save"))?;
+                d.end()
+                    .with_context(|_| format_err!("Failed parsing Ron save"))?;
+                Ok(value)
+            }
+            SaveFormat::Bincode => Ok(bincode::deserialize(bytes)
+                .with_context(|_| format_err!("Failed parsing bincode save"))?),
+        }
+    }
+}
+
+/// Layout of a save game, for migrations.
+///
+/// `D` is the `SaveComponents::Data` of the components saved by the version, a tuple with an
+/// `Option` of the `Persistent::Data` of every component. Entities are identified by their index.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SaveFile<D> {
+    /// The version the save was written with
+    pub version: u32,
+    /// The data of the saved entities
+    pub entities: Vec<D>,
+}
+
+// The start of a `SaveFile`, to find the migrations to apply.
+#[derive(Deserialize)]
+#[serde(rename = "SaveFile")]
+struct SaveVersion {
+    version: u32,
+}
+
+type Migration = Box<dyn Fn(Vec<u8>, SaveFormat) -> Result<Vec<u8>, Error> + Send + Sync>;
+
+/// Writes and restores save games with the components `C`, see the module documentation.
+///
+/// ### Type parameters:
+///
+/// - `C`: `SaveComponents`, a tuple of `Persistent` components
+pub struct SaveGame<C> {
+    version: u32,
+    format: SaveFormat,
+    migrations: BTreeMap<u32, Migration>,
+    replace: bool,
+    _m: PhantomData<C>,
+}
+
+impl<C> SaveGame<C> {
+    /// Create save games of the given version, in the `Ron` format.
+    pub fn new(version: u32) -> Self {
+        SaveGame {
+            version,
+            format: SaveFormat::default(),
+            migrations: BTreeMap::new(),
+            replace: false,
+            _m: PhantomData,
+        }
+    }
+
+    /// Encode the save games with the given format.
+    pub fn with_format(mut self, format: SaveFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Delete the entities marked with `SaveMarker` when a save is loaded, so the save replaces
+    /// them instead of being restored alongside them. They are only deleted once the save is
+    /// restored successfully.
+    pub fn with_replace(mut self) -> Self {
+        self.replace = true;
+        self
+    }
+
+    /// Migrate the saves of the given version to the next version.
+    ///
+    /// The migration gets an encoded `SaveFile` of the version, and returns it encoded for
+    /// `version + 1`. It can use `SaveFormat::decode` and `SaveFormat::encode` with a `SaveFile`
+    /// of each version.
+    pub fn with_migration<F>(mut self, version: u32, migration: F) -> Self
+    where
+        F: Fn(Vec<u8>, SaveFormat) -> Result<Vec<u8>, Error> + Send + Sync + 'static,
+    {
+        self.migrations.insert(version, Box::new(migration));
+        self
+    }
+
+    /// The version of the save games written.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Save the entities marked with `SaveMarker`.
+    pub fn save<'a>(&self, world: &'a mut World) -> Result<Vec<u8>, Error>
+    where
+        C: SaveComponents<'a>,
+    {
+        world.register::<SaveMarker>();
+        C::SystemData::setup(&mut world.res);
+        let world: &'a World = world;
+
+        let entities = world.entities();
+        let markers = world.read_storage::<SaveMarker>();
+        let mut system_data = C::SystemData::fetch(&world.res);
+        let saved = (&*entities, &markers).join().map(|(e, _)| e).collect();
+        self.write(saved, &mut system_data)
+    }
+
+    /// Restore a save in new entities, marked with `SaveMarker`.
+    ///
+    /// The entities already marked are kept, unless the `SaveGame` was created `with_replace`.
+    ///
+    /// Returns the created entities, in the order they were saved.
+    pub fn load<'a>(&self, world: &'a mut World, bytes: &[u8]) -> Result<Vec<Entity>, Error>
+    where
+        C: SaveComponents<'a>,
+    {
+        world.register::<SaveMarker>();
+        C::SystemData::setup(&mut world.res);
+        let world: &'a World = world;
+
+        let entities = world.entities();
+        let mut markers = world.write_storage::<SaveMarker>();
+        let mut system_data = C::SystemData::fetch(&world.res);
+        self.read(bytes, &entities, &mut markers, &mut system_data)
+    }
+
+    fn write<'a>(
+        &self,
+        saved: Vec<Entity>,
+        system_data: &mut C::SystemData,
+    ) -> Result<Vec<u8>, Error>
+    where
+        C: SaveComponents<'a>,
+    {
+        let ids = SaveIds::new(saved);
+        let entities = ids
+            .entities
+            .iter()
+            .map(|entity| C::save(*entity, &ids, system_data))
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.format.encode(&SaveFile {
+            version: self.version,
+            entities,
+        })
+    }
+
+    fn read<'a>(
+        &self,
+        bytes: &[u8],
+        entities: &Entities<'a>,
+        markers: &mut WriteStorage<'a, SaveMarker>,
+        system_data: &mut C::SystemData,
+    ) -> Result<Vec<Entity>, Error>
+    where
+        C: SaveComponents<'a>,
+    {
+        let bytes = self.migrate(bytes)?;
+        let save: SaveFile<C::Data> = self.format.decode(&bytes)?;
+
+        let replaced: Vec<Entity> = if self.replace {
+            (&**entities, &*markers).join().map(|(e, _)| e).collect()
+        } else {
+            Vec::new()
+        };
+        let ids = SaveIds::new(save.entities.iter().map(|_| entities.create()).collect());
+        let restored = ids
+            .entities
+            .iter()
+            .zip(save.entities)
+            .map(|(entity, data)| {
+                markers.insert(*entity, SaveMarker)?;
+                C::load(*entity, data, &ids, system_data)
+            })
+            .collect::<Result<(), Error>>();
+        if let Err(e) = restored {
+            for entity in &ids.entities {
+                let _ = entities.delete(*entity);
+            }
+            return Err(e);
+        }
+        for entity in replaced {
+            entities
+                .delete(entity)
+                .with_context(|_| format_err!("Failed deleting replaced entity"))?;
+        }
+        Ok(ids.entities)
+    }
+
+    // Applies the migrations from the version of the save to the current version.
+    fn migrate(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut version = self.format.decode::<SaveVersion>(bytes)?.version;
+        if version > self.version {
+            return Err(format_err!(
+                "Save version {} is newer than the supported version {}",
+                version,
+                self.version
+            ));
+        }
+
+        let mut bytes = bytes.to_vec();
+        while version < self.version {
+            let migration = self
+                .migrations
+                .get(&version)
+                .ok_or_else(|| format_err!("No migration from save version {}", version))?;
+            bytes = migration(bytes, self.format)
+                .with_context(|_| format_err!("Failed migrating save version {}", version))?;
+            version += 1;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Requests to the `SaveGameSystem`, to write and restore save game files.
+#[derive(Debug, Default)]
+pub struct SaveGameRequests {
+    requests: Vec<SaveGameRequest>,
+}
+
+#[derive(Debug)]
+enum SaveGameRequest {
+    Save(PathBuf),
+    Load(PathBuf),
+}
+
+impl SaveGameRequests {
+    /// Save the marked entities to the given file.
+    pub fn save<P>(&mut self, path: P)
+    where
+        P: Into<PathBuf>,
+    {
+        self.requests.push(SaveGameRequest::Save(path.into()));
+    }
+
+    /// Restore the save of the given file.
+    pub fn load<P>(&mut self, path: P)
+    where
+        P: Into<PathBuf>,
+    {
+        self.requests.push(SaveGameRequest::Load(path.into()));
+    }
+}
+
+/// Outcome of a request to the `SaveGameSystem`.
+#[derive(Debug)]
+pub enum SaveGameEvent {
+    /// The marked entities were saved to the file
+    Saved(PathBuf),
+    /// The save of the file was restored in the given entities
+    Loaded(PathBuf, Vec<Entity>),
+    /// The request for the file failed
+    Failed(PathBuf, Error),
+}
+
+/// System writing and restoring the save game files requested with `SaveGameRequests`,
+/// reporting the outcome with a `SaveGameEvent`.
+///
+/// ### Type parameters:
+///
+/// - `C`: `SaveComponents`, a tuple of `Persistent` components
+pub struct SaveGameSystem<C> {
+    save_game: SaveGame<C>,
+}
+
+impl<C> SaveGameSystem<C> {
+    /// Create a system using the given `SaveGame`.
+    pub fn new(save_game: SaveGame<C>) -> Self {
+        SaveGameSystem { save_game }
+    }
+}
+
+impl<'a, C> System<'a> for SaveGameSystem<C>
+where
+    C: SaveComponents<'a>,
+{
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, SaveMarker>,
+        Write<'a, SaveGameRequests>,
+        Write<'a, EventChannel<SaveGameEvent>>,
+        C::SystemData,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut markers, mut requests, mut events, mut system_data): Self::SystemData,
+    ) {
+        for request in requests.requests.drain(..) {
+            let event = match request {
+                SaveGameRequest::Save(path) => {
+                    let saved = (&*entities, &markers).join().map(|(e, _)| e).collect();
+                    let result = self
+                        .save_game
+                        .write(saved, &mut system_data)
+                        .and_then(|bytes| Ok(fs::write(&path, bytes)?));
+                    match result {
+                        Ok(()) => SaveGameEvent::Saved(path),
+                        Err(e) => SaveGameEvent::Failed(path, e),
+                    }
+                }
+                SaveGameRequest::Load(path) => {
+                    let result = fs::read(&path).map_err(Error::from).and_then(|bytes| {
+                        self.save_game
+                            .read(&bytes, &entities, &mut markers, &mut system_data)
+                    });
+                    match result {
+                        Ok(restored) => SaveGameEvent::Loaded(path, restored),
+                        Err(e) => SaveGameEvent::Failed(path, e),
+                    }
+                }
+            };
+            if let SaveGameEvent::Failed(ref path, ref e) = event {
+                error!("Save game {:?} failed: {}", path, e);
+            }
+            events.single_write(event);
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+    }
+}
+
+/// Bundle adding the `SaveGameSystem`.
+///
+/// ### Type parameters:
+///
+/// - `C`: `SaveComponents`, a tuple of `Persistent` components
+pub struct SaveGameBundle<C> {
+    save_game: SaveGame<C>,
+}
+
+impl<C> SaveGameBundle<C> {
+    /// Create a bundle using the given `SaveGame`.
+    pub fn new(save_game: SaveGame<C>) -> Self {
+        SaveGameBundle { save_game }
+    }
+}
+
+impl<'a, 'b, C> SystemBundle<'a, 'b> for SaveGameBundle<C>
+where
+    C: for<'c> SaveComponents<'c> + Send + Sync + 'static,
+{
+    fn build(self, builder: &mut DispatcherBuilder<'a, 'b>) -> Result<(), Error> {
+        builder.add(SaveGameSystem::new(self.save_game), "save_game_system", &[]);
+        Ok(())
+    }
+}
+
+/// Resource loading the assets of type `A` referenced by save games, by source and name.
+///
+/// `Handle<A>` is saved as the id of the source and the name of its asset, and needs this
+/// resource to be loaded back.
+pub struct SaveAssetFormat<A: Asset> {
+    load: Box<dyn Fn(&str, &str, &Loader, &AssetStorage<A>) -> Handle<A> + Send + Sync>,
+}
+
+impl<A: Asset> SaveAssetFormat<A> {
+    /// Load the assets with the given format, from the source they were loaded from when saved.
+    pub fn new<F>(format: F, options: F::Options) -> Self
+    where
+        F: Format<A> + Clone + Sync,
+        F::Options: Clone + Sync,
+    {
+        SaveAssetFormat {
+            load: Box::new(move |source, name, loader, storage| {
+                loader.load_from(name, format.clone(), options.clone(), source, (), storage)
+            }),
+        }
+    }
+}
+
+impl<'a, A> Persistent<'a> for Handle<A>
+where
+    A: Asset,
+{
+    type SystemData = (
+        ReadExpect<'a, Loader>,
+        Read<'a, AssetStorage<A>>,
+        Option<Read<'a, SaveAssetFormat<A>>>,
+    );
+    // the id of the source and the name of the asset.
+    type Data = (String, String);
+
+    fn save(
+        &self,
+        _: &SaveIds,
+        system_data: &mut Self::SystemData,
+    ) -> Result<(String, String), Error> {
+        let storage = &system_data.1;
+        match (storage.source(self), storage.name(self)) {
+            (Some(source), Some(name)) if name != "<Data>" => {
+                Ok((source.to_string(), name.to_string()))
+            }
+            _ => Err(format_err!(
+                "{} asset {:?} was not loaded from a file, it can't be saved",
+                A::NAME,
+                self
+            )),
+        }
+    }
+
+    fn load(
+        (source, name): (String, String),
+        _: &SaveIds,
+        system_data: &mut Self::SystemData,
+    ) -> Result<Self, Error> {
+        let format = system_data.2.as_ref().ok_or_else(|| {
+            format_err!(
+                "Loading {} asset {:?} needs a `SaveAssetFormat` resource",
+                A::NAME,
+                name
+            )
+        })?;
+        Ok((format.load)(
+            &source,
+            &name,
+            &system_data.0,
+            &system_data.1,
+        ))
+    }
+}
+
+impl<'a> Persistent<'a> for Parent {
+    type SystemData = ();
+    type Data = u32;
+
+    fn save(&self, ids: &SaveIds, _: &mut ()) -> Result<u32, Error> {
+        ids.id(self.entity)
+    }
+
+    fn load(id: u32, ids: &SaveIds, _: &mut ()) -> Result<Self, Error> {
+        Ok(Parent {
+            entity: ids.entity(id)?,
+        })
+    }
+}
+
+impl<'a> Persistent<'a> for Transform {
+    type SystemData = ();
+    type Data = Self;
+
+    fn save(&self, _: &SaveIds, _: &mut ()) -> Result<Self, Error> {
+        Ok(self.clone())
+    }
+
+    fn load(data: Self, _: &SaveIds, _: &mut ()) -> Result<Self, Error> {
+        Ok(data)
+    }
+}
+
+impl<'a> Persistent<'a> for Named {
+    type SystemData = ();
+    type Data = Self;
+
+    fn save(&self, _: &SaveIds, _: &mut ()) -> Result<Self, Error> {
+        Ok(self.clone())
+    }
+
+    fn load(data: Self, _: &SaveIds, _: &mut ()) -> Result<Self, Error> {
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use rayon::{ThreadPool, ThreadPoolBuilder};
+
+    use amethyst_assets::{Memory, ProcessingState, RonFormat};
+    use amethyst_core::{
+        specs::prelude::{Builder, VecStorage, World},
+        Named, Parent, Transform,
+    };
+
+    use super::*;
+
+    type Saved = (Named, Transform, Parent);
+
+    struct Note(String);
+
+    impl Asset for Note {
+        const NAME: &'static str = "Note";
+        type Data = String;
+        type HandleStorage = VecStorage<Handle<Self>>;
+    }
+
+    // Processes the notes until the one of the handle is loaded, returning its text.
+    fn note(world: &World, pool: &ThreadPool, handle: &Handle<Note>) -> Option<String> {
+        let mut storage = world.write_resource::<AssetStorage<Note>>();
+        for frame in 0..500 {
+            storage.process(
+                |text| Ok(ProcessingState::Loaded(Note(text))),
+                frame,
+                pool,
+                None,
+            );
+            if let Some(note) = storage.get(handle) {
+                return Some(note.0.clone());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    #[test]
+    fn saves_are_restored_with_their_links() {
+        for format in &[SaveFormat::Ron, SaveFormat::Bincode] {
+            let save_game = SaveGame::<Saved>::new(1).with_format(*format);
+            let mut world = World::new();
+            world.register::<SaveMarker>();
+            world.register::<Named>();
+            world.register::<Transform>();
+            world.register::<Parent>();
+
+            let mut moved = Transform::default();
+            moved.set_x(3.0);
+            let root = world
+                .create_entity()
+                .with(SaveMarker)
+                .with(Named::new("root"))
+                .build();
+            world
+                .create_entity()
+                .with(SaveMarker)
+                .with(Named::new("child"))
+                .with(moved.clone())
+                .with(Parent { entity: root })
+                .build();
+            world.create_entity().with(Named::new("unsaved")).build();
+
+            let bytes = save_game.save(&mut world).expect("Failed to save");
+            let mut restored = World::new();
+            let entities = save_game
+                .load(&mut restored, &bytes)
+                .expect("Failed to load");
+
+            assert_eq!(entities.len(), 2);
+            let names = restored.read_storage::<Named>();
+            assert_eq!(names.get(entities[0]).map(|n| &*n.name), Some("root"));
+            assert_eq!(names.get(entities[1]).map(|n| &*n.name), Some("child"));
+            assert_eq!(
+                restored
+                    .read_storage::<Parent>()
+                    .get(entities[1])
+                    .map(|p| p.entity),
+                Some(entities[0])
+            );
+            assert_eq!(
+                restored
+                    .read_storage::<Transform>()
+                    .get(entities[1])
+                    .map(|t| t.translation().x),
+                Some(3.0)
+            );
+            assert!(restored
+                .read_storage::<SaveMarker>()
+                .get(entities[0])
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn loading_replaces_the_marked_entities() {
+        let mut world = World::new();
+        world.register::<SaveMarker>();
+        world.register::<Named>();
+        let saved = world
+            .create_entity()
+            .with(SaveMarker)
+            .with(Named::new("saved"))
+            .build();
+        let unmarked = world.create_entity().with(Named::new("unmarked")).build();
+        let bytes = SaveGame::<(Named,)>::new(1)
+            .save(&mut world)
+            .expect("Failed to save");
+
+        let loaded = SaveGame::<(Named,)>::new(1)
+            .load(&mut world, &bytes)
+            .expect("Failed to load");
+        world.maintain();
+        assert!(world.is_alive(saved));
+        assert_eq!(world.read_storage::<SaveMarker>().join().count(), 2);
+
+        let replacing = SaveGame::<(Named,)>::new(1).with_replace();
+        let reloaded = replacing.load(&mut world, &bytes).expect("Failed to load");
+        world.maintain();
+        assert!(!world.is_alive(saved));
+        assert!(!world.is_alive(loaded[0]));
+        assert!(world.is_alive(unmarked));
+        let names = world.read_storage::<Named>();
+        assert_eq!(
+            (&world.read_storage::<SaveMarker>(), &names)
+                .join()
+                .map(|(_, n)| n.name.to_string())
+                .collect::<Vec<_>>(),
+            vec!["saved"]
+        );
+        assert_eq!(names.get(reloaded[0]).map(|n| &*n.name), Some("saved"));
+    }
+
+    #[test]
+    fn handles_are_loaded_from_their_source() {
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let mut loader = Loader::with_default_source(
+            Memory::new().with_asset("note.ron", r#""base""#),
+            pool.clone(),
+        );
+        loader.add_source("mods", Memory::new().with_asset("note.ron", r#""modded""#));
+        let mut world = World::new();
+        world.register::<SaveMarker>();
+        world.register::<Handle<Note>>();
+        world.add_resource(AssetStorage::<Note>::new());
+        world.add_resource(SaveAssetFormat::<Note>::new(RonFormat, ()));
+
+        let handle = loader.load_from(
+            "note.ron",
+            RonFormat,
+            (),
+            "mods",
+            (),
+            &world.read_resource::<AssetStorage<Note>>(),
+        );
+        world.add_resource(loader);
+        assert_eq!(note(&world, &pool, &handle), Some("modded".to_string()));
+        world.create_entity().with(SaveMarker).with(handle).build();
+
+        let save_game = SaveGame::<(Handle<Note>,)>::new(1);
+        let bytes = save_game.save(&mut world).expect("Failed to save");
+        let loaded = save_game.load(&mut world, &bytes).expect("Failed to load");
+        assert_eq!(loaded.len(), 1);
+        let handle = world
+            .read_storage::<Handle<Note>>()
+            .get(loaded[0])
+            .cloned()
+            .expect("The handle wasn't restored");
+        assert_eq!(note(&world, &pool, &handle), Some("modded".to_string()));
+    }
+
+    #[test]
+    fn old_saves_are_migrated() {
+        let old = SaveGame::<(Named,)>::new(1);
+        let mut world = World::new();
+        world.register::<Named>();
+        world.register::<SaveMarker>();
+        world
+            .create_entity()
+            .with(SaveMarker)
+            .with(Named::new("player"))
+            .build();
+        let bytes = old.save(&mut world).expect("Failed to save");
+
+        let save_game =
+            SaveGame::<(Named, Transform)>::new(2).with_migration(1, |bytes, format| {
+                let old: SaveFile<(Option<Named>,)> = format.decode(&bytes)?;
+                format.encode(&SaveFile {
+                    version: 2,
+                    entities: old
+                        .entities
+                        .into_iter()
+                        .map(|(named,)| (named, Some(Transform::default())))
+                        .collect(),
+                })
+            });
+        let mut restored = World::new();
+        let entities = save_game
+            .load(&mut restored, &bytes)
+            .expect("Failed to load");
+        assert!(restored
+            .read_storage::<Transform>()
+            .get(entities[0])
+            .is_some());
+        assert!(SaveGame::<(Named,)>::new(0)
+            .load(&mut World::new(), &bytes)
+            .is_err());
+    }
+}
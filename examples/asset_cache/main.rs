@@ -0,0 +1,47 @@
+//! Prebuilds the binary cache of the example assets, which `Loader::load_cached` loads instead of
+//! importing the asset files.
+//!
+//! ```
+//! cargo run --example asset_cache -- [assets directory] [cache directory]
+//! ```
+
+use std::{env, path::PathBuf};
+
+use amethyst::{
+    assets::{BinaryCache, CachePrebuilder, Prefab, RonFormat},
+    renderer::{Mesh, ObjFormat, PosNormTex},
+    utils::{application_root_dir, scene::BasicScenePrefab},
+    Error,
+};
+
+type MyPrefabData = BasicScenePrefab<Vec<PosNormTex>>;
+
+fn main() -> Result<(), Error> {
+    amethyst::start_logger(Default::default());
+
+    let app_root = application_root_dir()?;
+    let mut args = env::args().skip(1);
+    let assets_directory = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| app_root.join("examples/assets"));
+    let cache_directory = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| app_root.join("examples/assets_cache"));
+
+    let report = CachePrebuilder::new(BinaryCache::new(cache_directory))
+        .with_format::<Mesh, _>(".obj", ObjFormat, ())
+        .with_format::<Prefab<MyPrefabData>, _>("prefab/example.ron", RonFormat, ())
+        .build(&assets_directory)?;
+
+    for name in &report.built {
+        println!("Cached {}", name);
+    }
+    println!("{} assets already cached", report.up_to_date.len());
+    println!("{} stale cached assets removed", report.removed.len());
+    for (name, e) in &report.failed {
+        eprintln!("Failed to cache {}: {}", name, e);
+    }
+    Ok(())
+}
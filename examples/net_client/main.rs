@@ -32,13 +32,15 @@ fn main() -> Result<()> {
 pub struct State1;
 impl SimpleState for State1 {
     fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
-        data.world
-            .create_entity()
-            .with(NetConnection::<()>::new(
-                "127.0.0.1:3455".parse().unwrap(),
-                "127.0.0.1:3454".parse().unwrap(),
-            ))
-            .build();
+        let mut connection = NetConnection::<()>::new(
+            "127.0.0.1:3455".parse().unwrap(),
+            "127.0.0.1:3454".parse().unwrap(),
+        );
+        connection.queue(NetEvent::Connect {
+            client_uuid: data.world.read_resource::<NetIdentity>().uuid,
+            receive_port: 3457,
+        });
+        data.world.create_entity().with(connection).build();
     }
 }
 
@@ -67,7 +69,7 @@ impl<'a> System<'a> for SpamSystem {
                     ),
                 };
 
-                conn.send_buffer.single_write(ev);
+                conn.queue(ev);
             }
         }
     }
@@ -15,11 +15,14 @@ fn main() -> Result<()> {
     amethyst::start_logger(Default::default());
 
     let game_data = GameDataBuilder::default()
-        .with_bundle(NetworkBundle::<()>::new(
-            "127.0.0.1:3455".parse().unwrap(),
-            "127.0.0.1:3454".parse().unwrap(),
-            vec![Box::new(FilterConnected::<()>::new())],
-        ))?
+        .with_bundle(
+            NetworkBundle::<()>::new(
+                "127.0.0.1:3455".parse().unwrap(),
+                "127.0.0.1:3454".parse().unwrap(),
+                vec![Box::new(FilterConnected::<()>::new())],
+            )
+            .with_server_mode(AcceptAll),
+        )?
         .with(SpamReceiveSystem::new(), "rcv", &[]);
     let mut game = Application::build("./", State1)?
         .with_frame_limit(
@@ -31,19 +34,10 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-/// Default empty state
+/// Default empty state.
+/// The connection to the client is created once it sends a `NetEvent::Connect`.
 pub struct State1;
-impl SimpleState for State1 {
-    fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
-        data.world
-            .create_entity()
-            .with(NetConnection::<()>::new(
-                "127.0.0.1:3457".parse().unwrap(),
-                "127.0.0.1:3456".parse().unwrap(),
-            ))
-            .build();
-    }
-}
+impl SimpleState for State1 {}
 
 /// A simple system that receives a ton of network events.
 struct SpamReceiveSystem {